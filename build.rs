@@ -0,0 +1,5 @@
+fn main() {
+    // Node addons leave the N-API symbols to be resolved when Node loads them
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
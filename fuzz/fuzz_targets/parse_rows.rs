@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use transactions::fuzz::Row;
+
+fuzz_target!(|rows: Vec<Row>| transactions::fuzz::parse_rows(&rows));
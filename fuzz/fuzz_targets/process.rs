@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use transactions::transaction::Transaction;
+
+fuzz_target!(|transactions: Vec<Transaction>| transactions::fuzz::process(&transactions));
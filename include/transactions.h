@@ -0,0 +1,70 @@
+#ifndef TRANSACTIONS_H
+#define TRANSACTIONS_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The outcome of [`engine_process_row`]
+typedef enum RowStatus {
+  // The transaction was applied
+  ROW_STATUS_APPLIED = 0,
+  // The transaction was valid but not applied, e.g. because of insufficient funds
+  ROW_STATUS_REJECTED = 1,
+  // The row was a header or contained no transaction
+  ROW_STATUS_SKIPPED = 2,
+  // The row could not be parsed, or has a partner column, which is not supported
+  ROW_STATUS_MALFORMED = -1,
+  // A null pointer or a row that is not valid UTF-8 was passed
+  ROW_STATUS_INVALID_ARGUMENT = -2,
+} RowStatus;
+
+// An engine along with the columns of the rows it is handed
+typedef struct TxEngine TxEngine;
+
+// Creates an engine without any accounts. It must be freed with [`engine_free`].
+struct TxEngine *engine_new(void);
+
+// Processes one CSV row, with or without its line break.
+//
+// # Safety
+//
+// `engine` must have been returned by [`engine_new`] and not been freed yet, and
+// `row` must point to a null-terminated string.
+enum RowStatus engine_process_row(struct TxEngine *engine, const char *row);
+
+// Processes `count` CSV rows in order, writing the outcome of each to `statuses`
+// unless it is null. Returns the number of transactions applied.
+//
+// # Safety
+//
+// `engine` must have been returned by [`engine_new`] and not been freed yet,
+// `rows` must point to `count` null-terminated strings, and `statuses` must be
+// null or point to room for `count` statuses.
+size_t engine_process_rows(struct TxEngine *engine,
+                           const char *const *rows,
+                           size_t count,
+                           enum RowStatus *statuses);
+
+// Writes the accounts as a null-terminated CSV to `buffer`, unless it is null or
+// fewer than the returned number of bytes (excluding the terminator) fit into
+// `capacity`, like `snprintf`. Call with a null buffer first to learn the size.
+//
+// # Safety
+//
+// `engine` must have been returned by [`engine_new`] and not been freed yet, and
+// `buffer` must be null or point to at least `capacity` writable bytes.
+size_t engine_serialize_csv(const struct TxEngine *engine, char *buffer, size_t capacity);
+
+// Frees an engine. Does nothing if `engine` is null.
+//
+// # Safety
+//
+// `engine` must have been returned by [`engine_new`] and not been freed yet.
+void engine_free(struct TxEngine *engine);
+
+#endif  /* TRANSACTIONS_H */
@@ -1,19 +1,75 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, io, thread};
 
-use crate::transaction::ClientID;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default)]
+use crate::engine::{Activities, Engine};
+use crate::transaction::{ClientID, Transaction, TransactionType};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Account {
     pub available: f32,
     pub held: f32,
     pub total: f32,
     pub locked: bool,
+    /// Set once a transaction of the account broke a limit of the policy.
+    /// Not part of the CSV output.
+    #[serde(default)]
+    pub flagged: bool,
 }
 
 impl Account {
-    fn serialize(&self, client_id: ClientID) -> String {
-        format!(
-            "{},{},{},{},{}\n",
+    fn parse(input: &str) -> Result<(ClientID, Self), &'static str> {
+        let mut columns = input.split(',').map(str::trim);
+        let mut next = |missing| columns.next().ok_or(missing);
+
+        let client_id = next("no client ID")?
+            .parse::<ClientID>()
+            .map_err(|_| "invalid client ID")?;
+        let account = Account {
+            available: next("no available amount")?
+                .parse()
+                .map_err(|_| "invalid available amount")?,
+            held: next("no held amount")?
+                .parse()
+                .map_err(|_| "invalid held amount")?,
+            total: next("no total amount")?
+                .parse()
+                .map_err(|_| "invalid total amount")?,
+            locked: next("no locked flag")?
+                .parse()
+                .map_err(|_| "invalid locked flag")?,
+            flagged: false,
+        };
+        Ok((client_id, account))
+    }
+
+    /// Checks that the balances are consistent: `available + held == total`
+    /// (up to floating-point rounding) and `held` is not negative, as well as
+    /// `available` unless `allow_negative_available` is set. Negative available
+    /// funds can legitimately result from disputing funds that were already withdrawn.
+    pub fn check_invariants(&self, allow_negative_available: bool) -> Result<(), &'static str> {
+        let magnitude = self
+            .available
+            .abs()
+            .max(self.held.abs())
+            .max(self.total.abs())
+            .max(1.0);
+        if (self.available + self.held - self.total).abs() > magnitude * f32::EPSILON * 16.0 {
+            return Err("available + held != total");
+        }
+        if self.held < 0.0 {
+            return Err("negative held funds");
+        }
+        if !allow_negative_available && self.available < 0.0 {
+            return Err("negative available funds");
+        }
+        Ok(())
+    }
+
+    fn serialize(&self, client_id: ClientID, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
             client_id, self.available, self.held, self.total, self.locked
         )
     }
@@ -21,9 +77,346 @@ impl Account {
 
 pub fn serialize_accounts(accounts: &HashMap<ClientID, Account>) -> String {
     let mut string = String::new();
-    string.push_str("client,available,held,total,locked\n");
+    write_accounts(accounts, &mut string).expect("writing to a string cannot fail");
+    string
+}
+
+/// Like [`serialize_accounts`], writing to `out` instead
+pub fn write_accounts(
+    accounts: &HashMap<ClientID, Account>,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    out.write_str("client,available,held,total,locked\n")?;
     for (client_id, account) in accounts.iter() {
-        string.push_str(&account.serialize(*client_id));
+        account.serialize(*client_id, out)?;
+    }
+    Ok(())
+}
+
+/// A transaction applied to an account and the account after it, as kept by
+/// [`Engine::index_by_client`](crate::engine::Engine::index_by_client)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementLine {
+    pub transaction: Transaction,
+    pub account: Account,
+}
+
+/// Writes the lines of a client's statement as CSV with the columns `tx`, `type`,
+/// `amount`, `available`, `held`, `total`, and `locked`. Disputes, resolves, and
+/// chargebacks have no amount of their own.
+pub fn serialize_statement(lines: &[StatementLine]) -> String {
+    let mut string = String::from("tx,type,amount,available,held,total,locked\n");
+    for StatementLine {
+        transaction,
+        account,
+    } in lines
+    {
+        let amount = if transaction.ty.refers_back() {
+            String::new()
+        } else {
+            transaction.amount.to_string()
+        };
+        string.push_str(&format!(
+            "{},{},{amount},{},{},{},{}\n",
+            transaction.id,
+            transaction.ty.as_str(),
+            account.available,
+            account.held,
+            account.total,
+            account.locked
+        ));
     }
     string
 }
+
+/// What was applied to an account, kept up to date by the engine for
+/// [`serialize_extended_accounts`]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Activity {
+    pub deposits: u64,
+    pub deposited: f32,
+    pub withdrawals: u64,
+    pub withdrawn: f32,
+    /// Disputes opened, whether they were resolved or charged back later on
+    pub disputes: u64,
+    pub chargebacks: u64,
+}
+
+impl Activity {
+    /// Counts an applied transaction
+    pub(crate) fn record(&mut self, transaction: &Transaction) {
+        match transaction.ty {
+            TransactionType::Deposit => {
+                self.deposits += 1;
+                self.deposited += transaction.amount;
+            }
+            TransactionType::Withdrawal => {
+                self.withdrawals += 1;
+                self.withdrawn += transaction.amount;
+            }
+            TransactionType::Dispute => self.disputes += 1,
+            TransactionType::Chargeback => self.chargebacks += 1,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Like [`serialize_accounts`], with the columns of each account's [`Activity`]
+/// appended and sorted by client ID
+pub fn serialize_extended_accounts(
+    accounts: &HashMap<ClientID, Account>,
+    activity: Activities,
+) -> String {
+    let mut clients: Vec<_> = accounts.iter().collect();
+    clients.sort_unstable_by_key(|(client_id, _)| **client_id);
+
+    let mut string = String::from(EXTENDED_HEADER);
+    for (client_id, account) in clients {
+        serialize_extended(*client_id, account, activity, &mut string);
+    }
+    string
+}
+
+const EXTENDED_HEADER: &str = "client,available,held,total,locked,\
+                               deposits,deposited,withdrawals,withdrawn,disputes,chargebacks\n";
+
+/// Appends the row of an account to the output of [`serialize_extended_accounts`]
+fn serialize_extended(
+    client_id: ClientID,
+    account: &Account,
+    activity: Activities,
+    string: &mut String,
+) {
+    let activity = activity.get(client_id).cloned().unwrap_or_default();
+    account
+        .serialize(client_id, string)
+        .expect("writing to a string cannot fail");
+    string.pop(); // The line break
+    string.push_str(&format!(
+        ",{},{},{},{},{},{}\n",
+        activity.deposits,
+        activity.deposited,
+        activity.withdrawals,
+        activity.withdrawn,
+        activity.disputes,
+        activity.chargebacks
+    ));
+}
+
+/// Like [`serialize_accounts`], or [`serialize_extended_accounts`] if `activity` is
+/// given, with the rows formatted by `threads` threads at once. Each thread formats
+/// a contiguous range of clients, so the accounts come out sorted by client ID.
+pub fn serialize_accounts_in_parallel(
+    accounts: &HashMap<ClientID, Account>,
+    activity: Option<Activities>,
+    threads: usize,
+) -> String {
+    let mut clients: Vec<_> = accounts
+        .iter()
+        .map(|(client_id, account)| (*client_id, account))
+        .collect();
+    clients.sort_unstable_by_key(|(client_id, _)| *client_id);
+
+    let mut output = Vec::new();
+    stream_accounts_in_parallel(&clients, activity, threads, &mut output)
+        .expect("writing to memory cannot fail");
+    String::from_utf8(output).expect("the rows are UTF-8")
+}
+
+/// The accounts of `engine` like [`serialize_accounts`], or
+/// [`serialize_extended_accounts`] if `extended`, ordered by client ID
+pub fn serialize_engine_accounts(engine: &Engine, extended: bool) -> String {
+    let mut output = Vec::new();
+    stream_accounts(
+        engine.accounts_sorted(),
+        extended.then(|| engine.activity()),
+        &mut output,
+    )
+    .expect("writing to memory cannot fail");
+    String::from_utf8(output).expect("the rows are UTF-8")
+}
+
+/// Rows formatted before they are written out when streaming, per thread
+const ROWS_PER_BATCH: usize = 16 * 1024;
+
+/// Like [`serialize_accounts`], or [`serialize_extended_accounts`] if `activity` is
+/// given, writing the rows to `out` in batches as they are formatted, so that the
+/// output is never held in memory as a whole
+pub fn stream_accounts<'a>(
+    accounts: impl IntoIterator<Item = (ClientID, &'a Account)>,
+    activity: Option<Activities>,
+    out: &mut impl io::Write,
+) -> io::Result<()> {
+    out.write_all(header(activity.is_some()).as_bytes())?;
+    let mut accounts = accounts.into_iter().peekable();
+    let mut rows = String::new();
+    while accounts.peek().is_some() {
+        serialize_rows(accounts.by_ref().take(ROWS_PER_BATCH), activity, &mut rows);
+        out.write_all(rows.as_bytes())?;
+        rows.clear();
+    }
+    Ok(())
+}
+
+/// Like [`stream_accounts`], with the rows formatted by `threads` threads at once.
+/// The accounts are written in the order given.
+pub fn stream_accounts_in_parallel(
+    accounts: &[(ClientID, &Account)],
+    activity: Option<Activities>,
+    threads: usize,
+    out: &mut impl io::Write,
+) -> io::Result<()> {
+    out.write_all(header(activity.is_some()).as_bytes())?;
+    let threads = threads.max(1);
+    for batch in accounts.chunks(threads * ROWS_PER_BATCH) {
+        let chunk_size = batch.len().div_ceil(threads);
+        let chunks: Vec<String> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        let mut string = String::new();
+                        serialize_rows(chunk.iter().copied(), activity, &mut string);
+                        string
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("formatting thread panicked"))
+                .collect()
+        });
+        for chunk in chunks {
+            out.write_all(chunk.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// The header of [`serialize_accounts`], or of [`serialize_extended_accounts`] if
+/// `extended`
+pub(crate) fn header(extended: bool) -> &'static str {
+    if extended {
+        EXTENDED_HEADER
+    } else {
+        "client,available,held,total,locked\n"
+    }
+}
+
+/// Appends the rows of `accounts` without a header, extended by the columns of
+/// their activity if `activity` is given
+pub(crate) fn serialize_rows<'a>(
+    accounts: impl IntoIterator<Item = (ClientID, &'a Account)>,
+    activity: Option<Activities>,
+    string: &mut String,
+) {
+    for (client_id, account) in accounts {
+        match activity {
+            Some(activity) => serialize_extended(client_id, account, activity, string),
+            None => Account::serialize(account, client_id, string)
+                .expect("writing to a string cannot fail"),
+        }
+    }
+}
+
+/// Reads accounts back in from the CSV format written by [`serialize_accounts`]
+pub fn parse_accounts(
+    reader: impl io::BufRead,
+) -> Result<HashMap<ClientID, Account>, &'static str> {
+    let mut rows = reader.lines();
+
+    rows.next(); // Skip row of column types
+
+    let mut accounts = HashMap::<ClientID, Account>::new();
+    for row in rows {
+        let row = row.map_err(|_| "failed reading row")?;
+        if row.trim().is_empty() {
+            continue;
+        }
+        let (client_id, account) = Account::parse(&row)?;
+        if accounts.insert(client_id, account).is_some() {
+            return Err("duplicate client ID");
+        }
+    }
+
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::transaction::parse_transactions;
+
+    #[test]
+    fn it_counts_the_activity_of_accounts() {
+        let transactions_string = "type,       client, tx, amount\n\
+                                   deposit,    1,      1,  10.0\n\
+                                   deposit,    1,      2,  5.0\n\
+                                   withdrawal, 1,      3,  3.0\n\
+                                   withdrawal, 1,      4,  30.0\n\
+                                   dispute,    1,      2\n\
+                                   chargeback, 1,      2\n\
+                                   deposit,    2,      5,  1.0\n\
+                                   ";
+        let mut engine = Engine::default();
+        engine.track_activity();
+        for transaction in parse_transactions(io::Cursor::new(transactions_string)).unwrap() {
+            let _ = engine.process(&transaction);
+        }
+        assert_eq!(
+            serialize_extended_accounts(engine.accounts(), engine.activity()),
+            "client,available,held,total,locked,\
+             deposits,deposited,withdrawals,withdrawn,disputes,chargebacks\n\
+             1,7,0,7,true,2,15,1,3,1,1\n\
+             2,1,0,1,false,1,1,0,0,0,0\n"
+        );
+        assert_eq!(
+            serialize_accounts_in_parallel(engine.accounts(), Some(engine.activity()), 3),
+            serialize_extended_accounts(engine.accounts(), engine.activity())
+        );
+        assert_eq!(
+            serialize_accounts_in_parallel(engine.accounts(), None, 2),
+            "client,available,held,total,locked\n\
+             1,7,0,7,true\n\
+             2,1,0,1,false\n"
+        );
+        let mut streamed = Vec::new();
+        stream_accounts(
+            engine.accounts_sorted(),
+            Some(engine.activity()),
+            &mut streamed,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            serialize_extended_accounts(engine.accounts(), engine.activity())
+        );
+    }
+
+    #[test]
+    fn it_detects_invariant_violations() {
+        let inconsistent = Account {
+            available: 1.0,
+            held: 1.0,
+            total: 1.0,
+            locked: false,
+            flagged: false,
+        };
+        assert!(inconsistent.check_invariants(true).is_err());
+
+        let overdrawn = Account {
+            available: -1.0,
+            held: 2.0,
+            total: 1.0,
+            locked: false,
+            flagged: false,
+        };
+        assert!(overdrawn.check_invariants(false).is_err());
+        assert!(overdrawn.check_invariants(true).is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+//! Detection of suspicious patterns in the input, for review by a risk team.
+//! This looks at the transactions as submitted, whether they were applied or not.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalyOptions {
+    /// Flag clients with at least this many chargebacks...
+    pub min_chargebacks: u64,
+    /// ...amounting to more than this share of their deposits
+    pub max_chargeback_ratio: f64,
+    /// Number of consecutive rows in which to count each client's transactions
+    pub window: usize,
+    /// Flag clients with more than this many distinct transaction IDs within the
+    /// window
+    pub max_ids_per_window: usize,
+}
+
+impl Default for AnomalyOptions {
+    fn default() -> Self {
+        Self {
+            min_chargebacks: 2,
+            max_chargeback_ratio: 0.2,
+            window: 100,
+            max_ids_per_window: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnomalyKind {
+    /// A deposit immediately followed by a withdrawal of the same amount
+    DepositWithdrawn,
+    /// A large share of a client's deposits was charged back
+    HighChargebackRatio,
+    /// Many transaction IDs of one client in a short stretch of the input
+    Burst,
+}
+
+impl AnomalyKind {
+    pub fn code(&self) -> &'static str {
+        use AnomalyKind::*;
+
+        match self {
+            DepositWithdrawn => "deposit_withdrawn",
+            HighChargebackRatio => "high_chargeback_ratio",
+            Burst => "burst",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub client_id: ClientID,
+    pub kind: AnomalyKind,
+    /// The transaction at which the pattern was detected, if it is tied to one
+    pub tx: Option<TransactionID>,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Detector {
+    options: AnomalyOptions,
+    /// Each client's previous transaction
+    previous: HashMap<ClientID, Transaction>,
+    deposits: HashMap<ClientID, u64>,
+    chargebacks: HashMap<ClientID, u64>,
+    /// The clients and IDs of the rows within the window, oldest first
+    window: VecDeque<(ClientID, TransactionID)>,
+    /// The distinct IDs of each client within the window, with their number of rows
+    window_ids: HashMap<ClientID, HashMap<TransactionID, usize>>,
+    /// Clients flagged for a burst that has not ended yet, i.e. which have
+    /// had transactions within the window ever since
+    bursting: HashSet<ClientID>,
+    anomalies: Vec<Anomaly>,
+}
+
+impl Detector {
+    pub fn new(options: AnomalyOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    pub fn observe(&mut self, transaction: &Transaction) {
+        let client_id = transaction.client_id;
+
+        if let Some(previous) = self.previous.get(&client_id) {
+            if previous.ty == TransactionType::Deposit
+                && transaction.ty == TransactionType::Withdrawal
+                && transaction.amount >= previous.amount
+            {
+                self.anomalies.push(Anomaly {
+                    client_id,
+                    kind: AnomalyKind::DepositWithdrawn,
+                    tx: Some(transaction.id),
+                    detail: format!(
+                        "withdrew {} right after depositing {} in transaction {}",
+                        transaction.amount, previous.amount, previous.id
+                    ),
+                });
+            }
+        }
+        self.previous.insert(client_id, transaction.clone());
+
+        match transaction.ty {
+            TransactionType::Deposit => *self.deposits.entry(client_id).or_default() += 1,
+            TransactionType::Chargeback => *self.chargebacks.entry(client_id).or_default() += 1,
+            _ => {}
+        }
+
+        self.window.push_back((client_id, transaction.id));
+        let ids = self.window_ids.entry(client_id).or_default();
+        *ids.entry(transaction.id).or_default() += 1;
+        if ids.len() > self.options.max_ids_per_window && self.bursting.insert(client_id) {
+            self.anomalies.push(Anomaly {
+                client_id,
+                kind: AnomalyKind::Burst,
+                tx: Some(transaction.id),
+                detail: format!(
+                    "more than {} transaction IDs within {} rows",
+                    self.options.max_ids_per_window, self.options.window
+                ),
+            });
+        }
+        if self.window.len() > self.options.window {
+            let (oldest, id) = self.window.pop_front().unwrap();
+            let ids = self.window_ids.get_mut(&oldest).unwrap();
+            let count = ids.get_mut(&id).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ids.remove(&id);
+            }
+            if ids.is_empty() {
+                self.window_ids.remove(&oldest);
+                self.bursting.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns everything detected, ordered by client and then by when it was detected
+    pub fn finish(mut self) -> Vec<Anomaly> {
+        for (&client_id, &chargebacks) in &self.chargebacks {
+            let deposits = self.deposits.get(&client_id).copied().unwrap_or(0);
+            if chargebacks >= self.options.min_chargebacks
+                && chargebacks as f64 > deposits as f64 * self.options.max_chargeback_ratio
+            {
+                self.anomalies.push(Anomaly {
+                    client_id,
+                    kind: AnomalyKind::HighChargebackRatio,
+                    tx: None,
+                    detail: format!("{chargebacks} chargebacks for {deposits} deposits"),
+                });
+            }
+        }
+        // Stable, so the order of detection is kept per client
+        self.anomalies.sort_by_key(|anomaly| anomaly.client_id);
+        self.anomalies
+    }
+}
+
+pub fn serialize_anomalies(anomalies: &[Anomaly]) -> String {
+    let mut string = String::new();
+    string.push_str("client,anomaly,tx,detail\n");
+    for anomaly in anomalies {
+        string.push_str(&format!(
+            "{},{},{},{}\n",
+            anomaly.client_id,
+            anomaly.kind.code(),
+            anomaly.tx.map(|tx| tx.to_string()).unwrap_or_default(),
+            anomaly.detail
+        ));
+    }
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::transaction::parse_transactions;
+
+    #[test]
+    fn it_flags_suspicious_patterns() {
+        let transactions_string = "type,       client, tx, amount\n\
+                                   deposit,    1,      1,  10.0\n\
+                                   deposit,    2,      2,  5.0\n\
+                                   withdrawal, 1,      3,  10.0\n\
+                                   deposit,    2,      4,  5.0\n\
+                                   dispute,    2,      2\n\
+                                   chargeback, 2,      2\n\
+                                   dispute,    2,      4\n\
+                                   chargeback, 2,      4\n\
+                                   deposit,    3,      5,  1.0\n\
+                                   deposit,    3,      6,  1.0\n\
+                                   deposit,    3,      7,  1.0\n\
+                                   deposit,    4,      8,  1.0\n\
+                                   dispute,    4,      8\n\
+                                   resolve,    4,      8\n\
+                                   ";
+        let mut detector = Detector::new(AnomalyOptions {
+            window: 3,
+            max_ids_per_window: 2,
+            ..AnomalyOptions::default()
+        });
+        for transaction in parse_transactions(io::Cursor::new(transactions_string)).unwrap() {
+            detector.observe(&transaction);
+        }
+
+        assert_eq!(
+            serialize_anomalies(&detector.finish()),
+            "client,anomaly,tx,detail\n\
+             1,deposit_withdrawn,3,withdrew 10 right after depositing 10 in transaction 1\n\
+             2,high_chargeback_ratio,,2 chargebacks for 2 deposits\n\
+             3,burst,7,more than 2 transaction IDs within 3 rows\n"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Transactions read from Arrow record batches and accounts written to one, for
+//! data pipelines that hold their data in Arrow, like DataFusion or Polars.
+//!
+//! Batches of transactions have the columns `type` (a string), `client` and `tx`
+//! (integers), and optionally `amount` (a float or decimal, null for disputes,
+//! resolves, and chargebacks). Other columns are ignored.
+
+use std::sync::Arc;
+
+use arrow_array::{
+    cast::AsArray,
+    types::{
+        Decimal128Type, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type,
+        UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+    },
+    Array, ArrayRef, BooleanArray, Float32Array, RecordBatch, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+
+use crate::engine::{BatchResult, Engine};
+use crate::transaction::{CustomType, Transaction, TransactionType};
+
+/// The schema of [`accounts_batch`]
+pub fn accounts_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt64, false),
+        Field::new("available", DataType::Float32, false),
+        Field::new("held", DataType::Float32, false),
+        Field::new("total", DataType::Float32, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+}
+
+/// Processes the transactions of `batch` in order, like
+/// [`Engine::process_batch`]. Nothing is processed if any row of the batch is
+/// invalid. Types besides the built-in ones are accepted if `engine` has a handler
+/// for them.
+pub fn process_record_batch(
+    engine: &mut Engine,
+    batch: &RecordBatch,
+) -> Result<BatchResult, String> {
+    let transactions = transactions(batch, |ty| {
+        engine.custom_types().any(|custom_type| custom_type == ty)
+    })?;
+    Ok(engine.process_batch(&transactions))
+}
+
+/// The transactions of `batch`, of the built-in types or those `is_custom_type`
+pub fn transactions(
+    batch: &RecordBatch,
+    is_custom_type: impl Fn(&str) -> bool,
+) -> Result<Vec<Transaction>, String> {
+    let column = |name| {
+        batch
+            .column_by_name(name)
+            .ok_or_else(|| format!("no {name} column"))
+    };
+    let types = strings(column("type")?).ok_or("the type column must be of strings")?;
+    let clients = integers(column("client")?).ok_or("the client column must be of integers")?;
+    let ids = integers(column("tx")?).ok_or("the tx column must be of integers")?;
+    let amounts = match batch.column_by_name("amount") {
+        Some(column) => amounts(column).ok_or("the amount column must be of numbers")?,
+        None => vec![None; batch.num_rows()],
+    };
+
+    let mut transactions = Vec::with_capacity(batch.num_rows());
+    for row in 0..batch.num_rows() {
+        let invalid = |what| format!("invalid {what} in row {row}");
+        let ty = types[row].ok_or_else(|| invalid("transaction type"))?;
+        let ty = match TransactionType::try_from(ty) {
+            Ok(ty) => ty,
+            Err(()) if is_custom_type(ty) => TransactionType::Custom(CustomType::new(ty)),
+            Err(()) => return Err(invalid("transaction type")),
+        };
+        let client_id = clients[row]
+            .and_then(|client| client.try_into().ok())
+            .ok_or_else(|| invalid("client ID"))?;
+        let id = ids[row]
+            .and_then(|id| id.try_into().ok())
+            .ok_or_else(|| invalid("transaction ID"))?;
+        let amount = match (amounts[row], ty.refers_back()) {
+            (Some(amount), _) if !amount.is_finite() || amount < 0.0 => {
+                return Err(invalid("amount"))
+            }
+            (Some(amount), _) => amount as f32,
+            (None, true) => 0.0,
+            (None, false) => return Err(format!("missing amount in row {row}")),
+        };
+        transactions.push(Transaction {
+            ty,
+            client_id,
+            id,
+            amount,
+        });
+    }
+    Ok(transactions)
+}
+
+fn strings(array: &ArrayRef) -> Option<Vec<Option<&str>>> {
+    Some(match array.data_type() {
+        DataType::Utf8 => array.as_string::<i32>().iter().collect(),
+        DataType::LargeUtf8 => array.as_string::<i64>().iter().collect(),
+        DataType::Utf8View => array.as_string_view().iter().collect(),
+        _ => return None,
+    })
+}
+
+fn integers(array: &ArrayRef) -> Option<Vec<Option<i128>>> {
+    macro_rules! widen {
+        ($($ty:ty),*) => {
+            $(if let Some(array) = array.as_primitive_opt::<$ty>() {
+                return Some(array.iter().map(|value| value.map(i128::from)).collect());
+            })*
+        };
+    }
+    widen!(
+        Int8Type, Int16Type, Int32Type, Int64Type, UInt8Type, UInt16Type, UInt32Type, UInt64Type
+    );
+    None
+}
+
+fn amounts(array: &ArrayRef) -> Option<Vec<Option<f64>>> {
+    Some(match array.data_type() {
+        DataType::Float32 => array
+            .as_primitive::<Float32Type>()
+            .iter()
+            .map(|amount| amount.map(f64::from))
+            .collect(),
+        DataType::Float64 => array.as_primitive::<Float64Type>().iter().collect(),
+        DataType::Decimal128(_, scale) => {
+            let divisor = 10f64.powi(i32::from(*scale));
+            array
+                .as_primitive::<Decimal128Type>()
+                .iter()
+                .map(|amount| amount.map(|amount| amount as f64 / divisor))
+                .collect()
+        }
+        _ => return None,
+    })
+}
+
+/// The accounts of `engine` sorted by client ID, with the columns `client`,
+/// `available`, `held`, `total`, and `locked`
+pub fn accounts_batch(engine: &Engine) -> RecordBatch {
+    let accounts = engine.accounts_sorted();
+    let mut clients = Vec::with_capacity(accounts.len());
+    let mut available = Vec::with_capacity(accounts.len());
+    let mut held = Vec::with_capacity(accounts.len());
+    let mut total = Vec::with_capacity(accounts.len());
+    let mut locked = Vec::with_capacity(accounts.len());
+    for (client_id, account) in accounts {
+        // Client IDs are 64 bits wide with `client-id-u64`
+        #[allow(clippy::unnecessary_cast)]
+        clients.push(client_id as u64);
+        available.push(account.available);
+        held.push(account.held);
+        total.push(account.total);
+        locked.push(account.locked);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from(clients)),
+        Arc::new(Float32Array::from(available)),
+        Arc::new(Float32Array::from(held)),
+        Arc::new(Float32Array::from(total)),
+        Arc::new(BooleanArray::from(locked)),
+    ];
+    RecordBatch::try_new(accounts_schema(), columns).expect("the columns match the schema")
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Decimal128Array, Int32Array, StringArray, UInt32Array};
+
+    use super::*;
+
+    #[test]
+    fn it_processes_record_batches() {
+        let batch = |types: Vec<&str>, amounts: Vec<Option<i128>>| {
+            let rows = types.len();
+            RecordBatch::try_from_iter([
+                ("type", Arc::new(StringArray::from(types)) as ArrayRef),
+                ("client", Arc::new(Int32Array::from(vec![1; rows]))),
+                (
+                    "tx",
+                    Arc::new(UInt32Array::from_iter_values(1..=rows as u32)),
+                ),
+                (
+                    "amount",
+                    Arc::new(
+                        Decimal128Array::from(amounts)
+                            .with_precision_and_scale(10, 2)
+                            .unwrap(),
+                    ),
+                ),
+            ])
+            .unwrap()
+        };
+
+        let mut engine = Engine::default();
+        let result = process_record_batch(
+            &mut engine,
+            &batch(
+                vec!["deposit", "Withdrawal", "withdrawal"],
+                vec![Some(1050), Some(250), Some(5000)],
+            ),
+        )
+        .unwrap();
+        assert_eq!(result.applied, 2);
+        assert_eq!(result.rejections.len(), 1);
+        assert_eq!(
+            process_record_batch(&mut engine, &batch(vec!["deposit"], vec![None])),
+            Err("missing amount in row 0".to_string())
+        );
+
+        let accounts = accounts_batch(&engine);
+        assert_eq!(accounts.schema(), accounts_schema());
+        assert_eq!(
+            accounts.column(0).as_primitive::<UInt64Type>().values(),
+            &[1]
+        );
+        assert_eq!(
+            accounts.column(3).as_primitive::<Float32Type>().values(),
+            &[8.0]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Assertions about the state of an account at a point in an input, written as
+//! rows like `#assert,5,available,7.5` among the transactions, so that test
+//! cases can check what happens in the middle of a file and not just at its end.
+
+use csv::StringRecord;
+
+use crate::account::Account;
+use crate::diff::normalize_amount;
+use crate::engine::Engine;
+use crate::transaction::ClientID;
+
+/// The first field of an assertion row
+pub const MARKER: &str = "#assert";
+
+/// What an assertion expects of an account
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expectation {
+    Available(f64),
+    Held(f64),
+    Total(f64),
+    Locked(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assertion {
+    /// The line of the assertion row
+    pub line: u64,
+    pub client_id: ClientID,
+    pub expectation: Expectation,
+}
+
+impl Assertion {
+    /// Parses the fields of a row read from `line`. Returns `None` if the row is
+    /// not an assertion.
+    pub fn parse(record: &StringRecord, line: u64) -> Option<Result<Self, &'static str>> {
+        let mut fields = record.iter().map(str::trim);
+        if fields.next() != Some(MARKER) {
+            return None;
+        }
+        let mut parse = || {
+            let client_id = fields
+                .next()
+                .ok_or("no client ID in assertion")?
+                .parse()
+                .map_err(|_| "invalid client ID in assertion")?;
+            let field = fields.next().ok_or("no field in assertion")?;
+            let value = fields.next().ok_or("no value in assertion")?;
+            let amount = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|amount| amount.is_finite())
+                    .ok_or("invalid amount in assertion")
+            };
+            let expectation = match field {
+                "available" => Expectation::Available(amount()?),
+                "held" => Expectation::Held(amount()?),
+                "total" => Expectation::Total(amount()?),
+                "locked" => {
+                    Expectation::Locked(value.parse().map_err(|_| "invalid locked in assertion")?)
+                }
+                _ => return Err("expected available, held, total, or locked in assertion"),
+            };
+            if fields.any(|field| !field.is_empty()) {
+                return Err("too many fields in assertion");
+            }
+            Ok(Self {
+                line,
+                client_id,
+                expectation,
+            })
+        };
+        Some(parse())
+    }
+
+    /// Checks the account of the client in `engine`. Amounts are compared as
+    /// written by [`normalize_amount`], and a client without an account is taken
+    /// to have no funds.
+    pub fn check(&self, engine: &Engine) -> Result<(), String> {
+        let account = engine
+            .account(self.client_id)
+            .cloned()
+            .unwrap_or_else(Account::default);
+        let (field, actual, expected) = match self.expectation {
+            Expectation::Available(expected) => (
+                "available",
+                normalize_amount(account.available.into()),
+                normalize_amount(expected),
+            ),
+            Expectation::Held(expected) => (
+                "held",
+                normalize_amount(account.held.into()),
+                normalize_amount(expected),
+            ),
+            Expectation::Total(expected) => (
+                "total",
+                normalize_amount(account.total.into()),
+                normalize_amount(expected),
+            ),
+            Expectation::Locked(expected) => {
+                ("locked", account.locked.to_string(), expected.to_string())
+            }
+        };
+        match actual == expected {
+            true => Ok(()),
+            false => Err(format!(
+                "assertion at line {} failed: client {} has {field} {actual}, expected {expected}",
+                self.line, self.client_id
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{ParseOptions, TransactionReader};
+
+    #[test]
+    fn it_checks_assertions_mid_input() {
+        let record = |row: &str| StringRecord::from(row.split(',').collect::<Vec<_>>());
+        assert_eq!(Assertion::parse(&record("deposit,1,1,5"), 2), None);
+        assert_eq!(
+            Assertion::parse(&record("#assert, 5, held, 2.5"), 3),
+            Some(Ok(Assertion {
+                line: 3,
+                client_id: 5,
+                expectation: Expectation::Held(2.5),
+            }))
+        );
+        assert_eq!(
+            Assertion::parse(&record("#assert,5,frozen,true"), 3),
+            Some(Err(
+                "expected available, held, total, or locked in assertion"
+            ))
+        );
+
+        let input = "type,client,tx,amount\n\
+                     deposit,5,1,10.0\n\
+                     #assert,5,available,10\n\
+                     withdrawal,5,2,2.5\n\
+                     #assert,5,available,7.5\n\
+                     #assert,5,locked,true\n\
+                     #assert,6,total,0\n";
+        let options = ParseOptions {
+            assertions: true,
+            ..ParseOptions::default()
+        };
+        let mut reader = TransactionReader::with_options(input.as_bytes(), options).unwrap();
+        let mut engine = Engine::default();
+        let mut results = Vec::new();
+        loop {
+            let transaction = reader.next();
+            for assertion in reader.take_assertions() {
+                results.push(assertion.check(&engine));
+            }
+            match transaction {
+                Some(transaction) => engine.process(&transaction.unwrap()).unwrap(),
+                None => break,
+            }
+        }
+        assert_eq!(
+            results,
+            [
+                Ok(()),
+                Ok(()),
+                Err(
+                    "assertion at line 6 failed: client 5 has locked false, expected true"
+                        .to_string()
+                ),
+                Ok(()),
+            ]
+        );
+        assert_eq!(reader.rows_skipped, 4);
+    }
+}
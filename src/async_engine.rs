@@ -0,0 +1,251 @@
+//! An engine running on a tokio task, fed from any number of asynchronous sources
+//! at once, so that servers need no blocking thread per connection (see the
+//! `async` feature). Each source is parsed on the task reading it, and only the
+//! parsed transactions are handed to the engine.
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::engine::Engine;
+use crate::report::Summary;
+use crate::transaction::{
+    Encoding, IdFormat, ParseOptions, RowParser, Transaction, BYTE_ORDER_MARK, READ_FAILED,
+    ROW_EXCEEDS_LIMITS,
+};
+
+/// How many rows may wait for the engine before sources have to wait as well, if
+/// not specified otherwise
+pub const QUEUE_LENGTH: usize = 1024;
+
+const ENGINE_STOPPED: &str = "the engine task stopped";
+const PARTNER_UNSUPPORTED: &str = "inputs with a partner column are not supported";
+const STRING_IDS_UNSUPPORTED: &str = "string transaction IDs are not supported";
+
+enum Command {
+    /// A parsed row, which may contain no transaction, with its timestamp
+    Row(Option<(Transaction, Option<u64>)>),
+    Query(Box<dyn FnOnce(&Engine) + Send>),
+}
+
+/// An engine processing transactions on its own task. Must be created within a
+/// tokio runtime.
+pub struct AsyncEngine {
+    handle: EngineHandle,
+    task: JoinHandle<(Engine, Summary)>,
+}
+
+impl AsyncEngine {
+    /// Moves `engine` to a new task, parsing the rows of sources with `options`.
+    /// Rows of the types the engine has handlers for are accepted as well.
+    pub fn spawn(engine: Engine, options: ParseOptions) -> Self {
+        Self::with_queue_length(engine, options, QUEUE_LENGTH)
+    }
+
+    /// Like [`AsyncEngine::spawn`], pausing the reading of sources while
+    /// `queue_length` rows wait for the engine
+    pub fn with_queue_length(
+        mut engine: Engine,
+        mut options: ParseOptions,
+        queue_length: usize,
+    ) -> Self {
+        options
+            .custom_types
+            .extend(engine.custom_types().map(String::from));
+        let (commands, mut receiver) = mpsc::channel(queue_length);
+        let task = tokio::spawn(async move {
+            let mut summary = Summary::default();
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    Command::Row(None) => summary.rows_skipped += 1,
+                    Command::Row(Some((transaction, timestamp))) => {
+                        summary.rows_parsed += 1;
+                        if let Some(timestamp) = timestamp {
+                            engine.set_clock(timestamp);
+                        }
+                        summary.record(&transaction, engine.process(&transaction));
+                    }
+                    Command::Query(query) => query(&engine),
+                }
+            }
+            summary.finish(&engine);
+            (engine, summary)
+        });
+        Self {
+            handle: EngineHandle { commands, options },
+            task,
+        }
+    }
+
+    /// A handle for feeding and querying the engine from other tasks
+    pub fn handle(&self) -> EngineHandle {
+        self.handle.clone()
+    }
+
+    /// See [`EngineHandle::ingest`]
+    pub async fn ingest(&self, source: impl AsyncBufRead + Unpin) -> Result<(), String> {
+        self.handle.ingest(source).await
+    }
+
+    /// See [`EngineHandle::query`]
+    pub async fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Engine) -> T + Send + 'static,
+    ) -> Result<T, String> {
+        self.handle.query(query).await
+    }
+
+    /// Waits for the rows handed over so far to be processed and returns the engine
+    /// with the summary of all sources. Handles still alive keep the engine from
+    /// finishing.
+    pub async fn finish(self) -> Result<(Engine, Summary), String> {
+        drop(self.handle);
+        self.task.await.map_err(|_| ENGINE_STOPPED.to_string())
+    }
+}
+
+/// Hands rows to an [`AsyncEngine`]
+#[derive(Clone)]
+pub struct EngineHandle {
+    commands: mpsc::Sender<Command>,
+    options: ParseOptions,
+}
+
+impl EngineHandle {
+    /// Reads a CSV input from `source` until its end, handing its transactions to
+    /// the engine. The first row may be a header, which then determines the order
+    /// of the columns of this source. Fails on the first malformed row, after
+    /// which the rows before it stay processed.
+    pub async fn ingest(&self, mut source: impl AsyncBufRead + Unpin) -> Result<(), String> {
+        if self.options.ids == IdFormat::String {
+            // The numbering of IDs would differ between sources
+            return Err(STRING_IDS_UNSUPPORTED.to_string());
+        }
+        let mut parser: Option<RowParser> = None;
+        let mut buffer = Vec::new();
+        for line in 1.. {
+            let malformed = |err| format!("{err} at line {line}");
+            buffer.clear();
+            // At most the longest row allowed, its line break, and a byte to tell
+            // whether it is longer
+            let limit = self.options.max_line_length.saturating_add(3) as u64;
+            let read = (&mut source)
+                .take(limit)
+                .read_until(b'\n', &mut buffer)
+                .await
+                .map_err(|_| malformed(READ_FAILED))?;
+            if read == 0 {
+                break;
+            }
+            if buffer.ends_with(b"\n") {
+                buffer.pop();
+                if buffer.ends_with(b"\r") {
+                    buffer.pop();
+                }
+            }
+            if buffer.len() > self.options.max_line_length {
+                return Err(malformed(ROW_EXCEEDS_LIMITS));
+            }
+            let row = match self.options.encoding {
+                Encoding::Utf8 => String::from_utf8(buffer.clone())
+                    .map_err(|_| malformed("row is not valid UTF-8"))?,
+                // Every byte of Latin-1 is the code point of the same value
+                Encoding::Latin1 => buffer.iter().map(|byte| char::from(*byte)).collect(),
+            };
+            let row = match line {
+                1 => row.strip_prefix(BYTE_ORDER_MARK).unwrap_or(&row),
+                _ => &row,
+            };
+
+            let parser = match &mut parser {
+                Some(parser) => parser,
+                // Blank lines and comments may precede the header
+                None if self.options.is_ignored(row) => continue,
+                None => {
+                    let (new_parser, is_header) =
+                        RowParser::from_first_row(row, self.options.clone()).map_err(malformed)?;
+                    if new_parser.has_partner_column() {
+                        return Err(PARTNER_UNSUPPORTED.to_string());
+                    }
+                    let parser = parser.insert(new_parser);
+                    if is_header {
+                        continue;
+                    }
+                    parser
+                }
+            };
+            let transaction = parser.parse(row, &mut None).map_err(malformed)?;
+            let row = transaction.map(|transaction| (transaction, parser.timestamp(row)));
+            self.send(Command::Row(row)).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs `query` on the engine once the rows handed over before are processed
+    pub async fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Engine) -> T + Send + 'static,
+    ) -> Result<T, String> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Command::Query(Box::new(move |engine| {
+            // The caller may have stopped waiting for the answer
+            let _ = sender.send(query(engine));
+        })))
+        .await?;
+        receiver.await.map_err(|_| ENGINE_STOPPED.to_string())
+    }
+
+    async fn send(&self, command: Command) -> Result<(), String> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| ENGINE_STOPPED.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Rejection;
+
+    #[test]
+    fn it_processes_several_sources_at_once() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // Sources wait for the engine after every row
+            let engine =
+                AsyncEngine::with_queue_length(Engine::default(), ParseOptions::default(), 1);
+            let first = "type, client, tx, amount\n\
+                         deposit, 1, 1, 5.0\n\
+                         withdrawal, 1, 2, 9.0\n";
+            // Without a header, and with the columns in the default order
+            let second = "\n\
+                          deposit, 2, 3, 2.5\r\n\
+                          withdrawal, 2, 4, 1.5";
+            let (first, second) = tokio::join!(
+                engine.ingest(first.as_bytes()),
+                engine.ingest(second.as_bytes())
+            );
+            assert_eq!((first, second), (Ok(()), Ok(())));
+            assert_eq!(
+                engine
+                    .query(|engine| engine.account(2).unwrap().total)
+                    .await,
+                Ok(1.0)
+            );
+            assert_eq!(
+                engine.ingest("deposit, x, 5, 1.0".as_bytes()).await,
+                Err("invalid client ID at line 1".to_string())
+            );
+
+            let (engine, summary) = engine.finish().await.unwrap();
+            assert_eq!(engine.account(1).unwrap().total, 5.0);
+            assert_eq!(summary.rows_parsed, 4);
+            assert_eq!(summary.rejected[&Rejection::InsufficientFunds], 1);
+        });
+    }
+}
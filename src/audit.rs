@@ -0,0 +1,100 @@
+//! An append-only record of actions taken for compliance reasons, one JSON object per line.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::compression::{CompressedWriter, Compression};
+use crate::transaction::Transaction;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEntry<'a> {
+    /// What happened, like `blocked` for a transaction of a blocklisted client
+    pub event: &'static str,
+    /// What was done about it, like `rejected` or `locked`
+    pub action: &'static str,
+    /// The line of the input the transaction was read from
+    pub line: u64,
+    /// The partner the transaction was submitted by, which together with the
+    /// transaction ID identifies it in inputs with a `partner` column
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partner: Option<&'a str>,
+    #[serde(flatten)]
+    pub transaction: &'a Transaction,
+    /// The row's columns besides the known ones, like a memo
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub columns: BTreeMap<String, String>,
+}
+
+pub struct AuditLog {
+    writer: CompressedWriter<BufWriter<fs::File>>,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `path` for appending, creating it if necessary. With
+    /// `compression`, the entries of each run are appended as a compressed stream of
+    /// their own, which decompressors read as one.
+    pub fn open(path: &Path, compression: Option<Compression>) -> Result<Self, String> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("could not open audit log: {err}"))?;
+        let writer = CompressedWriter::new(BufWriter::new(file), compression)
+            .map_err(|err| format!("could not open audit log: {err}"))?;
+        Ok(Self { writer })
+    }
+
+    pub fn record(&mut self, entry: &AuditEntry) -> Result<(), String> {
+        let json = serde_json::to_string(entry).expect("audit entries are always serializable");
+        writeln!(self.writer, "{json}").map_err(|err| format!("could not write audit log: {err}"))
+    }
+
+    /// Writes the buffered entries, ending a compressed block so that what was
+    /// written so far can be decompressed
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .map_err(|err| format!("could not write audit log: {err}"))
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.writer
+            .finish()
+            .map(drop)
+            .map_err(|err| format!("could not write audit log: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tx;
+    use crate::transaction::TransactionType;
+
+    #[test]
+    fn it_namespaces_entries_by_partner() {
+        let transaction = tx(TransactionType::Deposit, 1, 7, 5.0);
+        let entry = |partner| AuditEntry {
+            event: "blocked",
+            action: "rejected",
+            line: 2,
+            partner,
+            transaction: &transaction,
+            columns: BTreeMap::new(),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&entry(Some("acme"))).unwrap(),
+            r#"{"event":"blocked","action":"rejected","line":2,"partner":"acme","type":"deposit","client":1,"tx":7,"amount":5.0}"#
+        );
+        assert!(!serde_json::to_string(&entry(None))
+            .unwrap()
+            .contains("partner"));
+    }
+}
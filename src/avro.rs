@@ -0,0 +1,357 @@
+//! Avro container files, which embed their schema, as inputs of transactions and
+//! as output of accounts.
+//!
+//! An input is a file of records with the fields of the columns of a CSV input,
+//! like `type`, `client`, `tx`, and `amount`. Its records are turned into CSV rows
+//! and parsed by a [`TransactionReader`](crate::transaction::TransactionReader),
+//! so they are validated like any other input. Fields can be strings, enums,
+//! numbers including decimals, booleans, timestamps, and unions of these with
+//! `null`, which is read as an empty field.
+
+use std::io::{self, Read};
+
+use apache_avro::{
+    schema::{DecimalSchema, RecordField},
+    types::Value,
+    Reader, Schema, Writer,
+};
+
+use crate::account::Account;
+use crate::engine::{Activities, Engine};
+use crate::partner::Partitions;
+use crate::transaction::ClientID;
+
+/// The records of an Avro container file, read as CSV rows with a header of the
+/// names of the record's fields
+pub struct AvroRows<R: Read> {
+    records: Reader<'static, R>,
+    /// The scale of each field that is a decimal
+    scales: Vec<Option<usize>>,
+    /// The header or the row of the record read last
+    row: Vec<u8>,
+    /// How much of `row` was read
+    position: usize,
+}
+
+impl<R: Read> AvroRows<R> {
+    pub fn new(reader: R) -> Result<Self, String> {
+        let records = Reader::new(reader).map_err(|err| format!("invalid Avro file: {err}"))?;
+        let Schema::Record(schema) = records.writer_schema() else {
+            return Err("the records of the Avro file must be of a record schema".to_string());
+        };
+        let scales = schema.fields.iter().map(decimal_scale).collect();
+        let mut row = Vec::new();
+        for (index, field) in schema.fields.iter().enumerate() {
+            push_field(&mut row, index, &field.name);
+        }
+        row.push(b'\n');
+        Ok(Self {
+            records,
+            scales,
+            row,
+            position: 0,
+        })
+    }
+
+    /// Replaces `row` with the row of the next record, returning false at the end
+    fn next_row(&mut self) -> io::Result<bool> {
+        let fields = match self.records.next() {
+            None => return Ok(false),
+            Some(Ok(Value::Record(fields))) => fields,
+            Some(Ok(_)) => return Err(io::Error::other("expected an Avro record")),
+            Some(Err(err)) => return Err(io::Error::other(format!("invalid Avro record: {err}"))),
+        };
+        self.row.clear();
+        self.position = 0;
+        for (index, (name, value)) in fields.into_iter().enumerate() {
+            let scale = self.scales.get(index).copied().flatten();
+            let field = field_text(value, scale)
+                .map_err(|err| io::Error::other(format!("field {name}: {err}")))?;
+            push_field(&mut self.row, index, &field);
+        }
+        self.row.push(b'\n');
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for AvroRows<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.row.len() && !self.next_row()? {
+            return Ok(0);
+        }
+        let length = buf.len().min(self.row.len() - self.position);
+        buf[..length].copy_from_slice(&self.row[self.position..][..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+fn decimal_scale(field: &RecordField) -> Option<usize> {
+    let scale = |schema: &Schema| match schema {
+        Schema::Decimal(DecimalSchema { scale, .. }) => Some(*scale),
+        _ => None,
+    };
+    match &field.schema {
+        Schema::Union(union) => union.variants().iter().find_map(scale),
+        schema => scale(schema),
+    }
+}
+
+/// Appends a field to a CSV row, quoted if necessary
+fn push_field(row: &mut Vec<u8>, index: usize, field: &str) {
+    if index > 0 {
+        row.push(b',');
+    }
+    if field.contains([',', '"', '\n', '\r']) {
+        row.push(b'"');
+        row.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+        row.push(b'"');
+    } else {
+        row.extend_from_slice(field.as_bytes());
+    }
+}
+
+/// The text of a field as it would be written in a CSV input. Timestamps are in
+/// seconds since the Unix epoch, like the `timestamp` column.
+fn field_text(value: Value, scale: Option<usize>) -> Result<String, &'static str> {
+    Ok(match value {
+        Value::Null => String::new(),
+        Value::Boolean(boolean) => boolean.to_string(),
+        Value::Int(int) => int.to_string(),
+        Value::Long(long) => long.to_string(),
+        Value::Float(float) => float.to_string(),
+        Value::Double(double) => double.to_string(),
+        Value::String(string) | Value::Enum(_, string) => string,
+        Value::Union(_, value) => field_text(*value, scale)?,
+        Value::Decimal(decimal) => {
+            let bytes = Vec::<u8>::try_from(&decimal).map_err(|_| "invalid decimal")?;
+            decimal_text(&bytes, scale.ok_or("decimal without a scale")?)?
+        }
+        Value::BigDecimal(decimal) => decimal.to_string(),
+        Value::TimestampMillis(millis) | Value::LocalTimestampMillis(millis) => {
+            millis.div_euclid(1000).to_string()
+        }
+        Value::TimestampMicros(micros) | Value::LocalTimestampMicros(micros) => {
+            micros.div_euclid(1_000_000).to_string()
+        }
+        Value::TimestampNanos(nanos) | Value::LocalTimestampNanos(nanos) => {
+            nanos.div_euclid(1_000_000_000).to_string()
+        }
+        _ => return Err("unsupported type"),
+    })
+}
+
+/// Formats the big-endian two's complement `bytes` of an unscaled decimal
+fn decimal_text(bytes: &[u8], scale: usize) -> Result<String, &'static str> {
+    if bytes.len() > 16 {
+        return Err("decimal out of range");
+    }
+    let negative = bytes.first().is_some_and(|byte| byte & 0x80 != 0);
+    let mut extended = [if negative { 0xFF } else { 0 }; 16];
+    extended[16 - bytes.len()..].copy_from_slice(bytes);
+    let unscaled = i128::from_be_bytes(extended);
+
+    let digits = format!("{:0>width$}", unscaled.unsigned_abs(), width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+    let sign = if negative { "-" } else { "" };
+    Ok(match fraction {
+        "" => format!("{sign}{integer}"),
+        fraction => format!("{sign}{integer}.{fraction}"),
+    })
+}
+
+/// The schema of written accounts, with a leading `partner` field if `partner`
+/// and the fields of [`Activity`] if `extended`
+fn accounts_schema(partner: bool, extended: bool) -> Schema {
+    let mut fields = Vec::new();
+    if partner {
+        fields.push(("partner", "string"));
+    }
+    fields.extend([
+        ("client", "long"),
+        ("available", "float"),
+        ("held", "float"),
+        ("total", "float"),
+        ("locked", "boolean"),
+    ]);
+    if extended {
+        fields.extend([
+            ("deposits", "long"),
+            ("deposited", "float"),
+            ("withdrawals", "long"),
+            ("withdrawn", "float"),
+            ("disputes", "long"),
+            ("chargebacks", "long"),
+        ]);
+    }
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, ty)| format!(r#"{{"name":"{name}","type":"{ty}"}}"#))
+        .collect();
+    Schema::parse_str(&format!(
+        r#"{{"type":"record","name":"Account","namespace":"transactions","fields":[{}]}}"#,
+        fields.join(",")
+    ))
+    .expect("the schema of accounts is valid")
+}
+
+/// Writes the accounts of `engine` as an Avro container file, sorted by client ID
+/// and extended by their activity if `extended`
+pub fn serialize_accounts(engine: &Engine, extended: bool) -> Result<Vec<u8>, String> {
+    let schema = accounts_schema(false, extended);
+    let mut writer = Writer::new(&schema, Vec::new()).map_err(|err| err.to_string())?;
+    append_accounts(&mut writer, None, engine, extended)?;
+    writer.into_inner().map_err(|err| err.to_string())
+}
+
+/// Like [`serialize_accounts`], with a leading `partner` field
+pub fn serialize_partitioned_accounts(
+    partitions: &Partitions,
+    extended: bool,
+) -> Result<Vec<u8>, String> {
+    let schema = accounts_schema(true, extended);
+    let mut writer = Writer::new(&schema, Vec::new()).map_err(|err| err.to_string())?;
+    for (partner, engine) in partitions.iter() {
+        append_accounts(&mut writer, Some(partner), engine, extended)?;
+    }
+    writer.into_inner().map_err(|err| err.to_string())
+}
+
+fn append_accounts(
+    writer: &mut Writer<Vec<u8>>,
+    partner: Option<&str>,
+    engine: &Engine,
+    extended: bool,
+) -> Result<(), String> {
+    let activity = extended.then(|| engine.activity());
+    for (client_id, account) in engine.accounts_sorted() {
+        let record = account_record(partner, client_id, account, activity)?;
+        writer.append_value(record).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn account_record(
+    partner: Option<&str>,
+    client_id: ClientID,
+    account: &Account,
+    activity: Option<Activities>,
+) -> Result<Value, String> {
+    // Client IDs are 64 bits wide with `client-id-u64`
+    #[allow(clippy::unnecessary_cast)]
+    let client = i64::try_from(client_id as u64)
+        .map_err(|_| format!("client ID {client_id} is too large for an Avro long"))?;
+    let count = |count: u64| Value::Long(count.try_into().unwrap_or(i64::MAX));
+
+    let mut fields = Vec::new();
+    if let Some(partner) = partner {
+        fields.push(("partner", Value::String(partner.to_string())));
+    }
+    fields.extend([
+        ("client", Value::Long(client)),
+        ("available", Value::Float(account.available)),
+        ("held", Value::Float(account.held)),
+        ("total", Value::Float(account.total)),
+        ("locked", Value::Boolean(account.locked)),
+    ]);
+    if let Some(activity) = activity {
+        let activity = activity.get(client_id).cloned().unwrap_or_default();
+        fields.extend([
+            ("deposits", count(activity.deposits)),
+            ("deposited", Value::Float(activity.deposited)),
+            ("withdrawals", count(activity.withdrawals)),
+            ("withdrawn", Value::Float(activity.withdrawn)),
+            ("disputes", count(activity.disputes)),
+            ("chargebacks", count(activity.chargebacks)),
+        ]);
+    }
+    Ok(Value::Record(
+        fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_reads_transactions_and_writes_accounts() {
+        let schema = Schema::parse_str(
+            r#"{"type": "record", "name": "Transaction", "fields": [
+                {"name": "type", "type": {"type": "enum", "name": "Type",
+                    "symbols": ["deposit", "withdrawal", "dispute"]}},
+                {"name": "client", "type": "int"},
+                {"name": "tx", "type": "long"},
+                {"name": "amount", "type": ["null",
+                    {"type": "bytes", "logicalType": "decimal", "precision": 10, "scale": 4}]},
+                {"name": "memo", "type": "string"}
+            ]}"#,
+        )
+        .unwrap();
+        let mut writer = Writer::new(&schema, Vec::new()).unwrap();
+        let record = |ty: (u32, &str), tx, amount: Option<i64>, memo: &str| {
+            let amount = match amount {
+                Some(amount) => Value::Union(
+                    1,
+                    Box::new(Value::Decimal(amount.to_be_bytes().to_vec().into())),
+                ),
+                None => Value::Union(0, Box::new(Value::Null)),
+            };
+            Value::Record(vec![
+                ("type".to_string(), Value::Enum(ty.0, ty.1.to_string())),
+                ("client".to_string(), Value::Int(1)),
+                ("tx".to_string(), Value::Long(tx)),
+                ("amount".to_string(), amount),
+                ("memo".to_string(), Value::String(memo.to_string())),
+            ])
+        };
+        writer
+            .append_value(record((0, "deposit"), 1, Some(52_500), "salary, June"))
+            .unwrap();
+        writer
+            .append_value(record((1, "withdrawal"), 2, Some(20_000), ""))
+            .unwrap();
+        writer
+            .append_value(record((2, "dispute"), 1, None, ""))
+            .unwrap();
+        let file = writer.into_inner().unwrap();
+
+        let mut rows = String::new();
+        AvroRows::new(&file[..])
+            .unwrap()
+            .read_to_string(&mut rows)
+            .unwrap();
+        assert_eq!(
+            rows,
+            "type,client,tx,amount,memo\n\
+             deposit,1,1,5.2500,\"salary, June\"\n\
+             withdrawal,1,2,2.0000,\n\
+             dispute,1,1,,\n"
+        );
+
+        let mut engine = Engine::default();
+        let reader = TransactionReader::new(io::BufReader::new(AvroRows::new(&file[..]).unwrap()));
+        for transaction in reader.unwrap() {
+            engine.process(&transaction.unwrap()).unwrap();
+        }
+        let accounts = serialize_accounts(&engine, false).unwrap();
+        let records: Vec<Value> = Reader::new(&accounts[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            records,
+            [Value::Record(vec![
+                ("client".to_string(), Value::Long(1)),
+                ("available".to_string(), Value::Float(-2.0)),
+                ("held".to_string(), Value::Float(5.25)),
+                ("total".to_string(), Value::Float(3.25)),
+                ("locked".to_string(), Value::Boolean(false)),
+            ])]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! ISO 20022 camt.053 bank-to-customer statements as input. Every booked entry of
+//! a statement becomes a deposit if it credits the account and a withdrawal if it
+//! debits it, for the client the account is mapped to in a TOML file:
+//!
+//! ```toml
+//! DE89370400440532013000 = 1
+//! ```
+//!
+//! Accounts are identified by their IBAN, or by their other ID if they have no
+//! IBAN. Entries are turned into a CSV input, with the bank's reference as the
+//! transaction ID, so they are checked like any other input.
+
+use std::collections::HashMap;
+
+use quick_xml::{escape::resolve_predefined_entity, events::Event, Reader};
+
+use crate::report::day;
+use crate::transaction::ClientID;
+
+/// Parses the client of each account from a TOML table of IBANs or other IDs
+pub fn parse_accounts(text: &str) -> Result<HashMap<String, ClientID>, String> {
+    toml::from_str(text).map_err(|err| err.to_string())
+}
+
+/// An entry of a statement
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Entry {
+    /// The IBAN or other ID of the statement's account
+    pub account: String,
+    /// Whether the entry credits the account rather than debiting it
+    pub credit: bool,
+    pub amount: String,
+    pub currency: Option<String>,
+    /// The reference of the account servicer, or the entry reference if there is
+    /// none
+    pub reference: Option<String>,
+    /// The booking date in seconds since the Unix epoch
+    pub booked_at: Option<u64>,
+    pub end_to_end_id: Option<String>,
+    /// The unstructured remittance information
+    pub remittance: Option<String>,
+    /// Whether the entry is booked, as opposed to pending or informational
+    pub booked: bool,
+}
+
+/// Reads the entries of all statements of a camt.053 document
+pub fn read_entries(xml: &str) -> Result<Vec<Entry>, String> {
+    let mut reader = Reader::from_str(xml);
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut account = String::new();
+    let mut entry: Option<Entry> = None;
+    let mut entries = Vec::new();
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|err| format!("invalid XML at byte {}: {err}", reader.error_position()))?;
+        match event {
+            Event::Start(start) => {
+                let name = start.local_name().as_ref().to_string();
+                if name == "Ntry" {
+                    entry = Some(Entry {
+                        account: account.clone(),
+                        booked: true,
+                        ..Entry::default()
+                    });
+                } else if name == "Amt" && path.last().is_some_and(|parent| parent == "Ntry") {
+                    let currency = start
+                        .try_get_attribute("Ccy")
+                        .map_err(|err| err.to_string())?
+                        .map(|currency| currency.value.into_owned());
+                    if let Some(entry) = &mut entry {
+                        entry.currency = currency;
+                    }
+                }
+                path.push(name);
+                text.clear();
+            }
+            Event::Text(content) => text.push_str(&content),
+            Event::CData(content) => text.push_str(&content),
+            Event::GeneralRef(reference) => {
+                match reference
+                    .resolve_char_ref()
+                    .map_err(|err| err.to_string())?
+                {
+                    Some(char) => text.push(char),
+                    None => text.push_str(
+                        resolve_predefined_entity(&reference)
+                            .ok_or_else(|| format!("unknown entity &{};", &*reference))?,
+                    ),
+                }
+            }
+            Event::End(_) => {
+                let value = text.trim();
+                let path_ends_with = |suffix: &[&str]| {
+                    path.len() >= suffix.len()
+                        && path[path.len() - suffix.len()..]
+                            .iter()
+                            .zip(suffix)
+                            .all(|(name, expected)| name == expected)
+                };
+                if path_ends_with(&["Stmt", "Acct", "Id", "IBAN"])
+                    || path_ends_with(&["Stmt", "Acct", "Id", "Othr", "Id"])
+                {
+                    account = value.to_string();
+                } else if path_ends_with(&["Stmt", "Ntry"]) {
+                    entries.extend(entry.take());
+                } else if let Some(entry) = &mut entry {
+                    read_entry_field(entry, &path, value)?;
+                }
+                path.pop();
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// Sets the field of `entry` at `path` to `value`
+fn read_entry_field(entry: &mut Entry, path: &[String], value: &str) -> Result<(), String> {
+    let Some(start) = path.iter().rposition(|name| name == "Ntry") else {
+        return Ok(());
+    };
+    let field: Vec<&str> = path[start + 1..].iter().map(String::as_str).collect();
+    match field[..] {
+        ["Amt"] => entry.amount = value.to_string(),
+        ["CdtDbtInd"] => {
+            entry.credit = match value {
+                "CRDT" => true,
+                "DBIT" => false,
+                _ => return Err(format!("invalid credit or debit indicator {value}")),
+            }
+        }
+        // Older versions have the code directly in `Sts`
+        ["Sts"] | ["Sts", "Cd"] if !value.is_empty() => entry.booked = value == "BOOK",
+        ["BookgDt", "Dt"] => {
+            let day = day(value).ok_or_else(|| format!("invalid booking date {value}"))?;
+            entry.booked_at = Some(day * 86_400);
+        }
+        ["BookgDt", "DtTm"] => {
+            entry.booked_at =
+                Some(date_time(value).ok_or_else(|| format!("invalid booking date {value}"))?);
+        }
+        ["AcctSvcrRef"] => entry.reference = Some(value.to_string()),
+        ["NtryRef"] => {
+            entry.reference.get_or_insert_with(|| value.to_string());
+        }
+        ["NtryDtls", "TxDtls", "Refs", "EndToEndId"] => {
+            entry.end_to_end_id.get_or_insert_with(|| value.to_string());
+        }
+        ["NtryDtls", "TxDtls", "RmtInf", "Ustrd"] => {
+            entry.remittance.get_or_insert_with(|| value.to_string());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Parses an ISO 8601 date and time like `2024-06-01T10:15:00+02:00` into seconds
+/// since the Unix epoch. Fractions of seconds are ignored, and times without an
+/// offset are taken to be UTC.
+fn date_time(value: &str) -> Option<u64> {
+    let (date, time) = value.split_once('T')?;
+    let number = |range: std::ops::Range<usize>| time.get(range)?.parse::<u64>().ok();
+    let (hours, minutes, seconds) = (number(0..2)?, number(3..5)?, number(6..8)?);
+    let rest = time.get(8..)?;
+    let rest = rest.trim_start_matches(|char: char| char == '.' || char.is_ascii_digit());
+    let offset = match rest {
+        "" | "Z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours: i64 = rest.get(1..3)?.parse().ok()?;
+            let minutes: i64 = rest.get(4..6)?.parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+    let local = day(date)? * 86_400 + hours * 3600 + minutes * 60 + seconds;
+    local.checked_add_signed(-offset)
+}
+
+/// The booked entries as a CSV input with the columns `type`, `client`, `tx`,
+/// `amount`, `currency`, `end_to_end_id`, and `remittance`, plus `timestamp` if
+/// every entry has a booking date
+pub fn to_csv(entries: &[Entry], accounts: &HashMap<String, ClientID>) -> Result<String, String> {
+    let entries: Vec<&Entry> = entries.iter().filter(|entry| entry.booked).collect();
+    let timestamps = entries.iter().all(|entry| entry.booked_at.is_some());
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut header = vec![
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "currency",
+        "end_to_end_id",
+        "remittance",
+    ];
+    if timestamps {
+        header.push("timestamp");
+    }
+    writer
+        .write_record(&header)
+        .map_err(|err| err.to_string())?;
+    for entry in entries {
+        let client = accounts
+            .get(&entry.account)
+            .ok_or_else(|| format!("no client for account {}", entry.account))?;
+        let reference = entry
+            .reference
+            .as_deref()
+            .ok_or_else(|| format!("entry of {} without a reference", entry.account))?;
+        let mut record = vec![
+            if entry.credit {
+                "deposit"
+            } else {
+                "withdrawal"
+            }
+            .to_string(),
+            client.to_string(),
+            reference.to_string(),
+            entry.amount.clone(),
+            entry.currency.clone().unwrap_or_default(),
+            entry.end_to_end_id.clone().unwrap_or_default(),
+            entry.remittance.clone().unwrap_or_default(),
+        ];
+        if let Some(booked_at) = entry.booked_at.filter(|_| timestamps) {
+            record.push(booked_at.to_string());
+        }
+        writer
+            .write_record(&record)
+            .map_err(|err| err.to_string())?;
+    }
+    let bytes = writer.into_inner().map_err(|err| err.to_string())?;
+    Ok(String::from_utf8(bytes).expect("the fields are strings"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_statement_entries_to_transactions() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.08">
+              <BkToCstmrStmt>
+                <Stmt>
+                  <Acct><Id><IBAN>DE89370400440532013000</IBAN></Id></Acct>
+                  <Bal><Amt Ccy="EUR">100.00</Amt></Bal>
+                  <Ntry>
+                    <Amt Ccy="EUR">250.00</Amt>
+                    <CdtDbtInd>CRDT</CdtDbtInd>
+                    <Sts><Cd>BOOK</Cd></Sts>
+                    <BookgDt><Dt>2024-06-01</Dt></BookgDt>
+                    <AcctSvcrRef>REF-1</AcctSvcrRef>
+                    <NtryDtls><TxDtls>
+                      <Refs><EndToEndId>E2E-1</EndToEndId></Refs>
+                      <RmtInf><Ustrd>Invoice 7, June &amp; July</Ustrd></RmtInf>
+                    </TxDtls></NtryDtls>
+                  </Ntry>
+                  <Ntry>
+                    <Amt Ccy="EUR">40.5</Amt>
+                    <CdtDbtInd>DBIT</CdtDbtInd>
+                    <Sts><Cd>BOOK</Cd></Sts>
+                    <BookgDt><DtTm>2024-06-02T10:00:00+02:00</DtTm></BookgDt>
+                    <NtryRef>REF-2</NtryRef>
+                  </Ntry>
+                  <Ntry>
+                    <Amt Ccy="EUR">1.00</Amt>
+                    <CdtDbtInd>CRDT</CdtDbtInd>
+                    <Sts><Cd>PDNG</Cd></Sts>
+                    <AcctSvcrRef>REF-3</AcctSvcrRef>
+                  </Ntry>
+                </Stmt>
+              </BkToCstmrStmt>
+            </Document>"#;
+        let entries = read_entries(xml).unwrap();
+        assert_eq!(entries.len(), 3);
+        let accounts = parse_accounts("DE89370400440532013000 = 7").unwrap();
+        assert_eq!(
+            to_csv(&entries, &accounts).unwrap(),
+            "type,client,tx,amount,currency,end_to_end_id,remittance,timestamp\n\
+             deposit,7,REF-1,250.00,EUR,E2E-1,\"Invoice 7, June & July\",1717200000\n\
+             withdrawal,7,REF-2,40.5,EUR,,,1717315200\n"
+        );
+        assert_eq!(
+            to_csv(&entries, &HashMap::new()),
+            Err("no client for account DE89370400440532013000".to_string())
+        );
+    }
+}
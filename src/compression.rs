@@ -0,0 +1,149 @@
+//! Outputs written as gzip or zstd streams. Without the `compression` feature,
+//! there is no compression to choose and outputs are written as they are.
+
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+
+#[cfg(feature = "compression")]
+use flate2::write::GzEncoder;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "compression")]
+    Gzip,
+    #[cfg(feature = "compression")]
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            #[cfg(feature = "compression")]
+            "gzip" => Ok(Compression::Gzip),
+            #[cfg(feature = "compression")]
+            "zstd" => Ok(Compression::Zstd),
+            #[cfg(not(feature = "compression"))]
+            "gzip" | "zstd" => Err("compression needs a build with --features compression"),
+            _ => Err("expected gzip or zstd"),
+        }
+    }
+}
+
+impl Compression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            #[cfg(feature = "compression")]
+            Compression::Gzip => "gzip",
+            #[cfg(feature = "compression")]
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// The extension of files compressed this way, like `gz`
+    pub fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "compression")]
+            Compression::Gzip => "gz",
+            #[cfg(feature = "compression")]
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Compresses `bytes` in one go
+    pub fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        let mut writer =
+            CompressedWriter::new(Vec::new(), Some(self)).expect("writing to memory does not fail");
+        writer
+            .write_all(bytes)
+            .expect("writing to memory does not fail");
+        writer.finish().expect("writing to memory does not fail")
+    }
+}
+
+/// A writer compressing what is written to it, or passing it on unchanged
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    #[cfg(feature = "compression")]
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "compression")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Option<Compression>) -> io::Result<Self> {
+        Ok(match compression {
+            None => CompressedWriter::Plain(writer),
+            #[cfg(feature = "compression")]
+            Some(Compression::Gzip) => {
+                CompressedWriter::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            #[cfg(feature = "compression")]
+            Some(Compression::Zstd) => CompressedWriter::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Ends the compressed stream and returns the underlying writer. Without this,
+    /// the stream is cut off.
+    pub fn finish(self) -> io::Result<W> {
+        // Only plain writers are left without the compression feature
+        #[allow(clippy::infallible_destructuring_match)]
+        let mut writer = match self {
+            CompressedWriter::Plain(writer) => writer,
+            #[cfg(feature = "compression")]
+            CompressedWriter::Gzip(encoder) => encoder.finish()?,
+            #[cfg(feature = "compression")]
+            CompressedWriter::Zstd(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "compression")]
+            CompressedWriter::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "compression")]
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Plain(writer) => writer.flush(),
+            #[cfg(feature = "compression")]
+            CompressedWriter::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "compression")]
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn it_compresses_streams() {
+        let text = "client,available,held,total,locked\n1,1.5,0,1.5,false\n".repeat(100);
+
+        let gzip = Compression::Gzip.compress(text.as_bytes());
+        assert!(gzip.len() < text.len());
+        let mut decompressed = String::new();
+        flate2::read::MultiGzDecoder::new(&gzip[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, text);
+
+        let zstd = Compression::Zstd.compress(text.as_bytes());
+        assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), text.as_bytes());
+    }
+}
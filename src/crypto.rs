@@ -0,0 +1,172 @@
+//! Optional authenticated encryption of persisted state artifacts.
+//!
+//! Encrypted files consist of [`MAGIC`], a random nonce, and the
+//! ChaCha20-Poly1305 ciphertext (including its authentication tag).
+//! The magic is authenticated as associated data.
+
+use std::{
+    env::{self, VarError},
+    fs,
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, Generate, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+/// Environment variable holding a hex-encoded 256-bit key
+pub const KEY_ENV_VAR: &str = "TRANSACTIONS_STATE_KEY";
+
+const MAGIC: &[u8; 8] = b"TXENC\x00\x00\x01";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct StateKey(Key);
+
+impl StateKey {
+    /// Loads the key from `key_file` if given, otherwise from [`KEY_ENV_VAR`].
+    /// Returns `None` if neither is provided, in which case state is persisted unencrypted.
+    pub fn load(key_file: Option<&Path>) -> Result<Option<Self>, String> {
+        if let Some(path) = key_file {
+            let bytes = fs::read(path).map_err(|err| format!("could not read key file: {err}"))?;
+            // Accept both raw keys and hex-encoded ones
+            let key = if bytes.len() == KEY_LEN {
+                bytes
+            } else {
+                let text = String::from_utf8(bytes).map_err(|_| "invalid key file")?;
+                decode_hex(text.trim()).ok_or("invalid key file")?
+            };
+            return Self::from_bytes(&key).map(Some);
+        }
+
+        match env::var(KEY_ENV_VAR) {
+            Ok(hex) => {
+                let key = decode_hex(hex.trim()).ok_or("invalid key in environment")?;
+                Self::from_bytes(&key).map(Some)
+            }
+            Err(VarError::NotPresent) => Ok(None),
+            Err(VarError::NotUnicode(_)) => Err(format!("{KEY_ENV_VAR} is not valid Unicode")),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        Key::try_from(bytes)
+            .map(Self)
+            .map_err(|_| format!("key must be {KEY_LEN} bytes long"))
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        let nonce = Nonce::generate();
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: MAGIC,
+                },
+            )
+            .expect("encryption of in-memory buffers cannot fail");
+
+        let mut output = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&ciphertext);
+        output
+    }
+
+    pub fn decrypt(&self, input: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let body = input
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or("not an encrypted file")?;
+        if body.len() < NONCE_LEN {
+            return Err("truncated encrypted file");
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let nonce = Nonce::try_from(nonce).map_err(|_| "truncated encrypted file")?;
+
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| "decryption failed: wrong key or corrupted file")
+    }
+}
+
+pub fn is_encrypted(input: &[u8]) -> bool {
+    input.starts_with(MAGIC)
+}
+
+/// Decrypts `input` if it is encrypted, passing plaintext through unchanged.
+/// Plaintext is rejected once a key is provided, as it would not be authenticated.
+pub fn open(input: Vec<u8>, key: Option<&StateKey>) -> Result<Vec<u8>, String> {
+    if !is_encrypted(&input) {
+        return match key {
+            Some(_) => Err("file is not encrypted although a key was provided".to_string()),
+            None => Ok(input),
+        };
+    }
+    let key = key.ok_or_else(|| {
+        format!("file is encrypted but no key was provided (use --key-file or {KEY_ENV_VAR})")
+    })?;
+    key.decrypt(&input).map_err(str::to_string)
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> StateKey {
+        StateKey::from_bytes(&[7; KEY_LEN]).unwrap()
+    }
+
+    #[test]
+    fn it_roundtrips_and_authenticates() {
+        let key = test_key();
+        let mut encrypted = key.encrypt(b"{\"version\": 1}");
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"{\"version\": 1}");
+
+        *encrypted.last_mut().unwrap() ^= 1;
+        assert!(key.decrypt(&encrypted).is_err());
+
+        let other_key = StateKey::from_bytes(&[8; KEY_LEN]).unwrap();
+        assert!(other_key.decrypt(&key.encrypt(b"secret")).is_err());
+    }
+
+    #[test]
+    fn it_passes_plaintext_through_only_without_a_key() {
+        assert_eq!(open(b"{}".to_vec(), None).unwrap(), b"{}");
+        assert!(open(test_key().encrypt(b"{}"), None).is_err());
+        assert!(open(b"{}".to_vec(), Some(&test_key())).is_err());
+    }
+
+    #[test]
+    fn it_decodes_hex() {
+        assert_eq!(decode_hex("00ff7f"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x7f]), "00ff7f");
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
@@ -0,0 +1,211 @@
+//! A live terminal dashboard of a run, drawn below the cursor on stderr so that
+//! stdout still carries the output.
+
+use std::{
+    io::{self, IsTerminal},
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Layout},
+    widgets::{Block, List, Paragraph, Row, Table},
+    Frame, Terminal, TerminalOptions, Viewport,
+};
+
+use crate::account::Account;
+use crate::engine::Engine;
+use crate::run::Run;
+use crate::transaction::ClientID;
+
+/// Lines taken up by the dashboard
+const HEIGHT: u16 = 16;
+/// How often the dashboard is redrawn at most
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of accounts with the highest balances shown
+const TOP_ACCOUNTS: usize = 10;
+
+/// The dashboard as drawn on a terminal
+pub type TerminalDashboard = Dashboard<CrosstermBackend<io::Stderr>>;
+
+pub struct Dashboard<B: Backend> {
+    terminal: Terminal<B>,
+    started: Instant,
+    last_drawn: Option<Instant>,
+}
+
+impl TerminalDashboard {
+    pub fn start() -> Result<Self, String> {
+        if !io::stderr().is_terminal() {
+            return Err("--dashboard requires stderr to be a terminal".to_string());
+        }
+        let terminal = Terminal::with_options(
+            CrosstermBackend::new(io::stderr()),
+            TerminalOptions {
+                viewport: Viewport::Inline(HEIGHT),
+            },
+        )
+        .map_err(|err| format!("could not start dashboard: {err}"))?;
+        Ok(Self::new(terminal))
+    }
+}
+
+impl<B: Backend> Dashboard<B> {
+    pub fn new(terminal: Terminal<B>) -> Self {
+        Self {
+            terminal,
+            started: Instant::now(),
+            last_drawn: None,
+        }
+    }
+
+    /// Redraws the dashboard unless it was redrawn only recently.
+    /// `rows` is the number of rows read so far.
+    pub fn update<'a>(
+        &mut self,
+        engines: impl IntoIterator<Item = &'a Engine>,
+        run: &Run,
+        rows: u64,
+    ) -> Result<(), B::Error> {
+        if self
+            .last_drawn
+            .is_some_and(|last_drawn| last_drawn.elapsed() < REFRESH_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.draw(engines, run, rows)
+    }
+
+    /// Redraws the dashboard regardless of when it was last drawn,
+    /// e.g. to show the final state of a run
+    pub fn draw<'a>(
+        &mut self,
+        engines: impl IntoIterator<Item = &'a Engine>,
+        run: &Run,
+        rows: u64,
+    ) -> Result<(), B::Error> {
+        let seconds = self.started.elapsed().as_secs_f64();
+        let throughput = if seconds == 0.0 {
+            0.0
+        } else {
+            rows as f64 / seconds
+        };
+        let mut accounts: Vec<(ClientID, &Account)> = engines
+            .into_iter()
+            .flat_map(|engine| engine.accounts().iter())
+            .map(|(client_id, account)| (*client_id, account))
+            .collect();
+        let locked = accounts
+            .iter()
+            .filter(|(_, account)| account.locked)
+            .count();
+        accounts.sort_by(|(_, a), (_, b)| b.total.total_cmp(&a.total));
+        accounts.truncate(TOP_ACCOUNTS);
+
+        self.terminal
+            .draw(|frame| render(frame, &accounts, run, rows, throughput, locked))?;
+        self.last_drawn = Some(Instant::now());
+        Ok(())
+    }
+}
+
+fn render(
+    frame: &mut Frame,
+    top_accounts: &[(ClientID, &Account)],
+    run: &Run,
+    rows: u64,
+    throughput: f64,
+    locked: usize,
+) {
+    let [status, details] =
+        Layout::vertical([Constraint::Length(3), Constraint::Fill(1)]).areas(frame.area());
+    let [accounts_area, rejections_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(details);
+
+    let rejected: u64 = run.summary().rejected.values().sum();
+    frame.render_widget(
+        Paragraph::new(format!(
+            "rows: {rows}   rows per second: {throughput:.0}   rejected: {rejected}   \
+             locked accounts: {locked}"
+        ))
+        .block(Block::bordered().title("transactions")),
+        status,
+    );
+
+    let accounts = top_accounts.iter().map(|(client_id, account)| {
+        Row::new([
+            client_id.to_string(),
+            account.available.to_string(),
+            account.held.to_string(),
+            account.total.to_string(),
+            account.locked.to_string(),
+        ])
+    });
+    frame.render_widget(
+        Table::new(accounts, [Constraint::Ratio(1, 5); 5])
+            .header(Row::new(["client", "available", "held", "total", "locked"]))
+            .block(Block::bordered().title("top accounts by balance")),
+        accounts_area,
+    );
+
+    let rejections = run.recent_rejections().map(|rejection| {
+        format!(
+            "line {}: {} (client {}, tx {})",
+            rejection.line,
+            rejection.rejection.code(),
+            rejection.client_id,
+            rejection.id
+        )
+    });
+    frame.render_widget(
+        List::new(rejections).block(Block::bordered().title("recent rejections")),
+        rejections_area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+
+    use super::*;
+    use crate::run::RunOptions;
+    use crate::testing::tx;
+    use crate::transaction::{RowContext, TransactionType};
+
+    #[test]
+    fn it_shows_the_state_of_the_run() {
+        let options = RunOptions {
+            quiet: true,
+            ..RunOptions::default()
+        };
+        let mut run = Run::new(options, None);
+        let mut engine = Engine::default();
+        let row = RowContext {
+            line: 2,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+        for (ty, id) in [
+            (TransactionType::Deposit, 1),
+            (TransactionType::Withdrawal, 2),
+        ] {
+            let transaction = tx(ty, 7, id, 5.0);
+            run.process(&mut engine, &transaction, &row).unwrap();
+        }
+
+        let mut dashboard = Dashboard::new(Terminal::new(TestBackend::new(100, HEIGHT)).unwrap());
+        dashboard.draw([&engine], &run, 2).unwrap();
+        let screen: String = dashboard
+            .terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("rejected: 1"));
+        assert!(screen.contains("locked accounts: 0"));
+        assert!(screen.contains("line 2: insufficient_funds (client 7, tx 2)"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Comparison of two sets of accounts, e.g. the outputs of two consecutive days.
+//!
+//! Amounts are compared as [`normalize_amount`] writes them, whether they are
+//! held by accounts or written in an accounts output.
+
+use std::collections::HashMap;
+
+use crate::account::Account;
+use crate::transaction::ClientID;
+
+/// The columns of accounts outputs holding amounts
+const AMOUNT_COLUMNS: [&str; 5] = ["available", "held", "total", "deposited", "withdrawn"];
+
+/// `amount` with four decimal places and without the sign of negative zero, so
+/// that amounts compare equal regardless of how they happen to be formatted
+pub fn normalize_amount(amount: f64) -> String {
+    format!("{:.4}", amount + 0.0)
+}
+
+/// The balances of `account` as they are compared
+fn normalize_balances(account: &Account) -> String {
+    format!(
+        "{},{},{},{}",
+        normalize_amount(account.available.into()),
+        normalize_amount(account.held.into()),
+        normalize_amount(account.total.into()),
+        account.locked
+    )
+}
+
+/// The clients with an account in `a` or `b`, sorted by client ID
+fn clients_of(a: &HashMap<ClientID, Account>, b: &HashMap<ClientID, Account>) -> Vec<ClientID> {
+    let mut clients: Vec<ClientID> = a.keys().chain(b.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+    clients
+}
+
+/// Returns a CSV of every client whose account differs between `old` and `new`,
+/// ordered by client ID. The amount columns hold the change from `old` to `new`
+/// and `status` is one of `added`, `removed`, `locked`, `unlocked`, or `changed`.
+pub fn diff_accounts(old: &HashMap<ClientID, Account>, new: &HashMap<ClientID, Account>) -> String {
+    let mut string = String::new();
+    string.push_str("client,status,available,held,total,locked\n");
+    for client_id in clients_of(old, new) {
+        let (status, before, after) = match (old.get(&client_id), new.get(&client_id)) {
+            (None, Some(after)) => ("added", &Account::default(), after),
+            (Some(before), None) => ("removed", before, &Account::default()),
+            (Some(before), Some(after))
+                if normalize_balances(before) == normalize_balances(after) =>
+            {
+                continue
+            }
+            (Some(before), Some(after)) => {
+                let status = match (before.locked, after.locked) {
+                    (false, true) => "locked",
+                    (true, false) => "unlocked",
+                    _ => "changed",
+                };
+                (status, before, after)
+            }
+            (None, None) => unreachable!(),
+        };
+        string.push_str(&format!(
+            "{},{},{:+},{:+},{:+},{}\n",
+            client_id,
+            status,
+            after.available - before.available,
+            after.held - before.held,
+            after.total - before.total,
+            after.locked
+        ));
+    }
+    string
+}
+
+/// Describes every client whose account in `actual` differs from `expected`,
+/// ordered by client ID
+pub fn compare_accounts(
+    expected: &HashMap<ClientID, Account>,
+    actual: &HashMap<ClientID, Account>,
+) -> Vec<String> {
+    let balances = |account: &Account| {
+        format!(
+            "{},{},{},{}",
+            account.available, account.held, account.total, account.locked
+        )
+    };
+    clients_of(expected, actual)
+        .into_iter()
+        .filter_map(
+            |client_id| match (expected.get(&client_id), actual.get(&client_id)) {
+                (Some(expected), Some(actual))
+                    if normalize_balances(expected) != normalize_balances(actual) =>
+                {
+                    Some(format!(
+                        "client {client_id}: expected {} but got {}",
+                        balances(expected),
+                        balances(actual)
+                    ))
+                }
+                (Some(_), None) => Some(format!("client {client_id}: expected an account")),
+                (None, Some(actual)) => Some(format!(
+                    "client {client_id}: unexpected account {}",
+                    balances(actual)
+                )),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// `output` of accounts with the rows sorted by client ID and amounts written with
+/// [`normalize_amount`], so that outputs compare equal regardless of the order of
+/// accounts and how amounts happen to be formatted
+pub fn normalize_accounts(output: &str) -> Result<String, String> {
+    let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+    let header = lines.next().ok_or("no header")?;
+    let amount_columns: Vec<bool> = header
+        .split(',')
+        .map(|column| AMOUNT_COLUMNS.contains(&column.trim()))
+        .collect();
+
+    let mut rows = Vec::new();
+    for line in lines {
+        let fields = line
+            .split(',')
+            .zip(amount_columns.iter().chain([false].iter().cycle()))
+            .map(|(field, is_amount)| {
+                let field = field.trim();
+                match is_amount {
+                    true => field
+                        .parse::<f64>()
+                        .map(normalize_amount)
+                        .map_err(|_| format!("invalid amount {field:?}")),
+                    false => Ok(field.to_string()),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let client = fields[0]
+            .parse::<u64>()
+            .map_err(|_| format!("invalid client ID {:?}", fields[0]))?;
+        rows.push((client, fields.join(",")));
+    }
+    rows.sort();
+
+    let mut normalized = format!("{}\n", header.trim());
+    for (_, row) in rows {
+        normalized.push_str(&row);
+        normalized.push('\n');
+    }
+    Ok(normalized)
+}
+
+/// The rows of two outputs normalized with [`normalize_accounts`] that differ,
+/// each keyed by its client ID, with `-` for `expected` and `+` for `actual`
+pub fn diff_normalized(expected: &str, actual: &str) -> String {
+    let key = |line: &str| {
+        line.split(',')
+            .next()
+            .unwrap_or_default()
+            .parse::<u64>()
+            .ok()
+    };
+    let mut expected = expected.lines().peekable();
+    let mut actual = actual.lines().peekable();
+    let mut diff = String::new();
+    loop {
+        let (removed, added) = match (expected.peek(), actual.peek()) {
+            (None, None) => break,
+            (Some(old), Some(new)) if old == new => {
+                expected.next();
+                actual.next();
+                continue;
+            }
+            (Some(old), Some(new)) if key(old) == key(new) => (expected.next(), actual.next()),
+            (Some(old), Some(new)) if key(old) < key(new) => (expected.next(), None),
+            (Some(_), None) => (expected.next(), None),
+            _ => (None, actual.next()),
+        };
+        for (sign, line) in [("-", removed), ("+", added)] {
+            if let Some(line) = line {
+                diff.push_str(&format!("{sign} {line}\n"));
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(available: f32, held: f32, locked: bool) -> Account {
+        Account {
+            available,
+            held,
+            total: available + held,
+            locked,
+            flagged: false,
+        }
+    }
+
+    #[test]
+    fn it_reports_changes() {
+        let old = HashMap::from([
+            (1, account(10.0, 0.0, false)),
+            (2, account(5.0, 0.0, false)),
+            (3, account(1.0, 0.0, false)),
+            (4, account(2.0, 2.0, false)),
+        ]);
+        let new = HashMap::from([
+            (1, account(10.0, 0.0, false)),
+            (2, account(7.5, 0.0, false)),
+            (4, account(2.0, 0.0, true)),
+            (5, account(3.0, 0.0, false)),
+        ]);
+
+        assert_eq!(
+            diff_accounts(&old, &new),
+            "client,status,available,held,total,locked\n\
+             2,changed,+2.5,+0,+2.5,false\n\
+             3,removed,-1,+0,-1,false\n\
+             4,locked,+0,-2,-2,true\n\
+             5,added,+3,+0,+3,false\n"
+        );
+    }
+
+    #[test]
+    fn it_compares_with_expected_accounts() {
+        let expected =
+            HashMap::from([(1, account(1.0, 0.0, false)), (2, account(2.0, 0.0, false))]);
+        let actual = HashMap::from([(1, account(1.0, 0.0, true)), (3, account(3.0, 0.0, false))]);
+
+        assert_eq!(
+            compare_accounts(&expected, &actual),
+            [
+                "client 1: expected 1,0,1,false but got 1,0,1,true",
+                "client 2: expected an account",
+                "client 3: unexpected account 3,0,3,false",
+            ]
+        );
+
+        // Amounts are equal up to four decimal places, like in written outputs
+        let close = HashMap::from([(1, account(1.00001, -0.0, false))]);
+        assert!(
+            compare_accounts(&HashMap::from([(1, account(1.0, 0.0, false))]), &close).is_empty()
+        );
+    }
+}
@@ -0,0 +1,1033 @@
+use std::{
+    collections::{hash_map, HashMap, HashSet, VecDeque},
+    fmt, mem,
+    ops::Index,
+    sync::{Arc, OnceLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::{Account, Activity, StatementLine};
+use crate::handler::{Handlers, TransactionHandler};
+use crate::policy::{ClearingPeriod, DailyTotals, History, LockTally, LockTrigger, Policy};
+use crate::rules::RuleAction;
+use crate::transaction::{
+    ClientID, CustomType, DisputeState, ProcessedTransaction, Rejection, Transaction,
+    TransactionID, TransactionType,
+};
+
+/// Holds all account state and the index of processed transactions
+/// that disputes, resolves, and chargebacks refer back to.
+#[derive(Debug, Default, Clone)]
+pub struct Engine {
+    pub(crate) accounts: HashMap<ClientID, Account>,
+    pub(crate) transactions: TransactionIndex,
+    pub(crate) counters: Counters,
+    pub(crate) policy: Policy,
+    /// What the policy and the extended output need to know about each client
+    pub(crate) clients: HashMap<ClientID, ClientState>,
+    pub(crate) handlers: Handlers,
+    pub(crate) listeners: Listeners,
+    /// Set if the transaction processed last made the lock policy or the blocklist
+    /// lock the account
+    pub(crate) last_lock: Option<LockTrigger>,
+    /// When the transactions being processed happen, in seconds since the Unix epoch
+    pub(crate) clock: Option<u64>,
+    /// Deposits that have not cleared yet, by when they do
+    pub(crate) clearing: VecDeque<(u64, ClientID, TransactionID)>,
+    /// Set if enabled with [`Engine::track_activity`]
+    pub(crate) track_activity: bool,
+    /// Each client's applied transactions in order, if enabled with
+    /// [`Engine::index_by_client`]
+    pub(crate) by_client: Option<HashMap<ClientID, Vec<StatementLine>>>,
+    /// The clients with an account in order, built on demand and dropped whenever
+    /// an account is opened
+    pub(crate) sorted_clients: OnceLock<Vec<ClientID>>,
+}
+
+/// What the engine keeps about a client besides its account and transactions.
+/// Each part is only kept up to date while something needs it.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ClientState {
+    /// The recent transactions, with a velocity limit
+    pub(crate) history: History,
+    /// With a lock policy that looks back further than the current transaction
+    pub(crate) lock_tally: LockTally,
+    /// What was deposited and withdrawn on the current day, with daily limits
+    pub(crate) daily_totals: DailyTotals,
+    /// With [`Engine::track_activity`]
+    pub(crate) activity: Activity,
+}
+
+/// What was applied to each account, as returned by [`Engine::activity`]
+#[derive(Debug, Clone, Copy)]
+pub struct Activities<'a>(&'a HashMap<ClientID, ClientState>);
+
+impl<'a> Activities<'a> {
+    pub fn get(&self, client_id: ClientID) -> Option<&'a Activity> {
+        self.0.get(&client_id).map(|state| &state.activity)
+    }
+}
+
+/// Something that happened while processing a transaction, as passed to the
+/// listeners added with [`Engine::on_event`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineEvent<'a> {
+    Applied {
+        transaction: &'a Transaction,
+        /// The client's account after the transaction
+        account: &'a Account,
+    },
+    Rejected {
+        transaction: &'a Transaction,
+        rejection: Rejection,
+    },
+    /// A dispute, resolve, or chargeback moved transaction `id` into another state
+    DisputeStateChanged {
+        client_id: ClientID,
+        id: TransactionID,
+        from: DisputeState,
+        to: DisputeState,
+    },
+    Locked {
+        client_id: ClientID,
+    },
+    Unlocked {
+        client_id: ClientID,
+    },
+}
+
+type Listener = dyn Fn(EngineEvent) + Send + Sync;
+
+#[derive(Clone, Default)]
+pub(crate) struct Listeners(Vec<Arc<Listener>>);
+
+impl Listeners {
+    fn emit(&self, event: EngineEvent) {
+        for listener in &self.0 {
+            listener(event);
+        }
+    }
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} listeners", self.0.len())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Counters {
+    /// Number of transaction rows handed to the engine so far
+    pub processed: u64,
+    /// Number of transactions that were actually applied,
+    /// which is also the sequence number of the latest event
+    #[serde(default)]
+    pub applied: u64,
+}
+
+/// What became of the transactions handed to [`Engine::process_batch`]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BatchResult {
+    /// Number of transactions that were applied
+    pub applied: u64,
+    /// The position within the batch of each rejected transaction, with the reason
+    pub rejections: Vec<(usize, Rejection)>,
+    /// The clients that had transactions applied
+    pub affected_clients: HashSet<ClientID>,
+}
+
+/// The processed transactions, indexed by client and then by ID, so that
+/// disputes, resolves, and chargebacks only look among the transactions of the
+/// client they come from
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TransactionIndex {
+    clients: HashMap<ClientID, HashMap<TransactionID, ProcessedTransaction>>,
+    len: usize,
+}
+
+impl TransactionIndex {
+    pub fn get(&self, client_id: ClientID, id: TransactionID) -> Option<&ProcessedTransaction> {
+        self.clients.get(&client_id)?.get(&id)
+    }
+
+    pub fn get_mut(
+        &mut self,
+        client_id: ClientID,
+        id: TransactionID,
+    ) -> Option<&mut ProcessedTransaction> {
+        self.clients.get_mut(&client_id)?.get_mut(&id)
+    }
+
+    /// The transactions of `client_id`, if it has any
+    pub fn client(
+        &self,
+        client_id: ClientID,
+    ) -> Option<&HashMap<TransactionID, ProcessedTransaction>> {
+        self.clients.get(&client_id)
+    }
+
+    /// Indexes `transaction` under its client, returning the transaction of the
+    /// client with the same ID it replaces
+    pub fn insert(
+        &mut self,
+        id: TransactionID,
+        transaction: ProcessedTransaction,
+    ) -> Option<ProcessedTransaction> {
+        let client_id = transaction.client_id;
+        let transactions = self.clients.entry(client_id).or_default();
+        let len = transactions.len();
+        let replaced = transactions.insert(id, transaction);
+        self.len += transactions.len() - len;
+        replaced
+    }
+
+    /// Hands the transactions of `client_id` to `f`, keeping count of the ones it adds
+    fn with_client<T>(
+        &mut self,
+        client_id: ClientID,
+        f: impl FnOnce(&mut HashMap<TransactionID, ProcessedTransaction>) -> T,
+    ) -> T {
+        let transactions = self.clients.entry(client_id).or_default();
+        let len = transactions.len();
+        let result = f(transactions);
+        self.len = self.len + transactions.len() - len;
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// All transactions with their IDs, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionID, &ProcessedTransaction)> {
+        self.clients.values().flat_map(|transactions| {
+            transactions
+                .iter()
+                .map(|(id, transaction)| (*id, transaction))
+        })
+    }
+
+    fn into_entries(self) -> impl Iterator<Item = (TransactionID, ProcessedTransaction)> {
+        self.clients.into_values().flatten()
+    }
+
+    /// Approximate number of bytes allocated, assuming the tables of clients are
+    /// about as full as hash tables get
+    fn memory_usage(&self) -> usize {
+        let entry = mem::size_of::<(TransactionID, ProcessedTransaction)>() + 1;
+        let client = mem::size_of::<(ClientID, HashMap<TransactionID, ProcessedTransaction>)>() + 1;
+        self.clients.capacity() * client + self.len * entry * 8 / 7
+    }
+}
+
+impl Index<(ClientID, TransactionID)> for TransactionIndex {
+    type Output = ProcessedTransaction;
+
+    fn index(&self, (client_id, id): (ClientID, TransactionID)) -> &Self::Output {
+        self.get(client_id, id).expect("transaction is indexed")
+    }
+}
+
+impl Engine {
+    pub fn with_policy(policy: Policy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Changes the policy for all transactions processed from now on
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Applies rows of type `ty` with `handler` from now on. Readers only accept such
+    /// rows with `ty` among [`ParseOptions::custom_types`](crate::transaction::ParseOptions::custom_types),
+    /// which [`Session`](crate::session::Session) takes care of.
+    pub fn register_handler(&mut self, ty: &str, handler: impl TransactionHandler + 'static) {
+        // Deserializing transactions only resolves the names of known custom types
+        CustomType::new(ty);
+        self.handlers.insert(ty, Arc::new(handler));
+    }
+
+    /// The types of rows with a registered handler
+    pub fn custom_types(&self) -> impl Iterator<Item = &str> {
+        self.handlers.types()
+    }
+
+    /// Sets the time at which the transactions processed from now on happen, in
+    /// seconds since the Unix epoch. Daily limits are only enforced with a clock.
+    pub fn set_clock(&mut self, timestamp: u64) {
+        self.clock = Some(timestamp);
+    }
+
+    /// Calls `listener` with every event from now on. When processing with several
+    /// threads, it is called from all of them.
+    pub fn on_event(&mut self, listener: impl Fn(EngineEvent) + Send + Sync + 'static) {
+        self.listeners.0.push(Arc::new(listener));
+    }
+
+    /// Keeps the transactions applied from now on by client along with the account
+    /// after each, for [`Engine::transactions_for`] and [`Engine::statement`]. They
+    /// are not part of state documents.
+    pub fn index_by_client(&mut self) {
+        self.by_client.get_or_insert_with(HashMap::new);
+    }
+
+    /// Counts what is applied to each account from now on, for [`Engine::activity`]
+    /// and the extended output
+    pub fn track_activity(&mut self) {
+        self.track_activity = true;
+    }
+
+    /// Processes `transactions` in order, like [`Engine::process`] does
+    pub fn process_batch<'a>(
+        &mut self,
+        transactions: impl IntoIterator<Item = &'a Transaction>,
+    ) -> BatchResult {
+        let mut result = BatchResult::default();
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            match self.process(transaction) {
+                Ok(()) => {
+                    result.applied += 1;
+                    result.affected_clients.insert(transaction.client_id);
+                }
+                Err(rejection) => result.rejections.push((index, rejection)),
+            }
+        }
+        result
+    }
+
+    pub fn process(&mut self, transaction: &Transaction) -> Result<(), Rejection> {
+        if self.listeners.0.is_empty() {
+            return self.apply(transaction);
+        }
+
+        let client_id = transaction.client_id;
+        let was_locked = self
+            .accounts
+            .get(&client_id)
+            .is_some_and(|account| account.locked);
+        let result = self.apply(transaction);
+        // Transactions rejected by the allowlist or blocklist leave no account
+        let account = self.accounts.get(&client_id);
+        self.listeners.emit(match result {
+            Ok(()) => EngineEvent::Applied {
+                transaction,
+                account: account.expect("applied transactions have an account"),
+            },
+            Err(rejection) => EngineEvent::Rejected {
+                transaction,
+                rejection,
+            },
+        });
+        use DisputeState::*;
+        let transition = match transaction.ty {
+            TransactionType::Dispute => Some((Undisputed, Disputed)),
+            TransactionType::Resolve => Some((Disputed, Undisputed)),
+            TransactionType::Chargeback => Some((Disputed, ChargedBack)),
+            _ => None,
+        };
+        if let (Ok(()), Some((from, to))) = (result, transition) {
+            self.listeners.emit(EngineEvent::DisputeStateChanged {
+                client_id,
+                id: transaction.id,
+                from,
+                to,
+            });
+        }
+        match (was_locked, account.is_some_and(|account| account.locked)) {
+            (false, true) => self.listeners.emit(EngineEvent::Locked { client_id }),
+            (true, false) => self.listeners.emit(EngineEvent::Unlocked { client_id }),
+            _ => {}
+        }
+        result
+    }
+
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), Rejection> {
+        self.clear_deposits();
+        let result = self.apply_policy(transaction);
+        self.clear_deposits();
+        result
+    }
+
+    /// Makes the funds of the deposits whose clearing period is over available
+    fn clear_deposits(&mut self) {
+        let now = match (self.policy.clearing, self.clock) {
+            (Some(ClearingPeriod::Transactions(_)), _) => self.counters.processed,
+            (Some(ClearingPeriod::Seconds(_)), Some(clock)) => clock,
+            _ => return,
+        };
+        while let Some(&(clears_at, client_id, id)) = self.clearing.front() {
+            if clears_at > now {
+                break;
+            }
+            self.clearing.pop_front();
+            let Some(transaction) = self.transactions.get_mut(client_id, id) else {
+                continue;
+            };
+            transaction.clears_at = None;
+            // The funds of a disputed deposit become available once it is resolved
+            if transaction.dispute_state == DisputeState::Undisputed {
+                let account = self
+                    .accounts
+                    .get_mut(&transaction.client_id)
+                    .expect("deposits have an account");
+                account.held -= transaction.amount;
+                account.available += transaction.amount;
+            }
+        }
+    }
+
+    fn apply_policy(&mut self, transaction: &Transaction) -> Result<(), Rejection> {
+        if let Some(allowlist) = &self.policy.allowlist {
+            // Checked first, so that clients outside of it do not get an account
+            if !allowlist.contains(&transaction.client_id) {
+                self.counters.processed += 1;
+                return Err(Rejection::NotAllowed);
+            }
+        }
+        if let Some(blocklist) = &self.policy.blocklist {
+            // Checked before the account is opened, so that blocked clients only
+            // get an account if it is to be locked
+            if blocklist.clients.contains(&transaction.client_id) {
+                self.last_lock = None;
+                if blocklist.lock {
+                    let account = open_account(
+                        &mut self.accounts,
+                        &mut self.sorted_clients,
+                        transaction.client_id,
+                    );
+                    if !account.locked {
+                        account.locked = true;
+                        self.last_lock = Some(LockTrigger::Blocked);
+                    }
+                }
+                self.counters.processed += 1;
+                return Err(Rejection::Blocked);
+            }
+        }
+        let account = open_account(
+            &mut self.accounts,
+            &mut self.sorted_clients,
+            transaction.client_id,
+        );
+        let rule = self
+            .policy
+            .rules
+            .as_ref()
+            .and_then(|rules| rules.matching(transaction));
+        #[cfg(feature = "scripting")]
+        let script = self.policy.script.as_ref();
+        let mut daily_limited = false;
+        if let (Some(limits), Some(timestamp)) = (&self.policy.daily_limits, self.clock) {
+            let totals = &mut self
+                .clients
+                .entry(transaction.client_id)
+                .or_default()
+                .daily_totals;
+            if let Err(rejection) = limits.check(transaction, timestamp, totals) {
+                self.last_lock = None;
+                self.counters.processed += 1;
+                return Err(rejection);
+            }
+            daily_limited = true;
+        }
+        let handlers = &self.handlers;
+        let min_balance = self.policy.min_balance(transaction.client_id);
+        let kyc_limits = self.policy.kyc_limits(transaction.client_id);
+        let apply = |account: &mut Account, transactions: &mut _| {
+            if let Some(limits) = kyc_limits {
+                limits
+                    .check(transaction, account)
+                    .inspect_err(|_| account.flagged = true)?;
+            }
+            #[cfg(feature = "scripting")]
+            let flag = match script {
+                Some(script) => script.check(transaction, account)?,
+                None => false,
+            };
+            let mut process = |account: &mut Account| {
+                // Checked last, so that withdrawals rejected for any other reason,
+                // like the funds not covering them, are reported as such
+                if let (TransactionType::Withdrawal, Some(min_balance)) =
+                    (&transaction.ty, min_balance)
+                {
+                    let remaining = account.available - transaction.amount;
+                    if remaining > 0.0 && remaining < min_balance {
+                        return Err(Rejection::MinimumBalance);
+                    }
+                }
+                match &transaction.ty {
+                    TransactionType::Custom(ty) => {
+                        handlers.process(ty.name(), transaction, account)
+                    }
+                    _ => transaction.process(account, transactions),
+                }
+            };
+            let result = match rule {
+                Some(rule) => rule.apply(transaction, account, process),
+                None => process(account),
+            };
+            #[cfg(feature = "scripting")]
+            if flag && result.is_ok() {
+                account.flagged = true;
+            }
+            result
+        };
+        let result = match &self.policy.velocity {
+            Some(limit) => {
+                let history = &mut self
+                    .clients
+                    .entry(transaction.client_id)
+                    .or_default()
+                    .history;
+                let result = limit
+                    .check(transaction, history)
+                    .inspect_err(|_| account.flagged = true)
+                    .and_then(|()| {
+                        self.transactions
+                            .with_client(transaction.client_id, |transactions| {
+                                apply(account, transactions)
+                            })
+                    });
+                history.record(limit.window, transaction, result);
+                result
+            }
+            None => self
+                .transactions
+                .with_client(transaction.client_id, |transactions| {
+                    apply(account, transactions)
+                }),
+        };
+        if let (Ok(()), TransactionType::Chargeback, Some(fee)) =
+            (result, &transaction.ty, self.policy.chargeback_fee)
+        {
+            account.available -= fee;
+            account.total -= fee;
+        }
+        if let (Ok(()), true) = (result, daily_limited) {
+            self.clients
+                .get_mut(&transaction.client_id)
+                .expect("checked against the daily limits")
+                .daily_totals
+                .record(transaction);
+        }
+        let held_by_rule = rule.is_some_and(|rule| rule.action == RuleAction::Hold);
+        if let (Ok(()), true) = (result, held_by_rule) {
+            // Released by a resolve, like the funds of a disputed deposit
+            self.transactions
+                .get_mut(transaction.client_id, transaction.id)
+                .expect("deposits are indexed")
+                .dispute_state = DisputeState::Disputed;
+        }
+        if let (Ok(()), TransactionType::Deposit, Some(period), false) =
+            (result, &transaction.ty, self.policy.clearing, held_by_rule)
+        {
+            let clears_at = match period {
+                ClearingPeriod::Transactions(transactions) => {
+                    Some(self.counters.processed + 1 + transactions)
+                }
+                ClearingPeriod::Seconds(seconds) => self.clock.map(|clock| clock + seconds),
+            };
+            if let Some(clears_at) = clears_at {
+                account.available -= transaction.amount;
+                account.held += transaction.amount;
+                let deposit = self
+                    .transactions
+                    .get_mut(transaction.client_id, transaction.id)
+                    .expect("deposits are indexed");
+                deposit.clears_at = Some(clears_at);
+                self.clearing
+                    .push_back((clears_at, transaction.client_id, transaction.id));
+            }
+        }
+        self.last_lock = None;
+        if result.is_ok() && !account.locked {
+            let amount = match transaction.ty {
+                TransactionType::Dispute => {
+                    self.transactions[(transaction.client_id, transaction.id)].amount
+                }
+                _ => transaction.amount,
+            };
+            // Without a tally, the current transaction is all the lock policy sees
+            let mut fresh = LockTally::default();
+            let tally = match self.policy.lock.needs_tally() {
+                true => {
+                    &mut self
+                        .clients
+                        .entry(transaction.client_id)
+                        .or_default()
+                        .lock_tally
+                }
+                false => &mut fresh,
+            };
+            self.last_lock = self.policy.lock.record(tally, &transaction.ty, amount);
+            if self.last_lock.is_some() {
+                account.locked = true;
+            }
+        }
+        if result.is_ok() && self.track_activity {
+            self.clients
+                .entry(transaction.client_id)
+                .or_default()
+                .activity
+                .record(transaction);
+        }
+        if result.is_ok() {
+            if let Some(by_client) = &mut self.by_client {
+                by_client
+                    .entry(transaction.client_id)
+                    .or_default()
+                    .push(StatementLine {
+                        transaction: transaction.clone(),
+                        account: account.clone(),
+                    });
+            }
+        }
+        self.counters.processed += 1;
+        if result.is_ok() {
+            self.counters.applied += 1;
+        }
+        result
+    }
+
+    pub fn accounts(&self) -> &HashMap<ClientID, Account> {
+        &self.accounts
+    }
+
+    /// All accounts, ordered by client ID. The order is kept until the next account
+    /// is opened, so repeated calls do not sort again.
+    pub fn accounts_sorted(&self) -> impl ExactSizeIterator<Item = (ClientID, &Account)> {
+        self.sorted_clients()
+            .iter()
+            .map(|client_id| (*client_id, &self.accounts[client_id]))
+    }
+
+    /// At most `limit` accounts ordered by client ID, after skipping the first `offset`
+    pub fn accounts_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl ExactSizeIterator<Item = (ClientID, &Account)> {
+        let clients = self.sorted_clients();
+        let start = offset.min(clients.len());
+        let end = start.saturating_add(limit).min(clients.len());
+        clients[start..end]
+            .iter()
+            .map(|client_id| (*client_id, &self.accounts[client_id]))
+    }
+
+    fn sorted_clients(&self) -> &[ClientID] {
+        self.sorted_clients.get_or_init(|| {
+            let mut clients: Vec<ClientID> = self.accounts.keys().copied().collect();
+            clients.sort_unstable();
+            clients
+        })
+    }
+
+    /// The account of `client_id`, if it had any transactions
+    pub fn account(&self, client_id: ClientID) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    /// The deposit or withdrawal `id` of `client_id`. Transaction IDs are only
+    /// unique per client, as disputes only refer to the client's own transactions.
+    pub fn transaction(
+        &self,
+        client_id: ClientID,
+        id: TransactionID,
+    ) -> Option<&ProcessedTransaction> {
+        self.transactions.get(client_id, id)
+    }
+
+    /// The locked accounts, in no particular order
+    pub fn locked_accounts(&self) -> impl Iterator<Item = (ClientID, &Account)> {
+        self.accounts
+            .iter()
+            .filter(|(_, account)| account.locked)
+            .map(|(client_id, account)| (*client_id, account))
+    }
+
+    /// The deposits and withdrawals applied to the account of `client_id`, which
+    /// its disputes, resolves, and chargebacks may refer to
+    pub fn client_transactions(
+        &self,
+        client_id: ClientID,
+    ) -> Option<&HashMap<TransactionID, ProcessedTransaction>> {
+        self.transactions.client(client_id)
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// The transactions applied to the account of `client_id`, in order. Always
+    /// empty unless enabled with [`Engine::index_by_client`].
+    pub fn transactions_for(&self, client_id: ClientID) -> impl Iterator<Item = &Transaction> {
+        self.statement(client_id)
+            .iter()
+            .map(|line| &line.transaction)
+    }
+
+    /// The transactions applied to the account of `client_id` with the account after
+    /// each, in order. Always empty unless enabled with [`Engine::index_by_client`].
+    pub fn statement(&self, client_id: ClientID) -> &[StatementLine] {
+        self.by_client
+            .as_ref()
+            .and_then(|by_client| by_client.get(&client_id))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// The clients with a [`Engine::statement`], in no particular order
+    pub fn statement_clients(&self) -> impl Iterator<Item = ClientID> + '_ {
+        self.by_client.iter().flat_map(HashMap::keys).copied()
+    }
+
+    /// What was applied to each account
+    pub fn activity(&self) -> Activities<'_> {
+        Activities(&self.clients)
+    }
+
+    /// Splits the engine into `shards` engines, each owning the accounts and
+    /// transactions of the clients whose ID modulo `shards` is its index.
+    /// The counters go to the first shard.
+    pub(crate) fn split(self, shards: usize) -> Vec<Engine> {
+        let mut engines: Vec<Engine> = (0..shards).map(|_| self.empty_copy()).collect();
+        for (client_id, account) in self.accounts {
+            engines[shard_of(client_id, shards)]
+                .accounts
+                .insert(client_id, account);
+        }
+        for (id, transaction) in self.transactions.into_entries() {
+            engines[shard_of(transaction.client_id, shards)]
+                .transactions
+                .insert(id, transaction);
+        }
+        for (client_id, state) in self.clients {
+            engines[shard_of(client_id, shards)]
+                .clients
+                .insert(client_id, state);
+        }
+        for (client_id, transactions) in self.by_client.into_iter().flatten() {
+            if let Some(by_client) = &mut engines[shard_of(client_id, shards)].by_client {
+                by_client.insert(client_id, transactions);
+            }
+        }
+        for (clears_at, client_id, id) in self.clearing {
+            engines[shard_of(client_id, shards)]
+                .clearing
+                .push_back((clears_at, client_id, id));
+        }
+        for engine in &mut engines {
+            engine.clock = self.clock;
+        }
+        engines[0].counters = self.counters;
+        engines
+    }
+
+    /// An engine without any accounts or transactions, configured like this one
+    pub fn empty_copy(&self) -> Engine {
+        Engine {
+            policy: self.policy.clone(),
+            handlers: self.handlers.clone(),
+            listeners: self.listeners.clone(),
+            track_activity: self.track_activity,
+            by_client: self.by_client.as_ref().map(|_| HashMap::new()),
+            ..Engine::default()
+        }
+    }
+
+    /// Reassembles engines split with [`Engine::split`]
+    pub(crate) fn merge(engines: Vec<Engine>) -> Engine {
+        let mut merged = Engine::default();
+        for engine in engines {
+            merged.policy = engine.policy;
+            merged.handlers = engine.handlers;
+            merged.listeners = engine.listeners;
+            merged.accounts.extend(engine.accounts);
+            merged.track_activity = engine.track_activity;
+            merged.clients.extend(engine.clients);
+            merged.clock = merged.clock.max(engine.clock);
+            merged.clearing.extend(engine.clearing);
+            if let Some(by_client) = engine.by_client {
+                merged
+                    .by_client
+                    .get_or_insert_with(HashMap::new)
+                    .extend(by_client);
+            }
+            // Shards own disjoint clients, so their transactions never collide
+            for (id, transaction) in engine.transactions.into_entries() {
+                merged.transactions.insert(id, transaction);
+            }
+            merged.counters.processed += engine.counters.processed;
+            merged.counters.applied += engine.counters.applied;
+        }
+        merged.clearing.make_contiguous().sort_unstable();
+        merged
+    }
+
+    /// Approximate number of bytes allocated for the accounts, the transaction index,
+    /// and what is kept about each client
+    pub fn memory_usage(&self) -> usize {
+        fn table_size<K, V>(map: &HashMap<K, V>) -> usize {
+            // One control byte per bucket in addition to the entry itself
+            map.capacity() * (mem::size_of::<(K, V)>() + 1)
+        }
+        // Histories are only filled up to the window, so this saves visiting them
+        let histories = self.policy.velocity.as_ref().map_or(0, |limit| {
+            self.clients.len() * (limit.window + 1) * mem::size_of::<Option<f32>>()
+        });
+        table_size(&self.accounts)
+            + self.transactions.memory_usage()
+            + table_size(&self.clients)
+            + histories
+    }
+}
+
+/// The account of `client_id`, opened if necessary, which drops the sorted clients
+fn open_account<'a>(
+    accounts: &'a mut HashMap<ClientID, Account>,
+    sorted_clients: &mut OnceLock<Vec<ClientID>>,
+    client_id: ClientID,
+) -> &'a mut Account {
+    match accounts.entry(client_id) {
+        hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        hash_map::Entry::Vacant(entry) => {
+            sorted_clients.take();
+            entry.insert(Account::default())
+        }
+    }
+}
+
+/// The shard responsible for a client when processing with `shards` threads
+pub(crate) fn shard_of(client_id: ClientID, shards: usize) -> usize {
+    client_id as usize % shards
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::tx;
+
+    #[test]
+    fn it_estimates_memory_usage() {
+        let mut engine = Engine::default();
+        assert_eq!(engine.memory_usage(), 0);
+
+        for id in 0..100 {
+            engine
+                .process(&tx(TransactionType::Deposit, 1, id, 1.0))
+                .unwrap();
+        }
+        assert!(engine.memory_usage() >= 100 * mem::size_of::<ProcessedTransaction>());
+    }
+
+    #[test]
+    fn it_only_keeps_client_state_when_needed() {
+        let mut engine = Engine::default();
+        engine
+            .process(&tx(TransactionType::Deposit, 1, 1, 1.0))
+            .unwrap();
+        engine
+            .process(&tx(TransactionType::Dispute, 1, 1, 0.0))
+            .unwrap();
+        engine
+            .process(&tx(TransactionType::Chargeback, 1, 1, 0.0))
+            .unwrap();
+        assert!(engine.account(1).unwrap().locked);
+        assert!(engine.clients.is_empty());
+        assert_eq!(engine.activity().get(1), None);
+
+        engine.track_activity();
+        engine
+            .process(&tx(TransactionType::Deposit, 2, 2, 1.0))
+            .unwrap();
+        assert_eq!(engine.activity().get(2).unwrap().deposits, 1);
+    }
+
+    #[test]
+    fn it_indexes_transactions_per_client() {
+        let mut engine = Engine::default();
+        assert_eq!(
+            engine.process(&tx(TransactionType::Deposit, 1, 1, 5.0)),
+            Ok(())
+        );
+        assert_eq!(
+            engine.process(&tx(TransactionType::Deposit, 2, 1, 5.0)),
+            Ok(())
+        );
+        assert_eq!(
+            engine.process(&tx(TransactionType::Dispute, 2, 1, 0.0)),
+            Ok(())
+        );
+        assert_eq!(
+            engine.process(&tx(TransactionType::Dispute, 3, 1, 0.0)),
+            Err(Rejection::UnknownTransaction)
+        );
+
+        assert_eq!(engine.transactions.len(), 2);
+        assert_eq!(engine.transaction(2, 1).unwrap().amount, 5.0);
+        assert!(engine.transaction(3, 1).is_none());
+        assert_eq!(engine.account(2).unwrap().held, 5.0);
+        assert_eq!(engine.locked_accounts().count(), 0);
+        assert_eq!(
+            engine.client_transactions(1).unwrap()[&1].dispute_state,
+            DisputeState::Undisputed
+        );
+        assert_eq!(
+            engine.client_transactions(2).unwrap()[&1].dispute_state,
+            DisputeState::Disputed
+        );
+    }
+
+    #[test]
+    fn it_processes_batches() {
+        let mut engine = Engine::default();
+        use TransactionType::*;
+        let batch = [
+            tx(Deposit, 1, 1, 5.0),
+            tx(Withdrawal, 2, 2, 1.0),
+            tx(Deposit, 3, 3, 2.0),
+            tx(Withdrawal, 1, 4, 9.0),
+        ];
+        let result = engine.process_batch(&batch);
+        assert_eq!(result.applied, 2);
+        assert_eq!(
+            result.rejections,
+            [
+                (1, Rejection::InsufficientFunds),
+                (3, Rejection::InsufficientFunds)
+            ]
+        );
+        assert_eq!(result.affected_clients, HashSet::from([1, 3]));
+        assert_eq!(engine.counters().processed, 4);
+    }
+
+    #[test]
+    fn it_pages_through_accounts_in_order() {
+        let mut engine = Engine::default();
+        let deposit = |client_id| tx(TransactionType::Deposit, client_id, 1, 1.0);
+        let clients = |accounts: &mut dyn Iterator<Item = (ClientID, &Account)>| {
+            accounts.map(|(client_id, _)| client_id).collect::<Vec<_>>()
+        };
+        for client_id in [5, 3, 9] {
+            engine.process(&deposit(client_id)).unwrap();
+        }
+        assert_eq!(clients(&mut engine.accounts_sorted()), [3, 5, 9]);
+
+        // Opening an account drops the order built before
+        engine.process(&deposit(4)).unwrap();
+        assert_eq!(clients(&mut engine.accounts_sorted()), [3, 4, 5, 9]);
+        assert_eq!(clients(&mut engine.accounts_page(1, 2)), [4, 5]);
+        assert_eq!(clients(&mut engine.accounts_page(3, 10)), [9]);
+        assert_eq!(engine.accounts_page(10, 1).len(), 0);
+    }
+
+    #[test]
+    fn it_indexes_transactions_by_client() {
+        let mut engine = Engine::default();
+        engine.index_by_client();
+        use TransactionType::*;
+        for transaction in [
+            tx(Deposit, 1, 1, 5.0),
+            tx(Deposit, 2, 2, 3.0),
+            tx(Withdrawal, 1, 3, 9.0),
+            tx(Dispute, 1, 1, 0.0),
+        ] {
+            let _ = engine.process(&transaction);
+        }
+
+        let ids = |engine: &Engine, client_id| {
+            engine
+                .transactions_for(client_id)
+                .map(|transaction| transaction.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&engine, 1), [1, 1]);
+        let balances: Vec<_> = engine
+            .statement(1)
+            .iter()
+            .map(|line| (line.account.available, line.account.held))
+            .collect();
+        assert_eq!(balances, [(5.0, 0.0), (0.0, 5.0)]);
+        let merged = Engine::merge(engine.split(2));
+        assert_eq!(ids(&merged, 1), [1, 1]);
+        assert_eq!(ids(&merged, 2), [2]);
+        assert!(ids(&Engine::default(), 1).is_empty());
+    }
+
+    #[test]
+    fn it_notifies_listeners() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::default();
+        engine.on_event({
+            let events = events.clone();
+            move |event| {
+                let event = match event {
+                    EngineEvent::Applied { transaction, .. } => {
+                        format!("applied {}", transaction.id)
+                    }
+                    EngineEvent::Rejected { rejection, .. } => {
+                        format!("rejected {}", rejection.code())
+                    }
+                    EngineEvent::DisputeStateChanged { id, to, .. } => format!("{id} {to:?}"),
+                    EngineEvent::Locked { client_id } => format!("locked {client_id}"),
+                    EngineEvent::Unlocked { client_id } => format!("unlocked {client_id}"),
+                };
+                events.lock().unwrap().push(event);
+            }
+        });
+        use TransactionType::*;
+
+        engine.process(&tx(Deposit, 1, 1, 5.0)).unwrap();
+        engine.process(&tx(Withdrawal, 1, 2, 9.0)).unwrap_err();
+        engine.process(&tx(Dispute, 1, 1, 0.0)).unwrap();
+        engine.process(&tx(Chargeback, 1, 1, 0.0)).unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "applied 1",
+                "rejected insufficient_funds",
+                "applied 1",
+                "1 Disputed",
+                "applied 1",
+                "1 ChargedBack",
+                "locked 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_notifies_listeners_of_clients_without_an_account() {
+        let rejections = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::with_policy(Policy {
+            allowlist: Some(HashSet::from([1])),
+            ..Policy::default()
+        });
+        engine.on_event({
+            let rejections = rejections.clone();
+            move |event| {
+                if let EngineEvent::Rejected { rejection, .. } = event {
+                    rejections.lock().unwrap().push(rejection);
+                }
+            }
+        });
+
+        let deposit = tx(TransactionType::Deposit, 2, 1, 1.0);
+        assert_eq!(engine.process(&deposit), Err(Rejection::NotAllowed));
+        assert!(engine.accounts().is_empty());
+        assert_eq!(*rejections.lock().unwrap(), [Rejection::NotAllowed]);
+    }
+}
@@ -0,0 +1,157 @@
+//! An append-only stream of every applied transaction, one JSON object per line,
+//! from which the engine state can be deterministically reconstructed.
+//!
+//! If a key is provided, every line is instead the hex-encoded encryption of that object.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{self, StateKey};
+use crate::transaction::Transaction;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Starts at 1 and increases by exactly 1 with every event
+    pub seq: u64,
+    #[serde(flatten)]
+    pub transaction: Transaction,
+}
+
+pub struct EventLog<'a> {
+    writer: BufWriter<fs::File>,
+    key: Option<&'a StateKey>,
+}
+
+impl<'a> EventLog<'a> {
+    /// Opens the event log at `path` for appending, creating it if necessary.
+    ///
+    /// To keep the stream consistent, the log must contain exactly `applied` events,
+    /// i.e. it must have been written by the run that produced the current state.
+    pub fn open(path: &Path, applied: u64, key: Option<&'a StateKey>) -> Result<Self, String> {
+        let existing = if path.exists() {
+            read_events(path, key)?.len() as u64
+        } else {
+            0
+        };
+        if existing != applied {
+            return Err(format!(
+                "event log contains {existing} events but the state has {applied} applied transactions"
+            ));
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("could not open event log: {err}"))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            key,
+        })
+    }
+
+    pub fn append(&mut self, seq: u64, transaction: &Transaction) -> Result<(), String> {
+        let event = Event {
+            seq,
+            transaction: transaction.clone(),
+        };
+        let json = serde_json::to_string(&event).expect("events are always serializable");
+        let line = match self.key {
+            Some(key) => crypto::encode_hex(&key.encrypt(json.as_bytes())),
+            None => json,
+        };
+        writeln!(self.writer, "{line}").map_err(|err| format!("could not write event: {err}"))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .map_err(|err| format!("could not write event: {err}"))
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        self.flush()
+    }
+}
+
+pub fn read_events(path: &Path, key: Option<&StateKey>) -> Result<Vec<Event>, String> {
+    let file = fs::File::open(path).map_err(|err| format!("could not read event log: {err}"))?;
+    parse_events(io::BufReader::new(file), key)
+}
+
+pub fn parse_events(reader: impl BufRead, key: Option<&StateKey>) -> Result<Vec<Event>, String> {
+    let mut events = Vec::<Event>::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| format!("could not read event log: {err}"))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |reason: String| format!("invalid event on line {}: {reason}", index + 1);
+
+        let json = if line.starts_with('{') {
+            // Plaintext lines would bypass the authentication of encrypted logs
+            if key.is_some() {
+                return Err(invalid("not encrypted although a key was provided".into()));
+            }
+            line.as_bytes().to_vec()
+        } else {
+            let bytes = crypto::decode_hex(line).ok_or_else(|| invalid("not hex".into()))?;
+            crypto::open(bytes, key).map_err(invalid)?
+        };
+        let event: Event = serde_json::from_slice(&json).map_err(|err| invalid(err.to_string()))?;
+
+        let expected_seq = events.len() as u64 + 1;
+        if event.seq != expected_seq {
+            return Err(invalid(format!(
+                "expected sequence number {expected_seq} but found {}",
+                event.seq
+            )));
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionType;
+
+    #[test]
+    fn it_requires_contiguous_sequence_numbers() {
+        let valid = "{\"seq\":1,\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.0}\n\
+                     {\"seq\":2,\"type\":\"dispute\",\"client\":1,\"tx\":1,\"amount\":0.0}\n";
+        let events = parse_events(io::Cursor::new(valid), None).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].transaction.ty, TransactionType::Dispute);
+
+        let gap = "{\"seq\":1,\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.0}\n\
+                   {\"seq\":3,\"type\":\"dispute\",\"client\":1,\"tx\":1,\"amount\":0.0}\n";
+        assert!(parse_events(io::Cursor::new(gap), None).is_err());
+    }
+
+    #[test]
+    fn it_rejects_plaintext_events_when_a_key_is_provided() {
+        let key = StateKey::from_bytes(&[7; 32]).unwrap();
+        let first = "{\"seq\":1,\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.0}";
+        let second = "{\"seq\":2,\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":1.0}";
+        let encrypted = crypto::encode_hex(&key.encrypt(first.as_bytes()));
+
+        let log = format!("{encrypted}\n");
+        assert_eq!(
+            parse_events(io::Cursor::new(log), Some(&key))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let log = format!("{encrypted}\n{second}\n");
+        assert!(parse_events(io::Cursor::new(log), Some(&key)).is_err());
+    }
+}
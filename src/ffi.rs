@@ -0,0 +1,183 @@
+//! A C API for embedding the engine in other services, built with `--features ffi`.
+//! The header `include/transactions.h` is generated from this module with cbindgen.
+//!
+//! Rows are handed over one at a time. The first row may be a header, which then
+//! determines the order of the columns like it does for files.
+
+use std::{
+    ffi::{c_char, CStr},
+    ptr,
+};
+
+use crate::session::{RowOutcome, Session};
+
+/// An engine along with the columns of the rows it is handed
+pub struct TxEngine(Session);
+
+/// The outcome of [`engine_process_row`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowStatus {
+    /// The transaction was applied
+    Applied = 0,
+    /// The transaction was valid but not applied, e.g. because of insufficient funds
+    Rejected = 1,
+    /// The row was a header or contained no transaction
+    Skipped = 2,
+    /// The row could not be parsed, or has a partner column, which is not supported
+    Malformed = -1,
+    /// A null pointer or a row that is not valid UTF-8 was passed
+    InvalidArgument = -2,
+}
+
+impl From<Result<RowOutcome, String>> for RowStatus {
+    fn from(outcome: Result<RowOutcome, String>) -> Self {
+        match outcome {
+            Ok(RowOutcome::Applied) => RowStatus::Applied,
+            Ok(RowOutcome::Rejected(_)) => RowStatus::Rejected,
+            Ok(RowOutcome::Skipped) => RowStatus::Skipped,
+            Err(_) => RowStatus::Malformed,
+        }
+    }
+}
+
+/// Creates an engine without any accounts. It must be freed with [`engine_free`].
+#[no_mangle]
+pub extern "C" fn engine_new() -> *mut TxEngine {
+    Box::into_raw(Box::new(TxEngine(Session::default())))
+}
+
+/// Processes one CSV row, with or without its line break.
+///
+/// # Safety
+///
+/// `engine` must have been returned by [`engine_new`] and not been freed yet, and
+/// `row` must point to a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn engine_process_row(
+    engine: *mut TxEngine,
+    row: *const c_char,
+) -> RowStatus {
+    let Some(engine) = engine.as_mut() else {
+        return RowStatus::InvalidArgument;
+    };
+    if row.is_null() {
+        return RowStatus::InvalidArgument;
+    }
+    match CStr::from_ptr(row).to_str() {
+        Ok(row) => engine.0.process_row(row).into(),
+        Err(_) => RowStatus::InvalidArgument,
+    }
+}
+
+/// Processes `count` CSV rows in order, writing the outcome of each to `statuses`
+/// unless it is null. Returns the number of transactions applied.
+///
+/// # Safety
+///
+/// `engine` must have been returned by [`engine_new`] and not been freed yet,
+/// `rows` must point to `count` null-terminated strings, and `statuses` must be
+/// null or point to room for `count` statuses.
+#[no_mangle]
+pub unsafe extern "C" fn engine_process_rows(
+    engine: *mut TxEngine,
+    rows: *const *const c_char,
+    count: usize,
+    statuses: *mut RowStatus,
+) -> usize {
+    if rows.is_null() {
+        return 0;
+    }
+    let mut applied = 0;
+    for index in 0..count {
+        let status = engine_process_row(engine, *rows.add(index));
+        if status == RowStatus::Applied {
+            applied += 1;
+        }
+        if !statuses.is_null() {
+            *statuses.add(index) = status;
+        }
+    }
+    applied
+}
+
+/// Writes the accounts as a null-terminated CSV to `buffer`, unless it is null or
+/// fewer than the returned number of bytes (excluding the terminator) fit into
+/// `capacity`, like `snprintf`. Call with a null buffer first to learn the size.
+///
+/// # Safety
+///
+/// `engine` must have been returned by [`engine_new`] and not been freed yet, and
+/// `buffer` must be null or point to at least `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn engine_serialize_csv(
+    engine: *const TxEngine,
+    buffer: *mut c_char,
+    capacity: usize,
+) -> usize {
+    let Some(engine) = engine.as_ref() else {
+        return 0;
+    };
+    let csv = engine.0.accounts_csv();
+    if !buffer.is_null() && csv.len() < capacity {
+        ptr::copy_nonoverlapping(csv.as_ptr(), buffer.cast(), csv.len());
+        *buffer.add(csv.len()) = 0;
+    }
+    csv.len()
+}
+
+/// Frees an engine. Does nothing if `engine` is null.
+///
+/// # Safety
+///
+/// `engine` must have been returned by [`engine_new`] and not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn engine_free(engine: *mut TxEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_processes_rows() {
+        let engine = engine_new();
+        let status = |row: &CStr| unsafe { engine_process_row(engine, row.as_ptr()) };
+        assert_eq!(status(c"type, client, tx, amount"), RowStatus::Skipped);
+        assert_eq!(status(c"deposit, 1, 1, 5.0\n"), RowStatus::Applied);
+        assert_eq!(status(c"withdrawal, 1, 2, 9.0"), RowStatus::Rejected);
+        assert_eq!(status(c"deposit, x, 3, 1.0"), RowStatus::Malformed);
+        let rows = [c"deposit, 2, 4, 1.0".as_ptr(), c"dispute, 2, 5".as_ptr()];
+        let mut statuses = [RowStatus::InvalidArgument; 2];
+        assert_eq!(
+            unsafe { engine_process_rows(engine, rows.as_ptr(), 2, statuses.as_mut_ptr()) },
+            1
+        );
+        assert_eq!(statuses, [RowStatus::Applied, RowStatus::Rejected]);
+        assert_eq!(
+            unsafe { engine_process_row(engine, ptr::null()) },
+            RowStatus::InvalidArgument
+        );
+
+        let size = unsafe { engine_serialize_csv(engine, ptr::null_mut(), 0) };
+        let mut buffer = vec![0 as c_char; size + 1];
+        unsafe { engine_serialize_csv(engine, buffer.as_mut_ptr(), buffer.len()) };
+        let csv = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+        // Accounts come out in no particular order
+        let mut lines: Vec<&str> = csv.to_str().unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "1,5,0,5,false",
+                "2,1,0,1,false",
+                "client,available,held,total,locked"
+            ]
+        );
+
+        unsafe { engine_free(engine) };
+    }
+}
@@ -0,0 +1,314 @@
+//! Named pipes (FIFOs) as inputs that outlive their writers. A pipe ends
+//! whenever its writer disconnects, so [`Reconnecting`] instead waits for the
+//! next writer and reads on, until it is told to stop. [`Interruptible`] stops
+//! reading stdin and other streams that may block for good.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, TryRecvError},
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long to wait before checking a pipe without data again
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Whether `path` is a named pipe
+pub fn is_fifo(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// A named pipe read across writers. Every writer may repeat the header row of
+/// the first one, which is then left out. A row a writer left unfinished is
+/// dropped, as it may lack the end of a field, and must not be joined with the
+/// first row of the next writer either.
+pub struct Reconnecting<'a> {
+    file: fs::File,
+    stop: &'a AtomicBool,
+    /// The first row read from the pipe
+    header: Option<Vec<u8>>,
+    /// The row being read
+    row: Vec<u8>,
+    /// Rows ready to be read, from `position` on
+    rows: Vec<u8>,
+    position: usize,
+    /// Whether a writer wrote to the pipe since it was last found without one
+    connected: bool,
+    /// Whether no row of the current writer was read yet
+    new_writer: bool,
+}
+
+impl<'a> Reconnecting<'a> {
+    /// Opens the pipe at `path` without waiting for a writer. Reading ends once
+    /// `stop` is set and the pipe has no data.
+    pub fn open(path: &Path, stop: &'a AtomicBool) -> io::Result<Self> {
+        Ok(Self {
+            file: open_nonblocking(path)?,
+            stop,
+            header: None,
+            row: Vec::new(),
+            rows: Vec::new(),
+            position: 0,
+            connected: false,
+            new_writer: true,
+        })
+    }
+
+    /// Reads what the pipe has. Returns `false` at the end.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 8192];
+        match self.file.read(&mut chunk) {
+            Ok(0) => self.disconnected(),
+            Ok(read) => {
+                self.connected = true;
+                self.receive(&chunk[..read]);
+                return Ok(true);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(err) => return Err(err),
+        }
+        if self.position < self.rows.len() {
+            return Ok(true);
+        }
+        if self.stop.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+        Ok(true)
+    }
+
+    fn receive(&mut self, mut bytes: &[u8]) {
+        while let Some(end) = bytes.iter().position(|byte| *byte == b'\n') {
+            self.row.extend_from_slice(&bytes[..=end]);
+            bytes = &bytes[end + 1..];
+            self.end_row();
+        }
+        self.row.extend_from_slice(bytes);
+    }
+
+    fn end_row(&mut self) {
+        let row = std::mem::take(&mut self.row);
+        let new_writer = std::mem::replace(&mut self.new_writer, false);
+        match &self.header {
+            None => self.header = Some(row.clone()),
+            Some(header) if new_writer && header.trim_ascii_end() == row.trim_ascii_end() => return,
+            Some(_) => {}
+        }
+        self.rows.extend_from_slice(&row);
+    }
+
+    fn disconnected(&mut self) {
+        if !std::mem::replace(&mut self.connected, false) {
+            return;
+        }
+        self.row.clear();
+        self.new_writer = true;
+    }
+}
+
+impl Read for Reconnecting<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.rows.len() {
+            self.rows.clear();
+            self.position = 0;
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let available = &self.rows[self.position..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// A stream read on a thread of its own, so that reading ends once `stop` is
+/// set even while the stream has no data. Only complete rows are read then, as
+/// the rest of an unfinished row is not coming anymore.
+pub struct Interruptible<'a> {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    stop: &'a AtomicBool,
+    /// What was received and not read yet
+    received: Vec<u8>,
+    /// Whether the stream ended
+    ended: bool,
+}
+
+impl<'a> Interruptible<'a> {
+    pub fn new(mut stream: impl Read + Send + 'static, stop: &'a AtomicBool) -> Self {
+        // Bounded, so that a slow reader does not buffer the whole stream
+        let (sender, chunks) = mpsc::sync_channel(16);
+        // Left blocked in a read if stopped, until the process exits
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; 8192];
+            let result = match stream.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Ok(chunk)
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                return;
+            }
+        });
+        Self {
+            chunks,
+            stop,
+            received: Vec::new(),
+            ended: false,
+        }
+    }
+}
+
+impl Read for Interruptible<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let readable = match self.ended {
+                true => self.received.len(),
+                false => self
+                    .received
+                    .iter()
+                    .rposition(|byte| *byte == b'\n')
+                    .map_or(0, |end| end + 1),
+            };
+            if readable > 0 {
+                let read = readable.min(buf.len());
+                buf[..read].copy_from_slice(&self.received[..read]);
+                self.received.drain(..read);
+                return Ok(read);
+            }
+            if self.ended {
+                return Ok(0);
+            }
+            // Once stopped, only what was already received is read
+            let chunk = match self.stop.load(Ordering::Relaxed) {
+                true => match self.chunks.try_recv() {
+                    Ok(chunk) => chunk,
+                    Err(TryRecvError::Empty) => return Ok(0),
+                    Err(TryRecvError::Disconnected) => {
+                        self.ended = true;
+                        continue;
+                    }
+                },
+                false => match self.chunks.recv_timeout(POLL_INTERVAL) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        self.ended = true;
+                        continue;
+                    }
+                },
+            };
+            self.received.extend_from_slice(&chunk?);
+        }
+    }
+}
+
+/// Opens a pipe for reading without blocking until a writer connects, which
+/// also makes reads return at once when there is no data
+#[cfg(unix)]
+fn open_nonblocking(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_nonblocking(path: &Path) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{ffi::CString, io::Write, os::unix::ffi::OsStrExt};
+
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn it_reads_pipes_across_writers() {
+        let path = TempPath::new("fifo");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        assert!(is_fifo(&path));
+
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let mut reader = Reconnecting::open(&path, &STOP).unwrap();
+        let writer = thread::spawn({
+            let path = path.to_path_buf();
+            move || {
+                for rows in [
+                    "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2",
+                    "type,client,tx,amount\r\ndeposit,1,3,2.0\n",
+                ] {
+                    let mut pipe = fs::OpenOptions::new().write(true).open(&path).unwrap();
+                    pipe.write_all(rows.as_bytes()).unwrap();
+                    drop(pipe);
+                    thread::sleep(POLL_INTERVAL * 4);
+                }
+                STOP.store(true, Ordering::Relaxed);
+            }
+        });
+
+        let mut rows = String::new();
+        reader.read_to_string(&mut rows).unwrap();
+        writer.join().unwrap();
+        assert_eq!(
+            rows,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,3,2.0\n"
+        );
+    }
+
+    #[test]
+    fn it_stops_reading_streams_without_data() {
+        /// Blocks for good after its first rows, like stdin without a writer
+        struct Stalled(Option<&'static [u8]>);
+
+        impl Read for Stalled {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.take() {
+                    Some(rows) => (&*rows).read(buf),
+                    None => loop {
+                        thread::park();
+                    },
+                }
+            }
+        }
+
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let stream = Stalled(Some(b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2"));
+        let mut reader = Interruptible::new(stream, &STOP);
+        let stopper = thread::spawn(|| {
+            thread::sleep(POLL_INTERVAL * 4);
+            STOP.store(true, Ordering::Relaxed);
+        });
+
+        let mut rows = String::new();
+        reader.read_to_string(&mut rows).unwrap();
+        stopper.join().unwrap();
+        // The unfinished row is left out
+        assert_eq!(rows, "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    }
+}
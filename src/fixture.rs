@@ -0,0 +1,177 @@
+//! Benchmark inputs of made-up transactions, written to a directory along with
+//! the accounts they result in and a manifest, so that different versions can
+//! be compared on identical inputs and checked against the same balances.
+
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::account::{header, serialize_rows};
+use crate::crypto::encode_hex;
+use crate::engine::Engine;
+use crate::generator::{self, GeneratorOptions, TransactionStream};
+
+/// The file name of the input in the directory
+pub const INPUT: &str = "transactions.csv";
+/// The file name of the accounts the input results in
+pub const EXPECTED: &str = "expected.csv";
+/// The file name of the manifest in the directory
+pub const MANIFEST: &str = "manifest.json";
+
+/// A file in the directory
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FixtureFile {
+    /// The file name, relative to the directory
+    pub file: &'static str,
+    pub bytes: u64,
+    /// Hex-encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    pub seed: u64,
+    pub rows: u64,
+    pub options: GeneratorOptions,
+    pub input: FixtureFile,
+    /// The accounts of the input processed with the default policy, ordered by
+    /// client ID
+    pub expected: FixtureFile,
+    pub applied: u64,
+    pub rejected: u64,
+    pub accounts: usize,
+}
+
+/// Writes `rows` transactions generated from `seed` and `options` to
+/// `directory`, creating it if necessary, followed by the accounts they result
+/// in and the manifest. Returns the manifest.
+pub fn write(
+    directory: &Path,
+    rows: u64,
+    seed: u64,
+    options: GeneratorOptions,
+) -> Result<Manifest, String> {
+    fs::create_dir_all(directory)
+        .map_err(|err| format!("could not create fixture directory: {err}"))?;
+    let write_error =
+        |path: &Path, err: io::Error| format!("could not write {}: {err}", path.display());
+
+    let path = directory.join(INPUT);
+    let file = fs::File::create(&path).map_err(|err| write_error(&path, err))?;
+    let mut input = HashingWriter::new(BufWriter::new(file));
+    let mut engine = Engine::default();
+    let (mut applied, mut rejected) = (0, 0);
+    let transactions = TransactionStream::new(seed, options)
+        .take(rows as usize)
+        .inspect(|transaction| match engine.process(transaction) {
+            Ok(()) => applied += 1,
+            Err(_) => rejected += 1,
+        });
+    generator::write_csv(transactions, &mut input)
+        .and_then(|()| input.writer.flush())
+        .map_err(|err| write_error(&path, err))?;
+    let input = input.finish(INPUT);
+
+    let mut accounts = header(false).to_string();
+    serialize_rows(engine.accounts_sorted(), None, &mut accounts);
+    let path = directory.join(EXPECTED);
+    fs::write(&path, &accounts).map_err(|err| write_error(&path, err))?;
+    let expected = FixtureFile {
+        file: EXPECTED,
+        bytes: accounts.len() as u64,
+        sha256: encode_hex(&Sha256::digest(&accounts)),
+    };
+
+    let manifest = Manifest {
+        seed,
+        rows,
+        options,
+        input,
+        expected,
+        applied,
+        rejected,
+        accounts: engine.accounts().len(),
+    };
+    let mut contents =
+        serde_json::to_string_pretty(&manifest).expect("manifests are always serializable");
+    contents.push('\n');
+    let path = directory.join(MANIFEST);
+    fs::write(&path, &contents).map_err(|err| write_error(&path, err))?;
+    Ok(manifest)
+}
+
+/// Hashes and counts everything written through it, so that large inputs need
+/// not be read again
+struct HashingWriter<W> {
+    writer: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    fn finish(self, file: &'static str) -> FixtureFile {
+        FixtureFile {
+            file,
+            bytes: self.bytes,
+            sha256: encode_hex(&self.hasher.finalize()),
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::parse_accounts;
+    use crate::testing::TempPath;
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_writes_fixtures() {
+        let directory = TempPath::new("fixture");
+        let manifest = write(&directory, 5000, 3, GeneratorOptions::default()).unwrap();
+        let again = write(&directory, 5000, 3, GeneratorOptions::default()).unwrap();
+        assert_eq!(manifest, again);
+        assert_eq!(manifest.applied + manifest.rejected, 5000);
+
+        let input = fs::read(directory.join(INPUT)).unwrap();
+        assert_eq!(manifest.input.bytes, input.len() as u64);
+        assert_eq!(manifest.input.sha256, encode_hex(&Sha256::digest(&input)));
+
+        let mut engine = Engine::default();
+        for transaction in TransactionReader::new(input.as_slice()).unwrap() {
+            let _ = engine.process(&transaction.unwrap());
+        }
+        let expected = fs::read(directory.join(EXPECTED)).unwrap();
+        assert_eq!(
+            &parse_accounts(expected.as_slice()).unwrap(),
+            engine.accounts()
+        );
+        assert!(directory.join(MANIFEST).is_file());
+    }
+}
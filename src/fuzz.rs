@@ -0,0 +1,168 @@
+//! Entry points for fuzzing the parser and the engine, called by the targets in
+//! `fuzz/`. Malformed partner files must never crash a run, so each of these
+//! only fails by panicking.
+
+use std::fmt;
+
+use arbitrary::Arbitrary;
+
+use crate::account::serialize_accounts;
+use crate::engine::Engine;
+use crate::transaction::{ParseOptions, RowParser, Transaction, TransactionReader};
+
+/// The header of the inputs made of [`Row`]s
+pub const HEADER: &str = "type,client,tx,amount";
+
+/// A row of an input. Its fields are mostly close to valid ones, so that
+/// fuzzing gets past the first checks of the parser.
+#[derive(Debug, Clone, PartialEq, Arbitrary)]
+pub struct Row {
+    pub ty: Field,
+    pub client: Field,
+    pub tx: Field,
+    pub amount: Option<Field>,
+    /// Fields beyond the known columns
+    pub extra: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Arbitrary)]
+pub enum Field {
+    Empty,
+    /// A built-in transaction type, possibly in another case or padded
+    Type(TypeName, bool, bool),
+    Integer(i128),
+    /// A decimal of the digits and the number of them after the point
+    Decimal(u64, u8),
+    Float(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Arbitrary)]
+pub enum TypeName {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Empty => Ok(()),
+            Field::Type(name, uppercase, padded) => {
+                let name = match name {
+                    TypeName::Deposit => "deposit",
+                    TypeName::Withdrawal => "withdrawal",
+                    TypeName::Dispute => "dispute",
+                    TypeName::Resolve => "resolve",
+                    TypeName::Chargeback => "chargeback",
+                };
+                let padding = if *padded { " " } else { "" };
+                match uppercase {
+                    true => write!(f, "{padding}{}", name.to_uppercase()),
+                    false => write!(f, "{padding}{name}"),
+                }
+            }
+            Field::Integer(integer) => write!(f, "{integer}"),
+            Field::Decimal(digits, scale) => {
+                let digits = digits.to_string();
+                let scale = usize::from(*scale).min(digits.len());
+                let (integer, fraction) = digits.split_at(digits.len() - scale);
+                write!(f, "{integer}.{fraction}")
+            }
+            Field::Float(float) => write!(f, "{float}"),
+            Field::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.ty, self.client, self.tx)?;
+        for field in self.amount.iter().chain(&self.extra) {
+            write!(f, ",{field}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `data` as a whole input and processes the transactions in it,
+/// skipping malformed rows
+pub fn parse_input(data: &[u8]) {
+    let Ok(reader) = TransactionReader::new(data) else {
+        return;
+    };
+    let mut engine = Engine::default();
+    for transaction in reader.flatten() {
+        let _ = engine.process(&transaction);
+    }
+    serialize_accounts(engine.accounts());
+}
+
+/// Parses `rows` one at a time after [`HEADER`] and processes the transactions
+/// among them
+pub fn parse_rows(rows: &[Row]) {
+    let (mut parser, _) =
+        RowParser::from_first_row(HEADER, ParseOptions::default()).expect("the header is valid");
+    let mut engine = Engine::default();
+    for row in rows {
+        let mut warning = None;
+        if let Ok(Some(transaction)) = parser.parse(&row.to_string(), &mut warning) {
+            let _ = engine.process(&transaction);
+        }
+    }
+    serialize_accounts(engine.accounts());
+}
+
+/// Processes `transactions` with amounts that parsing would never let through,
+/// like negative or NaN ones. Only disputes, resolves, and chargebacks get an
+/// amount of 0, as the engine expects of them.
+pub fn process(transactions: &[Transaction]) {
+    let mut engine = Engine::default();
+    for transaction in transactions {
+        let _ = match transaction.ty.refers_back() {
+            true => engine.process(&Transaction {
+                amount: 0.0,
+                ..transaction.clone()
+            }),
+            false => engine.process(transaction),
+        };
+    }
+    serialize_accounts(engine.accounts());
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrary::Unstructured;
+
+    use super::*;
+
+    #[test]
+    fn it_survives_arbitrary_inputs() {
+        // A fixed xorshift sequence, so that failures can be reproduced
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut data = vec![0; 4096];
+        for _ in 0..200 {
+            for byte in &mut data {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *byte = state as u8;
+            }
+            parse_input(&data);
+            let mut unstructured = Unstructured::new(&data);
+            parse_rows(&Vec::<Row>::arbitrary(&mut unstructured).unwrap());
+            process(&Vec::<Transaction>::arbitrary(&mut unstructured).unwrap());
+        }
+
+        let row = Row {
+            ty: Field::Type(TypeName::Deposit, true, true),
+            client: Field::Integer(1),
+            tx: Field::Integer(2),
+            amount: Some(Field::Decimal(12345, 2)),
+            extra: vec![Field::Empty],
+        };
+        assert_eq!(row.to_string(), " DEPOSIT,1,2,123.45,");
+    }
+}
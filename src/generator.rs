@@ -0,0 +1,224 @@
+//! Reproducible streams of made-up transactions, for benchmarks, load tests, and
+//! tests that need more input than can be written by hand. The same seed and
+//! options always result in the same transactions, on every platform.
+
+use std::io;
+
+use serde::Serialize;
+
+use crate::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+
+/// How many of the latest deposits can still be disputed, which bounds the
+/// memory of a stream
+const DISPUTABLE_DEPOSITS: usize = 10_000;
+
+/// The shape of a generated stream
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GeneratorOptions {
+    /// Clients are numbered from 1 to this
+    pub clients: ClientID,
+    /// The chance of a deposit or withdrawal being a withdrawal
+    pub withdrawal_probability: f64,
+    /// The chance of a transaction disputing an earlier deposit of the same
+    /// client. Open disputes are settled at the same rate.
+    pub dispute_probability: f64,
+    /// The chance of a dispute being charged back rather than resolved
+    pub chargeback_probability: f64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            clients: 1000,
+            withdrawal_probability: 0.3,
+            dispute_probability: 0.01,
+            chargeback_probability: 0.2,
+        }
+    }
+}
+
+/// An endless stream of transactions. Deposits and withdrawals have increasing
+/// IDs, which wrap around after [`TransactionID::MAX`], and amounts with two
+/// decimal places of up to 10000 and 1000 respectively. Disputes, resolves, and chargebacks refer to
+/// deposits of the same client.
+pub struct TransactionStream {
+    rng: SplitMix64,
+    options: GeneratorOptions,
+    next_id: TransactionID,
+    deposits: Vec<(ClientID, TransactionID)>,
+    disputes: Vec<(ClientID, TransactionID)>,
+}
+
+impl TransactionStream {
+    pub fn new(seed: u64, options: GeneratorOptions) -> Self {
+        Self {
+            rng: SplitMix64(seed),
+            options,
+            next_id: 1,
+            deposits: Vec::new(),
+            disputes: Vec::new(),
+        }
+    }
+
+    fn client(&mut self) -> ClientID {
+        // Client IDs are 64 bits wide with `client-id-u64`
+        #[allow(clippy::unnecessary_cast)]
+        let clients = self.options.clients.max(1) as u64;
+        ClientID::try_from(self.rng.below(clients) + 1).expect("clients fit in a client ID")
+    }
+
+    /// An amount of up to `max` with two decimal places
+    fn amount(&mut self, max: u64) -> f32 {
+        (self.rng.below(max * 100) + 1) as f32 / 100.0
+    }
+}
+
+impl Iterator for TransactionStream {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let dispute_probability = self.options.dispute_probability;
+        if !self.disputes.is_empty() && self.rng.chance(dispute_probability) {
+            let index = self.rng.below(self.disputes.len() as u64) as usize;
+            let (client_id, id) = self.disputes.swap_remove(index);
+            let ty = match self.rng.chance(self.options.chargeback_probability) {
+                true => TransactionType::Chargeback,
+                false => TransactionType::Resolve,
+            };
+            return Some(Transaction {
+                ty,
+                client_id,
+                id,
+                amount: 0.0,
+            });
+        }
+        if !self.deposits.is_empty() && self.rng.chance(dispute_probability) {
+            let index = self.rng.below(self.deposits.len() as u64) as usize;
+            let (client_id, id) = self.deposits.swap_remove(index);
+            self.disputes.push((client_id, id));
+            return Some(Transaction {
+                ty: TransactionType::Dispute,
+                client_id,
+                id,
+                amount: 0.0,
+            });
+        }
+
+        let ty = match self.rng.chance(self.options.withdrawal_probability) {
+            true => TransactionType::Withdrawal,
+            false => TransactionType::Deposit,
+        };
+        let client_id = self.client();
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if ty == TransactionType::Deposit {
+            if self.deposits.len() == DISPUTABLE_DEPOSITS {
+                let index = self.rng.below(DISPUTABLE_DEPOSITS as u64) as usize;
+                self.deposits.swap_remove(index);
+            }
+            self.deposits.push((client_id, id));
+        }
+        // Withdrawals are smaller, so that most of them have the funds
+        let amount = match ty {
+            TransactionType::Withdrawal => self.amount(1000),
+            _ => self.amount(10_000),
+        };
+        Some(Transaction {
+            ty,
+            client_id,
+            id,
+            amount,
+        })
+    }
+}
+
+/// Writes `transactions` as a CSV input, with disputes, resolves, and
+/// chargebacks leaving the amount empty
+pub fn write_csv(
+    transactions: impl IntoIterator<Item = Transaction>,
+    mut writer: impl io::Write,
+) -> io::Result<()> {
+    writeln!(writer, "type,client,tx,amount")?;
+    for transaction in transactions {
+        write!(
+            writer,
+            "{},{},{},",
+            transaction.ty.as_str(),
+            transaction.client_id,
+            transaction.id
+        )?;
+        match transaction.ty.refers_back() {
+            true => writeln!(writer)?,
+            false => writeln!(writer, "{}", transaction.amount)?,
+        }
+    }
+    Ok(())
+}
+
+/// The SplitMix64 generator, which is small and fast and, unlike the generators
+/// of crates, is guaranteed never to change its output
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `bound`, which must not be 0
+    fn below(&mut self, bound: u64) -> u64 {
+        ((u128::from(self.next_u64()) * u128::from(bound)) >> 64) as u64
+    }
+
+    /// Whether an event of the given probability happens
+    fn chance(&mut self, probability: f64) -> bool {
+        // The top 53 bits, which an f64 holds exactly
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn it_generates_reproducible_streams() {
+        let options = GeneratorOptions {
+            clients: 5,
+            dispute_probability: 0.1,
+            ..GeneratorOptions::default()
+        };
+        let first: Vec<_> = TransactionStream::new(7, options).take(1000).collect();
+        let again: Vec<_> = TransactionStream::new(7, options).take(1000).collect();
+        let other: Vec<_> = TransactionStream::new(8, options).take(1000).collect();
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+
+        let mut deposits = HashMap::new();
+        for transaction in &first {
+            assert!((1..=5).contains(&transaction.client_id));
+            match transaction.ty {
+                TransactionType::Deposit => {
+                    deposits.insert(transaction.id, transaction.client_id);
+                }
+                TransactionType::Withdrawal => {}
+                _ => assert_eq!(deposits.get(&transaction.id), Some(&transaction.client_id)),
+            }
+        }
+        assert!(first
+            .iter()
+            .any(|transaction| transaction.ty == TransactionType::Chargeback));
+
+        let mut csv = Vec::new();
+        write_csv(first.into_iter().take(2), &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,client,tx,amount\ndeposit,1,1,9007.61\ndeposit,2,2,4679.54\n"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Export of a journal written with `--ledger` in the CSV layout of an accounting
+//! system, described in a TOML file:
+//!
+//! ```toml
+//! delimiter = ";"
+//!
+//! [accounts]
+//! cash = "1000"
+//! held_funds = "2100"
+//! chargeback_loss = "6100"
+//! fees = "4100"
+//! # Every client account, unless listed under [accounts.clients]
+//! client = "2000"
+//!
+//! [accounts.clients]
+//! 42 = "2042"
+//!
+//! [[column]]
+//! header = "Account"
+//! field = "account"
+//!
+//! [[column]]
+//! header = "Debit"
+//! field = "debit"
+//!
+//! [[column]]
+//! header = "Credit"
+//! field = "credit"
+//!
+//! [[column]]
+//! header = "Reference"
+//! field = "tx"
+//! ```
+//!
+//! The fields are `account` (the code the ledger account is mapped to),
+//! `ledger_account` (its name in the journal, like `client:42`), `client` (empty
+//! for system accounts), `tx`, `type`, `debit`, `credit`, and `amount` (positive
+//! for debits and negative for credits).
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::ledger::{LedgerAccount, Posting};
+use crate::transaction::ClientID;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GlLayout {
+    #[serde(default = "default_delimiter")]
+    delimiter: char,
+    /// Whether to write a row of column headers
+    #[serde(default = "default_header")]
+    header: bool,
+    accounts: AccountCodes,
+    #[serde(rename = "column")]
+    columns: Vec<Column>,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_header() -> bool {
+    true
+}
+
+/// The code of each ledger account in the accounting system
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccountCodes {
+    cash: Option<String>,
+    held_funds: Option<String>,
+    chargeback_loss: Option<String>,
+    fees: Option<String>,
+    /// Code of the accounts of clients not listed in `clients`
+    client: Option<String>,
+    #[serde(default)]
+    clients: HashMap<ClientID, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Column {
+    header: String,
+    field: Field,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    Account,
+    LedgerAccount,
+    Client,
+    Tx,
+    Type,
+    Debit,
+    Credit,
+    Amount,
+}
+
+impl GlLayout {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let layout: Self = toml::from_str(text).map_err(|err| err.to_string())?;
+        if layout.columns.is_empty() {
+            return Err("no columns".to_string());
+        }
+        Ok(layout)
+    }
+
+    /// Writes `postings` in this layout, failing on the first posting to an
+    /// account without a code
+    pub fn export(&self, postings: &[Posting]) -> Result<String, String> {
+        let mut string = String::new();
+        if self.header {
+            let headers: Vec<&str> = self
+                .columns
+                .iter()
+                .map(|column| column.header.as_str())
+                .collect();
+            self.write_row(&mut string, &headers);
+        }
+        for posting in postings {
+            let code = self
+                .code(posting.account)
+                .ok_or_else(|| format!("no account code for {} in the layout", posting.account))?;
+            let amount = |debit: bool| match (debit, posting.amount >= 0.0) {
+                (true, true) => posting.amount.to_string(),
+                (false, false) => (-posting.amount).to_string(),
+                _ => String::new(),
+            };
+            let fields: Vec<String> = self
+                .columns
+                .iter()
+                .map(|column| match column.field {
+                    Field::Account => code.to_string(),
+                    Field::LedgerAccount => posting.account.to_string(),
+                    Field::Client => match posting.account {
+                        LedgerAccount::Client(client_id) => client_id.to_string(),
+                        _ => String::new(),
+                    },
+                    Field::Tx => posting.tx.to_string(),
+                    Field::Type => posting.ty.clone(),
+                    Field::Debit => amount(true),
+                    Field::Credit => amount(false),
+                    Field::Amount => posting.amount.to_string(),
+                })
+                .collect();
+            self.write_row(
+                &mut string,
+                &fields.iter().map(String::as_str).collect::<Vec<_>>(),
+            );
+        }
+        Ok(string)
+    }
+
+    fn code(&self, account: LedgerAccount) -> Option<&str> {
+        let codes = &self.accounts;
+        match account {
+            LedgerAccount::Cash => codes.cash.as_deref(),
+            LedgerAccount::HeldFunds => codes.held_funds.as_deref(),
+            LedgerAccount::ChargebackLoss => codes.chargeback_loss.as_deref(),
+            LedgerAccount::Fees => codes.fees.as_deref(),
+            LedgerAccount::Client(client_id) => codes
+                .clients
+                .get(&client_id)
+                .or(codes.client.as_ref())
+                .map(String::as_str),
+        }
+    }
+
+    fn write_row(&self, string: &mut String, fields: &[&str]) {
+        for (index, field) in fields.iter().enumerate() {
+            if index > 0 {
+                string.push(self.delimiter);
+            }
+            string.push_str(field);
+        }
+        string.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_exports_postings_in_the_layout() {
+        let layout = GlLayout::parse(
+            r#"
+            delimiter = ";"
+
+            [accounts]
+            cash = "1000"
+            client = "2000"
+
+            [accounts.clients]
+            42 = "2042"
+
+            [[column]]
+            header = "Account"
+            field = "account"
+
+            [[column]]
+            header = "Debit"
+            field = "debit"
+
+            [[column]]
+            header = "Credit"
+            field = "credit"
+
+            [[column]]
+            header = "Reference"
+            field = "tx"
+            "#,
+        )
+        .unwrap();
+        let posting = |account, amount| Posting {
+            tx: 1,
+            ty: "deposit".to_string(),
+            account,
+            amount,
+        };
+
+        assert_eq!(
+            layout.export(&[
+                posting(LedgerAccount::Cash, 2.5),
+                posting(LedgerAccount::Client(42), -2.5),
+                posting(LedgerAccount::Client(7), 0.5),
+            ]),
+            Ok("Account;Debit;Credit;Reference\n\
+                1000;2.5;;1\n\
+                2042;;2.5;1\n\
+                2000;0.5;;1\n"
+                .to_string())
+        );
+        assert_eq!(
+            layout.export(&[posting(LedgerAccount::Fees, 1.0)]),
+            Err("no account code for fees in the layout".to_string())
+        );
+        assert!(GlLayout::parse("column = []\n[accounts]").is_err());
+    }
+}
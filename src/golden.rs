@@ -0,0 +1,189 @@
+//! Golden files: pairs of an input `NAME.input.csv` and the accounts
+//! `NAME.expected.csv` it must result in, like a corpus of partner files along
+//! with the outputs they were signed off with. Tests can likewise compare an
+//! accounts output with a stored snapshot using [`assert_snapshot`].
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::account::parse_accounts;
+use crate::diff::{compare_accounts, diff_normalized, normalize_accounts};
+use crate::engine::Engine;
+use crate::policy::Policy;
+use crate::transaction::{ParseOptions, TransactionReader};
+
+/// If set, [`assert_snapshot`] writes the snapshots instead of comparing with them
+pub const UPDATE_SNAPSHOTS_ENV_VAR: &str = "TRANSACTIONS_UPDATE_SNAPSHOTS";
+
+const INPUT_SUFFIX: &str = ".input.csv";
+const EXPECTED_SUFFIX: &str = ".expected.csv";
+
+/// An input and the file of the accounts expected from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldenFile {
+    pub input: PathBuf,
+    /// `None` if the input has no matching expected file
+    pub expected: Option<PathBuf>,
+}
+
+/// Every `*.input.csv` in `directory`, sorted by path
+pub fn find(directory: &Path) -> io::Result<Vec<GoldenFile>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let input = entry?.path();
+        let Some(name) = input
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(INPUT_SUFFIX))
+        else {
+            continue;
+        };
+        let expected = directory.join(format!("{name}{EXPECTED_SUFFIX}"));
+        files.push(GoldenFile {
+            expected: expected.is_file().then_some(expected),
+            input,
+        });
+    }
+    files.sort_by(|a, b| a.input.cmp(&b.input));
+    Ok(files)
+}
+
+/// Processes `input` and returns every client whose account differs from the
+/// accounts CSV `expected`. Both are compared as parsed accounts, so the order
+/// of rows and how amounts are written do not matter. Malformed rows are
+/// skipped, as in a normal run.
+pub fn check(
+    input: impl io::BufRead,
+    expected: impl io::BufRead,
+    options: ParseOptions,
+    policy: Policy,
+) -> Result<Vec<String>, String> {
+    let expected = parse_accounts(expected)
+        .map_err(|err| format!("expected accounts could not be parsed: {err}"))?;
+    let reader = TransactionReader::with_options(input, options)
+        .map_err(|err| format!("input could not be parsed: {err}"))?;
+    let mut engine = Engine::with_policy(policy);
+    for transaction in reader.flatten() {
+        let _ = engine.process(&transaction);
+    }
+    Ok(compare_accounts(&expected, engine.accounts()))
+}
+
+/// Compares the accounts output `actual` with the snapshot at `path`, both
+/// normalized with [`normalize_accounts`], returning a diff if they differ. With
+/// `update`, the snapshot is written instead.
+pub fn check_snapshot(actual: &str, path: &Path, update: bool) -> Result<(), String> {
+    let actual = normalize_accounts(actual).map_err(|err| format!("invalid output: {err}"))?;
+    if update {
+        return fs::write(path, &actual)
+            .map_err(|err| format!("could not write {}: {err}", path.display()));
+    }
+    let expected = fs::read_to_string(path).map_err(|err| {
+        format!(
+            "could not read snapshot {}: {err}\n\
+             set {UPDATE_SNAPSHOTS_ENV_VAR}=1 to write it",
+            path.display()
+        )
+    })?;
+    let expected = normalize_accounts(&expected)
+        .map_err(|err| format!("invalid snapshot {}: {err}", path.display()))?;
+    if expected == actual {
+        return Ok(());
+    }
+    Err(format!(
+        "accounts differ from snapshot {} (set {UPDATE_SNAPSHOTS_ENV_VAR}=1 to update it)\n{}",
+        path.display(),
+        diff_normalized(&expected, &actual)
+    ))
+}
+
+/// Panics with a diff unless the accounts output `actual` matches the snapshot
+/// at `path`, or writes the snapshot if [`UPDATE_SNAPSHOTS_ENV_VAR`] is set
+#[track_caller]
+pub fn assert_snapshot(actual: &str, path: impl AsRef<Path>) {
+    let update = std::env::var_os(UPDATE_SNAPSHOTS_ENV_VAR).is_some();
+    if let Err(err) = check_snapshot(actual, path.as_ref(), update) {
+        panic!("{err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn it_checks_golden_files() {
+        let directory = TempPath::new("golden");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("b.input.csv"), "").unwrap();
+        fs::write(directory.join("a.input.csv"), "").unwrap();
+        fs::write(directory.join("a.expected.csv"), "").unwrap();
+        fs::write(directory.join("notes.csv"), "").unwrap();
+        assert_eq!(
+            find(&directory).unwrap(),
+            [
+                GoldenFile {
+                    input: directory.join("a.input.csv"),
+                    expected: Some(directory.join("a.expected.csv")),
+                },
+                GoldenFile {
+                    input: directory.join("b.input.csv"),
+                    expected: None,
+                },
+            ]
+        );
+
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\nnonsense\n";
+        let check = |expected: &str| {
+            check(
+                input.as_bytes(),
+                expected.as_bytes(),
+                ParseOptions::default(),
+                Policy::default(),
+            )
+        };
+        assert_eq!(
+            check("client,available,held,total,locked\n1,5.0000,0,5,false\n"),
+            Ok(vec![])
+        );
+        assert_eq!(
+            check("client,available,held,total,locked\n1,5,0,5,false\n2,1,0,1,false\n"),
+            Ok(vec!["client 2: expected an account".to_string()])
+        );
+    }
+
+    #[test]
+    fn it_compares_snapshots() {
+        let output = "client,available,held,total,locked\n\
+                      10,38.99,0,38.99,false\n\
+                      5,7.5,0,7.5,false\n";
+        assert_eq!(
+            normalize_accounts(output).unwrap(),
+            "client,available,held,total,locked\n\
+             5,7.5000,0.0000,7.5000,false\n\
+             10,38.9900,0.0000,38.9900,false\n"
+        );
+
+        let path = TempPath::new("golden-snapshot.csv");
+        assert!(check_snapshot(output, &path, false).is_err());
+        check_snapshot(output, &path, true).unwrap();
+        assert_snapshot(
+            "client,available,held,total,locked\n5,7.50,0,7.5,false\n10,38.99,-0,38.99,false\n",
+            &path,
+        );
+
+        let changed = "client,available,held,total,locked\n\
+                       5,7.5,0,7.5,true\n\
+                       7,1,0,1,false\n";
+        let err = check_snapshot(changed, &path, false).unwrap_err();
+        assert!(err.ends_with(
+            "- 5,7.5000,0.0000,7.5000,false\n\
+             + 5,7.5000,0.0000,7.5000,true\n\
+             + 7,1.0000,0.0000,1.0000,false\n\
+             - 10,38.9900,0.0000,38.9900,false\n"
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+//! Rows of types the engine does not know itself, handled by code registered
+//! through [`Engine::register_handler`](crate::engine::Engine::register_handler)
+//! so that other crates can extend the vocabulary of the input.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::account::Account;
+use crate::transaction::{Rejection, Transaction};
+
+/// Applies rows of a custom type, whose [`Transaction::ty`] is
+/// [`TransactionType::Custom`](crate::transaction::TransactionType::Custom)
+pub trait TransactionHandler: Send + Sync {
+    /// Applies `transaction` to the client's `account`, or leaves it untouched and
+    /// returns the reason if it cannot be applied. Such transactions cannot be
+    /// disputed.
+    fn process(&self, transaction: &Transaction, account: &mut Account) -> Result<(), Rejection>;
+}
+
+/// Handlers by the type of rows they apply
+#[derive(Clone, Default)]
+pub(crate) struct Handlers(HashMap<String, Arc<dyn TransactionHandler>>);
+
+impl Handlers {
+    pub(crate) fn insert(&mut self, ty: &str, handler: Arc<dyn TransactionHandler>) {
+        self.0.insert(ty.to_string(), handler);
+    }
+
+    pub(crate) fn types(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    pub(crate) fn process(
+        &self,
+        ty: &str,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> Result<(), Rejection> {
+        match self.0.get(ty) {
+            Some(handler) => handler.process(transaction, account),
+            None => Err(Rejection::UnknownType),
+        }
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::session::{RowOutcome, Session};
+    use crate::transaction::{CustomType, ParseOptions, TransactionType};
+
+    /// Debits a fee from the available funds
+    struct Fee;
+
+    impl TransactionHandler for Fee {
+        fn process(
+            &self,
+            transaction: &Transaction,
+            account: &mut Account,
+        ) -> Result<(), Rejection> {
+            if account.available < transaction.amount {
+                return Err(Rejection::InsufficientFunds);
+            }
+            account.available -= transaction.amount;
+            account.total -= transaction.amount;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_routes_custom_types_to_their_handler() {
+        let mut engine = Engine::default();
+        engine.register_handler("fee", Fee);
+        let mut session = Session::new(engine, ParseOptions::default());
+
+        session
+            .push("type,client,tx,amount\ndeposit,1,1,5.0\nfee,1,2,1.5\n")
+            .unwrap();
+        assert_eq!(
+            session.process_row("fee,1,3,9.0"),
+            Ok(RowOutcome::Rejected(Rejection::InsufficientFunds))
+        );
+        assert!(session.process_row("bonus,1,4,9.0").is_err());
+        assert_eq!(session.engine().accounts()[&1].total, 3.5);
+        // Only deposits and withdrawals can be disputed
+        assert_eq!(
+            session.process_row("dispute,1,2,"),
+            Ok(RowOutcome::Rejected(Rejection::UnknownTransaction))
+        );
+
+        // Custom types keep their name, like in event logs
+        let fee = r#"{"type":"fee","client":1,"tx":2,"amount":1.5}"#;
+        let transaction: Transaction = serde_json::from_str(fee).unwrap();
+        assert_eq!(
+            transaction.ty,
+            TransactionType::Custom(CustomType::new("fee"))
+        );
+        assert_eq!(serde_json::to_string(&transaction).unwrap(), fee);
+        let unknown = r#"{"type":"rebate","client":1,"tx":4,"amount":9.0}"#;
+        assert!(serde_json::from_str::<Transaction>(unknown).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! A self-contained HTML page about a run, for reviewing it in a browser without
+//! any CSV tooling. Charts are inline SVG, so the page needs no scripts or
+//! network access.
+
+use std::fmt::Write;
+
+use crate::account::Account;
+use crate::engine::Engine;
+use crate::report::{Statistics, Summary};
+use crate::transaction::ClientID;
+
+/// How many accounts with the most funds are listed
+const TOP_ACCOUNTS: usize = 10;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:60em;color:#222}\
+                     table{border-collapse:collapse;margin-bottom:1em}\
+                     th,td{padding:.25em .75em;text-align:right;border-bottom:1px solid #ddd}\
+                     th:first-child,td:first-child{text-align:left}\
+                     svg text{font-size:12px}";
+
+/// Renders the page for an input that `engines` processed into `summary`
+pub fn render(summary: &Summary, engines: &[&Engine]) -> String {
+    let statistics = Statistics::of(summary, engines.iter().copied());
+    let mut accounts: Vec<(ClientID, &Account)> = engines
+        .iter()
+        .flat_map(|engine| engine.accounts.iter().map(|(id, account)| (*id, account)))
+        .collect();
+    accounts.sort_by(|(a_id, a), (b_id, b)| b.total.total_cmp(&a.total).then(a_id.cmp(b_id)));
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Transactions report</title>\n");
+    writeln!(html, "<style>{STYLE}</style>").unwrap();
+    html.push_str("</head>\n<body>\n<h1>Transactions report</h1>\n");
+
+    html.push_str("<h2>Summary</h2>\n");
+    table(
+        &mut html,
+        &["Figure", "Value"],
+        [
+            ["Rows parsed".to_string(), summary.rows_parsed.to_string()],
+            ["Rows skipped".to_string(), summary.rows_skipped.to_string()],
+            [
+                "Rows malformed".to_string(),
+                summary.rows_malformed.to_string(),
+            ],
+            ["Deposited".to_string(), statistics.deposited.to_string()],
+            ["Withdrawn".to_string(), statistics.withdrawn.to_string()],
+            [
+                "Charged back".to_string(),
+                statistics.charged_back.to_string(),
+            ],
+            [
+                "Active accounts".to_string(),
+                statistics.active_accounts.to_string(),
+            ],
+            [
+                "Locked accounts".to_string(),
+                statistics.locked_accounts.to_string(),
+            ],
+            [
+                "Empty accounts".to_string(),
+                statistics.empty_accounts.to_string(),
+            ],
+            [
+                "Open disputes".to_string(),
+                summary.open_disputes.to_string(),
+            ],
+        ],
+    );
+
+    html.push_str("<h2>Applied transactions</h2>\n");
+    bar_chart(
+        &mut html,
+        summary
+            .applied
+            .iter()
+            .map(|(ty, count)| (ty.as_str(), *count)),
+    );
+
+    html.push_str("<h2>Rejections</h2>\n");
+    bar_chart(
+        &mut html,
+        summary
+            .rejected
+            .iter()
+            .map(|(rejection, count)| (rejection.code(), *count)),
+    );
+
+    html.push_str("<h2>Top accounts</h2>\n");
+    account_table(&mut html, accounts.iter().take(TOP_ACCOUNTS));
+
+    html.push_str("<h2>Locked accounts</h2>\n");
+    account_table(
+        &mut html,
+        accounts.iter().filter(|(_, account)| account.locked),
+    );
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn table<const N: usize>(
+    html: &mut String,
+    headers: &[&str; N],
+    rows: impl IntoIterator<Item = [String; N]>,
+) {
+    html.push_str("<table>\n<tr>");
+    for header in headers {
+        write!(html, "<th>{}</th>", escape(header)).unwrap();
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            write!(html, "<td>{}</td>", escape(&cell)).unwrap();
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+fn account_table<'a>(
+    html: &mut String,
+    accounts: impl Iterator<Item = &'a (ClientID, &'a Account)>,
+) {
+    let mut accounts = accounts.peekable();
+    if accounts.peek().is_none() {
+        html.push_str("<p>None</p>\n");
+        return;
+    }
+    table(
+        html,
+        &["Client", "Available", "Held", "Total", "Locked"],
+        accounts.map(|(client_id, account)| {
+            [
+                client_id.to_string(),
+                account.available.to_string(),
+                account.held.to_string(),
+                account.total.to_string(),
+                account.locked.to_string(),
+            ]
+        }),
+    );
+}
+
+/// A horizontal bar per label, scaled to the largest count
+fn bar_chart<'a>(html: &mut String, bars: impl Iterator<Item = (&'a str, u64)>) {
+    const BAR_HEIGHT: usize = 20;
+    const LABEL_WIDTH: usize = 160;
+    const CHART_WIDTH: u64 = 400;
+
+    let bars: Vec<_> = bars.collect();
+    let Some(max) = bars.iter().map(|(_, count)| *count).max() else {
+        html.push_str("<p>None</p>\n");
+        return;
+    };
+    writeln!(
+        html,
+        "<svg width=\"{}\" height=\"{}\">",
+        LABEL_WIDTH + CHART_WIDTH as usize + 60,
+        bars.len() * BAR_HEIGHT
+    )
+    .unwrap();
+    for (index, (label, count)) in bars.iter().enumerate() {
+        let y = index * BAR_HEIGHT;
+        // Only counted labels are listed, so `max` is never 0
+        let width = count * CHART_WIDTH / max;
+        writeln!(
+            html,
+            "<text x=\"0\" y=\"{}\">{}</text>\
+             <rect x=\"{LABEL_WIDTH}\" y=\"{}\" width=\"{width}\" height=\"{}\" fill=\"#4a7ab8\"/>\
+             <text x=\"{}\" y=\"{}\">{count}</text>",
+            y + 14,
+            escape(label),
+            y + 2,
+            BAR_HEIGHT - 4,
+            LABEL_WIDTH + width as usize + 4,
+            y + 14,
+        )
+        .unwrap();
+    }
+    html.push_str("</svg>\n");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_renders_a_report() {
+        let transactions_string = "type,       client, tx, amount\n\
+                                   deposit,    1,      1,  5.0\n\
+                                   deposit,    2,      2,  7.0\n\
+                                   withdrawal, 1,      3,  9.0\n\
+                                   dispute,    2,      2\n\
+                                   chargeback, 2,      2\n\
+                                   ";
+        let mut engine = Engine::default();
+        let mut summary = Summary::default();
+        for transaction in TransactionReader::new(io::Cursor::new(transactions_string)).unwrap() {
+            let transaction = transaction.unwrap();
+            summary.record(&transaction, engine.process(&transaction));
+        }
+        summary.finish(&engine);
+
+        let html = render(&summary, &[&engine]);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains(">insufficient_funds</text>"));
+        assert!(html.contains(
+            "<h2>Locked accounts</h2>\n<table>\n\
+             <tr><th>Client</th><th>Available</th><th>Held</th><th>Total</th><th>Locked</th></tr>\n\
+             <tr><td>2</td><td>0</td><td>0</td><td>0</td><td>true</td></tr>\n\
+             </table>"
+        ));
+        assert!(!html.contains("<script"));
+    }
+}
@@ -0,0 +1,351 @@
+//! A double-entry view of a run: every applied transaction is journaled as debits
+//! and credits against an account per client and a few system accounts, so that
+//! a trial balance of the journal proves no money appeared or vanished.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+
+/// An account of the ledger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LedgerAccount {
+    /// Money the platform holds, debited by deposits and credited by withdrawals
+    /// and chargebacks
+    Cash,
+    /// Funds of all clients held because of disputes
+    HeldFunds,
+    /// Funds returned by chargebacks that the client had already withdrawn
+    ChargebackLoss,
+    /// Fees debited from clients, like the chargeback fee or fees of rules
+    Fees,
+    /// Funds of a client that are not held because of a dispute. What the client
+    /// owes after a chargeback is written off as [`LedgerAccount::ChargebackLoss`].
+    Client(ClientID),
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Cash => f.write_str("cash"),
+            LedgerAccount::HeldFunds => f.write_str("held_funds"),
+            LedgerAccount::ChargebackLoss => f.write_str("chargeback_loss"),
+            LedgerAccount::Fees => f.write_str("fees"),
+            LedgerAccount::Client(client_id) => write!(f, "client:{client_id}"),
+        }
+    }
+}
+
+impl FromStr for LedgerAccount {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Ok(match string {
+            "cash" => LedgerAccount::Cash,
+            "held_funds" => LedgerAccount::HeldFunds,
+            "chargeback_loss" => LedgerAccount::ChargebackLoss,
+            "fees" => LedgerAccount::Fees,
+            _ => LedgerAccount::Client(
+                string
+                    .strip_prefix("client:")
+                    .and_then(|client_id| client_id.parse().ok())
+                    .ok_or("invalid ledger account")?,
+            ),
+        })
+    }
+}
+
+/// Writes the journal of a run to a CSV file with the columns `tx`, `type`,
+/// `account`, `debit`, and `credit`, one line per posting
+pub struct Journal {
+    writer: BufWriter<fs::File>,
+    /// The balance of each client's ledger account, to tell how much of a
+    /// chargeback the client could not cover
+    clients: HashMap<ClientID, f32>,
+}
+
+impl Journal {
+    /// Creates the journal at `path`, replacing any existing file
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file =
+            fs::File::create(path).map_err(|err| format!("could not create ledger: {err}"))?;
+        let mut journal = Self {
+            writer: BufWriter::new(file),
+            clients: HashMap::new(),
+        };
+        journal.write("tx,type,account,debit,credit")?;
+        Ok(journal)
+    }
+
+    /// Journals an applied `transaction`. `referenced_amount` is the amount of the
+    /// transaction a dispute, resolve, or chargeback refers to. The total funds of
+    /// the client's account before and after the transaction account for fees and
+    /// custom types.
+    pub fn post(
+        &mut self,
+        transaction: &Transaction,
+        referenced_amount: f32,
+        total_before: f32,
+        total_after: f32,
+    ) -> Result<(), String> {
+        let postings = self.postings(transaction, referenced_amount, total_before, total_after);
+        for (account, amount) in postings {
+            if let LedgerAccount::Client(client_id) = account {
+                *self.clients.entry(client_id).or_default() += amount;
+            }
+            let (debit, credit) = if amount >= 0.0 {
+                (amount.to_string(), String::new())
+            } else {
+                (String::new(), (-amount).to_string())
+            };
+            self.write(&format!(
+                "{},{},{account},{debit},{credit}",
+                transaction.id,
+                transaction.ty.as_str()
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// The postings for a transaction, as amounts that are positive for debits and
+    /// negative for credits. Client accounts are liabilities, so crediting one
+    /// increases the client's funds.
+    fn postings(
+        &self,
+        transaction: &Transaction,
+        referenced_amount: f32,
+        total_before: f32,
+        total_after: f32,
+    ) -> Vec<(LedgerAccount, f32)> {
+        use LedgerAccount::*;
+        use TransactionType::*;
+
+        let client = Client(transaction.client_id);
+        let amount = match transaction.ty {
+            Dispute | Resolve | Chargeback => referenced_amount,
+            _ => transaction.amount,
+        };
+        let mut postings = match transaction.ty {
+            Deposit => vec![(Cash, amount), (client, -amount)],
+            Withdrawal => vec![(client, amount), (Cash, -amount)],
+            Dispute => vec![(client, amount), (HeldFunds, -amount)],
+            Resolve => vec![(HeldFunds, amount), (client, -amount)],
+            Chargeback => {
+                let mut postings = vec![(HeldFunds, amount), (Cash, -amount)];
+                // Whatever of the charged back funds the client already withdrew is lost
+                let balance = self
+                    .clients
+                    .get(&transaction.client_id)
+                    .copied()
+                    .unwrap_or_default();
+                let lost = amount.min(balance);
+                if lost > 0.0 {
+                    postings.extend([(ChargebackLoss, lost), (client, -lost)]);
+                }
+                postings
+            }
+            Custom(_) => Vec::new(),
+        };
+
+        // Money the engine moved beyond the transaction itself, computed the same
+        // way the engine does so that there is no rest due to rounding
+        let expected_total = match transaction.ty {
+            Deposit => total_before + amount,
+            Withdrawal | Chargeback => total_before - amount,
+            _ => total_before,
+        };
+        let rest = total_after - expected_total;
+        if rest != 0.0 {
+            // Custom types move money in or out, anything else is a fee
+            let other = match transaction.ty {
+                Custom(_) => Cash,
+                _ => Fees,
+            };
+            postings.extend([(client, -rest), (other, rest)]);
+        }
+        postings
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.writer, "{line}").map_err(|err| format!("could not write ledger: {err}"))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .map_err(|err| format!("could not write ledger: {err}"))
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        self.flush()
+    }
+}
+
+/// A line of a journal
+#[derive(Debug, Clone, PartialEq)]
+pub struct Posting {
+    pub tx: TransactionID,
+    /// The type of the transaction
+    pub ty: String,
+    pub account: LedgerAccount,
+    /// Positive for debits, negative for credits
+    pub amount: f32,
+}
+
+/// Reads a journal as written by [`Journal`]
+pub fn read_journal(reader: impl io::BufRead) -> Result<Vec<Posting>, String> {
+    let mut postings = Vec::new();
+    let mut rows = reader.lines();
+
+    rows.next(); // Skip row of column types
+
+    for (index, row) in rows.enumerate() {
+        let line = index + 2;
+        let row = row.map_err(|err| format!("could not read ledger: {err}"))?;
+        let columns: Vec<&str> = row.split(',').map(str::trim).collect();
+        let [tx, ty, account, debit, credit] = columns[..] else {
+            return Err(format!("expected 5 columns on line {line} of the ledger"));
+        };
+        let invalid = |what| format!("invalid {what} on line {line} of the ledger");
+        let amount = |column: &str| match column {
+            "" => Ok(0.0),
+            amount => amount.parse::<f32>().map_err(|_| invalid("amount")),
+        };
+        postings.push(Posting {
+            tx: tx.parse().map_err(|_| invalid("transaction ID"))?,
+            ty: ty.to_string(),
+            account: account.parse().map_err(|_| invalid("account"))?,
+            amount: amount(debit)? - amount(credit)?,
+        });
+    }
+    Ok(postings)
+}
+
+/// The balance of every account of a journal
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrialBalance {
+    /// Positive for debit balances, negative for credit balances
+    pub balances: BTreeMap<LedgerAccount, f64>,
+}
+
+impl TrialBalance {
+    /// Sums up the postings of a journal
+    pub fn of(postings: &[Posting]) -> Self {
+        let mut trial_balance = Self::default();
+        for posting in postings {
+            *trial_balance.balances.entry(posting.account).or_default() +=
+                f64::from(posting.amount);
+        }
+        trial_balance
+    }
+
+    /// Sum of the debit balances
+    pub fn debits(&self) -> f64 {
+        self.balances
+            .values()
+            .filter(|balance| **balance > 0.0)
+            .sum()
+    }
+
+    /// Sum of the credit balances
+    pub fn credits(&self) -> f64 {
+        -self
+            .balances
+            .values()
+            .filter(|balance| **balance < 0.0)
+            .sum::<f64>()
+    }
+
+    /// Whether the debits equal the credits, up to the rounding of the amounts
+    pub fn is_balanced(&self) -> bool {
+        let (debits, credits) = (self.debits(), self.credits());
+        (debits - credits).abs() <= debits.max(credits).max(1.0) * f32::EPSILON as f64
+    }
+}
+
+impl fmt::Display for TrialBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "account,debit,credit")?;
+        for (account, balance) in &self.balances {
+            // Printed at the precision of the journal's amounts
+            let balance = *balance as f32;
+            if balance >= 0.0 {
+                writeln!(f, "{account},{balance},")?;
+            } else {
+                writeln!(f, "{account},,{}", -balance)?;
+            }
+        }
+        writeln!(
+            f,
+            "total,{},{}",
+            self.debits() as f32,
+            self.credits() as f32
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::policy::Policy;
+    use crate::run::{Run, RunOptions};
+    use crate::testing::TempPath;
+    use crate::transaction::{RowContext, TransactionReader};
+
+    #[test]
+    fn it_journals_balanced_postings() {
+        let transactions_string = "type,       client, tx, amount\n\
+                                   deposit,    1,      1,  5.0\n\
+                                   deposit,    1,      2,  3.0\n\
+                                   withdrawal, 1,      3,  6.0\n\
+                                   dispute,    1,      2\n\
+                                   chargeback, 1,      2\n\
+                                   deposit,    2,      4,  2.5\n\
+                                   dispute,    2,      4\n\
+                                   resolve,    2,      4\n\
+                                   ";
+        let path = TempPath::new("ledger.csv");
+        let options = RunOptions {
+            quiet: true,
+            ..RunOptions::default()
+        };
+        let mut run = Run::new(options, None).with_journal(Journal::create(&path).unwrap());
+        let mut engine = Engine::with_policy(Policy {
+            chargeback_fee: Some(1.0),
+            ..Policy::default()
+        });
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+        for transaction in TransactionReader::new(io::Cursor::new(transactions_string)).unwrap() {
+            run.process(&mut engine, &transaction.unwrap(), &row)
+                .unwrap();
+        }
+        run.finish(&engine, 8, 0).unwrap();
+
+        let file = fs::File::open(&path).unwrap();
+        let trial_balance = TrialBalance::of(&read_journal(io::BufReader::new(file)).unwrap());
+        assert!(trial_balance.is_balanced());
+        // Client 1 withdrew 1.0 of the funds that were charged back, and still owes the fee
+        assert_eq!(
+            trial_balance.to_string(),
+            "account,debit,credit\n\
+             cash,1.5,\n\
+             held_funds,0,\n\
+             chargeback_loss,1,\n\
+             fees,,1\n\
+             client:1,1,\n\
+             client:2,,2.5\n\
+             total,3.5,3.5\n"
+        );
+    }
+}
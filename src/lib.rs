@@ -0,0 +1,90 @@
+//! A toy payments engine that reads transactions from a CSV, handles deposits,
+//! withdrawals, disputes, resolves, and chargebacks, and serializes the
+//! resulting accounts back to CSV.
+
+pub mod account;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod assertion;
+#[cfg(feature = "async")]
+pub mod async_engine;
+pub mod diff;
+pub mod engine;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixture;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+pub mod generator;
+pub mod golden;
+pub mod handler;
+#[cfg(feature = "node")]
+pub mod node;
+pub mod parallel;
+pub mod policy;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod report;
+pub mod rules;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod session;
+pub mod state;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+#[cfg(test)]
+mod testing;
+pub mod transaction;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// The command-line interface of the binary, which is not part of the API
+#[doc(hidden)]
+pub mod anomaly;
+#[doc(hidden)]
+pub mod audit;
+#[cfg(feature = "avro")]
+#[doc(hidden)]
+pub mod avro;
+#[cfg(feature = "camt")]
+#[doc(hidden)]
+pub mod camt;
+#[doc(hidden)]
+pub mod compression;
+#[doc(hidden)]
+pub mod crypto;
+#[cfg(feature = "dashboard")]
+#[doc(hidden)]
+pub mod dashboard;
+#[doc(hidden)]
+pub mod events;
+#[doc(hidden)]
+pub mod fifo;
+#[doc(hidden)]
+pub mod gl;
+#[doc(hidden)]
+pub mod html;
+#[doc(hidden)]
+pub mod ledger;
+#[doc(hidden)]
+pub mod merge;
+#[doc(hidden)]
+pub mod paranoid;
+#[doc(hidden)]
+pub mod partitioned;
+#[doc(hidden)]
+pub mod partner;
+#[cfg(feature = "remote")]
+#[doc(hidden)]
+pub mod remote;
+#[doc(hidden)]
+pub mod run;
+#[doc(hidden)]
+pub mod scenario;
+#[doc(hidden)]
+pub mod simulation;
+#[doc(hidden)]
+pub mod snapshot;
+#[cfg(feature = "xlsx")]
+#[doc(hidden)]
+pub mod xlsx;
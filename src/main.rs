@@ -1,58 +1,2283 @@
-use std::{collections::HashMap, env, fs, io, process::ExitCode};
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    iter, mem,
+    path::Path,
+    path::PathBuf,
+    process::{self, ExitCode},
+    slice,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+    time::Duration,
+};
 
-mod account;
-mod transaction;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 
-use account::{serialize_accounts, Account};
-use transaction::{parse_transactions, ClientID, Transaction, TransactionID};
+#[cfg(feature = "avro")]
+use transactions::avro;
+#[cfg(feature = "camt")]
+use transactions::camt;
+#[cfg(feature = "dashboard")]
+use transactions::dashboard::{Dashboard, TerminalDashboard};
+#[cfg(feature = "xlsx")]
+use transactions::xlsx;
+use transactions::{
+    account::{
+        parse_accounts, serialize_engine_accounts, serialize_statement, stream_accounts,
+        stream_accounts_in_parallel, Account,
+    },
+    anomaly::{serialize_anomalies, AnomalyOptions, Detector},
+    audit::AuditLog,
+    compression::{CompressedWriter, Compression},
+    crypto::{self, StateKey},
+    diff,
+    engine::Engine,
+    events::{self, EventLog},
+    fifo::{self, Interruptible, Reconnecting},
+    fixture,
+    generator::{self, GeneratorOptions, TransactionStream},
+    gl::GlLayout,
+    golden, html,
+    ledger::{self, Journal, Posting, TrialBalance},
+    merge::MergedReader,
+    parallel::{self, ShardedRun},
+    paranoid, partitioned,
+    partner::{
+        serialize_extended_partitioned_accounts, serialize_partitioned_accounts, Partitions,
+    },
+    policy::{Blocklist, ClearingPeriod, DailyLimits, Kyc, LockPolicy, Policy, VelocityLimit},
+    report::{
+        Aggregation, AggregationPeriod, Checksummed, DailyReports, FailedWithdrawalReport,
+        LockReport, Metrics, OutputChecksum, RunResult, Statistics, Summary,
+    },
+    rules::Rules,
+    run::Abort,
+    run::{LogFormat, Run, RunOptions},
+    scenario::Scenario,
+    simulation::{self, SimulationLimits},
+    snapshot::{SnapshotInterval, Snapshots},
+    state,
+    transaction::{
+        parse_type_aliases, AmountPolicy, ClientID, Encoding, IdFormat, IdOrderCheck, InternedIds,
+        MissingAmountPolicy, ParseOptions, TransactionReader, TransactionSource,
+    },
+};
+
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// CSV files of transactions to process. Several files are parsed in parallel
+    /// and merged in the order of their timestamps, or read in the order given if
+    /// they have no timestamp column. `-` reads stdin. With the `remote` feature,
+    /// inputs can also be `https://` or `s3://` URLs.
+    inputs: Vec<PathBuf>,
+    /// File containing a 256-bit key (raw or hex) used to encrypt and decrypt
+    /// persisted state; falls back to the TRANSACTIONS_STATE_KEY environment variable
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+    /// Append every applied transaction to this event log
+    #[arg(long, global = true)]
+    event_log: Option<PathBuf>,
+    /// Print a summary of the run to stderr once all transactions are processed
+    #[arg(long, global = true)]
+    summary: bool,
+    /// Write the summary of the run to this file instead of stderr
+    #[arg(long, global = true)]
+    summary_file: Option<PathBuf>,
+    /// Write a self-contained HTML page with statistics, the top and the locked
+    /// accounts, and charts of the applied and rejected transactions to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    report_html: Option<PathBuf>,
+    /// Skip malformed rows, but abort once more than this many rows were
+    /// malformed or rejected
+    #[arg(long, global = true, value_name = "N")]
+    max_errors: Option<u64>,
+    /// Skip and report malformed rows, however many there are
+    #[arg(long, global = true, conflicts_with = "max_errors")]
+    lenient: bool,
+    /// Check after every applied transaction that available + held == total and
+    /// that no funds are negative, halting on the first violation
+    #[arg(long, global = true)]
+    check_invariants: bool,
+    /// Check the internal state of the engine around every transaction, like the
+    /// transaction index and dispute states, as well as once at the end, halting
+    /// on the first inconsistency. Meant for canary runs after upgrades.
+    #[arg(long, global = true)]
+    paranoid: bool,
+    /// Check that deposits, withdrawals, and custom transactions appear in the
+    /// order of their IDs, halting on the first one that does not
+    #[arg(long, global = true)]
+    check_id_order: bool,
+    /// Check rows like '#assert,5,available,7.5' against the account of the
+    /// client at that point in the input, halting on the first that does not
+    /// hold. Fields are available, held, total, and locked.
+    #[arg(long, global = true)]
+    assertions: bool,
+    /// With --check-invariants or --paranoid, accept negative available funds
+    /// (e.g. from disputing funds that were already withdrawn)
+    #[arg(long, global = true)]
+    allow_negative_available: bool,
+    /// Strict mode: wait up to this many rows for the transaction a dispute, resolve,
+    /// or chargeback refers to, and reject it if it does not appear
+    #[arg(long, global = true, value_name = "ROWS")]
+    lookahead: Option<u64>,
+    /// What to do with dispute, resolve, and chargeback rows that carry an amount:
+    /// ignore, warn, or reject
+    #[arg(long, global = true, value_name = "POLICY", default_value = "ignore")]
+    dispute_amounts: AmountPolicy,
+    /// What to do with deposits and withdrawals without an amount: reject the
+    /// row, take the amount to be zero, or skip the row [default: zero, or reject
+    /// with --strict]
+    #[arg(long, global = true, value_name = "POLICY")]
+    missing_amounts: Option<MissingAmountPolicy>,
+    /// Treat deposits and withdrawals without an amount as malformed unless
+    /// --missing-amounts says otherwise
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Report every rejected transaction on stderr, along with warnings and
+    /// skipped rows
+    #[arg(long, global = true)]
+    log_rejections: bool,
+    /// How to report warnings, rejected transactions, and skipped rows on stderr:
+    /// text, or json for one object per line
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text")]
+    log_format: LogFormat,
+    /// Abort once the engine takes up more than this much
+    /// memory, in bytes or with a K, M, or G suffix
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    memory_limit: Option<usize>,
+    /// Number of threads processing transactions, each owning a shard of the
+    /// clients, or 0 for one per core. Only plain runs can be spread across
+    /// threads: logs, reports, and limits that follow all transactions in order
+    /// are not supported with more than one. [default: 1]
+    #[arg(long, global = true, value_name = "N")]
+    threads: Option<usize>,
+    /// Number of rows each thread may have queued before reading pauses, which
+    /// bounds the memory taken by rows read ahead [default: 1024]
+    #[arg(long, global = true, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    queue_length: Option<usize>,
+    /// Print timings and peak sizes of the run to stderr at the end, as JSON
+    /// with --log-format json
+    #[arg(long, global = true)]
+    metrics: bool,
+    /// Show a live dashboard of the run on stderr while processing, instead of
+    /// reporting warnings, rejected transactions, and skipped rows
+    #[cfg(feature = "dashboard")]
+    #[arg(long, global = true)]
+    dashboard: bool,
+    /// Write the accounts to this file while the inputs are still being read, like
+    /// a stream on stdin, replacing it atomically every --snapshot-rows rows or
+    /// --snapshot-seconds seconds and once the inputs end
+    #[arg(long, global = true, value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+    /// With --snapshot, write a snapshot after this many rows
+    #[arg(long, global = true, value_name = "N", requires = "snapshot", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_rows: Option<u64>,
+    /// With --snapshot, write a snapshot once this many seconds passed, as soon as
+    /// another row arrives
+    #[arg(long, global = true, value_name = "SECONDS", requires = "snapshot")]
+    snapshot_seconds: Option<u64>,
+    /// Keep reading named pipes (FIFOs) when their writer disconnects, waiting for
+    /// the next one until interrupted
+    #[arg(long, global = true)]
+    reconnect: bool,
+    /// Write the outcome of the run (exit code, summary, and checksums of the
+    /// outputs) to this file as JSON
+    #[arg(long, global = true, value_name = "PATH")]
+    result_json: Option<PathBuf>,
+    /// Apply the velocity limits to each client's most recent this many transactions
+    #[arg(long, global = true, value_name = "M", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    velocity_window: Option<usize>,
+    /// Reject withdrawals beyond this many within the velocity window
+    #[arg(long, global = true, value_name = "N", requires = "velocity_window")]
+    max_withdrawals: Option<usize>,
+    /// Reject withdrawals beyond this total amount within the velocity window
+    #[arg(
+        long,
+        global = true,
+        value_name = "AMOUNT",
+        requires = "velocity_window"
+    )]
+    max_withdrawal_amount: Option<f32>,
+    /// Reject all transactions of the clients listed in the first column of this CSV file
+    #[arg(long, global = true, value_name = "PATH")]
+    blocklist: Option<PathBuf>,
+    /// Also lock the accounts of blocklisted clients that have transactions
+    #[arg(long, global = true, requires = "blocklist")]
+    lock_blocked: bool,
+    /// Reject all transactions of clients not listed in the first column of this
+    /// CSV file
+    #[arg(long, global = true, value_name = "PATH")]
+    allowlist: Option<PathBuf>,
+    /// Debit this fee from an account whenever a chargeback is applied to it
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_fee)]
+    chargeback_fee: Option<f32>,
+    /// Lock an account once this many chargebacks were applied to it
+    #[arg(long, global = true, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    lock_after_chargebacks: u32,
+    /// Also lock an account once the amount ever disputed exceeds this percentage
+    /// of the amount deposited
+    #[arg(long, global = true, value_name = "PERCENT")]
+    lock_disputed_percent: Option<f32>,
+    /// Reject withdrawals that would take the available funds below this amount
+    #[arg(long, global = true, value_name = "AMOUNT")]
+    min_balance: Option<f32>,
+    /// Reject deposits beyond this total amount per client and day, which requires
+    /// a timestamp column
+    #[arg(long, global = true, value_name = "AMOUNT")]
+    daily_deposit_limit: Option<f32>,
+    /// Reject withdrawals beyond this total amount per client and day, which
+    /// requires a timestamp column
+    #[arg(long, global = true, value_name = "AMOUNT")]
+    daily_withdrawal_limit: Option<f32>,
+    /// Enforce the limits of each client's level of verification, as listed in this
+    /// CSV file of client IDs and levels, with the limits in the --rules file
+    #[arg(long, global = true, value_name = "PATH", requires = "rules")]
+    kyc: Option<PathBuf>,
+    /// Hold the funds of deposits until this many more transactions were processed
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        conflicts_with = "clearing_seconds"
+    )]
+    clearing_transactions: Option<u64>,
+    /// Hold the funds of deposits for this many seconds, which requires a
+    /// timestamp column
+    #[arg(long, global = true, value_name = "SECONDS")]
+    clearing_seconds: Option<u64>,
+    /// Apply the rules in this TOML file before each transaction (see the README)
+    #[arg(long, global = true, value_name = "PATH")]
+    rules: Option<PathBuf>,
+    /// Call on_transaction in this Rhai script before each transaction (see the README)
+    #[cfg(feature = "scripting")]
+    #[arg(long, global = true, value_name = "PATH")]
+    script: Option<PathBuf>,
+    /// Append a record of every action taken because of the blocklist to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// Compress the accounts output and the audit log: gzip or zstd
+    #[arg(long, global = true, value_name = "FORMAT")]
+    compress: Option<Compression>,
+    /// Write every account that gets locked to this CSV file, with the transaction
+    /// that locked it, why, and its balances at that point
+    #[arg(long, global = true, value_name = "PATH")]
+    lock_report: Option<PathBuf>,
+    /// Write every withdrawal rejected for insufficient funds to this CSV file,
+    /// with the funds the client had available at that point
+    #[arg(long, global = true, value_name = "PATH")]
+    failed_withdrawals: Option<PathBuf>,
+    /// Journal every applied transaction as double-entry postings in this CSV file
+    /// (see `report trial-balance`)
+    #[arg(long, global = true, value_name = "PATH")]
+    ledger: Option<PathBuf>,
+    /// Write the accounts at the end of each day to <YYYY-MM-DD>.csv in this
+    /// directory, going by the timestamp column
+    #[arg(long, global = true, value_name = "DIR")]
+    daily_reports: Option<PathBuf>,
+    /// Add columns with how many deposits, withdrawals, disputes, and chargebacks
+    /// were applied to each account, and the sums of its deposits and withdrawals
+    #[arg(long, global = true)]
+    extended_output: bool,
+    /// Write the count and volume of the applied transactions of each type per
+    /// --aggregate-period to this CSV file, overall and per client, going by the
+    /// timestamp column
+    #[arg(long, global = true, value_name = "PATH")]
+    aggregate: Option<PathBuf>,
+    /// The period of --aggregate: hour or day
+    #[arg(long, global = true, value_name = "PERIOD", default_value = "day")]
+    aggregate_period: AggregationPeriod,
+    /// Compare the resulting accounts against this accounts output (or state
+    /// document) and fail listing every client that differs
+    #[arg(long, global = true, value_name = "PATH")]
+    reconcile: Option<PathBuf>,
+    /// Write the accounts to this directory instead of stdout, one file per
+    /// --partition-size client IDs (or per partner), along with a manifest.json
+    #[arg(long, global = true, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+    /// How many client IDs each file of --output-dir covers
+    #[arg(long, global = true, value_name = "N", default_value_t = 10_000, value_parser = clap::builder::RangedU64ValueParser::<ClientID>::new().range(1..))]
+    partition_size: ClientID,
+    /// For inputs with a partner column, additionally write each partner's accounts
+    /// to <partner>.csv in this directory
+    #[arg(long, global = true, value_name = "DIR")]
+    partner_output_dir: Option<PathBuf>,
+    /// Write a statement of each client's applied transactions, with the funds
+    /// after each, to <client>.csv in this directory
+    #[arg(long, global = true, value_name = "DIR")]
+    statements: Option<PathBuf>,
+    /// Analyze the input for suspicious patterns and write them to this CSV file
+    #[arg(long, global = true, value_name = "PATH")]
+    anomalies: Option<PathBuf>,
+    /// Process the input a second time and fail unless both passes produce exactly
+    /// the same state
+    #[arg(long, global = true)]
+    verify_determinism: bool,
+    /// Process the transactions of every Nth client (by ID) a second time,
+    /// sequentially, and fail unless their accounts match those of the first pass
+    #[arg(long, global = true, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<ClientID>::new().range(1..))]
+    verify_sample: Option<ClientID>,
+    /// Accept amounts with a leading + or -
+    #[arg(long, global = true)]
+    signed_amounts: bool,
+    /// Accept amounts in scientific notation, like 1.5e3
+    #[arg(long, global = true)]
+    scientific_amounts: bool,
+    /// Accept amounts with a currency symbol or code, like $10.50 or "10.50 EUR",
+    /// which must match the currency column if there is one
+    #[arg(long, global = true)]
+    strip_currency: bool,
+    /// Accept , or _ between groups of three digits of amounts, like in "1,234.56"
+    /// (quoted) or 1_234.56
+    #[arg(long, global = true)]
+    thousands_separators: bool,
+    /// Keep the whitespace around fields and column names
+    #[arg(long, global = true)]
+    no_trim: bool,
+    /// Skip rows starting with this prefix, like '#', as comments
+    #[arg(long, global = true, value_name = "PREFIX")]
+    comment_prefix: Option<String>,
+    /// Accept the alternative names of transaction types in this TOML file, like
+    /// withdraw = "withdrawal"
+    #[arg(long, global = true, value_name = "PATH")]
+    type_aliases: Option<PathBuf>,
+    /// The character encoding of the input: utf-8 or latin-1
+    #[arg(long, global = true, value_name = "ENCODING", default_value = "utf-8")]
+    encoding: Encoding,
+    /// Maximum number of digits of amounts before the decimal point
+    #[arg(long, global = true, value_name = "N", default_value_t = ParseOptions::default().max_integer_digits)]
+    max_integer_digits: usize,
+    /// Maximum number of digits of amounts after the decimal point
+    #[arg(long, global = true, value_name = "N", default_value_t = ParseOptions::default().max_fraction_digits)]
+    max_fraction_digits: usize,
+    /// Maximum length of a row in bytes
+    #[arg(long, global = true, value_name = "BYTES", default_value_t = ParseOptions::default().max_line_length)]
+    max_line_length: usize,
+    /// Maximum number of fields of a row
+    #[arg(long, global = true, value_name = "N", default_value_t = ParseOptions::default().max_fields)]
+    max_fields: usize,
+    /// How transaction IDs are written: numeric, or string for any string like a
+    /// UUID
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "numeric")]
+    tx_ids: IdFormat,
+    /// With --tx-ids string, write the number assigned to each transaction ID to
+    /// this CSV file
+    #[arg(long, global = true, value_name = "PATH")]
+    tx_id_map: Option<PathBuf>,
+    /// The format of the inputs: csv, avro for Avro container files, camt for ISO
+    /// 20022 camt.053 bank statements, or xlsx for the first sheet of Excel
+    /// workbooks
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "csv")]
+    input_format: Format,
+    /// With --input-format camt, the TOML file mapping the IBAN or other ID of
+    /// each account to its client
+    #[arg(long, global = true, value_name = "PATH")]
+    camt_accounts: Option<PathBuf>,
+    /// The format of the accounts output: csv, or avro for an Avro container file
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "csv")]
+    output_format: Format,
+}
+
+/// The format of inputs and of the accounts output
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Format {
+    #[default]
+    Csv,
+    #[cfg(feature = "avro")]
+    Avro,
+    /// Only an input format
+    #[cfg(feature = "camt")]
+    Camt,
+    /// Only an input format
+    #[cfg(feature = "xlsx")]
+    Xlsx,
+}
+
+impl FromStr for Format {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Ok(match string {
+            "csv" => Format::Csv,
+            #[cfg(feature = "avro")]
+            "avro" => Format::Avro,
+            #[cfg(not(feature = "avro"))]
+            "avro" => return Err("Avro needs a build with --features avro"),
+            #[cfg(feature = "camt")]
+            "camt" => Format::Camt,
+            #[cfg(not(feature = "camt"))]
+            "camt" => return Err("camt needs a build with --features camt"),
+            #[cfg(feature = "xlsx")]
+            "xlsx" => Format::Xlsx,
+            #[cfg(not(feature = "xlsx"))]
+            "xlsx" => return Err("xlsx needs a build with --features xlsx"),
+            _ => return Err("expected csv, avro, camt, or xlsx"),
+        })
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Process a CSV file of transactions and print the full state as JSON
+    /// (encrypted if a key is provided)
+    ExportState {
+        /// CSV file of transactions to process
+        input: PathBuf,
+    },
+    /// Load a JSON state document, optionally process more transactions,
+    /// and print the resulting accounts
+    ImportState {
+        /// JSON state document as written by `export-state`
+        state: PathBuf,
+        /// CSV file of transactions to process on top of the imported state
+        input: Option<PathBuf>,
+    },
+    /// Reconstruct the accounts from an event log and print them. Options changing
+    /// the outcome of transactions, like --chargeback-fee, must match the original run.
+    Replay {
+        /// Event log as written with `--event-log`
+        events: PathBuf,
+        /// Only replay events up to and including this sequence number
+        #[arg(long)]
+        until: Option<u64>,
+        /// Verify the reconstructed state against this state document;
+        /// unless `--until` is given, replays as many events as the snapshot has applied
+        #[arg(long)]
+        verify: Option<PathBuf>,
+    },
+    /// Compare two accounts outputs or state documents and print what changed per client
+    Diff {
+        /// Accounts CSV or state document to compare against
+        old: PathBuf,
+        /// Accounts CSV or state document to compare
+        new: PathBuf,
+    },
+    /// Process a CSV file of transactions and print a report about it
+    Report {
+        #[command(subcommand)]
+        command: ReportCommand,
+    },
+    /// Check the expectations of scenario files
+    Scenario {
+        #[command(subcommand)]
+        command: ScenarioCommand,
+    },
+    /// Run every `*.input.csv` in a directory and compare the accounts with the
+    /// matching `*.expected.csv`
+    Test {
+        /// Directory of input and expected files
+        directory: PathBuf,
+    },
+    /// Print a reproducible CSV input of made-up transactions
+    Generate {
+        /// Number of transactions to generate
+        #[arg(long, value_name = "N")]
+        rows: usize,
+        #[command(flatten)]
+        workload: Workload,
+    },
+    /// Write a benchmark input of made-up transactions to a directory, along with
+    /// the accounts it results in and a manifest, and print the manifest
+    Fixture {
+        /// Directory to write `transactions.csv`, `expected.csv`, and
+        /// `manifest.json` to
+        directory: PathBuf,
+        /// Number of transactions to generate
+        #[arg(long, value_name = "N")]
+        rows: u64,
+        #[command(flatten)]
+        workload: Workload,
+    },
+    /// Process made-up transactions without reading an input and report the
+    /// throughput, memory, and invariant violations
+    #[command(group(clap::ArgGroup::new("limit").required(true).multiple(true)))]
+    Simulate {
+        /// Stop after this many transactions
+        #[arg(long, value_name = "N", group = "limit")]
+        rows: Option<u64>,
+        /// Stop after this many seconds
+        #[arg(long, value_name = "SECONDS", group = "limit")]
+        seconds: Option<f64>,
+        #[command(flatten)]
+        workload: Workload,
+    },
+    /// Print a completion script for a shell
+    Completions {
+        /// The shell to complete for: bash, elvish, fish, powershell, or zsh
+        shell: Shell,
+    },
+    /// Write man pages for the command and each of its subcommands
+    Man {
+        /// Directory to write the pages to
+        directory: PathBuf,
+    },
+}
+
+/// The mix of made-up transactions of `generate` and `simulate`
+#[derive(Args)]
+struct Workload {
+    /// The same seed always results in the same transactions
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    seed: u64,
+    /// Number of clients, numbered from 1
+    #[arg(long, value_name = "N", default_value_t = GeneratorOptions::default().clients)]
+    clients: ClientID,
+    /// The chance of a deposit or withdrawal being a withdrawal
+    #[arg(long, value_name = "P", default_value_t = GeneratorOptions::default().withdrawal_probability)]
+    withdrawal_probability: f64,
+    /// The chance of a transaction disputing an earlier deposit, and of an open
+    /// dispute being settled
+    #[arg(long, value_name = "P", default_value_t = GeneratorOptions::default().dispute_probability)]
+    dispute_probability: f64,
+    /// The chance of a dispute being charged back rather than resolved
+    #[arg(long, value_name = "P", default_value_t = GeneratorOptions::default().chargeback_probability)]
+    chargeback_probability: f64,
+}
+
+impl Workload {
+    fn options(&self) -> GeneratorOptions {
+        GeneratorOptions {
+            clients: self.clients,
+            withdrawal_probability: self.withdrawal_probability,
+            dispute_probability: self.dispute_probability,
+            chargeback_probability: self.chargeback_probability,
+        }
+    }
+
+    fn stream(&self) -> TransactionStream {
+        TransactionStream::new(self.seed, self.options())
+    }
+}
+
+#[derive(Subcommand)]
+enum ScenarioCommand {
+    /// Run every `*.scenario` file in a directory and report the ones whose
+    /// accounts or rejections do not match their expectations
+    Run {
+        /// Directory of scenario files
+        directory: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReportCommand {
+    /// Print the amounts deposited, withdrawn, and charged back, the number of
+    /// active, locked, and empty accounts, the largest transaction, and the number
+    /// of transactions of each type
+    Summary {
+        /// CSV file of transactions to process
+        input: PathBuf,
+    },
+    /// Print the balance of every account of a ledger written with `--ledger`,
+    /// failing unless the debits equal the credits
+    TrialBalance {
+        /// Ledger as written with `--ledger`
+        ledger: PathBuf,
+    },
+    /// Print the postings of a ledger written with `--ledger` in the CSV layout of
+    /// an accounting system (see the README)
+    GlExport {
+        /// Ledger as written with `--ledger`
+        ledger: PathBuf,
+        /// TOML file describing the columns and the code of each account
+        #[arg(long, value_name = "PATH")]
+        layout: PathBuf,
+    },
+}
+
+const PARTNER_STATE_UNSUPPORTED: &str =
+    "state documents do not support inputs with a partner column";
+const STRING_IDS_STATE_UNSUPPORTED: &str =
+    "state documents cannot be combined with --tx-ids string";
+
+/// Set once SIGINT or SIGTERM was received, after which reading stops
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops reading on the first SIGINT or SIGTERM, so that the transactions read so
+/// far are still processed and written out, and exits right away on the second
+fn handle_interrupts() {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        let handler = ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::Relaxed) {
+                process::exit(128 + 2);
+            }
+        });
+        if let Err(err) = handler {
+            eprintln!("could not handle interrupts: {err}");
+        }
+    });
+}
+
+/// The key of `--key-file` or the environment, only loaded once state is read or
+/// written, so that a broken key does not affect runs that keep no state
+struct LazyKey {
+    key_file: Option<PathBuf>,
+    key: OnceCell<Option<StateKey>>,
+}
+
+impl LazyKey {
+    fn get(&self) -> Result<Option<&StateKey>, Failure> {
+        if let Some(key) = self.key.get() {
+            return Ok(key.as_ref());
+        }
+        let key = StateKey::load(self.key_file.as_deref()).map_err(Failure::Input)?;
+        Ok(self.key.get_or_init(|| key).as_ref())
+    }
+}
+
+fn run_command(
+    command: Option<Command>,
+    cli: &Cli,
+    key: &LazyKey,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    match command {
+        Some(Command::ExportState { .. }) | Some(Command::ImportState { .. })
+            if cli.tx_ids == IdFormat::String =>
+        {
+            // The numbers of string IDs are only valid within a run
+            Err(Failure::Usage(STRING_IDS_STATE_UNSUPPORTED.to_string()))
+        }
+        Some(Command::ExportState { input }) => {
+            // State documents hold the activity of each account
+            let mut engine = Engine::default();
+            engine.track_activity();
+            let (engine, partitions) =
+                process_file(engine, slice::from_ref(&input), cli, key, report)?;
+            if partitions.is_some() {
+                return Err(Failure::Usage(PARTNER_STATE_UNSUPPORTED.to_string()));
+            }
+            let key = key.get()?;
+            let bytes = Metrics::time(&mut report.metrics.serialize, || {
+                let document = state::export_state(&engine);
+                match key {
+                    Some(key) => key.encrypt(document.as_bytes()),
+                    None => document.into_bytes(),
+                }
+            });
+            report.write_output(&bytes)
+        }
+        Some(Command::ImportState { state, input }) => {
+            let mut engine = read_state(&state, key)?;
+            if let Some(input) = input {
+                let partitions;
+                (engine, partitions) =
+                    process_file(engine, slice::from_ref(&input), cli, key, report)?;
+                if partitions.is_some() {
+                    return Err(Failure::Usage(PARTNER_STATE_UNSUPPORTED.to_string()));
+                }
+            }
+            write_result(&engine, None, cli, report)
+        }
+        Some(Command::Replay {
+            events,
+            until,
+            verify,
+        }) => {
+            let output = replay(&events, until, verify.as_deref(), key, policy(cli)?)?;
+            report.write_output(output.as_bytes())
+        }
+        Some(Command::Diff { old, new }) => {
+            let old = read_accounts(&old, key)?;
+            let new = read_accounts(&new, key)?;
+            report.write_output(diff::diff_accounts(&old, &new).as_bytes())
+        }
+        Some(Command::Report {
+            command: ReportCommand::Summary { input },
+        }) => {
+            let (engine, partitions) =
+                process_file(Engine::default(), slice::from_ref(&input), cli, key, report)?;
+            let summary = report
+                .summary
+                .as_ref()
+                .expect("processing sets the summary");
+            let statistics = match &partitions {
+                Some(partitions) => {
+                    Statistics::of(summary, partitions.iter().map(|(_, engine)| engine))
+                }
+                None => Statistics::of(summary, [&engine]),
+            };
+            report.write_output(statistics.to_string().as_bytes())
+        }
+        Some(Command::Report {
+            command: ReportCommand::TrialBalance { ledger },
+        }) => {
+            let trial_balance = TrialBalance::of(&read_ledger(&ledger)?);
+            report.write_output(trial_balance.to_string().as_bytes())?;
+            if !trial_balance.is_balanced() {
+                return Err(Failure::Other(format!(
+                    "ledger does not balance: debits of {} against credits of {}",
+                    trial_balance.debits(),
+                    trial_balance.credits()
+                )));
+            }
+            Ok(())
+        }
+        Some(Command::Report {
+            command: ReportCommand::GlExport { ledger, layout },
+        }) => {
+            let layout = fs::read_to_string(&layout)
+                .map_err(|err| Failure::Input(format!("could not read layout: {err}")))
+                .and_then(|text| {
+                    GlLayout::parse(&text)
+                        .map_err(|err| Failure::Parse(format!("could not parse layout: {err}")))
+                })?;
+            let output = layout
+                .export(&read_ledger(&ledger)?)
+                .map_err(Failure::Parse)?;
+            report.write_output(output.as_bytes())
+        }
+        Some(Command::Scenario {
+            command: ScenarioCommand::Run { directory },
+        }) => run_scenarios(&directory, cli, report),
+        Some(Command::Test { directory }) => run_golden_files(&directory, cli, report),
+        Some(Command::Generate { rows, workload }) => {
+            let mut output = Vec::new();
+            generator::write_csv(workload.stream().take(rows), &mut output)
+                .expect("writing to a Vec never fails");
+            report.write_output(&output)
+        }
+        Some(Command::Fixture {
+            directory,
+            rows,
+            workload,
+        }) => {
+            let manifest = fixture::write(&directory, rows, workload.seed, workload.options())
+                .map_err(Failure::Output)?;
+            let mut output =
+                serde_json::to_string_pretty(&manifest).expect("manifests are always serializable");
+            output.push('\n');
+            report.write_output(output.as_bytes())
+        }
+        Some(Command::Simulate {
+            rows,
+            seconds,
+            workload,
+        }) => run_simulation(rows, seconds, &workload, cli, report),
+        Some(Command::Completions { shell }) => {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut Cli::command(), "transactions", &mut script);
+            report.write_output(&script)
+        }
+        Some(Command::Man { directory }) => write_man_pages(&directory, report),
+        None => match cli.inputs.as_slice() {
+            [] => Err(Failure::Usage(
+                "no CSV file of transactions provided!".to_string(),
+            )),
+            inputs => {
+                let (engine, partitions) =
+                    process_file(Engine::default(), inputs, cli, key, report)?;
+                write_result(&engine, partitions.as_ref(), cli, report)?;
+                match &cli.reconcile {
+                    Some(expected) if partitions.is_none() => {
+                        reconcile(expected, engine.accounts(), key)
+                    }
+                    Some(_) => Err(Failure::Usage(
+                        "--reconcile cannot be combined with a partner column".to_string(),
+                    )),
+                    None => Ok(()),
+                }
+            }
+        },
+    }
+}
 
 fn main() -> ExitCode {
-    let mut args = env::args();
-
-    args.next(); // Skip program name
-
-    if let Some(arg) = args.next() {
-        if let Ok(file) = fs::File::open(&arg) {
-            let reader = io::BufReader::new(file);
-            match parse_transactions(reader) {
-                Ok(transactions) => {
-                    let accounts = handle_transactions(&transactions);
-                    let output = serialize_accounts(&accounts);
-                    print!("{output}");
-                    return ExitCode::from(0);
+    let mut cli = Cli::parse();
+    let mut report = Report {
+        metrics: Metrics::start(),
+        summary: None,
+        outputs: Vec::new(),
+        interrupted: false,
+    };
+
+    let key = LazyKey {
+        key_file: cli.key_file.clone(),
+        key: OnceCell::new(),
+    };
+    let command = cli.command.take();
+    let result = run_command(command, &cli, &key, &mut report);
+
+    let result = match result {
+        // The expected accounts are unlikely to match an incomplete input
+        Ok(()) | Err(Failure::Reconciliation(_)) if report.interrupted => {
+            Err(Failure::Interrupted(
+                "interrupted: the outputs only cover the rows read until then".to_string(),
+            ))
+        }
+        result => result,
+    };
+    let exit_code = match &result {
+        Ok(()) => {
+            report_metrics(&cli, &report.metrics);
+            0
+        }
+        Err(failure) => {
+            eprintln!("{failure}");
+            failure.exit_code()
+        }
+    };
+
+    if let Some(path) = &cli.result_json {
+        let run_result = RunResult {
+            succeeded: result.is_ok(),
+            exit_code,
+            error: result.err().map(|failure| failure.to_string()),
+            summary: report.summary,
+            outputs: report.outputs,
+        };
+        if let Err(err) = fs::write(path, run_result.to_json()) {
+            let failure = Failure::Output(format!("could not write result: {err}"));
+            eprintln!("{failure}");
+            return ExitCode::from(failure.exit_code());
+        }
+    }
+
+    ExitCode::from(exit_code)
+}
+
+/// Why the program failed, which determines its exit code
+#[derive(Debug)]
+enum Failure {
+    /// The options cannot be combined, with each other or with the input
+    Usage(String),
+    /// An input file could not be read
+    Input(String),
+    /// An input could not be parsed
+    Parse(String),
+    /// An account invariant was violated with --check-invariants, or processing
+    /// turned out not to be deterministic with --verify-determinism or
+    /// --verify-sample
+    InvariantViolated(String),
+    /// More rows were malformed or rejected than --max-errors allows
+    ErrorThreshold(String),
+    /// An output could not be written
+    Output(String),
+    /// A scenario did not meet its expectations
+    ScenarioFailed(String),
+    /// The accounts differ from those expected with --reconcile
+    Reconciliation(String),
+    /// Reading stopped early because of SIGINT or SIGTERM
+    Interrupted(String),
+    /// The run took up more memory than --memory-limit allows
+    MemoryLimit(String),
+    Other(String),
+}
+
+impl Failure {
+    /// Exit code 2 is shared with clap, which uses it for invalid arguments
+    fn exit_code(&self) -> u8 {
+        use Failure::*;
+
+        match self {
+            Other(_) => 1,
+            Usage(_) => 2,
+            Input(_) => 3,
+            Parse(_) => 4,
+            InvariantViolated(_) => 5,
+            ErrorThreshold(_) => 6,
+            Output(_) => 7,
+            ScenarioFailed(_) => 8,
+            Reconciliation(_) => 9,
+            Interrupted(_) => 10,
+            MemoryLimit(_) => 11,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Failure::*;
+
+        match self {
+            Usage(message)
+            | Input(message)
+            | Parse(message)
+            | InvariantViolated(message)
+            | ErrorThreshold(message)
+            | Output(message)
+            | ScenarioFailed(message)
+            | Reconciliation(message)
+            | Interrupted(message)
+            | MemoryLimit(message)
+            | Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<Abort> for Failure {
+    fn from(abort: Abort) -> Self {
+        match abort {
+            Abort::Malformed(message) | Abort::IdOrder(message) => Failure::Parse(message),
+            Abort::InvariantViolated(message) => Failure::InvariantViolated(message),
+            Abort::ErrorThreshold(message) => Failure::ErrorThreshold(message),
+            Abort::MemoryLimit(message) => Failure::MemoryLimit(message),
+            Abort::Assertion(message) => Failure::ScenarioFailed(message),
+            Abort::EventLog(message)
+            | Abort::AuditLog(message)
+            | Abort::LockReport(message)
+            | Abort::FailedWithdrawalReport(message)
+            | Abort::Ledger(message)
+            | Abort::DailyReports(message)
+            | Abort::Aggregation(message) => Failure::Output(message),
+        }
+    }
+}
+
+/// Everything reported about a run besides its output
+struct Report {
+    metrics: Metrics,
+    /// The summary of the processed input, if any
+    summary: Option<Summary>,
+    outputs: Vec<OutputChecksum>,
+    /// Whether reading the input stopped early because of a signal
+    interrupted: bool,
+}
+
+impl Report {
+    /// Writes the output of the run to stdout
+    fn write_output(&mut self, bytes: &[u8]) -> Result<(), Failure> {
+        Metrics::time(&mut self.metrics.serialize, || {
+            io::stdout().write_all(bytes)
+        })
+        .map_err(|err| Failure::Output(format!("could not write output: {err}")))?;
+        self.outputs.push(OutputChecksum::of("-", bytes));
+        Ok(())
+    }
+
+    /// Writes the accounts to stdout as they are formatted
+    fn write_accounts(
+        &mut self,
+        engine: &Engine,
+        partitions: Option<&Partitions>,
+        cli: &Cli,
+    ) -> Result<(), Failure> {
+        let stdout = Checksummed::new(io::BufWriter::new(io::stdout().lock()));
+        let checksum = Metrics::time(&mut self.metrics.serialize, || {
+            stream_result(engine, partitions, cli, stdout)?.finish("-")
+        })
+        .map_err(|err| Failure::Output(format!("could not write accounts: {err}")))?;
+        self.outputs.push(checksum);
+        Ok(())
+    }
+}
+
+fn process_file(
+    mut engine: Engine,
+    inputs: &[PathBuf],
+    cli: &Cli,
+    key: &LazyKey,
+    report: &mut Report,
+) -> Result<(Engine, Option<Partitions>), Failure> {
+    engine.set_policy(policy(cli)?);
+    if cli.statements.is_some() {
+        engine.index_by_client();
+    }
+    if cli.extended_output {
+        engine.track_activity();
+    }
+    if let Some(option) = inputs
+        .iter()
+        .any(|input| input.as_os_str() == "-" || fifo::is_fifo(input))
+        .then(|| {
+            [
+                (cli.verify_determinism, "--verify-determinism"),
+                (cli.verify_sample.is_some(), "--verify-sample"),
+            ]
+            .into_iter()
+            .find_map(|(set, option)| set.then_some(option))
+        })
+        .flatten()
+    {
+        // These read the inputs a second time
+        return Err(Failure::Usage(format!(
+            "stdin and pipes cannot be read twice, as {option} would"
+        )));
+    }
+    if let Some(option) = sequential_option(cli).filter(|_| threads(cli) > 1) {
+        return Err(Failure::Usage(format!(
+            "--threads greater than 1 cannot be combined with {option}"
+        )));
+    }
+    let initial_engine =
+        (cli.verify_determinism || cli.verify_sample.is_some()).then(|| engine.clone());
+
+    let event_log = cli
+        .event_log
+        .as_deref()
+        .map(|path| {
+            EventLog::open(path, engine.counters().applied, key.get()?).map_err(Failure::Output)
+        })
+        .transpose()?;
+
+    let mut run = Run::new(run_options(cli), event_log);
+    if let Some(path) = &cli.audit_log {
+        run = run.with_audit_log(AuditLog::open(path, cli.compress).map_err(Failure::Output)?);
+    }
+    if let Some(path) = &cli.lock_report {
+        run = run.with_lock_report(LockReport::create(path).map_err(Failure::Output)?);
+    }
+    if let Some(path) = &cli.failed_withdrawals {
+        run = run.with_failed_withdrawal_report(
+            FailedWithdrawalReport::create(path).map_err(Failure::Output)?,
+        );
+    }
+    if let Some(path) = &cli.ledger {
+        run = run.with_journal(Journal::create(path).map_err(Failure::Output)?);
+    }
+    if let Some(directory) = &cli.daily_reports {
+        run = run.with_daily_reports(DailyReports::create(directory).map_err(Failure::Output)?);
+    }
+    if let Some(path) = &cli.aggregate {
+        run = run.with_aggregation(Aggregation::new(path, cli.aggregate_period));
+    }
+
+    let threads = threads(cli);
+    #[cfg(feature = "dashboard")]
+    let mut dashboard = cli
+        .dashboard
+        .then(Dashboard::start)
+        .transpose()
+        .map_err(Failure::Output)?;
+    let mut snapshots = snapshots(cli, key)?;
+    let mut anomalies = cli
+        .anomalies
+        .is_some()
+        .then(|| Detector::new(AnomalyOptions::default()));
+    handle_interrupts();
+    let Pass {
+        engine,
+        partitions,
+        ids,
+        summary,
+        outcome,
+        interrupted,
+    } = process_pass(
+        engine,
+        inputs,
+        cli,
+        run,
+        threads,
+        &mut report.metrics,
+        Progress {
+            #[cfg(feature = "dashboard")]
+            dashboard: dashboard.as_mut(),
+            snapshots: snapshots.as_mut(),
+            anomalies: anomalies.as_mut(),
+        },
+    )?;
+    let aborted_by_threshold = matches!(outcome, Err(Abort::ErrorThreshold(_)));
+
+    if let Some(path) = &cli.summary_file {
+        let text = summary.to_string();
+        fs::write(path, &text)
+            .map_err(|err| Failure::Output(format!("could not write summary: {err}")))?;
+        report.outputs.push(OutputChecksum::of(
+            &path.display().to_string(),
+            text.as_bytes(),
+        ));
+    } else if cli.summary || aborted_by_threshold {
+        eprint!("{summary}");
+    }
+    if let Some(path) = &cli.report_html {
+        let engines: Vec<&Engine> = match &partitions {
+            Some(partitions) => partitions.iter().map(|(_, engine)| engine).collect(),
+            None => vec![&engine],
+        };
+        let page = html::render(&summary, &engines);
+        fs::write(path, &page)
+            .map_err(|err| Failure::Output(format!("could not write HTML report: {err}")))?;
+        report.outputs.push(OutputChecksum::of(
+            &path.display().to_string(),
+            page.as_bytes(),
+        ));
+    }
+    if let Some(path) = &cli.tx_id_map {
+        let map = ids.to_csv();
+        fs::write(path, &map)
+            .map_err(|err| Failure::Output(format!("could not write ID map: {err}")))?;
+        report.outputs.push(OutputChecksum::of(
+            &path.display().to_string(),
+            map.as_bytes(),
+        ));
+    }
+    report.summary = Some(summary);
+    report.interrupted = interrupted;
+    outcome?;
+
+    // Checks against a second pass would only fail after an interruption
+    let initial_engine = initial_engine.filter(|_| !interrupted);
+    if let (Some(every), Some(initial_engine)) = (cli.verify_sample, &initial_engine) {
+        if partitions.is_some() {
+            return Err(Failure::Usage(
+                "--verify-sample does not support inputs with a partner column".to_string(),
+            ));
+        }
+        verify_sample(initial_engine.clone(), inputs, cli, &engine, every)?;
+    }
+    if let Some(initial_engine) = initial_engine.filter(|_| cli.verify_determinism) {
+        let options = RunOptions {
+            quiet: true,
+            ..run_options(cli)
+        };
+        // The second pass is sequential, which also checks the parallel path against it
+        let second = process_pass(
+            initial_engine,
+            inputs,
+            cli,
+            Run::new(options, None),
+            1,
+            &mut Metrics::start(),
+            Progress::default(),
+        )?;
+        second.outcome?;
+        if second.interrupted {
+            report.interrupted = true;
+            return Ok((engine, partitions));
+        }
+        verify_determinism(
+            &fingerprint(&engine, partitions.as_ref()),
+            &fingerprint(&second.engine, second.partitions.as_ref()),
+        )
+        .map_err(Failure::InvariantViolated)?;
+    }
+
+    if let (Some(partitions), Some(directory)) = (&partitions, &cli.partner_output_dir) {
+        write_partner_outputs(partitions, directory, cli.extended_output, report)?;
+    }
+    if let Some(directory) = &cli.statements {
+        write_statements(&engine, directory, report)?;
+    }
+
+    if let (Some(path), Some(anomalies)) = (&cli.anomalies, anomalies) {
+        write_anomalies(anomalies, path, report)?;
+    }
+
+    Ok((engine, partitions))
+}
+
+/// Parses a size like `512M` into bytes
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, unit) = match size.find(|char: char| !char.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, ""),
+    };
+    let factor = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("unknown size unit {unit:?}")),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|digits| digits.checked_mul(factor))
+        .ok_or_else(|| format!("invalid size {size:?}"))
+}
+
+/// Parses a fee, which can neither be negative nor infinite or NaN
+fn parse_fee(fee: &str) -> Result<f32, String> {
+    fee.parse::<f32>()
+        .ok()
+        .filter(|fee| fee.is_finite() && *fee >= 0.0)
+        .ok_or_else(|| "expected a finite amount of at least 0".to_string())
+}
+
+fn report_metrics(cli: &Cli, metrics: &Metrics) {
+    if !cli.metrics {
+        return;
+    }
+    match cli.log_format {
+        LogFormat::Text => eprint!("{metrics}"),
+        LogFormat::Json => eprintln!("{}", metrics.to_json()),
+    }
+}
+
+/// The number of threads to process with, with 0 meaning one per core
+fn threads(cli: &Cli) -> usize {
+    match cli.threads {
+        None => 1,
+        Some(0) => parallel::default_threads(),
+        Some(threads) => threads,
+    }
+}
+
+/// The first option given that depends on the order of all transactions rather
+/// than just those of each client, or that looks at the engine while it
+/// processes, which the threads of a sharded run cannot honour
+fn sequential_option(cli: &Cli) -> Option<&'static str> {
+    #[cfg(feature = "dashboard")]
+    let dashboard = cli.dashboard;
+    #[cfg(not(feature = "dashboard"))]
+    let dashboard = false;
+    [
+        (cli.event_log.is_some(), "--event-log"),
+        (cli.audit_log.is_some(), "--audit-log"),
+        (cli.lock_report.is_some(), "--lock-report"),
+        (cli.failed_withdrawals.is_some(), "--failed-withdrawals"),
+        (cli.ledger.is_some(), "--ledger"),
+        (cli.daily_reports.is_some(), "--daily-reports"),
+        (cli.aggregate.is_some(), "--aggregate"),
+        (cli.lookahead.is_some(), "--lookahead"),
+        (cli.max_errors.is_some(), "--max-errors"),
+        (cli.memory_limit.is_some(), "--memory-limit"),
+        (dashboard, "--dashboard"),
+        (cli.snapshot.is_some(), "--snapshot"),
+        (cli.assertions, "--assertions"),
+        (cli.daily_deposit_limit.is_some(), "--daily-deposit-limit"),
+        (
+            cli.daily_withdrawal_limit.is_some(),
+            "--daily-withdrawal-limit",
+        ),
+        (
+            cli.clearing_transactions.is_some(),
+            "--clearing-transactions",
+        ),
+        (cli.clearing_seconds.is_some(), "--clearing-seconds"),
+    ]
+    .into_iter()
+    .find_map(|(set, option)| set.then_some(option))
+}
+
+fn parse_options(cli: &Cli) -> Result<ParseOptions, Failure> {
+    let type_aliases = match &cli.type_aliases {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|err| Failure::Input(format!("could not read type aliases: {err}")))?;
+            parse_type_aliases(&text)
+                .map_err(|err| Failure::Parse(format!("type aliases could not be parsed: {err}")))?
+        }
+        None => HashMap::new(),
+    };
+    Ok(ParseOptions {
+        dispute_amounts: cli.dispute_amounts,
+        missing_amounts: cli.missing_amounts.unwrap_or(match cli.strict {
+            true => MissingAmountPolicy::Reject,
+            false => MissingAmountPolicy::Zero,
+        }),
+        signed_amounts: cli.signed_amounts,
+        scientific_amounts: cli.scientific_amounts,
+        max_integer_digits: cli.max_integer_digits,
+        max_fraction_digits: cli.max_fraction_digits,
+        custom_types: HashSet::new(),
+        encoding: cli.encoding,
+        type_aliases,
+        comment_prefix: cli.comment_prefix.clone(),
+        strip_currency: cli.strip_currency,
+        thousands_separators: cli.thousands_separators,
+        trim: !cli.no_trim,
+        max_line_length: cli.max_line_length,
+        max_fields: cli.max_fields,
+        ids: cli.tx_ids,
+        assertions: cli.assertions,
+    })
+}
+
+fn policy(cli: &Cli) -> Result<Policy, Failure> {
+    let blocklist = match &cli.blocklist {
+        Some(path) => {
+            let file = fs::File::open(path)
+                .map_err(|err| Failure::Input(format!("could not read blocklist: {err}")))?;
+            let clients = Blocklist::parse(io::BufReader::new(file))
+                .map_err(|err| Failure::Parse(format!("blocklist could not be parsed: {err}")))?;
+            Some(Blocklist {
+                clients,
+                lock: cli.lock_blocked,
+            })
+        }
+        None => None,
+    };
+
+    let allowlist = match &cli.allowlist {
+        Some(path) => {
+            let file = fs::File::open(path)
+                .map_err(|err| Failure::Input(format!("could not read allowlist: {err}")))?;
+            let clients = Blocklist::parse(io::BufReader::new(file))
+                .map_err(|err| Failure::Parse(format!("allowlist could not be parsed: {err}")))?;
+            Some(clients)
+        }
+        None => None,
+    };
+
+    let rules = match &cli.rules {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|err| Failure::Input(format!("could not read rules: {err}")))?;
+            let rules = Rules::parse(&text)
+                .map_err(|err| Failure::Parse(format!("rules could not be parsed: {err}")))?;
+            Some(rules)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "scripting")]
+    let script = match &cli.script {
+        Some(path) => {
+            let source = fs::read_to_string(path)
+                .map_err(|err| Failure::Input(format!("could not read script: {err}")))?;
+            let script = transactions::script::Script::compile(&source)
+                .map_err(|err| Failure::Parse(format!("script could not be compiled: {err}")))?;
+            Some(script)
+        }
+        None => None,
+    };
+
+    let kyc = match &cli.kyc {
+        Some(path) => {
+            let file = fs::File::open(path)
+                .map_err(|err| Failure::Input(format!("could not read KYC levels: {err}")))?;
+            let levels = Kyc::parse(io::BufReader::new(file))
+                .map_err(|err| Failure::Parse(format!("KYC levels could not be parsed: {err}")))?;
+            let rules = rules.as_ref().expect("--kyc requires --rules");
+            if let Some(level) = levels
+                .values()
+                .find(|level| rules.kyc_limits(level).is_none())
+            {
+                return Err(Failure::Parse(format!(
+                    "KYC level {level:?} has no limits in the rules"
+                )));
+            }
+            Some(Kyc { levels })
+        }
+        None => None,
+    };
+
+    Ok(Policy {
+        velocity: cli.velocity_window.map(|window| VelocityLimit {
+            window,
+            max_withdrawals: cli.max_withdrawals,
+            max_withdrawal_amount: cli.max_withdrawal_amount,
+        }),
+        blocklist,
+        allowlist,
+        rules,
+        chargeback_fee: cli.chargeback_fee,
+        lock: LockPolicy {
+            chargebacks: Some(cli.lock_after_chargebacks),
+            disputed_share: cli.lock_disputed_percent.map(|percent| percent / 100.0),
+        },
+        min_balance: cli.min_balance,
+        daily_limits: (cli.daily_deposit_limit.is_some() || cli.daily_withdrawal_limit.is_some())
+            .then_some(DailyLimits {
+                deposit: cli.daily_deposit_limit,
+                withdrawal: cli.daily_withdrawal_limit,
+            }),
+        kyc,
+        clearing: cli
+            .clearing_transactions
+            .map(ClearingPeriod::Transactions)
+            .or(cli.clearing_seconds.map(ClearingPeriod::Seconds)),
+        #[cfg(feature = "scripting")]
+        script,
+    })
+}
+
+fn run_options(cli: &Cli) -> RunOptions {
+    // The dashboard takes the place of the messages on stderr
+    #[cfg(feature = "dashboard")]
+    let quiet = cli.dashboard;
+    #[cfg(not(feature = "dashboard"))]
+    let quiet = false;
+    RunOptions {
+        max_errors: cli.max_errors,
+        lenient: cli.lenient,
+        check_invariants: cli.check_invariants,
+        allow_negative_available: cli.allow_negative_available,
+        paranoid: cli.paranoid,
+        lookahead: cli.lookahead,
+        quiet,
+        log_rejections: cli.log_rejections,
+        log_format: cli.log_format,
+        memory_limit: cli.memory_limit,
+        queue_length: cli.queue_length,
+    }
+}
+
+/// The result of processing all of an input once
+struct Pass {
+    engine: Engine,
+    /// Set if the input has a partner column, in which case `engine` is left untouched
+    partitions: Option<Partitions>,
+    /// The numbers assigned to transaction IDs with `--tx-ids string`
+    ids: InternedIds,
+    summary: Summary,
+    /// Whether the run completed or why it was aborted
+    outcome: Result<(), Abort>,
+    /// Whether reading stopped early because of a signal
+    interrupted: bool,
+}
+
+/// What is updated while an input is processed
+#[derive(Default)]
+struct Progress<'a> {
+    #[cfg(feature = "dashboard")]
+    dashboard: Option<&'a mut TerminalDashboard>,
+    snapshots: Option<&'a mut Snapshots>,
+    anomalies: Option<&'a mut Detector>,
+}
+
+fn process_pass(
+    mut engine: Engine,
+    inputs: &[PathBuf],
+    cli: &Cli,
+    mut run: Run,
+    threads: usize,
+    metrics: &mut Metrics,
+    progress: Progress,
+) -> Result<Pass, Failure> {
+    let Progress {
+        #[cfg(feature = "dashboard")]
+        mut dashboard,
+        mut snapshots,
+        mut anomalies,
+    } = progress;
+    if cli.assertions && inputs.len() > 1 {
+        return Err(Failure::Usage(
+            "--assertions requires a single input".to_string(),
+        ));
+    }
+    let mut transactions = open_transactions(inputs, cli)?;
+
+    let mut partitions = None;
+    if transactions.has_partner_column() {
+        if let Some(option) = [
+            (cli.assertions, "--assertions"),
+            (cli.event_log.is_some(), "--event-log"),
+            (cli.lookahead.is_some(), "--lookahead"),
+            (cli.memory_limit.is_some(), "--memory-limit"),
+            (cli.daily_reports.is_some(), "--daily-reports"),
+            (cli.statements.is_some(), "--statements"),
+        ]
+        .into_iter()
+        .find_map(|(set, option)| set.then_some(option))
+        {
+            return Err(Failure::Usage(format!(
+                "a partner column cannot be combined with {option}"
+            )));
+        }
+        partitions = Some(Partitions::new(engine.empty_copy()));
+    }
+    if engine.policy().daily_limits.is_some() && !transactions.has_timestamp_column() {
+        return Err(Failure::Usage(
+            "daily limits require a timestamp column".to_string(),
+        ));
+    }
+    if cli.daily_reports.is_some() && !transactions.has_timestamp_column() {
+        return Err(Failure::Usage(
+            "--daily-reports requires a timestamp column".to_string(),
+        ));
+    }
+    if cli.aggregate.is_some() && !transactions.has_timestamp_column() {
+        return Err(Failure::Usage(
+            "--aggregate requires a timestamp column".to_string(),
+        ));
+    }
+    if matches!(engine.policy().clearing, Some(ClearingPeriod::Seconds(_)))
+        && !transactions.has_timestamp_column()
+    {
+        return Err(Failure::Usage(
+            "--clearing-seconds requires a timestamp column".to_string(),
+        ));
+    }
+
+    run = run.with_extra_columns(transactions.extra_columns().clone());
+
+    // Malformed rows and warnings are still handled here when processing in parallel
+    let mut sharded = (threads > 1 && partitions.is_none())
+        .then(|| ShardedRun::new(mem::take(&mut engine), threads, run.options().clone()));
+    let mut id_order = cli.check_id_order.then(IdOrderCheck::default);
+    let mut outcome = Ok(());
+    // A failed shard reports its error once it is finished
+    let mut shard_failed = false;
+    while let Some(transaction) = Metrics::time(&mut metrics.parse, || transactions.next()) {
+        if cli.assertions {
+            outcome = check_assertions(transactions.as_mut(), &engine);
+            if outcome.is_err() {
+                break;
+            }
+        }
+        outcome = Metrics::time(&mut metrics.process, || match transaction {
+            Ok(transaction) => {
+                if let Some(id_order) = &mut id_order {
+                    id_order
+                        .check(&transaction, &transactions.row())
+                        .map_err(Abort::IdOrder)?;
+                }
+                if let Some(warning) = transactions.warning() {
+                    run.warn(warning, &transaction, &transactions.row());
+                }
+                if let Some(anomalies) = anomalies.as_deref_mut() {
+                    anomalies.observe(&transaction);
+                }
+                match &mut sharded {
+                    Some(sharded) => {
+                        shard_failed = !sharded.process(transaction, &transactions.row());
+                        Ok(())
+                    }
+                    None => {
+                        let engine = match &mut partitions {
+                            // The reader ensures there is a partner
+                            Some(partitions) => partitions.engine(transactions.partner().unwrap()),
+                            None => &mut engine,
+                        };
+                        if let Some(timestamp) = transactions.timestamp() {
+                            engine.set_clock(timestamp);
+                        }
+                        run.process(engine, &transaction, &transactions.row())
+                    }
+                }
+            }
+            Err(err) => run.malformed(err, &transactions.row()),
+        });
+        match &partitions {
+            Some(partitions) => metrics.observe_all(partitions.iter().map(|(_, engine)| engine)),
+            // The shards are observed once they are merged
+            None if sharded.is_some() => {}
+            None => metrics.observe(&engine),
+        }
+        #[cfg(feature = "dashboard")]
+        if let Some(dashboard) = dashboard.as_deref_mut() {
+            let drawn = match &partitions {
+                Some(partitions) => dashboard.update(
+                    partitions.iter().map(|(_, engine)| engine),
+                    &run,
+                    transactions.rows_read(),
+                ),
+                None => dashboard.update([&engine], &run, transactions.rows_read()),
+            };
+            drawn.map_err(|err| Failure::Output(format!("could not draw dashboard: {err}")))?;
+        }
+        if let Some(snapshots) = snapshots.as_deref_mut() {
+            if snapshots.is_due(transactions.rows_read()) {
+                write_snapshot(
+                    snapshots,
+                    &engine,
+                    partitions.as_ref(),
+                    cli,
+                    transactions.rows_read(),
+                )?;
+                // The logs then cover at least the rows of the snapshot
+                run.flush()?;
+            }
+        }
+        if outcome.is_err() || shard_failed || INTERRUPTED.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+    if cli.assertions && outcome.is_ok() {
+        outcome = check_assertions(transactions.as_mut(), &engine);
+    }
+    if outcome.is_ok() {
+        outcome = Metrics::time(&mut metrics.process, || run.end_of_input());
+    }
+    #[cfg(feature = "dashboard")]
+    if let Some(dashboard) = dashboard {
+        let drawn = match &partitions {
+            Some(partitions) => dashboard.draw(
+                partitions.iter().map(|(_, engine)| engine),
+                &run,
+                transactions.rows_read(),
+            ),
+            None => dashboard.draw([&engine], &run, transactions.rows_read()),
+        };
+        drawn.map_err(|err| Failure::Output(format!("could not draw dashboard: {err}")))?;
+    }
+    if let Some(snapshots) = snapshots {
+        write_snapshot(
+            snapshots,
+            &engine,
+            partitions.as_ref(),
+            cli,
+            transactions.rows_read(),
+        )?;
+    }
+
+    let mut shard_summary = None;
+    if let Some(sharded) = sharded {
+        let (merged, summary, shard_outcome) =
+            Metrics::time(&mut metrics.process, || sharded.finish());
+        engine = merged;
+        shard_summary = Some(summary);
+        outcome = outcome.and(shard_outcome);
+        metrics.observe(&engine);
+    }
+    if cli.paranoid && outcome.is_ok() {
+        let checked = match &partitions {
+            Some(partitions) => partitions.iter().try_for_each(|(_, engine)| {
+                paranoid::check_engine(engine, cli.allow_negative_available)
+            }),
+            None => paranoid::check_engine(&engine, cli.allow_negative_available),
+        };
+        outcome = checked.map_err(|inconsistency| {
+            Abort::InvariantViolated(format!("paranoid check failed at the end: {inconsistency}"))
+        });
+    }
+
+    metrics.rows = transactions.rows_read();
+    let mut summary = run.finish(
+        &engine,
+        transactions.rows_read(),
+        transactions.rows_skipped(),
+    )?;
+    if let Some(shard_summary) = shard_summary {
+        summary.merge(shard_summary);
+    }
+    if let Some(partitions) = &partitions {
+        summary.finish_all(partitions.iter().map(|(_, engine)| engine));
+    }
+
+    Ok(Pass {
+        engine,
+        partitions,
+        ids: transactions.interned_ids().clone(),
+        summary,
+        outcome,
+        interrupted: INTERRUPTED.load(Ordering::Relaxed),
+    })
+}
+
+/// Writes the accounts of `engine` to `out` as they are formatted, ordered by
+/// client ID. With several threads, they are formatted in parallel.
+fn stream_output(engine: &Engine, cli: &Cli, out: &mut impl io::Write) -> io::Result<()> {
+    let activity = cli.extended_output.then(|| engine.activity());
+    match threads(cli) {
+        1 => stream_accounts(engine.accounts_sorted(), activity, out),
+        threads => {
+            let accounts: Vec<_> = engine.accounts_sorted().collect();
+            stream_accounts_in_parallel(&accounts, activity, threads, out)
+        }
+    }
+}
+
+/// Writes the accounts in `--output-format` to `out` as they are formatted,
+/// compressed if asked to
+fn stream_result<W: io::Write>(
+    engine: &Engine,
+    partitions: Option<&Partitions>,
+    cli: &Cli,
+    out: W,
+) -> io::Result<W> {
+    let mut out = CompressedWriter::new(out, cli.compress)?;
+    match cli.output_format {
+        Format::Csv => match partitions {
+            Some(partitions) if cli.extended_output => {
+                out.write_all(serialize_extended_partitioned_accounts(partitions).as_bytes())?
+            }
+            Some(partitions) => {
+                out.write_all(serialize_partitioned_accounts(partitions).as_bytes())?
+            }
+            None => stream_output(engine, cli, &mut out)?,
+        },
+        #[cfg(feature = "avro")]
+        Format::Avro => {
+            let bytes = match partitions {
+                Some(partitions) => {
+                    avro::serialize_partitioned_accounts(partitions, cli.extended_output)
                 }
-                Err(err) => {
-                    eprintln!("transactions could not be parsed: {}", err);
+                None => avro::serialize_accounts(engine, cli.extended_output),
+            };
+            out.write_all(&bytes.map_err(io::Error::other)?)?;
+        }
+        #[cfg(feature = "camt")]
+        Format::Camt => return Err(io::Error::other("camt is only an input format")),
+        #[cfg(feature = "xlsx")]
+        Format::Xlsx => return Err(io::Error::other("xlsx is only an input format")),
+    }
+    out.finish()
+}
+
+fn snapshots(cli: &Cli, key: &LazyKey) -> Result<Option<Snapshots>, Failure> {
+    let Some(path) = &cli.snapshot else {
+        return Ok(None);
+    };
+    let interval = SnapshotInterval {
+        rows: cli.snapshot_rows,
+        time: cli.snapshot_seconds.map(Duration::from_secs),
+    };
+    if interval == SnapshotInterval::default() {
+        return Err(Failure::Usage(
+            "--snapshot needs --snapshot-rows or --snapshot-seconds".to_string(),
+        ));
+    }
+    Ok(Some(Snapshots::new(
+        path.clone(),
+        interval,
+        key.get()?.cloned(),
+    )))
+}
+
+/// Replaces the snapshot with the accounts as written to stdout
+fn write_snapshot(
+    snapshots: &mut Snapshots,
+    engine: &Engine,
+    partitions: Option<&Partitions>,
+    cli: &Cli,
+    rows: u64,
+) -> Result<(), Failure> {
+    snapshots
+        .write(rows, |file| {
+            stream_result(engine, partitions, cli, file).map(drop)
+        })
+        .map_err(Failure::Output)
+}
+
+/// Writes the resulting accounts to stdout, or partitioned to `--output-dir`
+fn write_result(
+    engine: &Engine,
+    partitions: Option<&Partitions>,
+    cli: &Cli,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let Some(directory) = &cli.output_dir else {
+        return report.write_accounts(engine, partitions, cli);
+    };
+    if cli.output_format != Format::Csv {
+        return Err(Failure::Usage(
+            "--output-dir only writes CSV files".to_string(),
+        ));
+    }
+
+    let mut manifest = Metrics::time(&mut report.metrics.serialize, || match partitions {
+        Some(partitions) => partitioned::by_partner(partitions, cli.extended_output),
+        None => partitioned::by_client_range(engine, cli.partition_size, cli.extended_output),
+    });
+    let contents = manifest
+        .write(directory, cli.compress)
+        .map_err(Failure::Output)?;
+    for partition in &manifest.partitions {
+        report.outputs.push(OutputChecksum {
+            path: directory.join(&partition.file).display().to_string(),
+            bytes: partition.bytes,
+            sha256: partition.sha256.clone(),
+        });
+    }
+    report.outputs.push(OutputChecksum::of(
+        &directory.join(partitioned::MANIFEST).display().to_string(),
+        contents.as_bytes(),
+    ));
+    Ok(())
+}
+
+/// Writes the accounts of every partner to `<partner>.csv` in `directory`
+fn write_partner_outputs(
+    partitions: &Partitions,
+    directory: &Path,
+    extended: bool,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    fs::create_dir_all(directory)
+        .map_err(|err| Failure::Output(format!("could not create output directory: {err}")))?;
+    for (partner, engine) in partitions.iter() {
+        let path = directory.join(format!("{partner}.csv"));
+        let output = serialize_engine_accounts(engine, extended);
+        fs::write(&path, &output)
+            .map_err(|err| Failure::Output(format!("could not write {}: {err}", path.display())))?;
+        report.outputs.push(OutputChecksum::of(
+            &path.display().to_string(),
+            output.as_bytes(),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the statement of every client to `<client>.csv` in `directory`
+fn write_statements(engine: &Engine, directory: &Path, report: &mut Report) -> Result<(), Failure> {
+    fs::create_dir_all(directory)
+        .map_err(|err| Failure::Output(format!("could not create statement directory: {err}")))?;
+    let mut clients: Vec<ClientID> = engine.statement_clients().collect();
+    clients.sort_unstable();
+    for client_id in clients {
+        let path = directory.join(format!("{client_id}.csv"));
+        let statement = serialize_statement(engine.statement(client_id));
+        fs::write(&path, &statement)
+            .map_err(|err| Failure::Output(format!("could not write {}: {err}", path.display())))?;
+        report.outputs.push(OutputChecksum::of(
+            &path.display().to_string(),
+            statement.as_bytes(),
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the scenarios in `directory`, printing the outcome of each
+fn run_scenarios(directory: &Path, cli: &Cli, report: &mut Report) -> Result<(), Failure> {
+    let read_error = |err| Failure::Input(format!("could not read {}: {err}", directory.display()));
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(directory).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "scenario")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut output = String::new();
+    let mut failed = 0;
+    for path in &paths {
+        let text = fs::read_to_string(path)
+            .map_err(|err| Failure::Input(format!("could not read {}: {err}", path.display())))?;
+        let scenario = Scenario::parse(&text)
+            .map_err(|err| Failure::Parse(format!("{}: {err}", path.display())))?;
+        match scenario.run(cli.assertions) {
+            Ok(()) => output.push_str(&format!("ok {}\n", path.display())),
+            Err(mismatches) => {
+                failed += 1;
+                output.push_str(&format!("FAILED {}\n", path.display()));
+                for mismatch in mismatches {
+                    output.push_str(&format!("    {mismatch}\n"));
                 }
             }
+        }
+    }
+    output.push_str(&format!(
+        "{} scenarios, {} passed, {failed} failed\n",
+        paths.len(),
+        paths.len() - failed
+    ));
+    report.write_output(output.as_bytes())?;
+
+    if failed > 0 {
+        return Err(Failure::ScenarioFailed(format!(
+            "{failed} of {} scenarios failed",
+            paths.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Processes the transactions of `workload` with the policy of `cli` and writes
+/// what was measured, failing if an account ends up violating an invariant
+fn run_simulation(
+    rows: Option<u64>,
+    seconds: Option<f64>,
+    workload: &Workload,
+    cli: &Cli,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let time = seconds
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|err| Failure::Usage(format!("invalid --seconds: {err}")))?;
+    let mut engine = Engine::with_policy(policy(cli)?);
+    handle_interrupts();
+    let result = simulation::simulate(
+        &mut engine,
+        workload.stream(),
+        SimulationLimits { rows, time },
+        &INTERRUPTED,
+        cli.allow_negative_available,
+    );
+    let output = match cli.log_format {
+        LogFormat::Text => result.to_string(),
+        LogFormat::Json => format!("{}\n", result.to_json()),
+    };
+    report.write_output(output.as_bytes())?;
+
+    if !result.violations.is_empty() {
+        for violation in &result.violations {
+            eprintln!("{violation}");
+        }
+        return Err(Failure::InvariantViolated(format!(
+            "{} accounts violate an invariant",
+            result.violations.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks every golden file in `directory`, processing the inputs with the
+/// parse options and policy of `cli`
+fn run_golden_files(directory: &Path, cli: &Cli, report: &mut Report) -> Result<(), Failure> {
+    let files = golden::find(directory)
+        .map_err(|err| Failure::Input(format!("could not read {}: {err}", directory.display())))?;
+    let options = parse_options(cli)?;
+    let policy = policy(cli)?;
+
+    let mut output = String::new();
+    let (mut tests, mut failed) = (0, 0);
+    for file in &files {
+        let Some(expected) = &file.expected else {
+            output.push_str(&format!(
+                "skipped {} (no expected file)\n",
+                file.input.display()
+            ));
+            continue;
+        };
+        tests += 1;
+        let open = |path: &Path| {
+            fs::File::open(path)
+                .map(io::BufReader::new)
+                .map_err(|err| Failure::Input(format!("could not read {}: {err}", path.display())))
+        };
+        let mismatches = golden::check(
+            open(&file.input)?,
+            open(expected)?,
+            options.clone(),
+            policy.clone(),
+        )
+        .map_err(|err| Failure::Parse(format!("{}: {err}", file.input.display())))?;
+        if mismatches.is_empty() {
+            output.push_str(&format!("ok {}\n", file.input.display()));
         } else {
-            eprintln!("could not read transactions CSV file!");
+            failed += 1;
+            output.push_str(&format!("FAILED {}\n", file.input.display()));
+            for mismatch in mismatches {
+                output.push_str(&format!("    {mismatch}\n"));
+            }
+        }
+    }
+    output.push_str(&format!(
+        "{tests} tests, {} passed, {failed} failed\n",
+        tests - failed
+    ));
+    report.write_output(output.as_bytes())?;
+
+    if failed > 0 {
+        return Err(Failure::ScenarioFailed(format!(
+            "{failed} of {tests} tests failed"
+        )));
+    }
+    Ok(())
+}
+
+/// Writes a man page for the command and one for each subcommand to `directory`
+fn write_man_pages(directory: &Path, report: &mut Report) -> Result<(), Failure> {
+    fs::create_dir_all(directory)
+        .map_err(|err| Failure::Output(format!("could not create output directory: {err}")))?;
+    let mut command = Cli::command().disable_help_subcommand(true);
+    // Gives subcommands their full names, like `transactions-export-state`
+    command.build();
+    let mut commands = vec![command];
+    while let Some(command) = commands.pop() {
+        commands.extend(command.get_subcommands().cloned());
+        let man = clap_mangen::Man::new(command);
+        let path = directory.join(man.get_filename());
+        let mut page = Vec::new();
+        man.render(&mut page)
+            .and_then(|()| fs::write(&path, &page))
+            .map_err(|err| Failure::Output(format!("could not write {}: {err}", path.display())))?;
+        report
+            .outputs
+            .push(OutputChecksum::of(&path.display().to_string(), &page));
+    }
+    Ok(())
+}
+
+/// The complete state after processing, for comparing passes
+fn fingerprint(engine: &Engine, partitions: Option<&Partitions>) -> String {
+    match partitions {
+        Some(partitions) => partitions
+            .iter()
+            .map(|(partner, engine)| format!("partner {partner}\n{}", state::export_state(engine)))
+            .collect(),
+        None => state::export_state(engine),
+    }
+}
+
+/// Checks the assertions read since the last transaction against `engine`
+fn check_assertions(
+    transactions: &mut dyn TransactionSource,
+    engine: &Engine,
+) -> Result<(), Abort> {
+    transactions
+        .take_assertions()
+        .iter()
+        .try_for_each(|assertion| assertion.check(engine))
+        .map_err(Abort::Assertion)
+}
+
+/// Reads the transactions of `inputs`, merging them if there are several
+fn open_transactions(inputs: &[PathBuf], cli: &Cli) -> Result<Box<dyn TransactionSource>, Failure> {
+    #[cfg(feature = "camt")]
+    let camt_accounts = camt_accounts(cli)?;
+    let mut readers = Vec::with_capacity(inputs.len());
+    if cli.reconnect && cli.input_format != Format::Csv {
+        return Err(Failure::Usage(
+            "--reconnect only works with CSV inputs".to_string(),
+        ));
+    }
+    for input in inputs {
+        let input: Box<dyn io::Read + Send> = match cli.input_format {
+            Format::Csv if cli.reconnect && fifo::is_fifo(input) => {
+                Box::new(Reconnecting::open(input, &INTERRUPTED).map_err(|err| {
+                    Failure::Input(format!("could not open {}: {err}", input.display()))
+                })?)
+            }
+            // Read on a thread, so that interrupts are not stuck behind a blocking read
+            Format::Csv if input.as_os_str() == "-" || fifo::is_fifo(input) => {
+                Box::new(Interruptible::new(open_input(input)?, &INTERRUPTED))
+            }
+            Format::Csv => open_input(input)?,
+            #[cfg(feature = "avro")]
+            Format::Avro => Box::new(avro::AvroRows::new(open_input(input)?).map_err(|err| {
+                Failure::Parse(format!("transactions could not be parsed: {err}"))
+            })?),
+            #[cfg(feature = "camt")]
+            Format::Camt => {
+                let mut xml = String::new();
+                io::Read::read_to_string(&mut open_input(input)?, &mut xml)
+                    .map_err(|err| Failure::Input(format!("could not read statement: {err}")))?;
+                let csv = camt::read_entries(&xml)
+                    .and_then(|entries| camt::to_csv(&entries, &camt_accounts))
+                    .map_err(|err| {
+                        Failure::Parse(format!("statement could not be parsed: {err}"))
+                    })?;
+                Box::new(io::Cursor::new(csv))
+            }
+            #[cfg(feature = "xlsx")]
+            Format::Xlsx => {
+                // Workbooks are zip archives, which are read from the end
+                let mut workbook = Vec::new();
+                io::Read::read_to_end(&mut open_input(input)?, &mut workbook)
+                    .map_err(|err| Failure::Input(format!("could not read workbook: {err}")))?;
+                let csv = xlsx::to_csv(io::Cursor::new(workbook)).map_err(|err| {
+                    Failure::Parse(format!("workbook could not be parsed: {err}"))
+                })?;
+                Box::new(io::Cursor::new(csv))
+            }
+        };
+        let reader =
+            TransactionReader::with_options(io::BufReader::new(input), parse_options(cli)?)
+                .map_err(|err| {
+                    Failure::Parse(format!("transactions could not be parsed: {err}"))
+                })?;
+        readers.push(reader);
+    }
+    if readers.len() == 1 {
+        return Ok(Box::new(readers.pop().unwrap()));
+    }
+    let merged = MergedReader::new(readers)
+        .map_err(|err| Failure::Parse(format!("inputs could not be merged: {err}")))?;
+    Ok(Box::new(merged))
+}
+
+/// The clients of the accounts in `--camt-accounts`. Bank references are not
+/// numeric, so these inputs need `--tx-ids string`.
+#[cfg(feature = "camt")]
+fn camt_accounts(cli: &Cli) -> Result<HashMap<String, ClientID>, Failure> {
+    if cli.input_format != Format::Camt {
+        return Ok(HashMap::new());
+    }
+    if cli.tx_ids != IdFormat::String {
+        return Err(Failure::Usage(
+            "--input-format camt needs --tx-ids string".to_string(),
+        ));
+    }
+    let path = cli
+        .camt_accounts
+        .as_ref()
+        .ok_or_else(|| Failure::Usage("--input-format camt needs --camt-accounts".to_string()))?;
+    let text = fs::read_to_string(path)
+        .map_err(|err| Failure::Input(format!("could not read camt accounts: {err}")))?;
+    camt::parse_accounts(&text)
+        .map_err(|err| Failure::Parse(format!("camt accounts could not be parsed: {err}")))
+}
+
+/// Opens a file, stdin for `-`, or the object at a URL if built with the
+/// `remote` feature
+fn open_input(input: &Path) -> Result<Box<dyn io::Read + Send>, Failure> {
+    if input.as_os_str() == "-" {
+        return Ok(Box::new(io::stdin()));
+    }
+    #[cfg(feature = "remote")]
+    if let Some(location) = input
+        .to_str()
+        .filter(|location| transactions::remote::is_remote(location))
+    {
+        return transactions::remote::open(location).map_err(Failure::Input);
+    }
+    let file = fs::File::open(input)
+        .map_err(|_| Failure::Input("could not read transactions CSV file!".to_string()))?;
+    Ok(Box::new(file))
+}
+
+/// Writes the suspicious patterns found while processing to `path`
+fn write_anomalies(detector: Detector, path: &Path, report: &mut Report) -> Result<(), Failure> {
+    let output = serialize_anomalies(&detector.finish());
+    fs::write(path, &output)
+        .map_err(|err| Failure::Output(format!("could not write anomalies: {err}")))?;
+    report.outputs.push(OutputChecksum::of(
+        &path.display().to_string(),
+        output.as_bytes(),
+    ));
+    Ok(())
+}
+
+/// Processes the transactions of the clients whose ID is a multiple of `every`
+/// sequentially and compares their accounts to those of `engine`
+fn verify_sample(
+    initial_engine: Engine,
+    inputs: &[PathBuf],
+    cli: &Cli,
+    engine: &Engine,
+    every: ClientID,
+) -> Result<(), Failure> {
+    let mut transactions = open_transactions(inputs, cli)?;
+    // Malformed rows were already dealt with by the first pass
+    let rows = iter::from_fn(|| loop {
+        if let Ok(transaction) = transactions.next()? {
+            return Some((transaction, transactions.timestamp()));
+        }
+    });
+    parallel::verify_sample(initial_engine, rows, engine, every)
+        .map_err(Failure::InvariantViolated)?;
+    Ok(())
+}
+
+/// Compares the states produced by two passes over the same input byte for byte
+fn verify_determinism(first: &str, second: &str) -> Result<(), String> {
+    match first
+        .lines()
+        .zip(second.lines())
+        .position(|(first, second)| first != second)
+    {
+        Some(index) => Err(format!(
+            "determinism check failed: passes diverge at line {} of the state document",
+            index + 1
+        )),
+        None if first.len() != second.len() => {
+            Err("determinism check failed: state documents differ in length".to_string())
         }
+        None => Ok(()),
+    }
+}
+
+fn read_ledger(path: &Path) -> Result<Vec<Posting>, Failure> {
+    let file = fs::File::open(path)
+        .map_err(|err| Failure::Input(format!("could not read ledger: {err}")))?;
+    ledger::read_journal(io::BufReader::new(file)).map_err(Failure::Parse)
+}
+
+fn read_state(path: &Path, key: &LazyKey) -> Result<Engine, Failure> {
+    let bytes = fs::read(path)
+        .map_err(|err| Failure::Input(format!("could not read state file: {err}")))?;
+    parse_state(crypto::open(bytes, key.get()?).map_err(Failure::Input)?)
+}
+
+fn parse_state(bytes: Vec<u8>) -> Result<Engine, Failure> {
+    let document = String::from_utf8(bytes)
+        .map_err(|_| Failure::Parse("state file is not valid UTF-8".to_string()))?;
+    state::import_state(&document).map_err(Failure::Parse)
+}
+
+/// Reads accounts either from an accounts CSV, like a snapshot, or from a state
+/// document, either of which may be encrypted
+fn read_accounts(path: &Path, key: &LazyKey) -> Result<HashMap<ClientID, Account>, Failure> {
+    let bytes = fs::read(path)
+        .map_err(|err| Failure::Input(format!("could not read {}: {err}", path.display())))?;
+    let bytes = match crypto::is_encrypted(&bytes) {
+        true => crypto::open(bytes, key.get()?).map_err(Failure::Input)?,
+        false => bytes,
+    };
+    if bytes.trim_ascii_start().starts_with(b"{") {
+        Ok(parse_state(bytes)?.accounts().clone())
     } else {
-        eprintln!("no CSV file of transactions provided!");
+        parse_accounts(bytes.as_slice()).map_err(|err| {
+            Failure::Parse(format!(
+                "accounts could not be parsed from {}: {err}",
+                path.display()
+            ))
+        })
+    }
+}
+
+/// Fails unless `accounts` are exactly the accounts in the file at `expected`,
+/// printing each discrepancy to stderr
+fn reconcile(
+    expected: &Path,
+    accounts: &HashMap<ClientID, Account>,
+    key: &LazyKey,
+) -> Result<(), Failure> {
+    let discrepancies = diff::compare_accounts(&read_accounts(expected, key)?, accounts);
+    if discrepancies.is_empty() {
+        return Ok(());
+    }
+    for discrepancy in &discrepancies {
+        eprintln!("{discrepancy}");
     }
-    ExitCode::from(1)
+    Err(Failure::Reconciliation(format!(
+        "{} clients differ from {}",
+        discrepancies.len(),
+        expected.display()
+    )))
 }
 
-fn handle_transactions(transactions: &[Transaction]) -> HashMap<ClientID, Account> {
-    let mut accounts = HashMap::<ClientID, Account>::new();
-    let mut processed_transactions =
-        HashMap::<TransactionID, Transaction>::with_capacity(transactions.len());
+fn replay(
+    events_path: &Path,
+    until: Option<u64>,
+    verify: Option<&Path>,
+    key: &LazyKey,
+    policy: Policy,
+) -> Result<String, Failure> {
+    let snapshot = verify.map(|path| read_state(path, key)).transpose()?;
+    let until = until.or(snapshot
+        .as_ref()
+        .map(|snapshot| snapshot.counters().applied));
 
-    for transaction in transactions {
-        let account = accounts
-            .entry(transaction.client_id)
-            .or_insert_with(Account::default);
-        transaction.process(account, &processed_transactions);
-        processed_transactions.insert(transaction.id, transaction.clone());
+    let mut engine = Engine::with_policy(policy);
+    let file = fs::File::open(events_path)
+        .map_err(|err| Failure::Input(format!("could not read event log: {err}")))?;
+    let events =
+        events::parse_events(io::BufReader::new(file), key.get()?).map_err(Failure::Parse)?;
+    for event in events {
+        if until.is_some_and(|until| event.seq > until) {
+            break;
+        }
+        // Only applied transactions are recorded, so every event must apply again
+        engine.process(&event.transaction).map_err(|rejection| {
+            Failure::Parse(format!(
+                "event {} could not be replayed: {rejection:?}",
+                event.seq
+            ))
+        })?;
+    }
+    if let Some(until) = until {
+        if engine.counters().applied < until {
+            return Err(Failure::Input(format!(
+                "event log ends at sequence number {} before {until}",
+                engine.counters().applied
+            )));
+        }
     }
 
-    accounts
+    if let Some(snapshot) = snapshot {
+        state::verify(&engine, &snapshot).map_err(Failure::Reconciliation)?;
+    }
+    Ok(serialize_engine_accounts(&engine, false))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use transactions::account::serialize_accounts;
+    use transactions::transaction::{self, parse_transactions, Transaction};
+
+    fn handle_transactions(transactions: &[Transaction]) -> Engine {
+        let mut engine = Engine::default();
+        for transaction in transactions {
+            let _ = engine.process(transaction);
+        }
+        engine
+    }
+
+    fn snapshot(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/snapshots")
+            .join(format!("{name}.csv"))
+    }
 
     fn test_accounts_integrity<'a>(accounts: impl Iterator<Item = &'a Account>) {
         for account in accounts {
@@ -117,17 +2342,30 @@ mod tests {
             ]
         );
 
-        let accounts = handle_transactions(&transactions);
+        let engine = handle_transactions(&transactions);
+        let accounts = engine.accounts();
 
         assert!(accounts.len() == 3);
         test_accounts_integrity(accounts.values());
 
-        // We have no guaranteed account order
-        let output = serialize_accounts(&accounts);
-        assert!(output.starts_with("client,available,held,total,locked"));
-        assert!(output.contains("5,7.5,0,7.5,false"));
-        assert!(output.contains("20,49,0,49,false"));
-        assert!(output.contains("10,38.99,0,38.99,false"));
+        golden::assert_snapshot(
+            &serialize_accounts(accounts),
+            snapshot("deposits_and_withdrawals"),
+        );
+    }
+
+    #[test]
+    fn it_rejects_missing_amounts_only_in_strict_mode() {
+        let missing_amounts = |args: &[&str]| {
+            let cli = Cli::parse_from([&["transactions", "input.csv"], args].concat());
+            parse_options(&cli).unwrap().missing_amounts
+        };
+        assert_eq!(missing_amounts(&[]), MissingAmountPolicy::Zero);
+        assert_eq!(missing_amounts(&["--strict"]), MissingAmountPolicy::Reject);
+        assert_eq!(
+            missing_amounts(&["--strict", "--missing-amounts", "skip"]),
+            MissingAmountPolicy::Skip
+        );
     }
 
     #[test]
@@ -164,22 +2402,21 @@ mod tests {
             ]
         );
 
-        let accounts = handle_transactions(&transactions);
+        let engine = handle_transactions(&transactions);
+        let accounts = engine.accounts();
 
         assert!(accounts.len() == 1);
         test_accounts_integrity(accounts.values());
 
-        let output = serialize_accounts(&accounts);
-        assert!(output.starts_with("client,available,held,total,locked"));
-        assert!(output.contains("5,10,0,10,false\n"));
+        golden::assert_snapshot(&serialize_accounts(accounts), snapshot("disputes"));
     }
 
     #[test]
     fn it_handles_chargebacks() {
         let transactions_string = "type,      client, tx,  amount\n\
                                          deposit,    10,      2, 99.9999\n\
-                                         dispute,    10,      3,\n\
-                                         chargeback, 10,      3,\n\
+                                         dispute,    10,      2,\n\
+                                         chargeback, 10,      2,\n\
                                          ";
         let transactions = parse_transactions(io::Cursor::new(transactions_string)).unwrap();
 
@@ -196,26 +2433,25 @@ mod tests {
                 Transaction {
                     ty: Dispute,
                     client_id: 10,
-                    id: 3,
+                    id: 2,
                     amount: 0.0,
                 },
                 Transaction {
                     ty: Chargeback,
                     client_id: 10,
-                    id: 3,
+                    id: 2,
                     amount: 0.0,
                 },
             ]
         );
 
-        let accounts = handle_transactions(&transactions);
+        let engine = handle_transactions(&transactions);
+        let accounts = engine.accounts();
 
         assert!(accounts.len() == 1);
         test_accounts_integrity(accounts.values());
         assert!(accounts.values().next().unwrap().locked);
 
-        let output = serialize_accounts(&accounts);
-        assert!(output.starts_with("client,available,held,total,locked"));
-        assert!(output.contains("10,99.9999,0,99.9999,true\n"));
+        golden::assert_snapshot(&serialize_accounts(accounts), snapshot("chargebacks"));
     }
 }
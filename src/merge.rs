@@ -0,0 +1,302 @@
+//! Reading several inputs as one stream, each parsed on a thread of its own.
+//! Inputs with a `timestamp` column are merged in the order of their timestamps,
+//! and read one after another otherwise.
+
+use std::{
+    io, mem,
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
+    vec,
+};
+
+use crate::assertion::Assertion;
+use crate::transaction::{
+    ExtraColumns, IdFormat, InternedIds, RowContext, Transaction, TransactionReader,
+    TransactionSource,
+};
+
+/// How many rows the thread of each input hands over at once
+const BATCH_SIZE: usize = 256;
+
+/// How many batches the thread of each input may parse ahead
+const BATCHES_AHEAD: usize = 4;
+
+/// Where a row parsed by the thread of an input came from
+struct RowInfo {
+    line: u64,
+    offset: u64,
+    text: String,
+    timestamp: Option<u64>,
+    warning: Option<&'static str>,
+}
+
+type ParsedRow = (Result<Transaction, &'static str>, RowInfo);
+
+struct Input {
+    batches: Receiver<Vec<ParsedRow>>,
+    /// The rest of the batch received last
+    batch: vec::IntoIter<ParsedRow>,
+    /// The next row of the input, once received
+    next: Option<ParsedRow>,
+    /// Returns how many rows of the input contained no transaction. Unset once
+    /// the input is exhausted.
+    handle: Option<JoinHandle<u64>>,
+}
+
+impl Input {
+    /// Waits for the next row of the input unless there is one already. Returns
+    /// the number of rows skipped once the input turns out to be exhausted.
+    fn fill(&mut self) -> Option<u64> {
+        if self.next.is_some() {
+            return None;
+        }
+        self.next = self.batch.next();
+        if self.next.is_some() {
+            return None;
+        }
+        let handle = self.handle.take()?;
+        match self.batches.recv() {
+            Ok(batch) => {
+                self.batch = batch.into_iter();
+                self.next = self.batch.next();
+                self.handle = Some(handle);
+                None
+            }
+            Err(_) => Some(handle.join().expect("parsing thread panicked")),
+        }
+    }
+}
+
+/// The transactions of several inputs, as one [`TransactionSource`]. Rows are
+/// reported with the line and offset within their own input.
+pub struct MergedReader {
+    inputs: Vec<Input>,
+    by_timestamp: bool,
+    extra_columns: ExtraColumns,
+    /// Always empty, as string IDs are not supported
+    ids: InternedIds,
+    current: Option<RowInfo>,
+    rows_read: u64,
+    rows_skipped: u64,
+}
+
+impl MergedReader {
+    /// Starts parsing each of `readers` on a thread of its own. The inputs must all
+    /// have the same extra columns, and either all or none of them a `timestamp`
+    /// column. Inputs with a `partner` column or string IDs are not supported.
+    pub fn new<R: io::BufRead + Send + 'static>(
+        readers: Vec<TransactionReader<R>>,
+    ) -> Result<Self, &'static str> {
+        let Some(first) = readers.first() else {
+            return Err("no inputs");
+        };
+        let by_timestamp = first.has_timestamp_column();
+        let extra_columns = first.extra_columns().clone();
+        for reader in &readers {
+            if reader.has_partner_column() {
+                return Err("merged inputs cannot have a partner column");
+            }
+            if reader.id_format() == IdFormat::String {
+                return Err("merged inputs cannot have string transaction IDs");
+            }
+            if reader.has_timestamp_column() != by_timestamp {
+                return Err("either all or none of the merged inputs must have a timestamp column");
+            }
+            if *reader.extra_columns() != extra_columns {
+                return Err("merged inputs must have the same columns");
+            }
+        }
+
+        let inputs = readers
+            .into_iter()
+            .map(|mut reader| {
+                let (sender, batches) = mpsc::sync_channel(BATCHES_AHEAD);
+                let handle = thread::spawn(move || {
+                    let mut batch = Vec::with_capacity(BATCH_SIZE);
+                    while let Some(transaction) = reader.next() {
+                        let row = reader.row();
+                        let info = RowInfo {
+                            line: row.line,
+                            offset: row.offset,
+                            text: row.text.to_string(),
+                            timestamp: reader.timestamp(),
+                            warning: reader.warning(),
+                        };
+                        batch.push((transaction, info));
+                        if batch.len() == BATCH_SIZE {
+                            let full = mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                            // The merged reader was dropped
+                            if sender.send(full).is_err() {
+                                return reader.rows_skipped;
+                            }
+                        }
+                    }
+                    if !batch.is_empty() {
+                        let _ = sender.send(batch);
+                    }
+                    reader.rows_skipped
+                });
+                Input {
+                    batches,
+                    batch: Vec::new().into_iter(),
+                    next: None,
+                    handle: Some(handle),
+                }
+            })
+            .collect();
+        Ok(Self {
+            inputs,
+            by_timestamp,
+            extra_columns,
+            ids: InternedIds::default(),
+            current: None,
+            rows_read: 0,
+            rows_skipped: 0,
+        })
+    }
+
+    /// Whether input `index` has another row, counting its rows once it is exhausted
+    fn fill(&mut self, index: usize) -> bool {
+        if let Some(rows_skipped) = self.inputs[index].fill() {
+            // Only the rows with a transaction or an error were passed on
+            self.rows_read += rows_skipped;
+            self.rows_skipped += rows_skipped;
+        }
+        self.inputs[index].next.is_some()
+    }
+}
+
+impl Iterator for MergedReader {
+    type Item = Result<Transaction, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = if self.by_timestamp {
+            let mut earliest: Option<(Option<u64>, usize)> = None;
+            for index in 0..self.inputs.len() {
+                if !self.fill(index) {
+                    continue;
+                }
+                let timestamp = self.inputs[index].next.as_ref().unwrap().1.timestamp;
+                // Ties go to the input given first
+                if earliest.is_none_or(|(earliest, _)| timestamp < earliest) {
+                    earliest = Some((timestamp, index));
+                }
+            }
+            earliest?.1
+        } else {
+            (0..self.inputs.len()).find(|index| self.fill(*index))?
+        };
+        let (transaction, info) = self.inputs[index].next.take().unwrap();
+        self.current = Some(info);
+        self.rows_read += 1;
+        Some(transaction)
+    }
+}
+
+impl TransactionSource for MergedReader {
+    fn row(&self) -> RowContext<'_> {
+        let info = self.current.as_ref();
+        RowContext {
+            line: info.map_or(0, |info| info.line),
+            offset: info.map_or(0, |info| info.offset),
+            text: info.map_or("", |info| &info.text),
+            partner: None,
+        }
+    }
+
+    fn warning(&self) -> Option<&'static str> {
+        self.current.as_ref()?.warning
+    }
+
+    fn partner(&self) -> Option<&str> {
+        None
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.current.as_ref()?.timestamp
+    }
+
+    fn has_partner_column(&self) -> bool {
+        false
+    }
+
+    fn has_timestamp_column(&self) -> bool {
+        self.by_timestamp
+    }
+
+    fn extra_columns(&self) -> &ExtraColumns {
+        &self.extra_columns
+    }
+
+    fn interned_ids(&self) -> &InternedIds {
+        &self.ids
+    }
+
+    fn rows_read(&self) -> u64 {
+        self.rows_read
+    }
+
+    fn rows_skipped(&self) -> u64 {
+        self.rows_skipped
+    }
+
+    /// Assertions are not read from merged inputs, whose order is not that of
+    /// any one file
+    fn take_assertions(&mut self) -> Vec<Assertion> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionID;
+
+    fn reader(input: &str) -> TransactionReader<io::Cursor<String>> {
+        TransactionReader::new(io::Cursor::new(input.to_string())).unwrap()
+    }
+
+    fn ids(reader: MergedReader) -> Vec<TransactionID> {
+        reader.map(|transaction| transaction.unwrap().id).collect()
+    }
+
+    #[test]
+    fn it_merges_inputs_by_timestamp() {
+        let merged = MergedReader::new(vec![
+            reader(
+                "type,client,tx,amount,timestamp\n\
+                 deposit,1,1,1.0,10\n\
+                 deposit,1,2,1.0,30\n\
+                 ,,,,\n\
+                 deposit,1,3,1.0,30\n",
+            ),
+            reader(
+                "type,client,tx,amount,timestamp\n\
+                 deposit,2,4,1.0,20\n\
+                 deposit,2,5,1.0,30\n\
+                 deposit,2,6,1.0,40\n",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(ids(merged), [1, 4, 2, 3, 5, 6]);
+
+        // Without timestamps, inputs are read in the order given
+        let mut merged = MergedReader::new(vec![
+            reader("deposit,1,1,1.0\n,,,\n"),
+            reader("deposit,1,2,1.0\ndeposit,x,3,1.0\n"),
+        ])
+        .unwrap();
+        assert_eq!(merged.next().unwrap().unwrap().id, 1);
+        assert_eq!(merged.next().unwrap().unwrap().id, 2);
+        assert!(merged.next().unwrap().is_err());
+        assert_eq!(merged.row().line, 2);
+        assert_eq!(merged.next(), None);
+        assert_eq!((merged.rows_read(), merged.rows_skipped()), (4, 1));
+
+        assert!(MergedReader::new(vec![
+            reader("type,client,tx,amount\n"),
+            reader("type,client,tx,amount,timestamp\n"),
+        ])
+        .is_err());
+    }
+}
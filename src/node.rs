@@ -0,0 +1,108 @@
+//! A native Node.js addon, built with `--features node` (e.g. through `napi build`).
+
+use napi::{Error, Result};
+use napi_derive::napi;
+
+use crate::account::Account;
+use crate::session::{self, Session};
+use crate::transaction::ClientID;
+
+/// A client's account as handed to JS
+#[napi(object)]
+pub struct ClientAccount {
+    pub client: i64,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl ClientAccount {
+    fn new(client_id: ClientID, account: &Account) -> Self {
+        Self {
+            // Only client IDs beyond `i64::MAX` wrap around
+            client: client_id as i64,
+            available: to_f64(account.available),
+            held: to_f64(account.held),
+            total: to_f64(account.total),
+            locked: account.locked,
+        }
+    }
+}
+
+/// Widens an amount to the number it is printed as, so that e.g. `39.99` does not
+/// come out as `39.9900016784668`
+fn to_f64(amount: f32) -> f64 {
+    amount
+        .to_string()
+        .parse()
+        .expect("printed floats can always be parsed")
+}
+
+/// Processes a complete CSV input and returns the resulting accounts as CSV.
+/// Rejected transactions are left out, like on the command line.
+#[napi]
+pub fn process_csv(input: String) -> Result<String> {
+    session::process_csv(&input).map_err(Error::from_reason)
+}
+
+/// Ingests an input handed over in chunks, such as the chunks of a readable
+/// stream, and answers queries about the accounts at any point
+#[napi(js_name = "Engine")]
+#[derive(Default)]
+pub struct NodeEngine(Session);
+
+#[napi]
+impl NodeEngine {
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Processes the complete rows in the next chunk of input. Rows may be split
+    /// across chunks. Throws on a malformed row.
+    #[napi]
+    pub fn push(&mut self, chunk: String) -> Result<()> {
+        self.0.push(&chunk).map_err(Error::from_reason)
+    }
+
+    /// Processes the last row of an input that does not end with a newline
+    #[napi]
+    pub fn finish(&mut self) -> Result<()> {
+        self.0.finish().map_err(Error::from_reason)
+    }
+
+    /// The account of `client`, if it had any transactions
+    #[napi]
+    pub fn account(&self, client: i64) -> Option<ClientAccount> {
+        let client_id = ClientID::try_from(client).ok()?;
+        let account = self.0.engine().account(client_id)?;
+        Some(ClientAccount::new(client_id, account))
+    }
+
+    /// All accounts, ordered by client
+    #[napi]
+    pub fn accounts(&self) -> Vec<ClientAccount> {
+        self.0
+            .engine()
+            .accounts_sorted()
+            .map(|(client_id, account)| ClientAccount::new(client_id, account))
+            .collect()
+    }
+
+    /// At most `limit` accounts ordered by client, after skipping the first `offset`
+    #[napi]
+    pub fn accounts_page(&self, offset: u32, limit: u32) -> Vec<ClientAccount> {
+        self.0
+            .engine()
+            .accounts_page(offset as usize, limit as usize)
+            .map(|(client_id, account)| ClientAccount::new(client_id, account))
+            .collect()
+    }
+
+    /// The accounts as CSV
+    #[napi]
+    pub fn to_csv(&self) -> String {
+        self.0.accounts_csv()
+    }
+}
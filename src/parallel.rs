@@ -0,0 +1,440 @@
+//! Processing with one thread per shard of clients.
+//!
+//! Every transaction is routed to the shard owning its client, so the transactions
+//! of each client are still applied in input order. Since disputes, resolves, and
+//! chargebacks only ever affect the account of their own client, the resulting
+//! accounts are the same as when processing sequentially, as long as transaction
+//! IDs are unique. The only difference is that a reference to another client's
+//! transaction is rejected as unknown rather than as a client mismatch.
+//!
+//! This holds for policies that only look at the transactions of each client,
+//! like velocity windows. Clearing by transaction count and by time counts the
+//! transactions or reads the clock of all clients, which each shard only sees
+//! part of, so these policies must not be used with more than one shard.
+
+use std::{
+    collections::HashSet,
+    io,
+    num::NonZeroUsize,
+    sync::{
+        mpsc::{self, SyncSender},
+        RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::account::Account;
+use crate::engine::{self, Engine};
+use crate::report::Summary;
+use crate::run::{Abort, Run, RunOptions};
+use crate::transaction::{
+    ClientID, IdFormat, ParseOptions, ProcessedTransaction, Rejection, RowContext, Transaction,
+    TransactionID, TransactionReader,
+};
+
+/// How many rows can be queued for each shard before the reader has to wait
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// The number of threads to use if not specified otherwise
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// A transaction on its way to a shard, along with its row for diagnostics
+struct Row {
+    transaction: Transaction,
+    line: u64,
+    offset: u64,
+    text: String,
+}
+
+type Outcome = (Engine, Summary, Result<(), Abort>);
+
+struct Shard {
+    sender: SyncSender<Row>,
+    handle: JoinHandle<Outcome>,
+}
+
+/// A run spread across threads, with the same resulting accounts as processing
+/// sequentially unless the policy clears deposits (see the module documentation).
+/// Event logs and the lookahead window are not supported, and the
+/// [`RunOptions::max_errors`] and [`RunOptions::memory_limit`] limits only apply
+/// to each shard separately.
+pub struct ShardedRun {
+    shards: Vec<Shard>,
+}
+
+impl ShardedRun {
+    /// Spawns `threads` threads, each continuing with its share of `engine`
+    pub fn new(engine: Engine, threads: usize, options: RunOptions) -> Self {
+        let shards = engine
+            .split(threads)
+            .into_iter()
+            .map(|mut engine| {
+                let capacity = options.queue_length.unwrap_or(CHANNEL_CAPACITY);
+                let (sender, receiver) = mpsc::sync_channel::<Row>(capacity);
+                let options = options.clone();
+                let handle = thread::spawn(move || {
+                    let mut run = Run::new(options, None);
+                    let mut outcome = receiver.iter().try_for_each(|row| {
+                        let context = RowContext {
+                            line: row.line,
+                            offset: row.offset,
+                            text: &row.text,
+                            partner: None,
+                        };
+                        run.process(&mut engine, &row.transaction, &context)
+                    });
+                    if outcome.is_ok() {
+                        outcome = run.end_of_input();
+                    }
+                    let summary = run.finish(&engine, 0, 0);
+                    match summary {
+                        Ok(summary) => (engine, summary, outcome),
+                        Err(err) => (engine, Summary::default(), outcome.and(Err(err))),
+                    }
+                });
+                Shard { sender, handle }
+            })
+            .collect();
+        Self { shards }
+    }
+
+    /// Hands a transaction read from `row` to the shard owning its client.
+    /// Returns `false` if that shard already stopped because of an error,
+    /// which is then reported by [`ShardedRun::finish`].
+    pub fn process(&mut self, transaction: Transaction, row: &RowContext) -> bool {
+        let shard = &self.shards[engine::shard_of(transaction.client_id, self.shards.len())];
+        shard
+            .sender
+            .send(Row {
+                transaction,
+                line: row.line,
+                offset: row.offset,
+                text: row.echo(),
+            })
+            .is_ok()
+    }
+
+    /// Waits for all shards to process their remaining transactions and puts the
+    /// results back together. The summary lacks the counts describing the final
+    /// state and those of the reader. Fails with the first error of any shard.
+    pub fn finish(self) -> Outcome {
+        let mut engines = Vec::with_capacity(self.shards.len());
+        let mut summary = Summary::default();
+        let mut result = Ok(());
+        for shard in self.shards {
+            drop(shard.sender);
+            let (engine, shard_summary, outcome) =
+                shard.handle.join().expect("shard thread panicked");
+            engines.push(engine);
+            summary.merge(shard_summary);
+            result = result.and(outcome);
+        }
+        (Engine::merge(engines), summary, result)
+    }
+}
+
+/// An engine that several threads process transactions with at the same time, for
+/// multiple sources feeding one engine or handlers of a server. The clients are
+/// split into shards with a lock each, so sources only contend on transactions of
+/// clients in the same shard, while each client's transactions are all in one
+/// shard for disputes to refer to. Queries only take a shared lock of the shard
+/// they read, so they neither wait for each other nor stop other shards.
+pub struct SharedEngine {
+    shards: Vec<RwLock<(Engine, Summary)>>,
+    custom_types: Vec<String>,
+}
+
+impl SharedEngine {
+    /// Splits `engine` into `shards` shards
+    pub fn new(engine: Engine, shards: usize) -> Self {
+        let custom_types = engine.custom_types().map(String::from).collect();
+        Self {
+            shards: engine
+                .split(shards)
+                .into_iter()
+                .map(|engine| RwLock::new((engine, Summary::default())))
+                .collect(),
+            custom_types,
+        }
+    }
+
+    /// Processes `transaction` happening at `timestamp`, if known, while holding
+    /// only the lock of its client's shard
+    pub fn process(
+        &self,
+        transaction: &Transaction,
+        timestamp: Option<u64>,
+    ) -> Result<(), Rejection> {
+        let shard = engine::shard_of(transaction.client_id, self.shards.len());
+        let mut shard = self.shards[shard].write().expect("a shard was poisoned");
+        let (engine, summary) = &mut *shard;
+        if let Some(timestamp) = timestamp {
+            engine.set_clock(timestamp);
+        }
+        let result = engine.process(transaction);
+        summary.record(transaction, result);
+        result
+    }
+
+    /// Processes the CSV input read from `reader` with `options`, failing on the
+    /// first malformed row. Rows of the types the engine has handlers for are
+    /// accepted as well.
+    pub fn ingest(
+        &self,
+        reader: impl io::BufRead,
+        mut options: ParseOptions,
+    ) -> Result<(), String> {
+        if options.ids == IdFormat::String {
+            // The numbering of IDs would differ between sources
+            return Err("string transaction IDs are not supported".to_string());
+        }
+        options
+            .custom_types
+            .extend(self.custom_types.iter().cloned());
+        let mut transactions = TransactionReader::with_options(reader, options)?;
+        if transactions.has_partner_column() {
+            return Err("inputs with a partner column are not supported".to_string());
+        }
+        while let Some(transaction) = transactions.next() {
+            let transaction =
+                transaction.map_err(|err| format!("{err} at line {}", transactions.row().line))?;
+            // Rejected transactions are counted in the summary
+            let _ = self.process(&transaction, transactions.timestamp());
+        }
+        let mut shard = self.shards[0].write().expect("a shard was poisoned");
+        shard.1.rows_parsed += transactions.rows_read - transactions.rows_skipped;
+        shard.1.rows_skipped += transactions.rows_skipped;
+        Ok(())
+    }
+
+    /// A copy of the account of `client_id`, if it had any transactions
+    pub fn account(&self, client_id: ClientID) -> Option<Account> {
+        self.read(client_id, |engine| engine.account(client_id).cloned())
+    }
+
+    /// A copy of the deposit or withdrawal `id` of `client_id`
+    pub fn transaction(
+        &self,
+        client_id: ClientID,
+        id: TransactionID,
+    ) -> Option<ProcessedTransaction> {
+        self.read(client_id, |engine| {
+            engine.transaction(client_id, id).cloned()
+        })
+    }
+
+    /// Copies of all accounts, ordered by client ID. The shards are read one
+    /// after another, so transactions processed meanwhile may be partly included.
+    pub fn accounts(&self) -> Vec<(ClientID, Account)> {
+        let mut accounts: Vec<(ClientID, Account)> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().expect("a shard was poisoned");
+                shard
+                    .0
+                    .accounts()
+                    .iter()
+                    .map(|(client_id, account)| (*client_id, account.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        accounts.sort_unstable_by_key(|(client_id, _)| *client_id);
+        accounts
+    }
+
+    /// Runs `read` on the engine of the shard owning `client_id`
+    fn read<T>(&self, client_id: ClientID, read: impl FnOnce(&Engine) -> T) -> T {
+        let shard = engine::shard_of(client_id, self.shards.len());
+        let shard = self.shards[shard].read().expect("a shard was poisoned");
+        read(&shard.0)
+    }
+
+    /// Puts the shards back together, with the summary of all transactions
+    pub fn into_inner(self) -> (Engine, Summary) {
+        let mut engines = Vec::with_capacity(self.shards.len());
+        let mut summary = Summary::default();
+        for shard in self.shards {
+            let (engine, shard_summary) = shard.into_inner().expect("a shard was poisoned");
+            engines.push(engine);
+            summary.merge(shard_summary);
+        }
+        let engine = Engine::merge(engines);
+        summary.finish(&engine);
+        (engine, summary)
+    }
+}
+
+/// Checks the accounts of a parallel run against processing the transactions of a
+/// sample of its clients sequentially, from the same `initial` engine. The sample
+/// are the clients whose ID is a multiple of `every`. As each client's transactions
+/// only ever affect its own account, the parallel run must have left the sampled
+/// clients with the same accounts and transactions. Returns the number of clients
+/// checked.
+pub fn verify_sample(
+    mut initial: Engine,
+    transactions: impl IntoIterator<Item = (Transaction, Option<u64>)>,
+    parallel: &Engine,
+    every: ClientID,
+) -> Result<usize, String> {
+    let mut clients = HashSet::new();
+    for (transaction, timestamp) in transactions {
+        if transaction.client_id % every != 0 {
+            continue;
+        }
+        clients.insert(transaction.client_id);
+        if let Some(timestamp) = timestamp {
+            initial.set_clock(timestamp);
+        }
+        // Rejections are compared through the resulting state
+        let _ = initial.process(&transaction);
+    }
+    let mut sorted: Vec<ClientID> = clients.into_iter().collect();
+    sorted.sort_unstable();
+    for client_id in &sorted {
+        if parallel.account(*client_id) != initial.account(*client_id)
+            || parallel.client_transactions(*client_id) != initial.client_transactions(*client_id)
+        {
+            return Err(format!(
+                "sample check failed: client {client_id} differs from sequential processing"
+            ));
+        }
+    }
+    Ok(sorted.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::policy::{Policy, VelocityLimit};
+    use crate::state;
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_matches_sequential_processing() {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for id in 0..1000u32 {
+            let client = id % 7;
+            input.push_str(&format!("deposit,{client},{id},{}.5\n", id % 10));
+            if id % 3 == 0 {
+                input.push_str(&format!("dispute,{client},{id}\n"));
+            }
+            if id % 6 == 0 {
+                input.push_str(&format!("chargeback,{client},{id}\n"));
+            }
+            input.push_str(&format!("withdrawal,{client},{},1.25\n", id + 10_000));
+        }
+        let transactions = || {
+            TransactionReader::new(io::Cursor::new(input.as_str()))
+                .unwrap()
+                .map(Result::unwrap)
+        };
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+
+        // Velocity windows hold the transactions of one client, so the shards
+        // count them like a single engine does
+        let policy = Policy {
+            velocity: Some(VelocityLimit {
+                window: 3,
+                max_withdrawals: Some(1),
+                max_withdrawal_amount: None,
+            }),
+            ..Policy::default()
+        };
+        let mut sequential = Engine::with_policy(policy.clone());
+        let rejected = transactions()
+            .filter(|transaction| sequential.process(transaction).is_err())
+            .count();
+
+        let options = RunOptions {
+            quiet: true,
+            ..RunOptions::default()
+        };
+        let mut sharded = ShardedRun::new(Engine::with_policy(policy.clone()), 4, options);
+        for transaction in transactions() {
+            assert!(sharded.process(transaction, &row));
+        }
+        let (parallel, summary, outcome) = sharded.finish();
+        outcome.unwrap();
+
+        assert_eq!(
+            state::export_state(&parallel),
+            state::export_state(&sequential)
+        );
+        assert_eq!(summary.errors(), rejected as u64);
+
+        assert_eq!(
+            verify_sample(
+                Engine::with_policy(policy.clone()),
+                transactions().map(|transaction| (transaction, None)),
+                &parallel,
+                3
+            ),
+            Ok(3)
+        );
+        let mut tampered = parallel;
+        tampered.accounts.get_mut(&3).unwrap().available += 1.0;
+        assert!(verify_sample(
+            Engine::with_policy(policy),
+            transactions().map(|transaction| (transaction, None)),
+            &tampered,
+            3
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_is_shareable_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Engine>();
+        assert_send_sync::<SharedEngine>();
+    }
+
+    #[test]
+    fn it_ingests_several_sources_at_once() {
+        let engine = SharedEngine::new(Engine::default(), 4);
+        let sources = [
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             deposit,2,2,3.0\n\
+             dispute,1,1\n",
+            "client,type,tx,amount\n\
+             3,deposit,3,2.5\n\
+             2,withdrawal,4,4.0\n",
+        ];
+        thread::scope(|scope| {
+            for source in sources {
+                let engine = &engine;
+                scope.spawn(move || {
+                    engine
+                        .ingest(io::Cursor::new(source), ParseOptions::default())
+                        .unwrap()
+                });
+            }
+        });
+        assert_eq!(engine.account(1).unwrap().held, 5.0);
+        assert_eq!(engine.transaction(3, 3).unwrap().amount, 2.5);
+        assert_eq!(engine.accounts()[1].0, 2);
+        assert_eq!(
+            engine.ingest(
+                io::Cursor::new("deposit,x,5,1.0\n"),
+                ParseOptions::default()
+            ),
+            Err("invalid client ID at line 1".to_string())
+        );
+
+        let (engine, summary) = engine.into_inner();
+        assert_eq!(engine.accounts().len(), 3);
+        assert_eq!(summary.rows_parsed, 5);
+        assert_eq!(summary.rejected[&Rejection::InsufficientFunds], 1);
+    }
+}
@@ -0,0 +1,262 @@
+//! Assertions about the internal state of the engine around every transaction,
+//! for canary runs with `--paranoid` after upgrades. They are always compiled
+//! in, unlike `debug_assert!`, but only made when asked for.
+
+use crate::account::Account;
+use crate::engine::Engine;
+use crate::rules::RuleAction;
+use crate::transaction::{
+    DisputeState, ProcessedTransaction, Rejection, Transaction, TransactionType,
+};
+
+/// What the checks of a transaction compare with, captured before it is processed
+#[derive(Debug, Clone)]
+pub struct Before {
+    account: Option<Account>,
+    /// The transaction indexed under the client and ID of the transaction
+    indexed: Option<ProcessedTransaction>,
+    index_len: usize,
+    processed: u64,
+    applied: u64,
+}
+
+impl Before {
+    pub fn capture(engine: &Engine, transaction: &Transaction) -> Self {
+        Self {
+            account: engine.accounts.get(&transaction.client_id).cloned(),
+            indexed: engine
+                .transactions
+                .get(transaction.client_id, transaction.id)
+                .cloned(),
+            index_len: engine.transactions.len(),
+            processed: engine.counters.processed,
+            applied: engine.counters.applied,
+        }
+    }
+
+    /// Checks `engine` right after it processed `transaction` with `result`, with
+    /// negative available funds being fine if `allow_negative_available` is set
+    pub fn check(
+        &self,
+        engine: &Engine,
+        transaction: &Transaction,
+        result: Result<(), Rejection>,
+        allow_negative_available: bool,
+    ) -> Result<(), &'static str> {
+        if !transaction.amount.is_finite() {
+            return Err("amount is not finite");
+        }
+        if transaction.ty.refers_back() && transaction.amount != 0.0 {
+            return Err("amount on a dispute, resolve, or chargeback");
+        }
+        if engine.counters.processed != self.processed + 1 {
+            return Err("processed counter did not advance by one");
+        }
+        if engine.counters.applied != self.applied + u64::from(result.is_ok()) {
+            return Err("applied counter does not match the result");
+        }
+
+        if let Some(account) = engine.accounts.get(&transaction.client_id) {
+            check_account(account, allow_negative_available)?;
+        } else if !matches!(result, Err(Rejection::NotAllowed | Rejection::Blocked)) {
+            return Err("no account after processing a transaction");
+        }
+        if let Some(sorted) = engine.sorted_clients.get() {
+            if sorted.len() != engine.accounts.len() {
+                return Err("sorted clients are out of date");
+            }
+        }
+
+        let indexed = engine
+            .transactions
+            .get(transaction.client_id, transaction.id);
+        if indexed.is_some_and(|indexed| indexed.client_id != transaction.client_id) {
+            return Err("transaction indexed under another client");
+        }
+        let added = engine.transactions.len() as i64 - self.index_len as i64;
+        let stored = result.is_ok()
+            && matches!(
+                transaction.ty,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            );
+        match stored {
+            true => {
+                let indexed = indexed.ok_or("applied transaction is not indexed")?;
+                // Deposits held by a rule are indexed as disputed
+                let held_by_rule = engine
+                    .policy
+                    .rules
+                    .as_ref()
+                    .and_then(|rules| rules.matching(transaction))
+                    .is_some_and(|rule| rule.action == RuleAction::Hold);
+                let dispute_state = match held_by_rule {
+                    true => DisputeState::Disputed,
+                    false => DisputeState::Undisputed,
+                };
+                if indexed.ty != transaction.ty
+                    || indexed.amount != transaction.amount
+                    || indexed.dispute_state != dispute_state
+                {
+                    return Err("applied transaction is indexed with other values");
+                }
+                if added != i64::from(self.indexed.is_none()) {
+                    return Err("index size does not match the applied transaction");
+                }
+            }
+            false if added != 0 => return Err("index size changed without a new transaction"),
+            false => {}
+        }
+
+        if transaction.ty.refers_back() {
+            self.check_transition(engine, transaction, result)?;
+        }
+
+        if let (Ok(()), Some(by_client)) = (result, &engine.by_client) {
+            let last = by_client
+                .get(&transaction.client_id)
+                .and_then(|lines| lines.last());
+            if last.is_none_or(|line| line.transaction != *transaction) {
+                return Err("applied transaction is missing from the client index");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the dispute state of the transaction a dispute, resolve, or
+    /// chargeback refers to, and the effect on the total funds
+    fn check_transition(
+        &self,
+        engine: &Engine,
+        transaction: &Transaction,
+        result: Result<(), Rejection>,
+    ) -> Result<(), &'static str> {
+        let before = self.indexed.as_ref().map(|indexed| indexed.dispute_state);
+        let after = engine
+            .transactions
+            .get(transaction.client_id, transaction.id)
+            .map(|indexed| indexed.dispute_state);
+        if result.is_err() {
+            return match before == after {
+                true => Ok(()),
+                false => Err("rejected transaction changed a dispute state"),
+            };
+        }
+
+        use DisputeState::*;
+        let expected = match transaction.ty {
+            TransactionType::Dispute => (Undisputed, Disputed),
+            TransactionType::Resolve => (Disputed, Undisputed),
+            _ => (Disputed, ChargedBack),
+        };
+        if (before, after) != (Some(expected.0), Some(expected.1)) {
+            return Err("invalid dispute state transition");
+        }
+        let total = |account: Option<&Account>| account.map(|account| account.total);
+        let unchanged_total = matches!(
+            transaction.ty,
+            TransactionType::Dispute | TransactionType::Resolve
+        );
+        if unchanged_total
+            && total(self.account.as_ref()) != total(engine.accounts.get(&transaction.client_id))
+        {
+            return Err("dispute or resolve changed the total funds");
+        }
+        Ok(())
+    }
+}
+
+/// Checks every account and the transaction index of `engine` as a whole, which
+/// takes time in proportion to their size
+pub fn check_engine(engine: &Engine, allow_negative_available: bool) -> Result<(), String> {
+    for (client_id, account) in &engine.accounts {
+        check_account(account, allow_negative_available)
+            .map_err(|err| format!("client {client_id}: {err}"))?;
+    }
+    let mut len = 0;
+    for (id, indexed) in engine.transactions.iter() {
+        len += 1;
+        if !engine.accounts.contains_key(&indexed.client_id) {
+            return Err(format!(
+                "transaction {id} of client {} without an account",
+                indexed.client_id
+            ));
+        }
+        if !indexed.amount.is_finite() {
+            return Err(format!("transaction {id} has an amount that is not finite"));
+        }
+    }
+    if len != engine.transactions.len() {
+        return Err(format!(
+            "transaction index counts {} transactions but holds {len}",
+            engine.transactions.len()
+        ));
+    }
+    Ok(())
+}
+
+fn check_account(account: &Account, allow_negative_available: bool) -> Result<(), &'static str> {
+    if ![account.available, account.held, account.total]
+        .iter()
+        .all(|amount| amount.is_finite())
+    {
+        return Err("balance is not finite");
+    }
+    account.check_invariants(allow_negative_available)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tx;
+
+    fn process(engine: &mut Engine, transaction: &Transaction) -> Result<(), &'static str> {
+        let before = Before::capture(engine, transaction);
+        let result = engine.process(transaction);
+        before.check(engine, transaction, result, false)
+    }
+
+    #[test]
+    fn it_checks_the_engine_around_transactions() {
+        let mut engine = Engine::default();
+        for transaction in [
+            tx(TransactionType::Deposit, 1, 1, 10.0),
+            tx(TransactionType::Withdrawal, 1, 2, 20.0),
+            tx(TransactionType::Dispute, 1, 1, 0.0),
+            tx(TransactionType::Dispute, 1, 1, 0.0),
+            tx(TransactionType::Resolve, 1, 1, 0.0),
+            tx(TransactionType::Dispute, 1, 1, 0.0),
+            tx(TransactionType::Chargeback, 1, 1, 0.0),
+            tx(TransactionType::Resolve, 1, 3, 0.0),
+        ] {
+            assert_eq!(process(&mut engine, &transaction), Ok(()));
+        }
+        assert_eq!(check_engine(&engine, false), Ok(()));
+
+        engine.transactions.get_mut(1, 1).unwrap().dispute_state = DisputeState::Disputed;
+        let resolve = tx(TransactionType::Resolve, 1, 1, 0.0);
+        let before = Before::capture(&engine, &resolve);
+        engine.transactions.get_mut(1, 1).unwrap().dispute_state = DisputeState::ChargedBack;
+        assert_eq!(
+            before.check(&engine, &resolve, Ok(()), false),
+            Err("processed counter did not advance by one")
+        );
+        engine.counters.processed += 1;
+        engine.counters.applied += 1;
+        assert_eq!(
+            before.check(&engine, &resolve, Ok(()), false),
+            Err("invalid dispute state transition")
+        );
+
+        engine.accounts.get_mut(&1).unwrap().held = -1.0;
+        engine.accounts.get_mut(&1).unwrap().available += 1.0;
+        assert_eq!(
+            check_engine(&engine, false),
+            Err("client 1: negative held funds".to_string())
+        );
+        engine.accounts.get_mut(&1).unwrap().held = f32::NAN;
+        assert_eq!(
+            check_engine(&engine, false),
+            Err("client 1: balance is not finite".to_string())
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Accounts written to a directory as one file per partition, either a range of
+//! client IDs or a partner, along with a manifest of the files. Loaders can thus
+//! read the partitions in parallel, and know from the manifest when all of them
+//! are there, since it is written last.
+
+use std::{fs, path::Path};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::account::{header, serialize_rows};
+use crate::compression::Compression;
+use crate::crypto::encode_hex;
+use crate::engine::Engine;
+use crate::partner::Partitions;
+use crate::transaction::ClientID;
+
+/// The file name of the manifest in the directory
+pub const MANIFEST: &str = "manifest.json";
+
+/// A file of accounts in the directory
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Partition {
+    /// The file name, relative to the directory
+    pub file: String,
+    /// The lowest client ID the partition covers, for partitions of client IDs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_client: Option<ClientID>,
+    /// The highest client ID the partition covers, for partitions of client IDs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_client: Option<ClientID>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partner: Option<String>,
+    /// The number of accounts in the file
+    pub accounts: usize,
+    /// The size of the file
+    pub bytes: u64,
+    /// Hex-encoded SHA-256 of the file
+    pub sha256: String,
+    /// The accounts as written, before any compression
+    #[serde(skip)]
+    contents: String,
+}
+
+impl Partition {
+    fn new(file: String, accounts: usize, contents: String) -> Self {
+        Self {
+            file,
+            first_client: None,
+            last_client: None,
+            partner: None,
+            accounts,
+            bytes: 0,
+            sha256: String::new(),
+            contents,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    /// `client` or `partner`
+    pub partitioned_by: &'static str,
+    /// `gzip` or `zstd` if the files are compressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<&'static str>,
+    pub partitions: Vec<Partition>,
+}
+
+/// The accounts of `engine` in ranges of `size` client IDs, starting at 0. Ranges
+/// without accounts are left out. Extended by their activity if `extended`.
+pub fn by_client_range(engine: &Engine, size: ClientID, extended: bool) -> Manifest {
+    let size = size.max(1);
+    let activity = extended.then(|| engine.activity());
+    let mut partitions: Vec<Partition> = Vec::new();
+    let mut accounts = engine.accounts_sorted().peekable();
+    while let Some((client_id, _)) = accounts.peek() {
+        let first = client_id / size * size;
+        let last = first.saturating_add(size - 1);
+        let mut contents = header(extended).to_string();
+        let mut count = 0;
+        serialize_rows(
+            std::iter::from_fn(|| accounts.next_if(|(client_id, _)| *client_id <= last))
+                .inspect(|_| count += 1),
+            activity,
+            &mut contents,
+        );
+        let mut partition = Partition::new(format!("clients-{first}-{last}.csv"), count, contents);
+        partition.first_client = Some(first);
+        partition.last_client = Some(last);
+        partitions.push(partition);
+    }
+    Manifest {
+        partitioned_by: "client",
+        compression: None,
+        partitions,
+    }
+}
+
+/// The accounts of each partner, extended by their activity if `extended`
+pub fn by_partner(partitions: &Partitions, extended: bool) -> Manifest {
+    let partitions = partitions
+        .iter()
+        .map(|(partner, engine)| {
+            let mut contents = header(extended).to_string();
+            serialize_rows(
+                engine.accounts_sorted(),
+                extended.then(|| engine.activity()),
+                &mut contents,
+            );
+            let mut partition =
+                Partition::new(format!("{partner}.csv"), engine.accounts().len(), contents);
+            partition.partner = Some(partner.to_string());
+            partition
+        })
+        .collect();
+    Manifest {
+        partitioned_by: "partner",
+        compression: None,
+        partitions,
+    }
+}
+
+impl Manifest {
+    /// Writes the partitions to `directory`, creating it if necessary, and the
+    /// manifest after them. Compressed files get the extension of the compression.
+    /// Returns the contents of the manifest.
+    pub fn write(
+        &mut self,
+        directory: &Path,
+        compression: Option<Compression>,
+    ) -> Result<String, String> {
+        fs::create_dir_all(directory)
+            .map_err(|err| format!("could not create output directory: {err}"))?;
+        self.compression = compression.map(Compression::as_str);
+        for partition in &mut self.partitions {
+            let contents = std::mem::take(&mut partition.contents);
+            let bytes = match compression {
+                Some(compression) => {
+                    partition.file = format!("{}.{}", partition.file, compression.extension());
+                    compression.compress(contents.as_bytes())
+                }
+                None => contents.into_bytes(),
+            };
+            let path = directory.join(&partition.file);
+            fs::write(&path, &bytes)
+                .map_err(|err| format!("could not write {}: {err}", path.display()))?;
+            partition.bytes = bytes.len() as u64;
+            partition.sha256 = encode_hex(&Sha256::digest(&bytes));
+        }
+
+        let mut manifest =
+            serde_json::to_string_pretty(self).expect("manifests are always serializable");
+        manifest.push('\n');
+        let path = directory.join(MANIFEST);
+        fs::write(&path, &manifest)
+            .map_err(|err| format!("could not write {}: {err}", path.display()))?;
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::testing::TempPath;
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_partitions_accounts_by_client_range() {
+        let transactions_string = "type,    client, tx, amount\n\
+                                   deposit, 12,     1,  1.0\n\
+                                   deposit, 3,      2,  2.0\n\
+                                   deposit, 9,      3,  3.0\n\
+                                   deposit, 30,     4,  4.0\n\
+                                   ";
+        let mut engine = Engine::default();
+        for transaction in TransactionReader::new(io::Cursor::new(transactions_string)).unwrap() {
+            engine.process(&transaction.unwrap()).unwrap();
+        }
+
+        let mut manifest = by_client_range(&engine, 10, false);
+        let files: Vec<_> = manifest
+            .partitions
+            .iter()
+            .map(|partition| (partition.file.as_str(), partition.contents.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (
+                    "clients-0-9.csv",
+                    "client,available,held,total,locked\n3,2,0,2,false\n9,3,0,3,false\n"
+                ),
+                (
+                    "clients-10-19.csv",
+                    "client,available,held,total,locked\n12,1,0,1,false\n"
+                ),
+                (
+                    "clients-30-39.csv",
+                    "client,available,held,total,locked\n30,4,0,4,false\n"
+                ),
+            ]
+        );
+
+        let directory = TempPath::new("partitioned");
+        let written = manifest.write(&directory, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(directory.join("clients-10-19.csv")).unwrap(),
+            "client,available,held,total,locked\n12,1,0,1,false\n"
+        );
+        assert_eq!(
+            fs::read_to_string(directory.join(MANIFEST)).unwrap(),
+            written
+        );
+        assert!(written.contains("\"first_client\": 10,\n      \"last_client\": 19,"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Separate engines for the partners an input was submitted by, so that their
+//! accounts and transaction IDs never mix.
+
+use std::collections::BTreeMap;
+
+use crate::account::serialize_engine_accounts;
+use crate::engine::Engine;
+
+#[derive(Debug, Default, Clone)]
+pub struct Partitions {
+    template: Engine,
+    engines: BTreeMap<String, Engine>,
+}
+
+impl Partitions {
+    /// Every partner's engine will start out as a copy of `template`
+    pub fn new(template: Engine) -> Self {
+        Self {
+            template,
+            engines: BTreeMap::new(),
+        }
+    }
+
+    /// The engine of `partner`, created on first use
+    pub fn engine(&mut self, partner: &str) -> &mut Engine {
+        let template = &self.template;
+        self.engines
+            .entry(partner.to_string())
+            .or_insert_with(|| template.clone())
+    }
+
+    /// All partners' engines, ordered by partner
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Engine)> {
+        self.engines
+            .iter()
+            .map(|(partner, engine)| (partner.as_str(), engine))
+    }
+}
+
+/// Like [`crate::account::serialize_accounts`], with a leading `partner` column
+pub fn serialize_partitioned_accounts(partitions: &Partitions) -> String {
+    serialize_partitions(partitions, |engine| {
+        serialize_engine_accounts(engine, false)
+    })
+}
+
+/// Like [`crate::account::serialize_extended_accounts`], with a leading `partner`
+/// column
+pub fn serialize_extended_partitioned_accounts(partitions: &Partitions) -> String {
+    serialize_partitions(partitions, |engine| serialize_engine_accounts(engine, true))
+}
+
+fn serialize_partitions(partitions: &Partitions, serialize: impl Fn(&Engine) -> String) -> String {
+    // The header of the accounts, written even without any partners
+    let mut string = format!("partner,{}", serialize(&Engine::default()));
+    for (partner, engine) in partitions.iter() {
+        let accounts = serialize(engine);
+        for row in accounts.lines().skip(1) {
+            string.push_str(partner);
+            string.push(',');
+            string.push_str(row);
+            string.push('\n');
+        }
+    }
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_keeps_partners_apart() {
+        let transactions_string = "partner, type,    client, tx, amount\n\
+                                   a,       deposit, 1,      1,  5.0\n\
+                                   b,       deposit, 1,      1,  3.0\n\
+                                   a,       dispute, 1,      1\n\
+                                   ";
+        let mut reader = TransactionReader::new(io::Cursor::new(transactions_string)).unwrap();
+        let mut partitions = Partitions::default();
+        while let Some(transaction) = reader.next() {
+            let transaction = transaction.unwrap();
+            let partner = reader.partner().unwrap().to_string();
+            partitions.engine(&partner).process(&transaction).unwrap();
+        }
+
+        assert_eq!(
+            serialize_partitioned_accounts(&partitions),
+            "partner,client,available,held,total,locked\n\
+             a,1,0,5,5,false\n\
+             b,1,3,0,3,false\n"
+        );
+
+        let invalid = "partner,type,client,tx,amount\n../x,deposit,1,1,5.0\n";
+        let mut reader = TransactionReader::new(io::Cursor::new(invalid)).unwrap();
+        assert_eq!(reader.next(), Some(Err("invalid partner")));
+    }
+}
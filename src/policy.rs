@@ -0,0 +1,564 @@
+//! Optional limits enforced on top of the basic rules of processing transactions.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+};
+
+use crate::rules::{KycLimits, Rules};
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+use crate::transaction::{ClientID, Rejection, Transaction, TransactionType};
+
+/// Everything configurable about which transactions the engine accepts
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Policy {
+    pub velocity: Option<VelocityLimit>,
+    pub blocklist: Option<Blocklist>,
+    /// If set, only transactions of these clients are accepted, e.g. for a pilot
+    /// cohort. Read with [`Blocklist::parse`].
+    pub allowlist: Option<HashSet<ClientID>>,
+    pub rules: Option<Rules>,
+    /// Debited from an account whenever a chargeback is applied to it, even if
+    /// that leaves its available funds negative
+    pub chargeback_fee: Option<f32>,
+    pub lock: LockPolicy,
+    /// Withdrawals may not take the available funds below this, unless the client
+    /// is in a tier with its own minimum balance
+    pub min_balance: Option<f32>,
+    pub daily_limits: Option<DailyLimits>,
+    pub kyc: Option<Kyc>,
+    /// How long the funds of deposits are held before they become available
+    pub clearing: Option<ClearingPeriod>,
+    #[cfg(feature = "scripting")]
+    pub script: Option<Script>,
+}
+
+impl Policy {
+    /// The limits of the level of verification of `client_id`
+    pub fn kyc_limits(&self, client_id: ClientID) -> Option<&KycLimits> {
+        let level = self.kyc.as_ref()?.level(client_id);
+        self.rules.as_ref()?.kyc_limits(level)
+    }
+
+    /// The amount of available funds a withdrawal of `client_id` must leave
+    pub fn min_balance(&self, client_id: ClientID) -> Option<f32> {
+        self.rules
+            .as_ref()
+            .and_then(|rules| rules.min_balance(client_id))
+            .or(self.min_balance)
+    }
+}
+
+/// Clients whose transactions are all rejected, e.g. because of sanctions
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Blocklist {
+    pub clients: HashSet<ClientID>,
+    /// Also lock the account of a listed client once it has a transaction
+    pub lock: bool,
+}
+
+impl Blocklist {
+    /// Reads a CSV whose first column holds client IDs. A header row is skipped.
+    /// Allowlists are read the same way.
+    pub fn parse(reader: impl io::BufRead) -> Result<HashSet<ClientID>, &'static str> {
+        let mut clients = HashSet::new();
+        for (index, row) in reader.lines().enumerate() {
+            let row = row.map_err(|_| "failed reading row")?;
+            let field = row.split(',').next().unwrap_or_default().trim();
+            if field.is_empty() {
+                continue;
+            }
+            match field.parse::<ClientID>() {
+                Ok(client_id) => {
+                    clients.insert(client_id);
+                }
+                Err(_) if index == 0 => {}
+                Err(_) => return Err("invalid client ID"),
+            }
+        }
+        Ok(clients)
+    }
+}
+
+/// Each client's level of verification, whose limits are set in the rules file.
+/// Transactions beyond them are rejected and the account is flagged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Kyc {
+    pub levels: HashMap<ClientID, String>,
+}
+
+impl Kyc {
+    /// The level of clients that are not listed
+    pub const UNVERIFIED: &'static str = "none";
+
+    /// Reads a CSV of client IDs and their levels. A header row is skipped.
+    pub fn parse(reader: impl io::BufRead) -> Result<HashMap<ClientID, String>, &'static str> {
+        let mut levels = HashMap::new();
+        for (index, row) in reader.lines().enumerate() {
+            let row = row.map_err(|_| "failed reading row")?;
+            if row.trim().is_empty() {
+                continue;
+            }
+            let (client_id, level) = row.split_once(',').ok_or("no level")?;
+            match client_id.trim().parse::<ClientID>() {
+                Ok(client_id) => {
+                    levels.insert(client_id, level.trim().to_string());
+                }
+                Err(_) if index == 0 => {}
+                Err(_) => return Err("invalid client ID"),
+            }
+        }
+        Ok(levels)
+    }
+
+    pub fn level(&self, client_id: ClientID) -> &str {
+        self.levels
+            .get(&client_id)
+            .map_or(Self::UNVERIFIED, String::as_str)
+    }
+}
+
+/// Limits the withdrawals among each client's most recent transactions.
+/// A withdrawal exceeding a limit is rejected and the account is flagged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VelocityLimit {
+    /// How many of a client's most recent transactions the limits apply to,
+    /// including the withdrawal being checked
+    pub window: usize,
+    /// Maximum number of withdrawals within the window
+    pub max_withdrawals: Option<usize>,
+    /// Maximum total amount withdrawn within the window
+    pub max_withdrawal_amount: Option<f32>,
+}
+
+impl VelocityLimit {
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        history: &History,
+    ) -> Result<(), Rejection> {
+        if transaction.ty != TransactionType::Withdrawal {
+            return Ok(());
+        }
+
+        let withdrawals = history
+            .0
+            .iter()
+            .rev()
+            .take(self.window.saturating_sub(1))
+            .flatten();
+        let (count, amount) = withdrawals.fold((1, transaction.amount), |(count, sum), amount| {
+            (count + 1, sum + amount)
+        });
+        let exceeded = self.max_withdrawals.is_some_and(|max| count > max)
+            || self.max_withdrawal_amount.is_some_and(|max| amount > max);
+        if exceeded {
+            Err(Rejection::VelocityLimit)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How long the funds of a deposit are held before it clears, like with ACH
+/// transfers. A deposit that is disputed meanwhile only becomes available once
+/// the dispute is resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClearingPeriod {
+    /// Until this many more transactions were processed
+    Transactions(u64),
+    /// For this many seconds, going by the engine's clock. Deposits processed
+    /// without a clock clear right away.
+    Seconds(u64),
+}
+
+/// Caps on the amounts each client deposits and withdraws per calendar day (UTC),
+/// enforced by engines that have a clock (see [`Engine::set_clock`](crate::engine::Engine::set_clock))
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DailyLimits {
+    pub deposit: Option<f32>,
+    pub withdrawal: Option<f32>,
+}
+
+pub(crate) const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+impl DailyLimits {
+    /// Checks `transaction`, which happens at `timestamp`, against the client's
+    /// `totals`, starting them over on a new day
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        timestamp: u64,
+        totals: &mut DailyTotals,
+    ) -> Result<(), Rejection> {
+        let day = timestamp / SECONDS_PER_DAY;
+        if totals.day != day {
+            *totals = DailyTotals {
+                day,
+                ..DailyTotals::default()
+            };
+        }
+        let exceeded = match transaction.ty {
+            TransactionType::Deposit => self.deposit.map(|limit| (limit, totals.deposited)),
+            TransactionType::Withdrawal => self.withdrawal.map(|limit| (limit, totals.withdrawn)),
+            _ => None,
+        }
+        .is_some_and(|(limit, total)| total + transaction.amount > limit);
+        if exceeded {
+            Err(Rejection::DailyLimit)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl DailyTotals {
+    /// Adds an applied transaction of the current day
+    pub(crate) fn record(&mut self, transaction: &Transaction) {
+        match transaction.ty {
+            TransactionType::Deposit => self.deposited += transaction.amount,
+            TransactionType::Withdrawal => self.withdrawn += transaction.amount,
+            _ => {}
+        }
+    }
+}
+
+/// What a client deposited and withdrew on a day
+#[derive(Debug, Default, Clone)]
+pub(crate) struct DailyTotals {
+    /// Days since the Unix epoch
+    day: u64,
+    deposited: f32,
+    withdrawn: f32,
+}
+
+/// When an account is locked because of its chargebacks and disputes
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockPolicy {
+    /// Lock once this many chargebacks were applied to the account, by default
+    /// on the first one
+    pub chargebacks: Option<u32>,
+    /// Lock once the total amount ever disputed exceeds this share (between 0
+    /// and 1) of the total amount deposited
+    pub disputed_share: Option<f32>,
+}
+
+impl Default for LockPolicy {
+    fn default() -> Self {
+        Self {
+            chargebacks: Some(1),
+            disputed_share: None,
+        }
+    }
+}
+
+/// Why an account was locked, by the [`LockPolicy`] or the [`Blocklist`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockTrigger {
+    Chargebacks,
+    DisputedShare,
+    Blocked,
+}
+
+impl LockTrigger {
+    /// A stable identifier for the audit log
+    pub fn code(&self) -> &'static str {
+        match self {
+            LockTrigger::Chargebacks => "chargebacks",
+            LockTrigger::DisputedShare => "disputed_share",
+            LockTrigger::Blocked => "blocked",
+        }
+    }
+}
+
+impl LockPolicy {
+    /// Whether locking depends on more than the current transaction, so that each
+    /// client needs a [`LockTally`]
+    pub(crate) fn needs_tally(&self) -> bool {
+        self.chargebacks.is_some_and(|max| max > 1) || self.disputed_share.is_some()
+    }
+
+    /// Tallies an applied transaction, returning what triggered a lock if the
+    /// account needs to be locked now. `amount` is the amount of the transaction
+    /// or of the transaction it refers to.
+    pub(crate) fn record(
+        &self,
+        tally: &mut LockTally,
+        ty: &TransactionType,
+        amount: f32,
+    ) -> Option<LockTrigger> {
+        match ty {
+            TransactionType::Deposit => tally.deposited += amount,
+            TransactionType::Dispute => tally.disputed += amount,
+            TransactionType::Chargeback => tally.chargebacks += 1,
+            _ => return None,
+        }
+        if self
+            .chargebacks
+            .is_some_and(|max| *ty == TransactionType::Chargeback && tally.chargebacks >= max)
+        {
+            Some(LockTrigger::Chargebacks)
+        } else if self
+            .disputed_share
+            .is_some_and(|max| tally.disputed > max * tally.deposited)
+        {
+            Some(LockTrigger::DisputedShare)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a client's account went through, as far as the lock policy needs it
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LockTally {
+    chargebacks: u32,
+    disputed: f32,
+    deposited: f32,
+}
+
+/// A client's most recent transactions, holding the amount of each
+/// applied withdrawal and `None` for everything else
+#[derive(Debug, Default, Clone)]
+pub(crate) struct History(VecDeque<Option<f32>>);
+
+impl History {
+    pub(crate) fn record(
+        &mut self,
+        window: usize,
+        transaction: &Transaction,
+        result: Result<(), Rejection>,
+    ) {
+        let withdrawn = (transaction.ty == TransactionType::Withdrawal && result.is_ok())
+            .then_some(transaction.amount);
+        self.0.push_back(withdrawn);
+        while self.0.len() > window {
+            self.0.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::testing::tx;
+
+    #[test]
+    fn it_limits_withdrawals_within_the_window() {
+        let mut engine = Engine::with_policy(Policy {
+            velocity: Some(VelocityLimit {
+                window: 3,
+                max_withdrawals: Some(2),
+                max_withdrawal_amount: Some(10.0),
+            }),
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        assert_eq!(engine.process(&tx(Deposit, 1, 1, 100.0)), Ok(()));
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 2, 1.0)), Ok(()));
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 3, 1.0)), Ok(()));
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 1, 4, 1.0)),
+            Err(Rejection::VelocityLimit)
+        );
+        assert!(engine.accounts()[&1].flagged);
+
+        // Transaction 2 has left the window by now, but the amount is too large
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 1, 5, 9.5)),
+            Err(Rejection::VelocityLimit)
+        );
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 6, 5.0)), Ok(()));
+    }
+
+    #[test]
+    fn it_debits_chargeback_fees() {
+        let mut engine = Engine::with_policy(Policy {
+            chargeback_fee: Some(15.0),
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        engine.process(&tx(Deposit, 1, 1, 10.0)).unwrap();
+        engine.process(&tx(Deposit, 1, 2, 20.0)).unwrap();
+        engine.process(&tx(Dispute, 1, 1, 0.0)).unwrap();
+        engine.process(&tx(Resolve, 1, 1, 0.0)).unwrap();
+        assert_eq!(engine.accounts()[&1].total, 30.0);
+        engine.process(&tx(Dispute, 1, 2, 0.0)).unwrap();
+        engine.process(&tx(Chargeback, 1, 2, 0.0)).unwrap();
+        assert_eq!(engine.accounts()[&1].available, -5.0);
+        assert_eq!(engine.accounts()[&1].total, -5.0);
+    }
+
+    #[test]
+    fn it_locks_accounts_past_the_thresholds() {
+        let mut engine = Engine::with_policy(Policy {
+            lock: LockPolicy {
+                chargebacks: Some(2),
+                disputed_share: Some(0.7),
+            },
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        for id in 1..=3 {
+            engine.process(&tx(Deposit, 1, id, 10.0)).unwrap();
+        }
+        engine.process(&tx(Dispute, 1, 1, 0.0)).unwrap();
+        engine.process(&tx(Chargeback, 1, 1, 0.0)).unwrap();
+        assert!(!engine.accounts()[&1].locked);
+        engine.process(&tx(Dispute, 1, 2, 0.0)).unwrap();
+        engine.process(&tx(Chargeback, 1, 2, 0.0)).unwrap();
+        assert!(engine.accounts()[&1].locked);
+        assert_eq!(engine.last_lock, Some(LockTrigger::Chargebacks));
+
+        engine.process(&tx(Deposit, 2, 4, 10.0)).unwrap();
+        engine.process(&tx(Deposit, 2, 5, 2.0)).unwrap();
+        engine.process(&tx(Dispute, 2, 4, 0.0)).unwrap();
+        assert!(engine.accounts()[&2].locked);
+        assert_eq!(engine.last_lock, Some(LockTrigger::DisputedShare));
+    }
+
+    #[test]
+    fn it_keeps_the_minimum_balance() {
+        let rules =
+            Rules::parse("[tiers]\npremium = [2]\n\n[min_balance]\npremium = 0.0\n").unwrap();
+        let mut engine = Engine::with_policy(Policy {
+            min_balance: Some(10.0),
+            rules: Some(rules),
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        engine.process(&tx(Deposit, 1, 1, 15.0)).unwrap();
+        engine.process(&tx(Deposit, 1, 2, 15.0)).unwrap();
+        engine.process(&tx(Deposit, 2, 3, 15.0)).unwrap();
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 4, 15.0)), Ok(()));
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 1, 5, 10.0)),
+            Err(Rejection::MinimumBalance)
+        );
+        // Withdrawals the funds do not cover are rejected as such
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 1, 6, 20.0)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 2, 7, 15.0)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert!(Rules::parse("[min_balance]\ngold = 1.0\n").is_err());
+    }
+
+    #[test]
+    fn it_enforces_daily_limits() {
+        let mut engine = Engine::with_policy(Policy {
+            daily_limits: Some(DailyLimits {
+                deposit: Some(100.0),
+                withdrawal: None,
+            }),
+            ..Policy::default()
+        });
+        let deposit = |id| tx(TransactionType::Deposit, 1, id, 60.0);
+
+        engine.set_clock(SECONDS_PER_DAY + 10);
+        assert_eq!(engine.process(&deposit(1)), Ok(()));
+        assert_eq!(engine.process(&deposit(2)), Err(Rejection::DailyLimit));
+        engine.set_clock(2 * SECONDS_PER_DAY);
+        assert_eq!(engine.process(&deposit(3)), Ok(()));
+    }
+
+    #[test]
+    fn it_enforces_kyc_limits() {
+        let levels = Kyc::parse(io::Cursor::new("client,level\n2,full\n")).unwrap();
+        let rules =
+            Rules::parse("[kyc.none]\nmax_balance = 100.0\nmax_transaction = 50.0\n\n[kyc.full]\n")
+                .unwrap();
+        let mut engine = Engine::with_policy(Policy {
+            kyc: Some(Kyc { levels }),
+            rules: Some(rules),
+            ..Policy::default()
+        });
+        let deposit = |client_id, id, amount| tx(TransactionType::Deposit, client_id, id, amount);
+
+        assert_eq!(
+            engine.process(&deposit(1, 1, 80.0)),
+            Err(Rejection::KycLimit)
+        );
+        assert!(engine.accounts()[&1].flagged);
+        assert_eq!(engine.process(&deposit(1, 2, 50.0)), Ok(()));
+        assert_eq!(engine.process(&deposit(1, 3, 50.0)), Ok(()));
+        assert_eq!(
+            engine.process(&deposit(1, 4, 1.0)),
+            Err(Rejection::KycLimit)
+        );
+        assert_eq!(engine.process(&deposit(2, 5, 500.0)), Ok(()));
+    }
+
+    #[test]
+    fn it_holds_deposits_until_they_clear() {
+        let mut engine = Engine::with_policy(Policy {
+            clearing: Some(ClearingPeriod::Transactions(2)),
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        engine.process(&tx(Deposit, 1, 1, 10.0)).unwrap();
+        engine.process(&tx(Deposit, 1, 2, 5.0)).unwrap();
+        assert_eq!(engine.accounts()[&1].held, 15.0);
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 1, 3, 1.0)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(engine.accounts()[&1].available, 10.0);
+
+        // Disputing a deposit that has not cleared leaves its funds held, even
+        // once it clears, until the dispute is resolved
+        engine.process(&tx(Dispute, 1, 2, 0.0)).unwrap();
+        assert_eq!(engine.accounts()[&1].held, 5.0);
+        engine.process(&tx(Deposit, 1, 4, 1.0)).unwrap();
+        assert_eq!(engine.accounts()[&1].held, 6.0);
+        engine.process(&tx(Resolve, 1, 2, 0.0)).unwrap();
+        assert_eq!(engine.accounts()[&1].available, 15.0);
+        assert_eq!(engine.accounts()[&1].held, 1.0);
+    }
+
+    #[test]
+    fn it_rejects_blocklisted_clients() {
+        let clients = Blocklist::parse(io::Cursor::new("client\n2\n\n3, sanctions\n")).unwrap();
+        assert_eq!(clients, HashSet::from([2, 3]));
+        assert!(Blocklist::parse(io::Cursor::new("client\nx\n")).is_err());
+
+        let mut engine = Engine::with_policy(Policy {
+            blocklist: Some(Blocklist {
+                clients,
+                lock: true,
+            }),
+            ..Policy::default()
+        });
+        let deposit = |client_id, id| tx(TransactionType::Deposit, client_id, id, 1.0);
+        assert_eq!(engine.process(&deposit(1, 1)), Ok(()));
+        assert_eq!(engine.process(&deposit(2, 2)), Err(Rejection::Blocked));
+        assert!(engine.accounts()[&2].locked);
+        assert_eq!(engine.accounts()[&2].total, 0.0);
+
+        engine.policy.blocklist.as_mut().unwrap().lock = false;
+        assert_eq!(engine.process(&deposit(3, 3)), Err(Rejection::Blocked));
+        assert!(!engine.accounts().contains_key(&3));
+    }
+
+    #[test]
+    fn it_accepts_only_allowlisted_clients() {
+        let mut engine = Engine::with_policy(Policy {
+            allowlist: Some(HashSet::from([1])),
+            ..Policy::default()
+        });
+        let deposit = |client_id, id| tx(TransactionType::Deposit, client_id, id, 1.0);
+        assert_eq!(engine.process(&deposit(1, 1)), Ok(()));
+        assert_eq!(engine.process(&deposit(2, 2)), Err(Rejection::NotAllowed));
+        assert!(!engine.accounts().contains_key(&2));
+    }
+}
@@ -0,0 +1,123 @@
+//! Python bindings, built with `--features python` (e.g. through maturin).
+
+use std::mem;
+
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict};
+
+use crate::account::serialize_accounts;
+use crate::engine::Engine;
+use crate::session::{self, Session};
+use crate::transaction::{ClientID, ParseOptions, Transaction, TransactionID, TransactionType};
+
+/// Processes a complete CSV input and returns the resulting accounts as CSV.
+/// Rejected transactions are left out, like on the command line.
+#[pyfunction]
+fn process_csv(input: &str) -> PyResult<String> {
+    session::process_csv(input).map_err(PyValueError::new_err)
+}
+
+#[pyclass(name = "Transaction", frozen)]
+pub struct PyTransaction(Transaction);
+
+#[pymethods]
+impl PyTransaction {
+    #[new]
+    #[pyo3(signature = (ty, client, tx, amount = 0.0))]
+    fn new(ty: &str, client: ClientID, tx: TransactionID, amount: f32) -> PyResult<Self> {
+        let ty = TransactionType::try_from(ty)
+            .map_err(|()| PyValueError::new_err("invalid transaction type"))?;
+        Ok(Self(Transaction {
+            ty,
+            client_id: client,
+            id: tx,
+            amount,
+        }))
+    }
+
+    #[getter(r#type)]
+    fn ty(&self) -> &str {
+        self.0.ty.as_str()
+    }
+
+    #[getter]
+    fn client(&self) -> ClientID {
+        self.0.client_id
+    }
+
+    #[getter]
+    fn tx(&self) -> TransactionID {
+        self.0.id
+    }
+
+    #[getter]
+    fn amount(&self) -> f32 {
+        self.0.amount
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Transaction({:?}, {}, {}, {})",
+            self.0.ty.as_str(),
+            self.0.client_id,
+            self.0.id,
+            self.0.amount
+        )
+    }
+}
+
+#[pyclass(name = "Engine")]
+#[derive(Default)]
+pub struct PyEngine(Engine);
+
+#[pymethods]
+impl PyEngine {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a transaction, returning why it was rejected if it was not applied
+    fn process(&mut self, transaction: &PyTransaction) -> Option<&'static str> {
+        self.0
+            .process(&transaction.0)
+            .err()
+            .map(|rejection| rejection.code())
+    }
+
+    /// Processes all transactions of a CSV input. Rejected transactions are
+    /// skipped, while a malformed row raises a `ValueError`.
+    fn process_csv(&mut self, input: &str) -> PyResult<()> {
+        let mut session = Session::new(mem::take(&mut self.0), ParseOptions::default());
+        let result = session.push(input).and_then(|()| session.finish());
+        self.0 = session.into_engine();
+        result.map_err(PyValueError::new_err)
+    }
+
+    /// The accounts as a dict from client ID to a dict with the keys
+    /// `available`, `held`, `total`, and `locked`
+    fn accounts<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let accounts = PyDict::new(py);
+        for (client_id, account) in self.0.accounts() {
+            let balances = PyDict::new(py);
+            balances.set_item("available", account.available)?;
+            balances.set_item("held", account.held)?;
+            balances.set_item("total", account.total)?;
+            balances.set_item("locked", account.locked)?;
+            accounts.set_item(client_id, balances)?;
+        }
+        Ok(accounts)
+    }
+
+    /// The accounts as CSV
+    fn to_csv(&self) -> String {
+        serialize_accounts(self.0.accounts())
+    }
+}
+
+#[pymodule]
+fn transactions(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyEngine>()?;
+    module.add_class::<PyTransaction>()?;
+    module.add_function(wrap_pyfunction!(process_csv, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,834 @@
+//! Reports about a run, meant for operators rather than for further processing.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::account::Account;
+use crate::crypto;
+use crate::engine::Engine;
+use crate::policy::{LockTrigger, SECONDS_PER_DAY};
+use crate::transaction::{
+    ClientID, DisputeState, Rejection, Transaction, TransactionID, TransactionType,
+};
+
+/// Tallies of everything that happened while processing an input
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    /// Rows holding a transaction
+    pub rows_parsed: u64,
+    /// Blank rows, comments, and other rows without a transaction
+    pub rows_skipped: u64,
+    /// Rows that could not be parsed and were skipped
+    pub rows_malformed: u64,
+    pub applied: BTreeMap<TransactionType, u64>,
+    pub rejected: BTreeMap<Rejection, u64>,
+    pub open_disputes: usize,
+    pub locked_accounts: usize,
+}
+
+impl Summary {
+    pub fn record(&mut self, transaction: &Transaction, result: Result<(), Rejection>) {
+        match result {
+            Ok(()) => *self.applied.entry(transaction.ty).or_default() += 1,
+            Err(rejection) => *self.rejected.entry(rejection).or_default() += 1,
+        }
+    }
+
+    /// Adds the tallies of `other`, which covered a separate part of the input.
+    /// The counts describing the final state need to be filled in again afterwards.
+    pub fn merge(&mut self, other: Summary) {
+        self.rows_parsed += other.rows_parsed;
+        self.rows_skipped += other.rows_skipped;
+        self.rows_malformed += other.rows_malformed;
+        for (ty, count) in other.applied {
+            *self.applied.entry(ty).or_default() += count;
+        }
+        for (rejection, count) in other.rejected {
+            *self.rejected.entry(rejection).or_default() += count;
+        }
+    }
+
+    /// Number of rows that were malformed or rejected
+    pub fn errors(&self) -> u64 {
+        self.rows_malformed + self.rejected.values().sum::<u64>()
+    }
+
+    /// Fills in the counts that describe the final state rather than the run
+    pub fn finish(&mut self, engine: &Engine) {
+        self.finish_all([engine]);
+    }
+
+    /// Like [`Summary::finish`], for an input processed by several engines
+    pub fn finish_all<'a>(&mut self, engines: impl IntoIterator<Item = &'a Engine>) {
+        self.open_disputes = 0;
+        self.locked_accounts = 0;
+        for engine in engines {
+            self.open_disputes += engine
+                .transactions
+                .iter()
+                .filter(|(_, transaction)| transaction.dispute_state == DisputeState::Disputed)
+                .count();
+            self.locked_accounts += engine
+                .accounts
+                .values()
+                .filter(|account| account.locked)
+                .count();
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn tally<K>(counts: &BTreeMap<K, u64>, name: impl Fn(&K) -> &str) -> String {
+            if counts.is_empty() {
+                return "none".to_string();
+            }
+            counts
+                .iter()
+                .map(|(key, count)| format!("{} {count}", name(key)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+
+        writeln!(f, "rows parsed: {}", self.rows_parsed)?;
+        writeln!(f, "rows skipped: {}", self.rows_skipped)?;
+        writeln!(f, "rows malformed: {}", self.rows_malformed)?;
+        writeln!(f, "applied: {}", tally(&self.applied, |ty| ty.as_str()))?;
+        writeln!(
+            f,
+            "rejected: {}",
+            tally(&self.rejected, |rejection| rejection.code())
+        )?;
+        writeln!(f, "open disputes: {}", self.open_disputes)?;
+        writeln!(f, "locked accounts: {}", self.locked_accounts)
+    }
+}
+
+/// Aggregate figures about the money moved by a run, for the daily ops email
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Statistics {
+    pub deposited: f32,
+    pub withdrawn: f32,
+    /// Amount of deposits and withdrawals that were charged back
+    pub charged_back: f32,
+    /// Unlocked accounts with funds
+    pub active_accounts: usize,
+    pub locked_accounts: usize,
+    /// Unlocked accounts without any funds
+    pub empty_accounts: usize,
+    /// ID and amount of the largest deposit or withdrawal
+    pub largest_transaction: Option<(TransactionID, f32)>,
+    /// Applied transactions per type
+    pub counts: BTreeMap<TransactionType, u64>,
+}
+
+impl Statistics {
+    /// Gathers the figures from the final state of `engines`, which processed the
+    /// input `summary` was recorded for
+    pub fn of<'a>(summary: &Summary, engines: impl IntoIterator<Item = &'a Engine>) -> Self {
+        let mut statistics = Self {
+            counts: summary.applied.clone(),
+            ..Self::default()
+        };
+        // Summed with far more precision than the amounts have, so that the order
+        // of the hash maps does not show in the totals
+        let (mut deposited, mut withdrawn, mut charged_back) = (0.0f64, 0.0f64, 0.0f64);
+        for engine in engines {
+            for (id, transaction) in engine.transactions.iter() {
+                let amount = f64::from(transaction.amount);
+                match transaction.ty {
+                    TransactionType::Deposit => deposited += amount,
+                    TransactionType::Withdrawal => withdrawn += amount,
+                    _ => {}
+                }
+                if transaction.dispute_state == DisputeState::ChargedBack {
+                    charged_back += amount;
+                }
+                // Ties go to the earlier ID so the report does not depend on iteration order
+                let largest = statistics
+                    .largest_transaction
+                    .is_none_or(|(largest_id, largest)| {
+                        transaction.amount > largest
+                            || (transaction.amount == largest && id < largest_id)
+                    });
+                if largest {
+                    statistics.largest_transaction = Some((id, transaction.amount));
+                }
+            }
+            for account in engine.accounts.values() {
+                if account.locked {
+                    statistics.locked_accounts += 1;
+                } else if account.total == 0.0 {
+                    statistics.empty_accounts += 1;
+                } else {
+                    statistics.active_accounts += 1;
+                }
+            }
+        }
+        statistics.deposited = deposited as f32;
+        statistics.withdrawn = withdrawn as f32;
+        statistics.charged_back = charged_back as f32;
+        statistics
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "deposited: {}", self.deposited)?;
+        writeln!(f, "withdrawn: {}", self.withdrawn)?;
+        writeln!(f, "charged back: {}", self.charged_back)?;
+        writeln!(f, "active accounts: {}", self.active_accounts)?;
+        writeln!(f, "locked accounts: {}", self.locked_accounts)?;
+        writeln!(f, "empty accounts: {}", self.empty_accounts)?;
+        match self.largest_transaction {
+            Some((id, amount)) => writeln!(f, "largest transaction: {amount} (tx {id})")?,
+            None => writeln!(f, "largest transaction: none")?,
+        }
+        writeln!(f, "transactions:")?;
+        for (ty, count) in &self.counts {
+            writeln!(f, "  {}: {count}", ty.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// A CSV file listing every account locked during a run, with what caused the lock
+/// and the balances right after it, so support does not have to re-run the input
+pub struct LockReport(CsvReport);
+
+impl LockReport {
+    /// Creates the report at `path`, replacing any existing file
+    pub fn create(path: &Path) -> Result<Self, String> {
+        CsvReport::create(
+            path,
+            "lock report",
+            "client,tx,type,reason,charged_back,available,held,total",
+        )
+        .map(Self)
+    }
+
+    /// Records that `transaction` locked `account` because of `trigger`.
+    /// `charged_back` is the amount of the transaction a chargeback refers to.
+    pub fn record(
+        &mut self,
+        transaction: &Transaction,
+        trigger: LockTrigger,
+        charged_back: Option<f32>,
+        account: &Account,
+    ) -> Result<(), String> {
+        let charged_back = charged_back.map(|amount| amount.to_string());
+        self.0.write(&format!(
+            "{},{},{},{},{},{},{},{}",
+            transaction.client_id,
+            transaction.id,
+            transaction.ty.as_str(),
+            trigger.code(),
+            charged_back.as_deref().unwrap_or(""),
+            account.available,
+            account.held,
+            account.total
+        ))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.0.flush()
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.0.finish()
+    }
+}
+
+/// A CSV file listing every withdrawal rejected for insufficient funds, which
+/// partners otherwise only notice as a payout that never arrived
+pub struct FailedWithdrawalReport(CsvReport);
+
+impl FailedWithdrawalReport {
+    /// Creates the report at `path`, replacing any existing file
+    pub fn create(path: &Path) -> Result<Self, String> {
+        CsvReport::create(
+            path,
+            "failed withdrawals report",
+            "client,tx,amount,available",
+        )
+        .map(Self)
+    }
+
+    /// Records that `withdrawal` was rejected while the client had `available` funds
+    pub fn record(&mut self, withdrawal: &Transaction, available: f32) -> Result<(), String> {
+        self.0.write(&format!(
+            "{},{},{},{available}",
+            withdrawal.client_id, withdrawal.id, withdrawal.amount
+        ))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.0.flush()
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.0.finish()
+    }
+}
+
+/// Closing snapshots of every account at the end of each day (UTC) of an input
+/// with a timestamp column, written to `<YYYY-MM-DD>.csv` in a directory. Each
+/// day opens with the totals the previous day closed with.
+pub struct DailyReports {
+    directory: PathBuf,
+    /// The day being processed, in days since the Unix epoch
+    day: Option<u64>,
+    /// The total funds of each client when the current day opened
+    opening: HashMap<ClientID, f32>,
+}
+
+impl DailyReports {
+    /// Creates `directory` if necessary
+    pub fn create(directory: &Path) -> Result<Self, String> {
+        fs::create_dir_all(directory)
+            .map_err(|err| format!("could not create daily reports directory: {err}"))?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            day: None,
+            opening: HashMap::new(),
+        })
+    }
+
+    /// Must be called before a transaction happening at `timestamp` is processed,
+    /// to close the current day once it is over. Transactions that are earlier than
+    /// the current day count towards it.
+    pub fn advance(&mut self, engine: &Engine, timestamp: u64) -> Result<(), String> {
+        let day = timestamp / SECONDS_PER_DAY;
+        match self.day {
+            None => self.opening = totals(engine),
+            Some(current) if day > current => self.close(engine, current)?,
+            Some(_) => return Ok(()),
+        }
+        self.day = Some(day);
+        Ok(())
+    }
+
+    /// Closes the last day
+    pub fn finish(mut self, engine: &Engine) -> Result<(), String> {
+        match self.day {
+            Some(day) => self.close(engine, day),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self, engine: &Engine, day: u64) -> Result<(), String> {
+        let mut clients: Vec<_> = engine.accounts.iter().collect();
+        clients.sort_unstable_by_key(|(client_id, _)| **client_id);
+
+        let mut report = String::from("client,opening,movement,available,held,total,locked\n");
+        for (client_id, account) in clients {
+            let opening = self.opening.get(client_id).copied().unwrap_or_default();
+            report.push_str(&format!(
+                "{client_id},{opening},{},{},{},{},{}\n",
+                account.total - opening,
+                account.available,
+                account.held,
+                account.total,
+                account.locked
+            ));
+        }
+        let path = self.directory.join(format!("{}.csv", date(day)));
+        fs::write(&path, report)
+            .map_err(|err| format!("could not write {}: {err}", path.display()))?;
+        self.opening = totals(engine);
+        Ok(())
+    }
+}
+
+/// How long the periods of an [`Aggregation`] are
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AggregationPeriod {
+    Hour,
+    #[default]
+    Day,
+}
+
+impl FromStr for AggregationPeriod {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Ok(match string {
+            "hour" => AggregationPeriod::Hour,
+            "day" => AggregationPeriod::Day,
+            _ => return Err("expected hour or day"),
+        })
+    }
+}
+
+impl AggregationPeriod {
+    fn seconds(self) -> u64 {
+        match self {
+            AggregationPeriod::Hour => SECONDS_PER_HOUR,
+            AggregationPeriod::Day => SECONDS_PER_DAY,
+        }
+    }
+
+    /// Formats the period starting at `start` seconds since the Unix epoch as
+    /// `YYYY-MM-DD` or `YYYY-MM-DDTHH:00`
+    fn format(self, start: u64) -> String {
+        let day = date(start / SECONDS_PER_DAY);
+        match self {
+            AggregationPeriod::Hour => {
+                format!("{day}T{:02}:00", start % SECONDS_PER_DAY / SECONDS_PER_HOUR)
+            }
+            AggregationPeriod::Day => day,
+        }
+    }
+}
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+
+/// Count and volume of the applied transactions of each type per hour or day
+/// (UTC) of an input with a timestamp column, overall and per client, written to
+/// a CSV file with the columns `period`, `client`, `type`, `count`, and `volume`
+/// at the end. Overall rows have an empty `client` and come first in a period.
+pub struct Aggregation {
+    path: PathBuf,
+    period: AggregationPeriod,
+    /// By the start of the period, the client, and the type
+    buckets: BTreeMap<(u64, Option<ClientID>, TransactionType), (u64, f64)>,
+}
+
+impl Aggregation {
+    pub fn new(path: &Path, period: AggregationPeriod) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            period,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /// Counts an applied transaction that moved `amount` at `timestamp`. For
+    /// disputes, resolves, and chargebacks, that is the amount of the transaction
+    /// they refer to.
+    pub fn record(&mut self, transaction: &Transaction, amount: f32, timestamp: u64) {
+        let start = timestamp - timestamp % self.period.seconds();
+        for client in [None, Some(transaction.client_id)] {
+            let (count, volume) = self
+                .buckets
+                .entry((start, client, transaction.ty))
+                .or_default();
+            *count += 1;
+            *volume += f64::from(amount);
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        let mut report = String::from("period,client,type,count,volume\n");
+        for ((start, client, ty), (count, volume)) in self.buckets {
+            let client = client.map(|client| client.to_string()).unwrap_or_default();
+            // Printed at the precision of the amounts
+            report.push_str(&format!(
+                "{},{client},{},{count},{}\n",
+                self.period.format(start),
+                ty.as_str(),
+                volume as f32
+            ));
+        }
+        fs::write(&self.path, report).map_err(|err| format!("could not write aggregation: {err}"))
+    }
+}
+
+fn totals(engine: &Engine) -> HashMap<ClientID, f32> {
+    engine
+        .accounts
+        .iter()
+        .map(|(client_id, account)| (*client_id, account.total))
+        .collect()
+}
+
+/// Formats a number of days since the Unix epoch as `YYYY-MM-DD`
+pub(crate) fn date(day: u64) -> String {
+    // From Howard Hinnant's `civil_from_days`, shifting the year to start in March
+    // so that leap days come last
+    let days = day + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day_of_month:02}")
+}
+
+/// Parses a date written as `YYYY-MM-DD` into days since the Unix epoch, the
+/// inverse of [`date`]
+#[cfg_attr(not(feature = "camt"), allow(dead_code))]
+pub(crate) fn day(date: &str) -> Option<u64> {
+    let bytes = date.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| date.get(range)?.parse::<u64>().ok();
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Howard Hinnant's `days_from_civil`, with years starting in March like in `date`
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).checked_sub(719_468)
+}
+
+/// A CSV file written row by row while processing
+struct CsvReport {
+    writer: BufWriter<fs::File>,
+    /// What the file is called in errors
+    name: &'static str,
+}
+
+impl CsvReport {
+    fn create(path: &Path, name: &'static str, header: &str) -> Result<Self, String> {
+        let file =
+            fs::File::create(path).map_err(|err| format!("could not create {name}: {err}"))?;
+        let mut report = Self {
+            writer: BufWriter::new(file),
+            name,
+        };
+        report.write(header)?;
+        Ok(report)
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.writer, "{line}")
+            .map_err(|err| format!("could not write {}: {err}", self.name))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .map_err(|err| format!("could not write {}: {err}", self.name))
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        self.flush()
+    }
+}
+
+/// Timings and sizes of a run, for capacity planning
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    started: Instant,
+    /// Time spent reading and parsing rows
+    pub parse: Duration,
+    /// Time spent processing transactions
+    pub process: Duration,
+    /// Time spent serializing and writing the output
+    pub serialize: Duration,
+    pub rows: u64,
+    pub peak_accounts: usize,
+    pub peak_transactions: usize,
+    /// Peak of [`Engine::memory_usage`]
+    pub peak_memory: usize,
+}
+
+impl Metrics {
+    /// Starts measuring the wall time of the run
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            parse: Duration::ZERO,
+            process: Duration::ZERO,
+            serialize: Duration::ZERO,
+            rows: 0,
+            peak_accounts: 0,
+            peak_transactions: 0,
+            peak_memory: 0,
+        }
+    }
+
+    /// Runs `f`, adding the time it took to `phase`
+    pub fn time<T>(phase: &mut Duration, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        *phase += start.elapsed();
+        result
+    }
+
+    /// Updates the peak sizes from the current state of `engine`
+    pub fn observe(&mut self, engine: &Engine) {
+        self.observe_all([engine]);
+    }
+
+    /// Like [`Metrics::observe`], for an input processed by several engines
+    pub fn observe_all<'a>(&mut self, engines: impl IntoIterator<Item = &'a Engine>) {
+        let (mut accounts, mut transactions, mut memory) = (0, 0, 0);
+        for engine in engines {
+            accounts += engine.accounts.len();
+            transactions += engine.transactions.len();
+            memory += engine.memory_usage();
+        }
+        self.peak_accounts = self.peak_accounts.max(accounts);
+        self.peak_transactions = self.peak_transactions.max(transactions);
+        self.peak_memory = self.peak_memory.max(memory);
+    }
+
+    pub fn wall_time(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn rows_per_second(&self) -> f64 {
+        let seconds = (self.parse + self.process).as_secs_f64();
+        if seconds == 0.0 {
+            0.0
+        } else {
+            self.rows as f64 / seconds
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "wall_seconds": self.wall_time().as_secs_f64(),
+            "parse_seconds": self.parse.as_secs_f64(),
+            "process_seconds": self.process.as_secs_f64(),
+            "serialize_seconds": self.serialize.as_secs_f64(),
+            "rows": self.rows,
+            "rows_per_second": self.rows_per_second(),
+            "peak_accounts": self.peak_accounts,
+            "peak_transactions": self.peak_transactions,
+            "peak_memory_bytes": self.peak_memory,
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "wall time: {:.3}s", self.wall_time().as_secs_f64())?;
+        writeln!(
+            f,
+            "parse: {:.3}s, process: {:.3}s, serialize: {:.3}s",
+            self.parse.as_secs_f64(),
+            self.process.as_secs_f64(),
+            self.serialize.as_secs_f64()
+        )?;
+        writeln!(f, "rows per second: {:.0}", self.rows_per_second())?;
+        writeln!(f, "peak accounts: {}", self.peak_accounts)?;
+        writeln!(f, "peak transaction index size: {}", self.peak_transactions)?;
+        writeln!(f, "peak memory: ~{} bytes", self.peak_memory)
+    }
+}
+
+/// The outcome of a run in a form that orchestration can branch on
+#[derive(Debug, Serialize)]
+pub struct RunResult {
+    pub succeeded: bool,
+    pub exit_code: u8,
+    pub error: Option<String>,
+    pub summary: Option<Summary>,
+    pub outputs: Vec<OutputChecksum>,
+}
+
+impl RunResult {
+    pub fn to_json(&self) -> String {
+        let mut json =
+            serde_json::to_string_pretty(self).expect("run results are always serializable");
+        json.push('\n');
+        json
+    }
+}
+
+/// Identifies the exact contents written to an output
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutputChecksum {
+    /// The file written to, or `-` for stdout
+    pub path: String,
+    pub bytes: u64,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+impl OutputChecksum {
+    pub fn of(path: &str, contents: &[u8]) -> Self {
+        Self {
+            path: path.to_string(),
+            bytes: contents.len() as u64,
+            sha256: crypto::encode_hex(&Sha256::digest(contents)),
+        }
+    }
+}
+
+/// A writer keeping track of the size and checksum of what passes through it
+pub struct Checksummed<W> {
+    writer: W,
+    sha256: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> Checksummed<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            sha256: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Flushes the writer and returns the checksum of everything written, as an
+    /// output at `path`
+    pub fn finish(mut self, path: &str) -> io::Result<OutputChecksum> {
+        self.writer.flush()?;
+        Ok(OutputChecksum {
+            path: path.to_string(),
+            bytes: self.bytes,
+            sha256: crypto::encode_hex(&self.sha256.finalize()),
+        })
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.sha256.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::testing::{tx, TempPath};
+    use crate::transaction::TransactionReader;
+
+    #[test]
+    fn it_summarizes_a_run() {
+        let transactions_string = "type,       client, tx, amount\n\
+                                   deposit,    1,      1,  5.0\n\
+                                   \n\
+                                   withdrawal, 1,      2,  9.0\n\
+                                   dispute,    1,      1\n\
+                                   ";
+        let mut reader = TransactionReader::new(io::Cursor::new(transactions_string)).unwrap();
+        let mut engine = Engine::default();
+        let mut summary = Summary::default();
+        for transaction in &mut reader {
+            let transaction = transaction.unwrap();
+            summary.record(&transaction, engine.process(&transaction));
+        }
+        summary.rows_parsed = reader.rows_read - reader.rows_skipped;
+        summary.rows_skipped = reader.rows_skipped;
+        summary.finish(&engine);
+
+        assert_eq!(
+            summary.to_string(),
+            "rows parsed: 3\n\
+             rows skipped: 1\n\
+             rows malformed: 0\n\
+             applied: deposit 1, dispute 1\n\
+             rejected: insufficient_funds 1\n\
+             open disputes: 1\n\
+             locked accounts: 0\n"
+        );
+    }
+
+    #[test]
+    fn it_gathers_statistics() {
+        let transactions_string = "type,       client, tx, amount\n\
+                                   deposit,    1,      1,  5.0\n\
+                                   deposit,    2,      2,  7.0\n\
+                                   withdrawal, 1,      3,  2.0\n\
+                                   dispute,    2,      2\n\
+                                   chargeback, 2,      2\n\
+                                   deposit,    3,      4,  7.0\n\
+                                   withdrawal, 3,      5,  8.0\n\
+                                   deposit,    4,      6,  0.0\n\
+                                   ";
+        let mut engine = Engine::default();
+        let mut summary = Summary::default();
+        for transaction in TransactionReader::new(io::Cursor::new(transactions_string)).unwrap() {
+            let transaction = transaction.unwrap();
+            summary.record(&transaction, engine.process(&transaction));
+        }
+
+        assert_eq!(
+            Statistics::of(&summary, [&engine]).to_string(),
+            "deposited: 19\n\
+             withdrawn: 2\n\
+             charged back: 7\n\
+             active accounts: 2\n\
+             locked accounts: 1\n\
+             empty accounts: 1\n\
+             largest transaction: 7 (tx 2)\n\
+             transactions:\n  \
+             deposit: 4\n  \
+             withdrawal: 1\n  \
+             dispute: 1\n  \
+             chargeback: 1\n"
+        );
+    }
+
+    #[test]
+    fn it_formats_dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(20_741), "2026-10-15");
+        for day_number in [0, 59, 11_016, 20_741, 51_134] {
+            assert_eq!(day(&date(day_number)), Some(day_number));
+        }
+        assert_eq!(day("1969-12-31"), None);
+        assert_eq!(day("2026-13-01"), None);
+    }
+
+    #[test]
+    fn it_aggregates_transactions_per_period() {
+        let path = TempPath::new("aggregation.csv");
+        let mut aggregation = Aggregation::new(&path, AggregationPeriod::Hour);
+        let deposit = |client_id, amount| tx(TransactionType::Deposit, client_id, 1, amount);
+        aggregation.record(&deposit(1, 2.5), 2.5, 3_600);
+        aggregation.record(&deposit(2, 1.0), 1.0, 7_199);
+        aggregation.record(&deposit(1, 4.0), 4.0, 90_000);
+        aggregation.finish().unwrap();
+
+        let report = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            report,
+            "period,client,type,count,volume\n\
+             1970-01-01T01:00,,deposit,2,3.5\n\
+             1970-01-01T01:00,1,deposit,1,2.5\n\
+             1970-01-01T01:00,2,deposit,1,1\n\
+             1970-01-02T01:00,,deposit,1,4\n\
+             1970-01-02T01:00,1,deposit,1,4\n"
+        );
+    }
+
+    #[test]
+    fn it_checksums_outputs() {
+        let checksum = OutputChecksum::of("-", b"abc");
+        assert_eq!(checksum.bytes, 3);
+        assert_eq!(
+            checksum.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Rules matching transactions before they are applied, loaded from a TOML file so
+//! that limits can be adjusted without changing any code:
+//!
+//! ```toml
+//! [tiers]
+//! premium = [1, 2, 3]
+//!
+//! [[rule]]
+//! name = "large withdrawals"
+//! type = "withdrawal"
+//! min_amount = 10000.0
+//! action = "reject"
+//!
+//! [[rule]]
+//! name = "deposits of new clients"
+//! type = "deposit"
+//! clients = [7]
+//! action = "hold"
+//!
+//! [[rule]]
+//! name = "withdrawals outside of the premium tier"
+//! type = "withdrawal"
+//! tier = "!premium"
+//! action = "fee"
+//! fee = 0.5
+//! ```
+//!
+//! Tiers can also have their own minimum balance, overriding `--min-balance`:
+//!
+//! ```toml
+//! [min_balance]
+//! premium = 0.0
+//! ```
+//!
+//! Limits for each level of verification assigned with `--kyc` go here as well:
+//!
+//! ```toml
+//! [kyc.basic]
+//! max_balance = 1000.0
+//! max_transaction = 250.0
+//! ```
+//!
+//! A rule matches a transaction if all of its conditions do, and only the first
+//! matching rule is applied. The actions are:
+//!
+//! - `reject`: the transaction is rejected
+//! - `flag`: the transaction is applied and the account is flagged
+//! - `hold`: a deposit is applied but its amount is held instead of available, as
+//!   if it was disputed right away: a resolve referring to the deposit releases
+//!   the funds, and a chargeback reverses it
+//! - `fee`: the transaction is applied and the fee is debited from the account,
+//!   or it is rejected as having insufficient funds if the available funds would
+//!   not cover the fee
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::account::Account;
+use crate::transaction::{ClientID, Rejection, Transaction, TransactionType};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// Clients by tier
+    tiers: HashMap<String, HashSet<ClientID>>,
+    /// Minimum balances by tier
+    min_balances: HashMap<String, f32>,
+    /// Limits by level of verification
+    kyc: HashMap<String, KycLimits>,
+}
+
+/// Limits for the clients of one level of verification
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KycLimits {
+    /// Deposits may not take the total funds above this
+    pub max_balance: Option<f32>,
+    /// Maximum amount of a single deposit or withdrawal
+    pub max_transaction: Option<f32>,
+}
+
+impl KycLimits {
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        account: &Account,
+    ) -> Result<(), Rejection> {
+        if transaction.ty.refers_back() {
+            return Ok(());
+        }
+        let exceeded = self
+            .max_transaction
+            .is_some_and(|max| transaction.amount > max)
+            || (transaction.ty == TransactionType::Deposit
+                && self
+                    .max_balance
+                    .is_some_and(|max| account.total + transaction.amount > max));
+        if exceeded {
+            Err(Rejection::KycLimit)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: Option<TransactionType>,
+    pub clients: Option<HashSet<ClientID>>,
+    /// Name of a tier, or of a tier the client must not be in when prefixed with `!`
+    pub tier: Option<String>,
+    pub min_amount: Option<f32>,
+    pub max_amount: Option<f32>,
+    pub action: RuleAction,
+    /// Amount debited by the `fee` action
+    pub fee: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Reject,
+    Flag,
+    Hold,
+    Fee,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    tiers: HashMap<String, HashSet<ClientID>>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    min_balance: HashMap<String, f32>,
+    #[serde(default)]
+    kyc: HashMap<String, KycLimits>,
+}
+
+impl Rules {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: RulesFile = toml::from_str(text).map_err(|err| err.message().to_string())?;
+        for rule in &file.rules {
+            let invalid = |reason: &str| Err(format!("rule {:?} {reason}", rule.name));
+            if let Some(tier) = &rule.tier {
+                let tier = tier.strip_prefix('!').unwrap_or(tier);
+                if !file.tiers.contains_key(tier) {
+                    return invalid(&format!("refers to unknown tier {tier:?}"));
+                }
+            }
+            let ty = rule.ty.as_ref();
+            match (rule.action, rule.fee) {
+                (RuleAction::Hold, _) if ty != Some(&TransactionType::Deposit) => {
+                    return invalid("holds transactions other than deposits");
+                }
+                (RuleAction::Fee, _) if ty.is_none_or(|ty| ty.refers_back()) => {
+                    return invalid(
+                        "charges fees on transactions other than deposits or withdrawals",
+                    );
+                }
+                (RuleAction::Fee, Some(fee)) if fee >= 0.0 => {}
+                (RuleAction::Fee, _) => return invalid("has no valid fee"),
+                (_, Some(_)) => return invalid("has a fee but does not charge it"),
+                _ => {}
+            }
+        }
+        for tier in file.min_balance.keys() {
+            if !file.tiers.contains_key(tier) {
+                return Err(format!("minimum balance for unknown tier {tier:?}"));
+            }
+        }
+        Ok(Self {
+            rules: file.rules,
+            tiers: file.tiers,
+            min_balances: file.min_balance,
+            kyc: file.kyc,
+        })
+    }
+
+    /// The first rule matching `transaction`
+    pub fn matching(&self, transaction: &Transaction) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| self.matches(rule, transaction))
+    }
+
+    /// Whether `client_id` is in `tier`
+    pub fn in_tier(&self, client_id: ClientID, tier: &str) -> bool {
+        self.tiers
+            .get(tier)
+            .is_some_and(|clients| clients.contains(&client_id))
+    }
+
+    /// The lowest minimum balance of the tiers `client_id` is in
+    pub fn min_balance(&self, client_id: ClientID) -> Option<f32> {
+        self.min_balances
+            .iter()
+            .filter(|(tier, _)| self.in_tier(client_id, tier))
+            .map(|(_, min_balance)| *min_balance)
+            .min_by(f32::total_cmp)
+    }
+
+    /// The limits of a level of verification, if there are any
+    pub fn kyc_limits(&self, level: &str) -> Option<&KycLimits> {
+        self.kyc.get(level)
+    }
+
+    fn matches(&self, rule: &Rule, transaction: &Transaction) -> bool {
+        let tier_matches = |tier: &String| match tier.strip_prefix('!') {
+            Some(tier) => !self.in_tier(transaction.client_id, tier),
+            None => self.in_tier(transaction.client_id, tier),
+        };
+        rule.ty.as_ref().is_none_or(|ty| *ty == transaction.ty)
+            && rule
+                .clients
+                .as_ref()
+                .is_none_or(|clients| clients.contains(&transaction.client_id))
+            && rule.tier.as_ref().is_none_or(tier_matches)
+            && rule.min_amount.is_none_or(|min| transaction.amount >= min)
+            && rule.max_amount.is_none_or(|max| transaction.amount <= max)
+    }
+}
+
+impl Rule {
+    /// Applies `transaction` to `account` with `process` as modified by the action
+    /// of this rule
+    pub(crate) fn apply(
+        &self,
+        transaction: &Transaction,
+        account: &mut Account,
+        process: impl FnOnce(&mut Account) -> Result<(), Rejection>,
+    ) -> Result<(), Rejection> {
+        match self.action {
+            RuleAction::Reject => Err(Rejection::Rule),
+            RuleAction::Flag => {
+                account.flagged = true;
+                process(account)
+            }
+            RuleAction::Hold => process(account).map(|()| {
+                account.available -= transaction.amount;
+                account.held += transaction.amount;
+            }),
+            RuleAction::Fee => {
+                let fee = self.fee.unwrap_or_default();
+                // Funds that would be left after the transaction itself
+                let remaining = match transaction.ty {
+                    TransactionType::Withdrawal => account.available - transaction.amount,
+                    _ => account.available + transaction.amount,
+                };
+                if remaining < fee {
+                    return Err(Rejection::InsufficientFunds);
+                }
+                process(account).map(|()| {
+                    account.available -= fee;
+                    account.total -= fee;
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::policy::Policy;
+    use crate::testing::tx;
+
+    #[test]
+    fn it_applies_the_first_matching_rule() {
+        let rules = Rules::parse(
+            r#"
+            [tiers]
+            premium = [2]
+
+            [[rule]]
+            name = "large withdrawals"
+            type = "withdrawal"
+            min_amount = 100.0
+            action = "reject"
+
+            [[rule]]
+            name = "withdrawal fee"
+            type = "withdrawal"
+            tier = "!premium"
+            action = "fee"
+            fee = 1.0
+
+            [[rule]]
+            name = "held deposits"
+            type = "deposit"
+            clients = [3]
+            action = "hold"
+            "#,
+        )
+        .unwrap();
+        let mut engine = Engine::with_policy(Policy {
+            rules: Some(rules),
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        for (client_id, id) in [(1, 1), (2, 2), (3, 3)] {
+            assert_eq!(engine.process(&tx(Deposit, client_id, id, 500.0)), Ok(()));
+        }
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 1, 4, 200.0)),
+            Err(Rejection::Rule)
+        );
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 5, 10.0)), Ok(()));
+        assert_eq!(engine.accounts()[&1].total, 489.0);
+        assert_eq!(engine.process(&tx(Withdrawal, 2, 6, 10.0)), Ok(()));
+        assert_eq!(engine.accounts()[&2].total, 490.0);
+        assert_eq!(engine.accounts()[&3].available, 0.0);
+        assert_eq!(engine.accounts()[&3].held, 500.0);
+        // The funds are held until the deposit is resolved
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 3, 7, 10.0)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(engine.process(&tx(Resolve, 3, 3, 0.0)), Ok(()));
+        assert_eq!(engine.accounts()[&3].available, 500.0);
+        assert_eq!(engine.accounts()[&3].held, 0.0);
+
+        // Unless the fee is covered as well, the withdrawal is rejected
+        assert_eq!(engine.process(&tx(Deposit, 4, 8, 5.0)), Ok(()));
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 4, 9, 4.5)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(engine.accounts()[&4].total, 5.0);
+    }
+
+    #[test]
+    fn it_rejects_invalid_rules() {
+        assert!(Rules::parse("[[rule]]\nname = \"x\"\naction = \"explode\"\n").is_err());
+        assert!(Rules::parse("[[rule]]\nname = \"x\"\naction = \"hold\"\n").is_err());
+        assert!(
+            Rules::parse("[[rule]]\nname = \"x\"\ntier = \"gold\"\naction = \"flag\"\n").is_err()
+        );
+    }
+}
@@ -0,0 +1,835 @@
+//! Everything that happens around the engine while processing one input.
+
+use std::{collections::VecDeque, fmt, str::FromStr};
+
+use serde::Serialize;
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::engine::Engine;
+use crate::events::EventLog;
+use crate::ledger::Journal;
+use crate::paranoid;
+use crate::report::{Aggregation, DailyReports, FailedWithdrawalReport, LockReport, Summary};
+use crate::transaction::{
+    ClientID, CustomType, ExtraColumns, Rejection, RowContext, Transaction, TransactionID,
+    TransactionType,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// If set, malformed rows are skipped instead of aborting the run, but the run
+    /// is aborted once more than this many rows were malformed or rejected
+    pub max_errors: Option<u64>,
+    /// Skip malformed rows without ever aborting because of them
+    pub lenient: bool,
+    /// Check the account invariants after every applied transaction
+    /// and abort on the first violation
+    pub check_invariants: bool,
+    /// Do not treat negative available funds as an invariant violation
+    pub allow_negative_available: bool,
+    /// Check the internal state of the engine around every transaction with
+    /// [`paranoid`](crate::paranoid) and abort on the first inconsistency
+    pub paranoid: bool,
+    /// If set, a dispute, resolve, or chargeback referring to a transaction that was
+    /// not processed yet is retried once that transaction appears within this many
+    /// rows, and is otherwise rejected
+    pub lookahead: Option<u64>,
+    /// Do not report warnings, rejections, and skipped rows on stderr
+    pub quiet: bool,
+    /// Report every rejected transaction on stderr, which is not done by default
+    pub log_rejections: bool,
+    /// How warnings, rejections, and skipped rows are reported on stderr
+    pub log_format: LogFormat,
+    /// Abort once the accounts and the transaction index take up more than this many
+    /// bytes, as estimated by [`Engine::memory_usage`]
+    pub memory_limit: Option<usize>,
+    /// How many rows may be queued for each thread of a
+    /// [`ShardedRun`](crate::parallel::ShardedRun) before reading pauses,
+    /// [`CHANNEL_CAPACITY`](crate::parallel::CHANNEL_CAPACITY) if not set
+    pub queue_length: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable messages
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Ok(match string {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => return Err("expected one of text or json"),
+        })
+    }
+}
+
+/// Why a run was aborted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Abort {
+    /// A row could not be parsed and malformed rows are not skipped
+    Malformed(String),
+    /// An account invariant was violated while checking invariants
+    InvariantViolated(String),
+    /// More rows were malformed or rejected than allowed
+    ErrorThreshold(String),
+    /// The memory limit was exceeded
+    MemoryLimit(String),
+    /// The event log could not be written
+    EventLog(String),
+    /// The audit log could not be written
+    AuditLog(String),
+    /// The lock report could not be written
+    LockReport(String),
+    /// The failed withdrawals report could not be written
+    FailedWithdrawalReport(String),
+    /// The ledger could not be written
+    Ledger(String),
+    /// A daily report could not be written
+    DailyReports(String),
+    /// The aggregation could not be written
+    Aggregation(String),
+    /// Transaction IDs were out of order while checking their order
+    IdOrder(String),
+    /// An assertion in the input did not hold
+    Assertion(String),
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Abort::*;
+
+        match self {
+            Malformed(message)
+            | InvariantViolated(message)
+            | ErrorThreshold(message)
+            | MemoryLimit(message)
+            | EventLog(message)
+            | AuditLog(message)
+            | LockReport(message)
+            | FailedWithdrawalReport(message)
+            | Ledger(message)
+            | DailyReports(message)
+            | Aggregation(message)
+            | IdOrder(message)
+            | Assertion(message) => f.write_str(message),
+        }
+    }
+}
+
+/// A warning, rejection, or skipped row as reported with [`LogFormat::Json`]
+#[derive(Debug, Serialize)]
+struct Diagnostic<'a> {
+    /// `warning`, `rejected`, or `malformed`
+    kind: &'static str,
+    reason: &'a str,
+    line: u64,
+    offset: u64,
+    row: String,
+    #[serde(rename = "type")]
+    ty: Option<TransactionType>,
+    client: Option<ClientID>,
+    tx: Option<TransactionID>,
+    amount: Option<f32>,
+}
+
+pub struct Run<'a> {
+    options: RunOptions,
+    event_log: Option<EventLog<'a>>,
+    audit_log: Option<AuditLog>,
+    lock_report: Option<LockReport>,
+    failed_withdrawal_report: Option<FailedWithdrawalReport>,
+    journal: Option<Journal>,
+    daily_reports: Option<DailyReports>,
+    aggregation: Option<Aggregation>,
+    extra_columns: ExtraColumns,
+    summary: Summary,
+    /// The latest rejections, newest first
+    recent_rejections: VecDeque<RecentRejection>,
+    /// Transactions waiting for the transaction they refer to, oldest first
+    deferred: VecDeque<Deferred>,
+    /// Number of transactions handed to the run so far
+    position: u64,
+}
+
+/// A rejected transaction, for showing what is going on during a run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecentRejection {
+    pub line: u64,
+    pub rejection: Rejection,
+    pub client_id: ClientID,
+    pub id: TransactionID,
+}
+
+/// How many of the latest rejections are kept
+const RECENT_REJECTIONS: usize = 10;
+
+/// A transaction waiting for the transaction it refers to
+struct Deferred {
+    transaction: Transaction,
+    line: u64,
+    offset: u64,
+    text: String,
+    partner: Option<String>,
+    /// The position after which it will be rejected
+    expires_at: u64,
+}
+
+impl Deferred {
+    fn row(&self) -> RowContext<'_> {
+        RowContext {
+            line: self.line,
+            offset: self.offset,
+            text: &self.text,
+            partner: self.partner.as_deref(),
+        }
+    }
+}
+
+impl<'a> Run<'a> {
+    pub fn new(options: RunOptions, event_log: Option<EventLog<'a>>) -> Self {
+        Self {
+            options,
+            event_log,
+            audit_log: None,
+            lock_report: None,
+            failed_withdrawal_report: None,
+            journal: None,
+            daily_reports: None,
+            aggregation: None,
+            extra_columns: ExtraColumns::default(),
+            summary: Summary::default(),
+            recent_rejections: VecDeque::new(),
+            deferred: VecDeque::new(),
+            position: 0,
+        }
+    }
+
+    pub fn options(&self) -> &RunOptions {
+        &self.options
+    }
+
+    /// The tallies so far, lacking the counts that are filled in by [`Run::finish`]
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// The latest rejections, newest first
+    pub fn recent_rejections(&self) -> impl Iterator<Item = &RecentRejection> {
+        self.recent_rejections.iter()
+    }
+
+    /// Records actions taken because of the policy in `audit_log`
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Lists every account that gets locked in `lock_report`
+    pub fn with_lock_report(mut self, lock_report: LockReport) -> Self {
+        self.lock_report = Some(lock_report);
+        self
+    }
+
+    /// Lists every withdrawal rejected for insufficient funds in `report`
+    pub fn with_failed_withdrawal_report(mut self, report: FailedWithdrawalReport) -> Self {
+        self.failed_withdrawal_report = Some(report);
+        self
+    }
+
+    /// Journals every applied transaction in `journal`
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Writes closing snapshots of the accounts at the end of each day with
+    /// `daily_reports`, going by the engine's clock
+    pub fn with_daily_reports(mut self, daily_reports: DailyReports) -> Self {
+        self.daily_reports = Some(daily_reports);
+        self
+    }
+
+    /// Counts the applied transactions in `aggregation`, going by the engine's clock
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = Some(aggregation);
+        self
+    }
+
+    /// Keeps `extra_columns` of each row in the audit log
+    pub fn with_extra_columns(mut self, extra_columns: ExtraColumns) -> Self {
+        self.extra_columns = extra_columns;
+        self
+    }
+
+    /// Processes a transaction read from `row`
+    pub fn process(
+        &mut self,
+        engine: &mut Engine,
+        transaction: &Transaction,
+        row: &RowContext,
+    ) -> Result<(), Abort> {
+        self.position += 1;
+        if let (Some(daily_reports), Some(clock)) = (self.daily_reports.as_mut(), engine.clock) {
+            daily_reports
+                .advance(engine, clock)
+                .map_err(Abort::DailyReports)?;
+        }
+
+        if let Some(lookahead) = self.options.lookahead {
+            if transaction.ty.refers_back()
+                && engine
+                    .transactions
+                    .get(transaction.client_id, transaction.id)
+                    .is_none()
+            {
+                self.deferred.push_back(Deferred {
+                    transaction: transaction.clone(),
+                    line: row.line,
+                    offset: row.offset,
+                    text: row.text.to_string(),
+                    partner: row.partner.map(str::to_string),
+                    expires_at: self.position + lookahead,
+                });
+                return self.expire_deferred(false);
+            }
+        }
+
+        let total_before = total(engine, transaction.client_id);
+        let result = self.apply(engine, transaction, row)?;
+        self.conclude(engine, transaction, result, row, total_before)?;
+
+        if result.is_ok() && !self.deferred.is_empty() {
+            // Retry everything that was waiting for this transaction, in order
+            let (ready, waiting) =
+                std::mem::take(&mut self.deferred)
+                    .into_iter()
+                    .partition(|deferred| {
+                        (deferred.transaction.client_id, deferred.transaction.id)
+                            == (transaction.client_id, transaction.id)
+                    });
+            self.deferred = waiting;
+            for deferred in ready {
+                let total_before = total(engine, deferred.transaction.client_id);
+                let result = self.apply(engine, &deferred.transaction, &deferred.row())?;
+                self.conclude(
+                    engine,
+                    &deferred.transaction,
+                    result,
+                    &deferred.row(),
+                    total_before,
+                )?;
+            }
+        }
+        self.expire_deferred(false)
+    }
+
+    /// Has `engine` process `transaction`, checking its state around it if the
+    /// run is paranoid
+    fn apply(
+        &self,
+        engine: &mut Engine,
+        transaction: &Transaction,
+        row: &RowContext,
+    ) -> Result<Result<(), Rejection>, Abort> {
+        if !self.options.paranoid {
+            return Ok(engine.process(transaction));
+        }
+        let before = paranoid::Before::capture(engine, transaction);
+        let result = engine.process(transaction);
+        before
+            .check(
+                engine,
+                transaction,
+                result,
+                self.options.allow_negative_available,
+            )
+            .map_err(|inconsistency| {
+                Abort::InvariantViolated(format!(
+                    "paranoid check failed ({inconsistency}) after processing transaction at {row}"
+                ))
+            })?;
+        Ok(result)
+    }
+
+    /// Must be called once the input is exhausted, to reject any transactions
+    /// still waiting for the transaction they refer to
+    pub fn end_of_input(&mut self) -> Result<(), Abort> {
+        self.expire_deferred(true)
+    }
+
+    fn expire_deferred(&mut self, all: bool) -> Result<(), Abort> {
+        while let Some(deferred) = self.deferred.front() {
+            if !all && deferred.expires_at > self.position {
+                break;
+            }
+            let deferred = self.deferred.pop_front().unwrap();
+            self.summary
+                .record(&deferred.transaction, Err(Rejection::ReferenceNeverSeen));
+            self.reject(
+                Rejection::ReferenceNeverSeen,
+                &deferred.transaction,
+                &deferred.row(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Does the bookkeeping for a transaction the engine has processed. `total_before`
+    /// is the client's total funds before it was processed.
+    fn conclude(
+        &mut self,
+        engine: &Engine,
+        transaction: &Transaction,
+        result: Result<(), Rejection>,
+        row: &RowContext,
+        total_before: f32,
+    ) -> Result<(), Abort> {
+        self.summary.record(transaction, result);
+        if let (Some(trigger), Some(lock_report)) = (engine.last_lock, self.lock_report.as_mut()) {
+            let charged_back = (transaction.ty == TransactionType::Chargeback)
+                .then(|| engine.transactions[(transaction.client_id, transaction.id)].amount);
+            lock_report
+                .record(
+                    transaction,
+                    trigger,
+                    charged_back,
+                    &engine.accounts[&transaction.client_id],
+                )
+                .map_err(Abort::LockReport)?;
+        }
+        match result {
+            Ok(()) => {
+                if self.options.check_invariants {
+                    let account = &engine.accounts[&transaction.client_id];
+                    if let Err(violation) =
+                        account.check_invariants(self.options.allow_negative_available)
+                    {
+                        return Err(Abort::InvariantViolated(format!(
+                            "invariant violated ({violation}) after applying transaction at {row}: \
+                             client {} now has {account:?}",
+                            transaction.client_id
+                        )));
+                    }
+                }
+                if let Some(memory_limit) = self.options.memory_limit {
+                    let memory_usage = engine.memory_usage();
+                    if memory_usage > memory_limit {
+                        return Err(Abort::MemoryLimit(format!(
+                            "aborting: the engine takes up ~{memory_usage} bytes, \
+                             more than the memory limit of {memory_limit} bytes, at {row}"
+                        )));
+                    }
+                }
+                if let Some(event_log) = self.event_log.as_mut() {
+                    event_log
+                        .append(engine.counters.applied, transaction)
+                        .map_err(Abort::EventLog)?;
+                }
+                let referenced_amount = if transaction.ty.refers_back() {
+                    engine.transactions[(transaction.client_id, transaction.id)].amount
+                } else {
+                    0.0
+                };
+                if let Some(journal) = self.journal.as_mut() {
+                    journal
+                        .post(
+                            transaction,
+                            referenced_amount,
+                            total_before,
+                            total(engine, transaction.client_id),
+                        )
+                        .map_err(Abort::Ledger)?;
+                }
+                if let (Some(aggregation), Some(clock)) = (self.aggregation.as_mut(), engine.clock)
+                {
+                    let amount = if transaction.ty.refers_back() {
+                        referenced_amount
+                    } else {
+                        transaction.amount
+                    };
+                    aggregation.record(transaction, amount, clock);
+                }
+                if let (Some(trigger), Some(audit_log)) =
+                    (engine.last_lock, self.audit_log.as_mut())
+                {
+                    audit_log
+                        .record(&AuditEntry {
+                            event: trigger.code(),
+                            action: "locked",
+                            line: row.line,
+                            partner: row.partner,
+                            transaction,
+                            columns: self.extra_columns.capture(row.text),
+                        })
+                        .map_err(Abort::AuditLog)?;
+                }
+                if let (TransactionType::Chargeback, Some(fee), Some(audit_log)) = (
+                    &transaction.ty,
+                    engine.policy.chargeback_fee,
+                    self.audit_log.as_mut(),
+                ) {
+                    audit_log
+                        .record(&AuditEntry {
+                            event: "chargeback_fee",
+                            action: "debited",
+                            line: row.line,
+                            partner: row.partner,
+                            transaction: &Transaction {
+                                ty: TransactionType::Custom(CustomType::new("fee")),
+                                amount: fee,
+                                ..transaction.clone()
+                            },
+                            columns: self.extra_columns.capture(row.text),
+                        })
+                        .map_err(Abort::AuditLog)?;
+                }
+                Ok(())
+            }
+            Err(rejection) => {
+                if rejection == Rejection::Blocked {
+                    if let Some(audit_log) = self.audit_log.as_mut() {
+                        let locked = engine
+                            .policy
+                            .blocklist
+                            .as_ref()
+                            .is_some_and(|blocklist| blocklist.lock);
+                        audit_log
+                            .record(&AuditEntry {
+                                event: "blocked",
+                                action: if locked { "locked" } else { "rejected" },
+                                line: row.line,
+                                partner: row.partner,
+                                transaction,
+                                columns: self.extra_columns.capture(row.text),
+                            })
+                            .map_err(Abort::AuditLog)?;
+                    }
+                }
+                if let (TransactionType::Withdrawal, Rejection::InsufficientFunds, Some(report)) = (
+                    &transaction.ty,
+                    rejection,
+                    self.failed_withdrawal_report.as_mut(),
+                ) {
+                    let available = engine
+                        .accounts
+                        .get(&transaction.client_id)
+                        .map_or(0.0, |account| account.available);
+                    report
+                        .record(transaction, available)
+                        .map_err(Abort::FailedWithdrawalReport)?;
+                }
+                self.reject(rejection, transaction, row)
+            }
+        }
+    }
+
+    fn reject(
+        &mut self,
+        rejection: Rejection,
+        transaction: &Transaction,
+        row: &RowContext,
+    ) -> Result<(), Abort> {
+        self.recent_rejections.push_front(RecentRejection {
+            line: row.line,
+            rejection,
+            client_id: transaction.client_id,
+            id: transaction.id,
+        });
+        self.recent_rejections.truncate(RECENT_REJECTIONS);
+        if self.options.log_rejections {
+            self.report(
+                "rejected",
+                "rejected transaction",
+                rejection.code(),
+                Some(transaction),
+                row,
+            );
+        }
+        self.check_error_threshold()
+    }
+
+    /// Reports a problem with a row that was still read successfully
+    pub fn warn(&mut self, warning: &str, transaction: &Transaction, row: &RowContext) {
+        self.report("warning", "warning", warning, Some(transaction), row);
+    }
+
+    /// Writes a diagnostic to stderr in the configured format
+    fn report(
+        &self,
+        kind: &'static str,
+        message: &str,
+        reason: &str,
+        transaction: Option<&Transaction>,
+        row: &RowContext,
+    ) {
+        if self.options.quiet {
+            return;
+        }
+        match self.options.log_format {
+            LogFormat::Text => eprintln!("{message} ({reason}) at {row}"),
+            LogFormat::Json => {
+                let diagnostic = Diagnostic {
+                    kind,
+                    reason,
+                    line: row.line,
+                    offset: row.offset,
+                    row: row.echo(),
+                    ty: transaction.map(|transaction| transaction.ty),
+                    client: transaction.map(|transaction| transaction.client_id),
+                    tx: transaction.map(|transaction| transaction.id),
+                    amount: transaction.map(|transaction| transaction.amount),
+                };
+                eprintln!(
+                    "{}",
+                    serde_json::to_string(&diagnostic)
+                        .expect("diagnostics are always serializable")
+                );
+            }
+        }
+    }
+
+    /// Handles a row that could not be parsed into a transaction
+    pub fn malformed(&mut self, err: &str, row: &RowContext) -> Result<(), Abort> {
+        if self.options.max_errors.is_none() && !self.options.lenient {
+            return Err(Abort::Malformed(format!(
+                "transactions could not be parsed: {err} at {row}"
+            )));
+        }
+        self.report("malformed", "skipping malformed row", err, None, row);
+        self.summary.rows_malformed += 1;
+        self.check_error_threshold()
+    }
+
+    fn check_error_threshold(&self) -> Result<(), Abort> {
+        match self.options.max_errors {
+            Some(max_errors) if self.summary.errors() > max_errors => Err(Abort::ErrorThreshold(
+                format!("aborting: more than {max_errors} rows were malformed or rejected"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes what the logs and reports buffered so far, so that they can be read
+    /// while the run goes on
+    pub fn flush(&mut self) -> Result<(), Abort> {
+        if let Some(event_log) = &mut self.event_log {
+            event_log.flush().map_err(Abort::EventLog)?;
+        }
+        if let Some(audit_log) = &mut self.audit_log {
+            audit_log.flush().map_err(Abort::AuditLog)?;
+        }
+        if let Some(lock_report) = &mut self.lock_report {
+            lock_report.flush().map_err(Abort::LockReport)?;
+        }
+        if let Some(report) = &mut self.failed_withdrawal_report {
+            report.flush().map_err(Abort::FailedWithdrawalReport)?;
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.flush().map_err(Abort::Ledger)?;
+        }
+        Ok(())
+    }
+
+    /// `rows_read` and `rows_skipped` are as counted by the reader of the input.
+    /// Can also be called after the run was aborted to get a partial summary.
+    pub fn finish(
+        self,
+        engine: &Engine,
+        rows_read: u64,
+        rows_skipped: u64,
+    ) -> Result<Summary, Abort> {
+        if let Some(event_log) = self.event_log {
+            event_log.finish().map_err(Abort::EventLog)?;
+        }
+        if let Some(audit_log) = self.audit_log {
+            audit_log.finish().map_err(Abort::AuditLog)?;
+        }
+        if let Some(lock_report) = self.lock_report {
+            lock_report.finish().map_err(Abort::LockReport)?;
+        }
+        if let Some(report) = self.failed_withdrawal_report {
+            report.finish().map_err(Abort::FailedWithdrawalReport)?;
+        }
+        if let Some(journal) = self.journal {
+            journal.finish().map_err(Abort::Ledger)?;
+        }
+        if let Some(daily_reports) = self.daily_reports {
+            daily_reports.finish(engine).map_err(Abort::DailyReports)?;
+        }
+        if let Some(aggregation) = self.aggregation {
+            aggregation.finish().map_err(Abort::Aggregation)?;
+        }
+        let mut summary = self.summary;
+        summary.rows_parsed = rows_read.saturating_sub(rows_skipped + summary.rows_malformed);
+        summary.rows_skipped = rows_skipped;
+        summary.finish(engine);
+        Ok(summary)
+    }
+}
+
+/// The total funds of a client, who may not have an account yet
+fn total(engine: &Engine, client_id: ClientID) -> f32 {
+    engine
+        .accounts
+        .get(&client_id)
+        .map_or(0.0, |account| account.total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::policy::{Blocklist, Policy};
+    use crate::testing::tx;
+    use crate::testing::TempPath;
+    use crate::transaction::TransactionType;
+
+    #[test]
+    fn it_aborts_once_the_error_threshold_is_exceeded() {
+        let options = RunOptions {
+            max_errors: Some(1),
+            ..RunOptions::default()
+        };
+        let mut run = Run::new(options, None);
+        let mut engine = Engine::default();
+        let withdrawal = tx(TransactionType::Withdrawal, 1, 1, 1.0);
+
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+
+        assert!(run.malformed("invalid client ID", &row).is_ok());
+        assert!(run.process(&mut engine, &withdrawal, &row).is_err());
+
+        let summary = run.finish(&engine, 2, 0).unwrap();
+        assert_eq!(summary.errors(), 2);
+    }
+
+    #[test]
+    fn it_reports_locked_accounts() {
+        let path = TempPath::new("lock-report.csv");
+        let mut run = Run::new(RunOptions::default(), None)
+            .with_lock_report(LockReport::create(&path).unwrap());
+        let mut engine = Engine::with_policy(Policy {
+            blocklist: Some(Blocklist {
+                clients: HashSet::from([2]),
+                lock: true,
+            }),
+            ..Policy::default()
+        });
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+        use TransactionType::*;
+
+        for transaction in [
+            tx(Deposit, 1, 1, 5.0),
+            tx(Deposit, 1, 2, 3.0),
+            tx(Dispute, 1, 2, 0.0),
+            tx(Chargeback, 1, 2, 0.0),
+            tx(Deposit, 2, 3, 1.0),
+            tx(Deposit, 2, 4, 1.0),
+        ] {
+            run.process(&mut engine, &transaction, &row).unwrap();
+        }
+        run.finish(&engine, 6, 0).unwrap();
+
+        let report = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            report,
+            "client,tx,type,reason,charged_back,available,held,total\n\
+             1,2,chargeback,chargebacks,3,5,0,5\n\
+             2,3,deposit,blocked,,0,0,0\n"
+        );
+    }
+
+    #[test]
+    fn it_reports_failed_withdrawals() {
+        let path = TempPath::new("failed-withdrawals.csv");
+        let mut run = Run::new(RunOptions::default(), None)
+            .with_failed_withdrawal_report(FailedWithdrawalReport::create(&path).unwrap());
+        let mut engine = Engine::default();
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+        use TransactionType::*;
+
+        for transaction in [
+            tx(Deposit, 1, 1, 4.5),
+            tx(Withdrawal, 1, 2, 10.0),
+            tx(Withdrawal, 1, 3, 1.5),
+            tx(Dispute, 1, 4, 0.0),
+        ] {
+            run.process(&mut engine, &transaction, &row).unwrap();
+        }
+        run.finish(&engine, 4, 0).unwrap();
+
+        let report = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(report, "client,tx,amount,available\n1,2,10,4.5\n");
+    }
+
+    #[test]
+    fn it_aborts_on_malformed_rows_without_a_threshold() {
+        let mut run = Run::new(RunOptions::default(), None);
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "deposit,x,1,1.0",
+            partner: None,
+        };
+        assert!(run.malformed("invalid client ID", &row).is_err());
+    }
+
+    #[test]
+    fn it_waits_for_referenced_transactions_within_the_lookahead() {
+        let options = RunOptions {
+            lookahead: Some(1),
+            ..RunOptions::default()
+        };
+        let mut run = Run::new(options, None);
+        let mut engine = Engine::default();
+        let row = RowContext {
+            line: 1,
+            offset: 0,
+            text: "",
+            partner: None,
+        };
+
+        // Arrives one row late, which is tolerated
+        run.process(&mut engine, &tx(TransactionType::Dispute, 1, 1, 0.0), &row)
+            .unwrap();
+        run.process(&mut engine, &tx(TransactionType::Deposit, 1, 1, 5.0), &row)
+            .unwrap();
+        assert_eq!(engine.accounts()[&1].held, 5.0);
+
+        // Never arrives
+        run.process(&mut engine, &tx(TransactionType::Dispute, 1, 2, 0.0), &row)
+            .unwrap();
+        run.process(&mut engine, &tx(TransactionType::Deposit, 1, 3, 1.0), &row)
+            .unwrap();
+        run.process(&mut engine, &tx(TransactionType::Deposit, 1, 2, 1.0), &row)
+            .unwrap();
+        run.end_of_input().unwrap();
+
+        let summary = run.finish(&engine, 5, 0).unwrap();
+        assert_eq!(summary.rejected[&Rejection::ReferenceNeverSeen], 1);
+        assert_eq!(engine.accounts()[&1].held, 5.0);
+    }
+}
@@ -0,0 +1,234 @@
+//! Scenarios: inputs along with the accounts and rejections they are expected to
+//! result in, for regression tests that can be written without any Rust.
+//!
+//! A scenario consists of sections, each starting with a line like `[input]`:
+//!
+//! ```text
+//! # A withdrawal exceeding the available funds
+//! [input]
+//! type,client,tx,amount
+//! deposit,1,1,5.0
+//! withdrawal,1,2,9.0
+//!
+//! [accounts]
+//! client,available,held,total,locked
+//! 1,5,0,5,false
+//!
+//! [rejections]
+//! line,reason
+//! 3,insufficient_funds
+//! ```
+//!
+//! `input` is processed like a file, `accounts` is in the format of the output, and
+//! `rejections` lists the line within `input` and the reason of every transaction
+//! that was rejected, with `malformed` for rows that could not be parsed. Sections
+//! that are left out are not checked. Lines starting with `#` are comments, except
+//! within `input`.
+//!
+//! When run with assertions, `input` may also contain rows like
+//! `#assert,1,available,5`, which are checked against the accounts at that point
+//! of the input. See [`Assertion`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
+
+use crate::account::{parse_accounts, Account};
+use crate::assertion::Assertion;
+use crate::diff::compare_accounts;
+use crate::engine::Engine;
+use crate::transaction::{ClientID, ParseOptions, TransactionReader};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    input: String,
+    accounts: Option<HashMap<ClientID, Account>>,
+    /// Reasons by line
+    rejections: Option<BTreeMap<u64, String>>,
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut sections = HashMap::<&str, String>::new();
+        let mut section = None;
+        for (index, line) in text.lines().enumerate() {
+            if let Some(name) = line
+                .trim()
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                if !["input", "accounts", "rejections"].contains(&name) {
+                    return Err(format!("unknown section [{name}] on line {}", index + 1));
+                }
+                if sections.insert(name, String::new()).is_some() {
+                    return Err(format!("duplicate section [{name}] on line {}", index + 1));
+                }
+                section = Some(name);
+                continue;
+            }
+            let is_comment = line.trim_start().starts_with('#');
+            match section {
+                Some("input") => {}
+                _ if is_comment || line.trim().is_empty() => continue,
+                Some(_) => {}
+                None => return Err(format!("line {} is outside of any section", index + 1)),
+            }
+            let section = sections.get_mut(section.unwrap()).unwrap();
+            section.push_str(line);
+            section.push('\n');
+        }
+
+        let input = sections.remove("input").ok_or("no [input] section")?;
+        let accounts = sections
+            .remove("accounts")
+            .map(|accounts| {
+                parse_accounts(accounts.as_bytes())
+                    .map_err(|err| format!("invalid [accounts] section: {err}"))
+            })
+            .transpose()?;
+        let rejections = sections
+            .remove("rejections")
+            .map(|rejections| parse_rejections(&rejections))
+            .transpose()?;
+        Ok(Self {
+            input,
+            accounts,
+            rejections,
+        })
+    }
+
+    /// Processes the input and returns every way in which the result differs from
+    /// what was expected, including the assertions in the input that did not hold
+    /// if `assertions` is set
+    pub fn run(&self, assertions: bool) -> Result<(), Vec<String>> {
+        let options = ParseOptions {
+            assertions,
+            ..ParseOptions::default()
+        };
+        let mut reader =
+            TransactionReader::with_options(io::Cursor::new(self.input.as_str()), options)
+                .map_err(|err| vec![format!("input could not be parsed: {err}")])?;
+        let mut engine = Engine::default();
+        let mut rejections = BTreeMap::new();
+        let mut mismatches = Vec::new();
+        let check = |assertions: Vec<Assertion>, engine: &Engine, mismatches: &mut Vec<_>| {
+            mismatches.extend(
+                assertions
+                    .iter()
+                    .filter_map(|assertion| assertion.check(engine).err()),
+            );
+        };
+        while let Some(transaction) = reader.next() {
+            check(reader.take_assertions(), &engine, &mut mismatches);
+            let result = match transaction {
+                Ok(transaction) => engine
+                    .process(&transaction)
+                    .map_err(|rejection| rejection.code()),
+                Err(_) => Err("malformed"),
+            };
+            if let Err(reason) = result {
+                rejections.insert(reader.row().line, reason.to_string());
+            }
+        }
+        check(reader.take_assertions(), &engine, &mut mismatches);
+
+        if let Some(expected) = &self.accounts {
+            mismatches.extend(compare_accounts(expected, engine.accounts()));
+        }
+        if let Some(expected) = &self.rejections {
+            mismatches.extend(compare_rejections(expected, &rejections));
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(mismatches)
+        }
+    }
+}
+
+fn parse_rejections(section: &str) -> Result<BTreeMap<u64, String>, String> {
+    let mut rejections = BTreeMap::new();
+    // Skip row of column types
+    for row in section.lines().skip(1) {
+        let invalid = || format!("invalid [rejections] row {row:?}");
+        let (line, reason) = row.split_once(',').ok_or_else(invalid)?;
+        let line = line.trim().parse().map_err(|_| invalid())?;
+        if rejections.insert(line, reason.trim().to_string()).is_some() {
+            return Err(format!("duplicate rejection for line {line}"));
+        }
+    }
+    Ok(rejections)
+}
+
+fn compare_rejections(
+    expected: &BTreeMap<u64, String>,
+    actual: &BTreeMap<u64, String>,
+) -> Vec<String> {
+    let mut lines: Vec<u64> = expected.keys().chain(actual.keys()).copied().collect();
+    lines.sort_unstable();
+    lines.dedup();
+
+    lines
+        .into_iter()
+        .filter_map(|line| match (expected.get(&line), actual.get(&line)) {
+            (Some(expected), Some(actual)) if expected != actual => {
+                Some(format!("line {line}: expected {expected} but got {actual}"))
+            }
+            (Some(expected), None) => Some(format!(
+                "line {line}: expected {expected} but the transaction was applied"
+            )),
+            (None, Some(actual)) => Some(format!("line {line}: unexpected {actual}")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn it_reports_mismatches() {
+        let scenario = Scenario::parse(
+            "# Expects too much\n\
+             [input]\n\
+             type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             withdrawal,1,2,9.0\n\
+             deposit,x,3,1.0\n\
+             \n\
+             [accounts]\n\
+             client,available,held,total,locked\n\
+             1,9,0,9,false\n\
+             2,1,0,1,false\n\
+             \n\
+             [rejections]\n\
+             line,reason\n\
+             4,malformed\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            scenario.run(false),
+            Err(vec![
+                "client 1: expected 9,0,9,false but got 5,0,5,false".to_string(),
+                "client 2: expected an account".to_string(),
+                "line 3: unexpected insufficient_funds".to_string(),
+            ])
+        );
+        assert!(Scenario::parse("[inputs]\n").is_err());
+    }
+
+    #[test]
+    fn it_passes_the_bundled_scenarios() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let scenario = Scenario::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(scenario.run(true), Ok(()), "{}", path.display());
+        }
+    }
+}
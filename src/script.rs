@@ -0,0 +1,198 @@
+//! Hooks on transactions written in [Rhai](https://rhai.rs), for one-off quirks
+//! that are not worth handling in the engine itself. Built with `--features
+//! scripting`.
+//!
+//! A script defines `on_transaction(tx, account)`, which is called before each
+//! transaction is applied. `tx` has the fields `type`, `client`, `tx`, and
+//! `amount`, and `account` those of the client's account before the transaction:
+//! `available`, `held`, `total`, `locked`, and `flagged`. Returning `"reject"`
+//! vetoes the transaction, returning `"flag"` applies it and flags the account, and
+//! returning nothing applies it as usual:
+//!
+//! ```rhai
+//! fn on_transaction(tx, account) {
+//!     if tx.client == 42 && tx.type == "withdrawal" && tx.amount > account.available / 2.0 {
+//!         return "flag";
+//!     }
+//! }
+//! ```
+//!
+//! A script failing at runtime, running into one of the limits below, or returning
+//! anything else rejects the transaction as a script error. A flag only sticks if
+//! the transaction is applied.
+
+use std::{fmt, sync::Arc};
+
+use rhai::{Dynamic, Map, Scope, AST};
+
+use crate::account::Account;
+use crate::transaction::{Rejection, Transaction};
+
+const HOOK: &str = "on_transaction";
+
+/// Limits on each call of the hook, so that a script cannot hold up processing
+/// or take up memory without bounds
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 4096;
+const MAX_ARRAY_SIZE: usize = 1024;
+const MAX_MAP_SIZE: usize = 1024;
+
+#[derive(Clone)]
+pub struct Script {
+    engine: Arc<rhai::Engine>,
+    ast: AST,
+    source: String,
+}
+
+impl Script {
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let mut engine = rhai::Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_ARRAY_SIZE)
+            .set_max_map_size(MAX_MAP_SIZE);
+        let ast = engine.compile(source).map_err(|err| err.to_string())?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == HOOK && function.params.len() == 2)
+        {
+            return Err(format!("no function {HOOK}(tx, account)"));
+        }
+        Ok(Self {
+            engine: Arc::new(engine),
+            ast,
+            source: source.to_string(),
+        })
+    }
+
+    /// Calls the hook on `transaction`, returning whether `account` is to be
+    /// flagged once the transaction is applied
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        account: &Account,
+    ) -> Result<bool, Rejection> {
+        let verdict = self
+            .engine
+            .call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &self.ast,
+                HOOK,
+                (transaction_map(transaction), account_map(account)),
+            )
+            .map_err(|_| Rejection::ScriptError)?;
+        if verdict.is_unit() {
+            return Ok(false);
+        }
+        match verdict.into_immutable_string().as_deref() {
+            Ok("flag") => Ok(true),
+            Ok("reject") => Err(Rejection::Script),
+            _ => Err(Rejection::ScriptError),
+        }
+    }
+}
+
+fn transaction_map(transaction: &Transaction) -> Map {
+    Map::from([
+        ("type".into(), transaction.ty.as_str().into()),
+        (
+            "client".into(),
+            // Only client IDs beyond `i64::MAX` wrap around
+            (transaction.client_id as rhai::INT).into(),
+        ),
+        ("tx".into(), rhai::INT::from(transaction.id).into()),
+        ("amount".into(), transaction.amount.into()),
+    ])
+}
+
+fn account_map(account: &Account) -> Map {
+    Map::from([
+        ("available".into(), account.available.into()),
+        ("held".into(), account.held.into()),
+        ("total".into(), account.total.into()),
+        ("locked".into(), account.locked.into()),
+        ("flagged".into(), account.flagged.into()),
+    ])
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::policy::Policy;
+    use crate::testing::tx;
+    use crate::transaction::TransactionType;
+
+    #[test]
+    fn it_vetoes_and_flags_transactions() {
+        let script = Script::compile(
+            r#"
+            fn on_transaction(tx, account) {
+                if tx.client == 2 {
+                    return "reject";
+                }
+                if tx.client == 3 {
+                    return "approve";
+                }
+                if tx.client == 4 {
+                    loop {}
+                }
+                if tx.type == "withdrawal" && tx.amount > account.available / 2.0 {
+                    return "flag";
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let mut engine = Engine::with_policy(Policy {
+            script: Some(script),
+            ..Policy::default()
+        });
+        use TransactionType::*;
+
+        assert_eq!(engine.process(&tx(Deposit, 1, 1, 10.0)), Ok(()));
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 2, 2.0)), Ok(()));
+        assert!(!engine.accounts()[&1].flagged);
+        assert_eq!(engine.process(&tx(Withdrawal, 1, 3, 6.0)), Ok(()));
+        assert!(engine.accounts()[&1].flagged);
+        assert_eq!(
+            engine.process(&tx(Deposit, 2, 4, 10.0)),
+            Err(Rejection::Script)
+        );
+        assert_eq!(
+            engine.process(&tx(Deposit, 3, 5, 10.0)),
+            Err(Rejection::ScriptError)
+        );
+        assert_eq!(
+            engine.process(&tx(Deposit, 4, 6, 10.0)),
+            Err(Rejection::ScriptError)
+        );
+
+        // A flag does not stick to a withdrawal that is rejected anyway
+        assert_eq!(engine.process(&tx(Deposit, 5, 7, 10.0)), Ok(()));
+        assert_eq!(
+            engine.process(&tx(Withdrawal, 5, 8, 20.0)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert!(!engine.accounts()[&5].flagged);
+
+        assert!(Script::compile("fn on_row(row) {}").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Processing of input handed over in memory rather than read from a file, for
+//! embedding the engine (see the `wasm`, `ffi`, `node`, and `python` features).
+//! Nothing in here does any I/O.
+
+use std::{fmt, mem};
+
+use crate::account::write_accounts;
+use crate::engine::Engine;
+use crate::report::Summary;
+use crate::transaction::{ParseOptions, Rejection, RowParser};
+
+const PARTNER_UNSUPPORTED: &str = "inputs with a partner column are not supported";
+
+/// Processes a complete CSV input and returns the resulting accounts as CSV.
+/// Rejected transactions are left out, like on the command line.
+pub fn process_csv(input: &str) -> Result<String, String> {
+    let mut session = Session::default();
+    session.push(input)?;
+    session.finish()?;
+    Ok(session.accounts_csv())
+}
+
+/// What became of a row handed to [`Session::process_row`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowOutcome {
+    Applied,
+    Rejected(Rejection),
+    /// The row was a header or contained no transaction
+    Skipped,
+}
+
+/// Processes an input handed over in chunks or rows, keeping the engine's state
+/// across calls. The first row may be a header, which then determines the order of
+/// the columns like it does for files.
+#[derive(Debug, Default, Clone)]
+pub struct Session {
+    engine: Engine,
+    options: ParseOptions,
+    /// Set once the first row was seen
+    parser: Option<RowParser>,
+    /// The start of a row whose end is still to come
+    pending: String,
+    line: u64,
+    summary: Summary,
+}
+
+impl Session {
+    /// Processes all transactions with `engine`, parsing rows with `options`.
+    /// Rows of the types the engine has handlers for are accepted as well.
+    pub fn new(engine: Engine, mut options: ParseOptions) -> Self {
+        options
+            .custom_types
+            .extend(engine.custom_types().map(String::from));
+        Self {
+            engine,
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Processes the complete rows in the next chunk of input. Rows may be split
+    /// across chunks. Fails on the first malformed row.
+    pub fn push(&mut self, chunk: &str) -> Result<(), String> {
+        self.pending.push_str(chunk);
+        let Some(end) = self.pending.rfind('\n') else {
+            return Ok(());
+        };
+        let rows: String = self.pending.drain(..=end).collect();
+        rows.lines()
+            .try_for_each(|row| self.process_row(row).map(|_| ()))
+    }
+
+    /// Processes the last row of an input that does not end with a newline
+    pub fn finish(&mut self) -> Result<(), String> {
+        let row = mem::take(&mut self.pending);
+        if row.is_empty() {
+            return Ok(());
+        }
+        self.process_row(&row).map(|_| ())
+    }
+
+    /// Processes a single row, with or without its line break
+    pub fn process_row(&mut self, row: &str) -> Result<RowOutcome, String> {
+        self.line += 1;
+        let line = self.line;
+        let malformed = |err| format!("{err} at line {line}");
+        let parser = match &mut self.parser {
+            Some(parser) => parser,
+            // Blank lines and comments may precede the header
+            None if self.options.is_ignored(row) => return Ok(RowOutcome::Skipped),
+            None => {
+                let (parser, is_header) =
+                    RowParser::from_first_row(row, self.options.clone()).map_err(malformed)?;
+                let parser = self.parser.insert(parser);
+                if is_header && !parser.has_partner_column() {
+                    return Ok(RowOutcome::Skipped);
+                }
+                parser
+            }
+        };
+        if parser.has_partner_column() {
+            return Err(PARTNER_UNSUPPORTED.to_string());
+        }
+
+        self.summary.rows_parsed += 1;
+        let Some(transaction) = parser.parse(row, &mut None).map_err(malformed)? else {
+            self.summary.rows_skipped += 1;
+            return Ok(RowOutcome::Skipped);
+        };
+        if let Some(timestamp) = parser.timestamp(row) {
+            self.engine.set_clock(timestamp);
+        }
+        let result = self.engine.process(&transaction);
+        self.summary.record(&transaction, result);
+        Ok(match result {
+            Ok(()) => RowOutcome::Applied,
+            Err(rejection) => RowOutcome::Rejected(rejection),
+        })
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn into_engine(self) -> Engine {
+        self.engine
+    }
+
+    /// The summary of the rows so far
+    pub fn summary(&self) -> Summary {
+        let mut summary = self.summary.clone();
+        summary.finish(&self.engine);
+        summary
+    }
+
+    /// Writes the accounts so far as CSV
+    pub fn write_accounts(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        write_accounts(self.engine.accounts(), out)
+    }
+
+    /// The accounts so far as CSV
+    pub fn accounts_csv(&self) -> String {
+        let mut string = String::new();
+        self.write_accounts(&mut string)
+            .expect("writing to a string cannot fail");
+        string
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_processes_chunks_like_a_complete_input() {
+        let input = "type, client, tx, amount\r\n\
+                     deposit, 1, 1, 5.0\r\n\
+                     withdrawal, 1, 2, 9.0\r\n\
+                     \r\n\
+                     deposit, 2, 3, 2.5\r\n\
+                     withdrawal, 1, 4, 1.5";
+        // Accounts come out in no particular order
+        let sorted = |accounts: String| {
+            let mut lines: Vec<String> = accounts.lines().map(String::from).collect();
+            lines.sort();
+            lines
+        };
+        let expected = sorted(process_csv(input).unwrap());
+
+        let mut session = Session::default();
+        for chunk in input.as_bytes().chunks(7) {
+            session.push(std::str::from_utf8(chunk).unwrap()).unwrap();
+        }
+        session.finish().unwrap();
+        assert_eq!(sorted(session.accounts_csv()), expected);
+
+        let summary = session.summary();
+        assert_eq!(summary.rows_parsed, 5);
+        assert_eq!(summary.rows_skipped, 1);
+        assert_eq!(summary.rejected[&Rejection::InsufficientFunds], 1);
+    }
+
+    #[test]
+    fn it_reports_the_outcome_of_each_row() {
+        let mut session = Session::default();
+        assert_eq!(
+            session.process_row("type,client,tx,amount"),
+            Ok(RowOutcome::Skipped)
+        );
+        assert_eq!(
+            session.process_row("deposit,1,1,5.0\n"),
+            Ok(RowOutcome::Applied)
+        );
+        assert_eq!(
+            session.process_row("withdrawal,1,2,9.0"),
+            Ok(RowOutcome::Rejected(Rejection::InsufficientFunds))
+        );
+        assert_eq!(
+            session.process_row("deposit,x,3,1.0"),
+            Err("invalid client ID at line 4".to_string())
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Load tests that feed generated transactions straight into the engine, so that
+//! capacity can be measured without writing and parsing huge inputs first.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::engine::Engine;
+use crate::report::Metrics;
+use crate::transaction::Transaction;
+
+/// When a simulation ends, whichever comes first
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimulationLimits {
+    pub rows: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+/// What a simulation measured
+#[derive(Debug, Clone)]
+pub struct SimulationReport {
+    pub metrics: Metrics,
+    pub applied: u64,
+    pub rejected: u64,
+    /// Transactions processed in the slowest full second, the throughput that
+    /// can be sustained rather than the average
+    pub slowest_second: Option<u64>,
+    pub accounts: usize,
+    /// Every account that violates an invariant at the end
+    pub violations: Vec<String>,
+}
+
+/// Processes `transactions` with `engine` until one of `limits` is reached, the
+/// transactions run out, or `stop` is set, and then checks the invariants of
+/// every account
+pub fn simulate(
+    engine: &mut Engine,
+    transactions: impl IntoIterator<Item = Transaction>,
+    limits: SimulationLimits,
+    stop: &AtomicBool,
+    allow_negative_available: bool,
+) -> SimulationReport {
+    let mut metrics = Metrics::start();
+    let (mut applied, mut rejected) = (0, 0);
+    let mut second = (Instant::now(), 0);
+    let mut slowest_second = None::<u64>;
+    for transaction in transactions {
+        if limits.rows.is_some_and(|rows| metrics.rows >= rows)
+            || limits.time.is_some_and(|time| metrics.wall_time() >= time)
+            || stop.load(Ordering::Relaxed)
+        {
+            break;
+        }
+        match Metrics::time(&mut metrics.process, || engine.process(&transaction)) {
+            Ok(()) => applied += 1,
+            Err(_) => rejected += 1,
+        }
+        metrics.rows += 1;
+        metrics.observe(engine);
+
+        second.1 += 1;
+        if second.0.elapsed() >= Duration::from_secs(1) {
+            slowest_second = Some(slowest_second.map_or(second.1, |slowest| slowest.min(second.1)));
+            second = (Instant::now(), 0);
+        }
+    }
+
+    let violations = engine
+        .accounts_sorted()
+        .filter_map(|(client_id, account)| {
+            let violation = account.check_invariants(allow_negative_available).err()?;
+            Some(format!("client {client_id}: {violation}"))
+        })
+        .collect();
+    SimulationReport {
+        metrics,
+        applied,
+        rejected,
+        slowest_second,
+        accounts: engine.accounts().len(),
+        violations,
+    }
+}
+
+impl SimulationReport {
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "wall_seconds": self.metrics.wall_time().as_secs_f64(),
+            "process_seconds": self.metrics.process.as_secs_f64(),
+            "transactions": self.metrics.rows,
+            "applied": self.applied,
+            "rejected": self.rejected,
+            "transactions_per_second": self.metrics.rows_per_second(),
+            "slowest_second": self.slowest_second,
+            "accounts": self.accounts,
+            "peak_transactions": self.metrics.peak_transactions,
+            "peak_memory_bytes": self.metrics.peak_memory,
+            "invariant_violations": self.violations.len(),
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "wall time: {:.3}s",
+            self.metrics.wall_time().as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "transactions: {} ({} applied, {} rejected)",
+            self.metrics.rows, self.applied, self.rejected
+        )?;
+        writeln!(
+            f,
+            "transactions per second: {:.0}",
+            self.metrics.rows_per_second()
+        )?;
+        if let Some(slowest_second) = self.slowest_second {
+            writeln!(f, "slowest second: {slowest_second} transactions")?;
+        }
+        writeln!(f, "accounts: {}", self.accounts)?;
+        writeln!(
+            f,
+            "peak transaction index size: {}",
+            self.metrics.peak_transactions
+        )?;
+        writeln!(f, "peak memory: ~{} bytes", self.metrics.peak_memory)?;
+        writeln!(f, "invariant violations: {}", self.violations.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{GeneratorOptions, TransactionStream};
+
+    #[test]
+    fn it_simulates_workloads() {
+        let mut engine = Engine::default();
+        let options = GeneratorOptions::default();
+        let limits = SimulationLimits {
+            rows: Some(10_000),
+            time: None,
+        };
+        let stop = AtomicBool::new(false);
+        let report = simulate(
+            &mut engine,
+            TransactionStream::new(1, options),
+            limits,
+            &stop,
+            true,
+        );
+        assert_eq!(report.metrics.rows, 10_000);
+        assert_eq!(report.applied + report.rejected, 10_000);
+        assert!(report.applied > 9_000);
+        assert_eq!(report.accounts, 1000);
+        assert!(report.metrics.peak_memory > 0);
+        assert_eq!(report.violations, Vec::<String>::new());
+
+        stop.store(true, Ordering::Relaxed);
+        let report = simulate(
+            &mut engine,
+            TransactionStream::new(1, options),
+            limits,
+            &stop,
+            true,
+        );
+        assert_eq!(report.metrics.rows, 0);
+    }
+}
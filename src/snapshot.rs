@@ -0,0 +1,143 @@
+//! Snapshots of the accounts written while a run is still reading its input,
+//! like a stream on stdin that has no end, so that other programs can follow
+//! the run by reading a file. Given a key, snapshots are encrypted like state
+//! documents.
+
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::crypto::StateKey;
+
+/// When snapshots are due
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SnapshotInterval {
+    /// Rows read since the last snapshot
+    pub rows: Option<u64>,
+    /// Time passed since the last snapshot
+    pub time: Option<Duration>,
+}
+
+/// Snapshots written to one path, each replacing the one before
+pub struct Snapshots {
+    path: PathBuf,
+    interval: SnapshotInterval,
+    key: Option<StateKey>,
+    last_rows: u64,
+    last_written: Instant,
+}
+
+impl Snapshots {
+    pub fn new(path: PathBuf, interval: SnapshotInterval, key: Option<StateKey>) -> Self {
+        Self {
+            path,
+            interval,
+            key,
+            last_rows: 0,
+            last_written: Instant::now(),
+        }
+    }
+
+    /// Whether a snapshot is due after `rows` rows were read. Time is only
+    /// checked as rows arrive, since the accounts do not change in between.
+    pub fn is_due(&self, rows: u64) -> bool {
+        rows > self.last_rows
+            && (self
+                .interval
+                .rows
+                .is_some_and(|every| rows - self.last_rows >= every)
+                || self
+                    .interval
+                    .time
+                    .is_some_and(|every| self.last_written.elapsed() >= every))
+    }
+
+    /// Replaces the snapshot with what `write` writes, taken after `rows` rows were
+    /// read
+    pub fn write(
+        &mut self,
+        rows: u64,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> Result<(), String> {
+        write_atomically(&self.path, |file| match &self.key {
+            Some(key) => {
+                let mut snapshot = Vec::new();
+                write(&mut snapshot)?;
+                file.write_all(&key.encrypt(&snapshot))
+            }
+            None => write(file),
+        })
+        .map_err(|err| format!("could not write snapshot: {err}"))?;
+        self.last_rows = rows;
+        self.last_written = Instant::now();
+        Ok(())
+    }
+}
+
+/// Writes to a temporary file next to `path` with `write` and renames it to
+/// `path`, so that readers see either the old or the new contents in full
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<fs::File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = BufWriter::new(fs::File::create(&temporary)?);
+    write(&mut file)?;
+    file.flush()?;
+    drop(file);
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn it_writes_snapshots_when_due() {
+        let path = TempPath::new("snapshot.csv");
+        let mut snapshots = Snapshots::new(
+            path.to_path_buf(),
+            SnapshotInterval {
+                rows: Some(100),
+                time: None,
+            },
+            None,
+        );
+        assert!(!snapshots.is_due(99));
+        assert!(snapshots.is_due(100));
+        snapshots
+            .write(100, |file| file.write_all(b"client\n1\n"))
+            .unwrap();
+        assert!(!snapshots.is_due(150));
+        assert!(snapshots.is_due(200));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "client\n1\n");
+
+        snapshots.interval.time = Some(Duration::ZERO);
+        assert!(!snapshots.is_due(100));
+        assert!(snapshots.is_due(101));
+    }
+
+    #[test]
+    fn it_encrypts_snapshots_given_a_key() {
+        let path = TempPath::new("encrypted-snapshot.csv");
+        let key = StateKey::from_bytes(&[7; 32]).unwrap();
+        let mut snapshots = Snapshots::new(
+            path.to_path_buf(),
+            SnapshotInterval {
+                rows: Some(1),
+                time: None,
+            },
+            Some(key.clone()),
+        );
+        snapshots
+            .write(1, |file| file.write_all(b"client\n1\n"))
+            .unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(key.decrypt(&bytes).unwrap(), b"client\n1\n");
+    }
+}
@@ -0,0 +1,246 @@
+//! A human-readable JSON representation of the complete engine state,
+//! used to inspect, hand-edit, and migrate state between versions.
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::{Account, Activity};
+use crate::engine::{Counters, Engine};
+use crate::transaction::{ClientID, ProcessedTransaction, TransactionID};
+
+/// Bumped whenever the document layout changes in a backwards-incompatible way
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateDocument {
+    version: u32,
+    counters: Counters,
+    accounts: Vec<AccountEntry>,
+    transactions: Vec<TransactionEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountEntry {
+    client: ClientID,
+    #[serde(flatten)]
+    account: Account,
+    #[serde(default, skip_serializing_if = "Activity::is_empty")]
+    activity: Activity,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionEntry {
+    tx: TransactionID,
+    #[serde(flatten)]
+    transaction: ProcessedTransaction,
+}
+
+pub fn export_state(engine: &Engine) -> String {
+    // Sorted so that documents are stable and diffable
+    let mut accounts: Vec<AccountEntry> = engine
+        .accounts
+        .iter()
+        .map(|(client, account)| AccountEntry {
+            client: *client,
+            account: account.clone(),
+            activity: engine.activity().get(*client).cloned().unwrap_or_default(),
+        })
+        .collect();
+    accounts.sort_by_key(|entry| entry.client);
+
+    let mut transactions: Vec<TransactionEntry> = engine
+        .transactions
+        .iter()
+        .map(|(tx, transaction)| TransactionEntry {
+            tx,
+            transaction: transaction.clone(),
+        })
+        .collect();
+    transactions.sort_by_key(|entry| (entry.tx, entry.transaction.client_id));
+
+    let document = StateDocument {
+        version: STATE_VERSION,
+        counters: engine.counters.clone(),
+        accounts,
+        transactions,
+    };
+
+    let mut string =
+        serde_json::to_string_pretty(&document).expect("state document is always serializable");
+    string.push('\n');
+    string
+}
+
+pub fn import_state(input: &str) -> Result<Engine, String> {
+    let document: StateDocument =
+        serde_json::from_str(input).map_err(|err| format!("invalid state document: {err}"))?;
+
+    if document.version > STATE_VERSION {
+        return Err(format!(
+            "state document version {} is newer than the supported version {}",
+            document.version, STATE_VERSION
+        ));
+    }
+
+    let mut engine = Engine {
+        counters: document.counters,
+        ..Engine::default()
+    };
+    for entry in document.accounts {
+        if !entry.activity.is_empty() {
+            // Kept up to date, as documents with activity were tracking it
+            engine.track_activity();
+            engine.clients.entry(entry.client).or_default().activity = entry.activity;
+        }
+        if engine
+            .accounts
+            .insert(entry.client, entry.account)
+            .is_some()
+        {
+            return Err(format!("duplicate account for client {}", entry.client));
+        }
+    }
+    for entry in document.transactions {
+        let client = entry.transaction.client_id;
+        // Applying a transaction always creates its account, and clearing relies on it
+        if !engine.accounts.contains_key(&client) {
+            return Err(format!(
+                "transaction {} of client {client} has no account",
+                entry.tx
+            ));
+        }
+        if engine
+            .transactions
+            .insert(entry.tx, entry.transaction)
+            .is_some()
+        {
+            return Err(format!(
+                "duplicate transaction {} of client {client}",
+                entry.tx
+            ));
+        }
+    }
+    let mut clearing: Vec<(u64, ClientID, TransactionID)> = engine
+        .transactions
+        .iter()
+        .filter_map(|(id, transaction)| Some((transaction.clears_at?, transaction.client_id, id)))
+        .collect();
+    clearing.sort_unstable();
+    engine.clearing = clearing.into();
+
+    Ok(engine)
+}
+
+/// Checks that `engine` holds exactly the accounts and transactions of `snapshot`.
+/// Counters are not compared as they depend on how the state was arrived at.
+pub fn verify(engine: &Engine, snapshot: &Engine) -> Result<(), String> {
+    let mut clients: Vec<&ClientID> = engine
+        .accounts
+        .keys()
+        .chain(snapshot.accounts.keys())
+        .collect();
+    clients.sort();
+    clients.dedup();
+    let mut tx_ids: Vec<(ClientID, TransactionID)> = engine
+        .transactions
+        .iter()
+        .chain(snapshot.transactions.iter())
+        .map(|(tx, transaction)| (transaction.client_id, tx))
+        .collect();
+    tx_ids.sort();
+    tx_ids.dedup();
+
+    let mismatches: Vec<String> = clients
+        .into_iter()
+        .filter(|client| engine.accounts.get(client) != snapshot.accounts.get(client))
+        .map(|client| format!("account {client}"))
+        .chain(
+            tx_ids
+                .into_iter()
+                .filter(|(client, tx)| {
+                    engine.transactions.get(*client, *tx) != snapshot.transactions.get(*client, *tx)
+                })
+                .map(|(client, tx)| format!("transaction {tx} of client {client}")),
+        )
+        .collect();
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "state does not match snapshot: {}",
+            mismatches.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::transaction::{parse_transactions, DisputeState};
+
+    #[test]
+    fn it_roundtrips_state() {
+        let transactions_string = "type,    client, tx, amount\n\
+                                   deposit, 1,      1,  5.0\n\
+                                   deposit, 2,      2,  3.0\n\
+                                   dispute, 2,      2\n\
+                                   ";
+        let mut engine = Engine::default();
+        for transaction in parse_transactions(io::Cursor::new(transactions_string)).unwrap() {
+            engine.process(&transaction).unwrap();
+        }
+
+        let exported = export_state(&engine);
+        let imported = import_state(&exported).unwrap();
+
+        assert_eq!(imported.counters, engine.counters);
+        assert_eq!(imported.accounts(), engine.accounts());
+        assert_eq!(
+            imported.transactions[(2, 2)].dispute_state,
+            DisputeState::Disputed
+        );
+        assert_eq!(export_state(&imported), exported);
+        assert!(verify(&imported, &engine).is_ok());
+
+        engine.accounts.get_mut(&1).unwrap().locked = true;
+        assert!(verify(&imported, &engine).is_err());
+    }
+
+    #[test]
+    fn it_rejects_newer_versions() {
+        let document = format!(
+            r#"{{"version": {}, "counters": {{"processed": 0}}, "accounts": [], "transactions": []}}"#,
+            STATE_VERSION + 1
+        );
+        assert!(import_state(&document).is_err());
+    }
+
+    #[test]
+    fn it_only_imports_built_in_types() {
+        let document = |ty| {
+            format!(
+                r#"{{"version": {STATE_VERSION}, "counters": {{"processed": 1}},
+                    "accounts": [{{"client": 1, "available": 5.0, "held": 0.0, "total": 5.0, "locked": false}}],
+                    "transactions": [{{"tx": 1, "type": "{ty}", "client": 1, "amount": 5.0, "dispute": "undisputed"}}]}}"#
+            )
+        };
+        assert!(import_state(&document("deposit")).is_ok());
+        assert!(import_state(&document("fee")).is_err());
+        assert!(import_state(&document("Deposit")).is_err());
+    }
+
+    #[test]
+    fn it_requires_an_account_for_every_transaction() {
+        let document = |client| {
+            format!(
+                r#"{{"version": {STATE_VERSION}, "counters": {{"processed": 1}},
+                    "accounts": [{{"client": 1, "available": 0.0, "held": 5.0, "total": 5.0, "locked": false}}],
+                    "transactions": [{{"tx": 1, "type": "deposit", "client": {client}, "amount": 5.0, "dispute": "undisputed", "clears_at": 10}}]}}"#
+            )
+        };
+        assert!(import_state(&document(1)).is_ok());
+        assert!(import_state(&document(2)).is_err());
+    }
+}
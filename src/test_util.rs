@@ -0,0 +1,153 @@
+//! Proptest strategies for sequences of transactions and a check of the
+//! invariants every account keeps, for property-testing code that embeds the
+//! engine against the same invariants as `--check-invariants`.
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn accounts_stay_consistent(transactions in adversarial_transactions(5, 100)) {
+//!         let mut engine = Engine::default();
+//!         for transaction in &transactions {
+//!             let _ = engine.process(transaction);
+//!         }
+//!         prop_assert_eq!(check_invariants(&engine, true), Ok(()));
+//!     }
+//! }
+//! ```
+
+use proptest::{prelude::*, sample::Index};
+
+use crate::engine::Engine;
+use crate::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+
+/// Positive amounts up to 10000 in steps of 0.25. Sums of these are exact in
+/// an `f32` up to about four million, so that a broken invariant points to the
+/// logic of the engine rather than to rounding.
+pub fn amount() -> impl Strategy<Value = f32> {
+    (1u16..=40_000).prop_map(|quarters| f32::from(quarters) / 4.0)
+}
+
+/// The built-in transaction types
+pub fn transaction_type() -> impl Strategy<Value = TransactionType> {
+    prop_oneof![
+        Just(TransactionType::Deposit),
+        Just(TransactionType::Withdrawal),
+        Just(TransactionType::Dispute),
+        Just(TransactionType::Resolve),
+        Just(TransactionType::Chargeback),
+    ]
+}
+
+/// Up to `len` transactions of clients below `clients` that a well-behaved
+/// partner could send: deposits and withdrawals have unique, increasing IDs,
+/// and disputes, resolves, and chargebacks refer to an earlier deposit of the
+/// same client. They may still be rejected, like withdrawals without funds.
+pub fn valid_transactions(
+    clients: ClientID,
+    len: usize,
+) -> impl Strategy<Value = Vec<Transaction>> {
+    let step = (
+        transaction_type(),
+        0..clients.max(1),
+        amount(),
+        any::<Index>(),
+    );
+    prop::collection::vec(step, 0..=len).prop_map(|steps| {
+        let mut deposits: Vec<(ClientID, TransactionID)> = Vec::new();
+        let mut next_id: TransactionID = 1;
+        let mut transactions = Vec::with_capacity(steps.len());
+        for (ty, client_id, amount, index) in steps {
+            let referenced = ty
+                .refers_back()
+                .then(|| {
+                    let of_client: Vec<_> = deposits
+                        .iter()
+                        .filter(|(client, _)| *client == client_id)
+                        .collect();
+                    (!of_client.is_empty()).then(|| **index.get(&of_client))
+                })
+                .flatten();
+            let transaction = match referenced {
+                Some((client_id, id)) => Transaction {
+                    ty,
+                    client_id,
+                    id,
+                    amount: 0.0,
+                },
+                None => {
+                    let ty = match ty {
+                        TransactionType::Withdrawal => TransactionType::Withdrawal,
+                        _ => TransactionType::Deposit,
+                    };
+                    let id = next_id;
+                    next_id += 1;
+                    if ty == TransactionType::Deposit {
+                        deposits.push((client_id, id));
+                    }
+                    Transaction {
+                        ty,
+                        client_id,
+                        id,
+                        amount,
+                    }
+                }
+            };
+            transactions.push(transaction);
+        }
+        transactions
+    })
+}
+
+/// Up to `len` transactions of clients below `clients` that still parse, but
+/// that the engine has to reject or handle with care: reused IDs, references to
+/// transactions of other clients or that never happened, resolves and
+/// chargebacks without a dispute, withdrawals beyond the funds, and anything
+/// after a chargeback locked the account. IDs are drawn from a small range so
+/// that such collisions are common.
+pub fn adversarial_transactions(
+    clients: ClientID,
+    len: usize,
+) -> impl Strategy<Value = Vec<Transaction>> {
+    let id = 1..=(len.max(1) as TransactionID / 2 + 1);
+    let transaction = (transaction_type(), 0..clients.max(1), id, amount()).prop_map(
+        |(ty, client_id, id, amount)| Transaction {
+            amount: if ty.refers_back() { 0.0 } else { amount },
+            ty,
+            client_id,
+            id,
+        },
+    );
+    prop::collection::vec(transaction, 0..=len)
+}
+
+/// Checks the invariants of every account of `engine`, as with
+/// `--check-invariants`. Negative available funds are accepted with
+/// `allow_negative_available`, since disputing funds that were already
+/// withdrawn legitimately leads to them.
+pub fn check_invariants(engine: &Engine, allow_negative_available: bool) -> Result<(), String> {
+    for (client_id, account) in engine.accounts_sorted() {
+        account
+            .check_invariants(allow_negative_available)
+            .map_err(|err| format!("client {client_id}: {err}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn it_keeps_the_invariants(
+            valid in valid_transactions(4, 100),
+            adversarial in adversarial_transactions(4, 100),
+        ) {
+            let mut engine = Engine::default();
+            for transaction in valid.iter().chain(&adversarial) {
+                let _ = engine.process(transaction);
+                prop_assert_eq!(check_invariants(&engine, true), Ok(()));
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Helpers shared by the tests of all modules
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use crate::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+
+pub fn tx(ty: TransactionType, client_id: ClientID, id: TransactionID, amount: f32) -> Transaction {
+    Transaction {
+        ty,
+        client_id,
+        id,
+        amount,
+    }
+}
+
+/// A path in the temporary directory that is unique to `name` and the test
+/// process. Whatever is created there is removed again once this is dropped.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("{}-{name}", std::process::id())))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        // Nothing may have been created, so errors are expected
+        let _ = match self.0.is_dir() {
+            true => fs::remove_dir_all(&self.0),
+            false => fs::remove_file(&self.0),
+        };
+    }
+}
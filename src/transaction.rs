@@ -1,159 +1,1977 @@
-use std::{collections::HashMap, io};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, io, mem,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use csv::StringRecord;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::account::Account;
+use crate::assertion::Assertion;
 
 pub type TransactionID = u32;
+/// 16 bits wide, unless built with the `client-id-u32` or `client-id-u64` feature
+#[cfg(not(any(feature = "client-id-u32", feature = "client-id-u64")))]
 pub type ClientID = u16;
+#[cfg(all(feature = "client-id-u32", not(feature = "client-id-u64")))]
+pub type ClientID = u32;
+#[cfg(feature = "client-id-u64")]
+pub type ClientID = u64;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Transaction {
+    #[serde(rename = "type", deserialize_with = "deserialize_any_type")]
     pub ty: TransactionType,
+    #[serde(rename = "client")]
     pub client_id: ClientID,
+    #[serde(rename = "tx")]
     pub id: TransactionID,
     pub amount: f32,
 }
 
 impl Transaction {
-    fn parse(input: &str) -> Result<Option<Self>, &'static str> {
-        let mut columns = input.split(',');
+    /// Parses the fields of a row, setting `warning` if it was accepted despite a
+    /// problem
+    fn parse(
+        record: &StringRecord,
+        columns: &Columns,
+        options: &ParseOptions,
+        ids: &mut InternedIds,
+        warning: &mut Option<&'static str>,
+    ) -> Result<Option<Self>, &'static str> {
+        if options.is_ignored_record(record) {
+            return Ok(None);
+        }
+        let (mut type_str, mut client_str, mut id_str, mut amount_str) = (None, None, None, None);
+        let mut currency_str = None;
+        for (index, field) in record.iter().enumerate() {
+            let field = Some(field);
+            if index == columns.ty {
+                type_str = field;
+            } else if index == columns.client {
+                client_str = field;
+            } else if index == columns.tx {
+                id_str = field;
+            } else if Some(index) == columns.amount {
+                amount_str = field;
+            } else if Some(index) == columns.currency {
+                currency_str = field;
+            }
+        }
 
-        let transaction_ty = if let Some(type_str) = columns.next() {
-            let trimmed_type_str = type_str.trim();
-            if trimmed_type_str.is_empty() {
+        let transaction_ty = if let Some(type_str) = type_str {
+            if type_str.is_empty() {
                 return Ok(None);
             }
-            if let Ok(ty) = TransactionType::try_from(type_str.trim()) {
+            if let Ok(ty) = TransactionType::try_from(type_str) {
                 ty
+            } else if let Some(ty) = (!options.type_aliases.is_empty())
+                .then(|| options.type_aliases.get(&type_str.to_ascii_lowercase()))
+                .flatten()
+            {
+                *ty
+            } else if options.custom_types.contains(type_str) {
+                TransactionType::Custom(CustomType::new(type_str))
             } else {
                 return Err("invalid transaction type");
             }
+        } else if record.iter().all(str::is_empty) {
+            return Ok(None);
         } else {
             return Err("no transaction type");
         };
 
+        if let (Some(amount), true) = (amount_str, options.strip_currency) {
+            let (amount, currency) = strip_currency(amount);
+            if let (Some(currency), Some(column)) = (currency, currency_str) {
+                if !column.is_empty() && !column.eq_ignore_ascii_case(currency) {
+                    return Err("amount is not in the currency of the currency column");
+                }
+            }
+            // A currency without a number, like `$`, is no amount either
+            amount_str = Some(amount);
+        }
+
+        if transaction_ty.refers_back() && amount_str.is_some_and(|amount| !amount.is_empty()) {
+            const MESSAGE: &str = "amount on a dispute, resolve, or chargeback";
+            match options.dispute_amounts {
+                AmountPolicy::Ignore => {}
+                AmountPolicy::Warn => *warning = Some(MESSAGE),
+                AmountPolicy::Reject => return Err(MESSAGE),
+            }
+            // The amount is taken from the referenced transaction instead
+            amount_str = None;
+        }
+
+        let has_amount = amount_str.is_some_and(|amount| !amount.is_empty());
+        if !has_amount
+            && matches!(
+                transaction_ty,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            match options.missing_amounts {
+                MissingAmountPolicy::Reject => return Err("no amount"),
+                MissingAmountPolicy::Zero => {}
+                MissingAmountPolicy::Skip => return Ok(None),
+            }
+        }
+
         let transaction = Transaction {
             ty: transaction_ty,
-            client_id: columns
-                .next()
+            client_id: client_str
                 .ok_or("no client ID")?
-                .trim()
                 .parse::<ClientID>()
                 .map_err(|_| "invalid client ID")?,
-            id: columns
-                .next()
-                .ok_or("no transaction ID")?
-                .trim()
-                .parse::<TransactionID>()
-                .map_err(|_| "invalid transaction ID")?,
-            amount: columns
-                .next()
-                .map(|amount| amount.trim().parse::<f32>().unwrap_or(0.0))
-                .unwrap_or(0.0),
+            id: match (id_str.ok_or("no transaction ID")?, options.ids) {
+                ("", IdFormat::String) => return Err("invalid transaction ID"),
+                (id, IdFormat::String) => ids.intern(id)?,
+                (id, IdFormat::Numeric) => id
+                    .parse::<TransactionID>()
+                    .map_err(|_| "invalid transaction ID")?,
+            },
+            amount: match amount_str {
+                Some(amount) if !amount.is_empty() => parse_amount(amount, options)?,
+                _ => 0.0,
+            },
         };
 
         Ok(Some(transaction))
     }
 
+    /// Applies this transaction to `account`, or leaves everything untouched and
+    /// returns the reason if it cannot be applied. Whether a chargeback locks the
+    /// account is up to the engine's [`LockPolicy`](crate::policy::LockPolicy).
     pub fn process(
         &self,
         account: &mut Account,
-        past_transactions: &HashMap<TransactionID, Transaction>,
-    ) {
+        past_transactions: &mut HashMap<TransactionID, ProcessedTransaction>,
+    ) -> Result<(), Rejection> {
         use TransactionType::*;
 
         match self.ty {
             Deposit => {
                 account.available += self.amount;
                 account.total += self.amount;
+                past_transactions.insert(self.id, ProcessedTransaction::new(self));
             }
             Withdrawal => {
                 let result = account.available - self.amount;
 
                 if result > 0.0 {
                     account.available = result;
-                    account.total = result;
+                    account.total -= self.amount;
+                    past_transactions.insert(self.id, ProcessedTransaction::new(self));
+                } else {
+                    return Err(Rejection::InsufficientFunds);
                 }
             }
+            // For the following we will assume any rejection is an error on the partner's side
             Dispute => {
                 debug_assert_eq!(self.amount, 0.0);
-                if let Some(transaction) = past_transactions.get(&self.id) {
+                let transaction = self.referenced(past_transactions, DisputeState::Undisputed)?;
+                // The funds of a deposit that has not cleared yet are already held
+                if transaction.clears_at.is_none() {
                     let disputed_amount = transaction.amount;
                     account.available -= disputed_amount;
                     account.held += disputed_amount;
-                } else {
-                    // We will assume this is an error on the partner's side
                 }
+                transaction.dispute_state = DisputeState::Disputed;
             }
             Resolve => {
                 debug_assert_eq!(self.amount, 0.0);
-                if let Some(transaction) = past_transactions.get(&self.id) {
-                    if transaction.ty == TransactionType::Dispute {
-                        let non_disputed_amount = transaction.amount;
-                        account.held -= non_disputed_amount;
-                        account.available += non_disputed_amount;
-                    }
+                let transaction = self.referenced(past_transactions, DisputeState::Disputed)?;
+                if transaction.clears_at.is_none() {
+                    let non_disputed_amount = transaction.amount;
+                    account.held -= non_disputed_amount;
+                    account.available += non_disputed_amount;
                 }
-                // Otherwise we will assume this is an error on the partner's side
+                transaction.dispute_state = DisputeState::Undisputed;
             }
             Chargeback => {
                 debug_assert_eq!(self.amount, 0.0);
-                if let Some(transaction) = past_transactions.get(&self.id) {
-                    if transaction.ty == TransactionType::Dispute {
-                        let disputed_amount = transaction.amount;
-                        account.held -= disputed_amount;
-                        account.total -= disputed_amount;
-                        account.locked = true;
-                    }
-                }
-                // Otherwise we will assume this is an error on the partner's side
+                let transaction = self.referenced(past_transactions, DisputeState::Disputed)?;
+                let disputed_amount = transaction.amount;
+                account.held -= disputed_amount;
+                account.total -= disputed_amount;
+                transaction.dispute_state = DisputeState::ChargedBack;
             }
+            // Left to the engine's handlers
+            Custom(_) => return Err(Rejection::UnknownType),
         }
+
+        Ok(())
+    }
+
+    /// Looks up the transaction a dispute, resolve, or chargeback refers to,
+    /// which must currently be in the `expected` dispute state.
+    fn referenced<'a>(
+        &self,
+        past_transactions: &'a mut HashMap<TransactionID, ProcessedTransaction>,
+        expected: DisputeState,
+    ) -> Result<&'a mut ProcessedTransaction, Rejection> {
+        let transaction = past_transactions
+            .get_mut(&self.id)
+            .ok_or(Rejection::UnknownTransaction)?;
+        if transaction.client_id != self.client_id {
+            return Err(Rejection::ClientMismatch);
+        }
+        if transaction.dispute_state != expected {
+            return Err(Rejection::InvalidDisputeState);
+        }
+        Ok(transaction)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Why a transaction was not applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    /// A withdrawal exceeding the available funds
+    InsufficientFunds,
+    /// A dispute, resolve, or chargeback referring to a transaction that was never processed
+    UnknownTransaction,
+    /// A dispute, resolve, or chargeback referring to another client's transaction.
+    /// The engine only looks among the transactions of the client itself, so it
+    /// rejects these as [`Rejection::UnknownTransaction`].
+    ClientMismatch,
+    /// In strict mode, a dispute, resolve, or chargeback referring to a transaction
+    /// that did not appear within the lookahead window
+    ReferenceNeverSeen,
+    /// A dispute of an already disputed transaction, or a resolve or chargeback
+    /// of a transaction that is not under dispute
+    InvalidDisputeState,
+    /// A withdrawal exceeding the velocity limit
+    VelocityLimit,
+    /// A transaction of a blocklisted client
+    Blocked,
+    /// A withdrawal that would take the available funds below the minimum balance
+    MinimumBalance,
+    /// A deposit or withdrawal exceeding the client's daily limit
+    DailyLimit,
+    /// A deposit or withdrawal beyond the limits of the client's level of verification
+    KycLimit,
+    /// A transaction of a custom type without a handler
+    UnknownType,
+    /// A transaction matching a rule that rejects it
+    Rule,
+    /// A transaction vetoed by the script
+    Script,
+    /// A transaction the script failed on or returned an unknown value for
+    ScriptError,
+    /// A transaction of a client missing from the allowlist
+    NotAllowed,
+}
+
+impl Rejection {
+    /// A stable identifier for reports and logs
+    pub fn code(&self) -> &'static str {
+        use Rejection::*;
+
+        match self {
+            InsufficientFunds => "insufficient_funds",
+            UnknownTransaction => "unknown_transaction",
+            ClientMismatch => "client_mismatch",
+            ReferenceNeverSeen => "reference_never_seen",
+            InvalidDisputeState => "invalid_dispute_state",
+            VelocityLimit => "velocity_limit",
+            Blocked => "blocked",
+            MinimumBalance => "minimum_balance",
+            DailyLimit => "daily_limit",
+            KycLimit => "kyc_limit",
+            UnknownType => "unknown_type",
+            Rule => "rule",
+            Script => "script",
+            ScriptError => "script_error",
+            NotAllowed => "not_allowed",
+        }
+    }
+}
+
+/// A deposit or withdrawal that has been applied to an account and can
+/// therefore be referred to by later disputes, resolves, and chargebacks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessedTransaction {
+    #[serde(rename = "type")]
+    pub ty: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: ClientID,
+    pub amount: f32,
+    #[serde(rename = "dispute")]
+    pub dispute_state: DisputeState,
+    /// For a deposit whose funds are still held until it clears, when that happens
+    /// (see [`ClearingPeriod`](crate::policy::ClearingPeriod))
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clears_at: Option<u64>,
+}
+
+impl ProcessedTransaction {
+    fn new(transaction: &Transaction) -> Self {
+        Self {
+            ty: transaction.ty,
+            client_id: transaction.client_id,
+            amount: transaction.amount,
+            dispute_state: DisputeState::Undisputed,
+            clears_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    Undisputed,
+    Disputed,
+    ChargedBack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// A type applied by a [`TransactionHandler`](crate::handler::TransactionHandler)
+    Custom(CustomType),
+}
+
+/// The name of a custom transaction type, stored once for the whole process so
+/// that transaction types stay small enough to be copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CustomType(u32);
+
+/// The names of the custom types, by their number
+static CUSTOM_TYPES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+impl CustomType {
+    /// The custom type named `name`, which is the same for every call with that name
+    pub fn new(name: &str) -> Self {
+        let mut names = CUSTOM_TYPES.lock().expect("custom types were poisoned");
+        let index = match names.iter().position(|known| *known == name) {
+            Some(index) => index,
+            None => {
+                // Only as many names are kept as there are custom types in use
+                names.push(Box::leak(name.into()));
+                names.len() - 1
+            }
+        };
+        Self(index as u32)
+    }
+
+    /// The custom type named `name` if one was created before
+    pub fn find(name: &str) -> Option<Self> {
+        let names = CUSTOM_TYPES.lock().expect("custom types were poisoned");
+        let index = names.iter().position(|known| *known == name)?;
+        Some(Self(index as u32))
+    }
+
+    pub fn name(self) -> &'static str {
+        CUSTOM_TYPES.lock().expect("custom types were poisoned")[self.0 as usize]
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for CustomType {
+    fn arbitrary(unstructured: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Arbitrary names would be kept forever
+        Ok(Self::new(unstructured.choose(&["fee", "bonus", "refund"])?))
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Only accepts the built-in types, which are the only ones that are indexed
+/// or can be named in rules
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        match TransactionType::try_from(name.as_str()) {
+            Ok(ty) if ty.as_str() == name => Ok(ty),
+            _ => Err(serde::de::Error::unknown_variant(
+                &name,
+                &["deposit", "withdrawal", "dispute", "resolve", "chargeback"],
+            )),
+        }
+    }
+}
+
+/// Deserializes the type of a transaction, which may be a custom one that a
+/// handler was registered for. Other names are not kept, as the input may be untrusted.
+fn deserialize_any_type<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TransactionType, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match TransactionType::try_from(name.as_str()) {
+        Ok(ty) => Ok(ty),
+        Err(()) => CustomType::find(&name)
+            .map(TransactionType::Custom)
+            .ok_or_else(|| serde::de::Error::custom("invalid transaction type")),
+    }
+}
+
+impl TransactionType {
+    /// Whether this type refers back to a previous deposit or withdrawal
+    /// instead of carrying an amount
+    pub fn refers_back(&self) -> bool {
+        use TransactionType::*;
+
+        matches!(self, Dispute | Resolve | Chargeback)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        use TransactionType::*;
+
+        match self {
+            Deposit => "deposit",
+            Withdrawal => "withdrawal",
+            Dispute => "dispute",
+            Resolve => "resolve",
+            Chargeback => "chargeback",
+            Custom(ty) => ty.name(),
+        }
+    }
 }
 
 impl TryFrom<&str> for TransactionType {
     type Error = ();
 
+    /// Ignores case, so that `Deposit` and `DEPOSIT` are deposits as well
     fn try_from(other: &str) -> Result<Self, Self::Error> {
         use TransactionType::*;
 
-        Ok(match other {
-            "deposit" => Deposit,
-            "withdrawal" => Withdrawal,
-            "dispute" => Dispute,
-            "resolve" => Resolve,
-            "chargeback" => Chargeback,
-            _ => return Err(()),
+        [Deposit, Withdrawal, Dispute, Resolve, Chargeback]
+            .into_iter()
+            .find(|ty| ty.as_str().eq_ignore_ascii_case(other))
+            .ok_or(())
+    }
+}
+
+/// Parses a TOML table of alternative names for transaction types, like
+/// `withdraw = "withdrawal"`, for [`ParseOptions::type_aliases`]
+pub fn parse_type_aliases(text: &str) -> Result<HashMap<String, TransactionType>, String> {
+    let aliases: HashMap<String, String> = toml::from_str(text).map_err(|err| err.to_string())?;
+    aliases
+        .into_iter()
+        .map(|(alias, ty)| {
+            let ty = TransactionType::try_from(ty.as_str())
+                .map_err(|_| format!("alias {alias} is for unknown transaction type {ty}"))?;
+            Ok((alias.to_ascii_lowercase(), ty))
         })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// What to do with rows of types that refer back to another transaction
+    /// but still carry an amount
+    pub dispute_amounts: AmountPolicy,
+    /// What to do with deposits and withdrawals without an amount
+    pub missing_amounts: MissingAmountPolicy,
+    /// Accept a leading `+` or `-` on amounts
+    pub signed_amounts: bool,
+    /// Accept amounts in scientific notation, like `1.5e3`
+    pub scientific_amounts: bool,
+    /// Maximum number of digits before the decimal point
+    pub max_integer_digits: usize,
+    /// Maximum number of digits after the decimal point
+    pub max_fraction_digits: usize,
+    /// Types of rows to accept besides the built-in ones, for engines with a
+    /// [`TransactionHandler`](crate::handler::TransactionHandler) for them
+    pub custom_types: HashSet<String>,
+    /// The character encoding of the input
+    pub encoding: Encoding,
+    /// Alternative names of types, in lowercase, which are matched ignoring case
+    pub type_aliases: HashMap<String, TransactionType>,
+    /// Skip rows starting with this, like `#`, as comments
+    pub comment_prefix: Option<String>,
+    /// Accept amounts with a currency symbol or code, like `$10.50` or
+    /// `"10.50 EUR"`, which must match the `currency` column if there is one
+    pub strip_currency: bool,
+    /// Accept `,` or `_` between groups of three digits before the decimal point,
+    /// like in `"1,234.56"` or `1_234.56`
+    pub thousands_separators: bool,
+    /// Remove whitespace around fields, including the column names of the header
+    pub trim: bool,
+    /// Maximum length of a row in bytes, not counting the line break, so that a
+    /// corrupted input without line breaks is not read into memory as a whole
+    pub max_line_length: usize,
+    /// Maximum number of fields of a row
+    pub max_fields: usize,
+    /// How transaction IDs are written
+    pub ids: IdFormat,
+    /// Read rows like `#assert,5,available,7.5` as
+    /// [`Assertion`](crate::assertion::Assertion)s rather than transactions or
+    /// comments
+    pub assertions: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            dispute_amounts: AmountPolicy::default(),
+            missing_amounts: MissingAmountPolicy::default(),
+            signed_amounts: false,
+            scientific_amounts: false,
+            max_integer_digits: 12,
+            max_fraction_digits: 8,
+            custom_types: HashSet::new(),
+            encoding: Encoding::default(),
+            type_aliases: HashMap::new(),
+            comment_prefix: None,
+            strip_currency: false,
+            thousands_separators: false,
+            trim: true,
+            max_line_length: 1 << 20,
+            max_fields: 1024,
+            ids: IdFormat::default(),
+            assertions: false,
+        }
     }
 }
 
-pub fn parse_transactions(reader: impl io::BufRead) -> Result<Vec<Transaction>, &'static str> {
-    // As opposed to loading all data into memory
-    // this will reuse a single buffer to process all data
-    let mut rows = reader.lines();
+impl ParseOptions {
+    /// Whether `row` is blank or a comment, and thus contains no transaction
+    /// nor a header
+    pub fn is_ignored(&self, row: &str) -> bool {
+        let row = row.trim_start();
+        row.is_empty()
+            || self
+                .comment_prefix
+                .as_deref()
+                .is_some_and(|prefix| row.starts_with(prefix))
+    }
+
+    /// Like [`ParseOptions::is_ignored`], for a row split into fields
+    fn is_ignored_record(&self, record: &StringRecord) -> bool {
+        record.iter().all(str::is_empty)
+            || record
+                .get(0)
+                .is_some_and(|field| self.is_ignored(field) && !field.trim().is_empty())
+    }
+}
+
+/// Splits rows into fields the way a conformant CSV reader does: fields may be
+/// quoted to contain commas, and quotes within quoted fields are doubled. Rows are
+/// framed by the caller, which knows their exact lines and offsets, so quoted
+/// fields cannot span lines.
+struct FieldSplitter {
+    trim: bool,
+    max_fields: usize,
+    /// Reused for all rows, as building a reader is expensive
+    reader: csv::Reader<io::Cursor<Vec<u8>>>,
+}
+
+impl FieldSplitter {
+    fn new(trim: bool, max_fields: usize) -> Self {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(if trim {
+                csv::Trim::All
+            } else {
+                csv::Trim::None
+            })
+            .from_reader(io::Cursor::new(Vec::new()));
+        Self {
+            trim,
+            max_fields,
+            reader,
+        }
+    }
+
+    fn split(&mut self, row: &str, record: &mut StringRecord) -> Result<(), &'static str> {
+        let input = self.reader.get_mut().get_mut();
+        input.clear();
+        input.extend_from_slice(row.as_bytes());
+        self.reader
+            .seek_raw(io::SeekFrom::Start(0), csv::Position::new())
+            .map_err(|_| "invalid row")?;
+        if !self.reader.read_record(record).map_err(|_| "invalid row")? {
+            record.clear();
+        }
+        if record.len() > self.max_fields {
+            return Err(ROW_EXCEEDS_LIMITS);
+        }
+        Ok(())
+    }
+}
+
+impl Clone for FieldSplitter {
+    fn clone(&self) -> Self {
+        Self::new(self.trim, self.max_fields)
+    }
+}
+
+impl fmt::Debug for FieldSplitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FieldSplitter")
+            .field("trim", &self.trim)
+            .field("max_fields", &self.max_fields)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses an amount, accepting only plain decimal numbers unless `options` say otherwise,
+/// rather than everything `f32::from_str` accepts, like `inf` or `NaN`.
+fn parse_amount(input: &str, options: &ParseOptions) -> Result<f32, &'static str> {
+    let without_separators;
+    let input = if options.thousands_separators && input.contains([',', '_']) {
+        without_separators = remove_thousands_separators(input)?;
+        &without_separators
+    } else {
+        input
+    };
+
+    let unsigned = match input.strip_prefix(['+', '-']) {
+        Some(_) if !options.signed_amounts => return Err("signed amount"),
+        Some(unsigned) => unsigned,
+        None => input,
+    };
+
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some(_) if !options.scientific_amounts => return Err("amount in scientific notation"),
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None),
+    };
+
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let is_digits = |digits: &str| digits.bytes().all(|byte| byte.is_ascii_digit());
+    if (integer.is_empty() && fraction.is_empty()) || !is_digits(integer) || !is_digits(fraction) {
+        return Err("invalid amount");
+    }
+    if integer.len() > options.max_integer_digits || fraction.len() > options.max_fraction_digits {
+        return Err("amount has too many digits");
+    }
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        if digits.is_empty() || !is_digits(digits) {
+            return Err("invalid amount");
+        }
+    }
+
+    let amount = input.parse::<f32>().map_err(|_| "invalid amount")?;
+    if !amount.is_finite() {
+        return Err("amount out of range");
+    }
+    Ok(amount)
+}
+
+/// Symbols accepted in front of amounts with [`ParseOptions::strip_currency`],
+/// with the ISO 4217 code of their currency
+const CURRENCY_SYMBOLS: [(&str, &str); 4] =
+    [("$", "USD"), ("€", "EUR"), ("£", "GBP"), ("¥", "JPY")];
+
+/// Splits a currency symbol or a trailing three-letter code, as well as quotes,
+/// off an amount. Returns the amount and the code of its currency, if any.
+fn strip_currency(input: &str) -> (&str, Option<&str>) {
+    let input = input.trim_matches('"').trim();
+    for (symbol, code) in CURRENCY_SYMBOLS {
+        if let Some(amount) = input.strip_prefix(symbol) {
+            return (amount.trim_start(), Some(code));
+        }
+    }
+    let amount = input.trim_end_matches(|char: char| char.is_ascii_alphabetic());
+    match &input[amount.len()..] {
+        code if code.len() == 3 => (amount.trim_end(), Some(code)),
+        _ => (input, None),
+    }
+}
+
+/// Removes the `,` or `_` between groups of three digits of the integer part of
+/// an amount
+fn remove_thousands_separators(input: &str) -> Result<String, &'static str> {
+    let (integer, rest) = input.split_at(input.find(['.', 'e', 'E']).unwrap_or(input.len()));
+    let digits = integer.trim_start_matches(['+', '-']);
+    let sign = &integer[..integer.len() - digits.len()];
+    let mut groups = digits.split([',', '_']);
+    let first = groups.next().unwrap_or_default();
+    if first.is_empty() || first.len() > 3 || groups.any(|group| group.len() != 3) {
+        return Err("invalid thousands separators");
+    }
+    Ok(format!("{sign}{}{rest}", digits.replace([',', '_'], "")))
+}
+
+/// How to handle a questionable but usable field
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AmountPolicy {
+    /// Silently ignore the field
+    #[default]
+    Ignore,
+    /// Ignore the field but report a warning for the row
+    Warn,
+    /// Treat the row as malformed
+    Reject,
+}
+
+impl FromStr for AmountPolicy {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        use AmountPolicy::*;
+
+        Ok(match string {
+            "ignore" => Ignore,
+            "warn" => Warn,
+            "reject" => Reject,
+            _ => return Err("expected one of ignore, warn, or reject"),
+        })
+    }
+}
+
+/// How to handle a deposit or withdrawal without an amount
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum MissingAmountPolicy {
+    /// Treat the row as malformed
+    Reject,
+    /// Take the amount to be 0
+    #[default]
+    Zero,
+    /// Skip the row like a blank line
+    Skip,
+}
+
+impl FromStr for MissingAmountPolicy {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        use MissingAmountPolicy::*;
+
+        Ok(match string {
+            "reject" => Reject,
+            "zero" => Zero,
+            "skip" => Skip,
+            _ => return Err("expected one of reject, zero, or skip"),
+        })
+    }
+}
+
+/// How transaction IDs are written in the input
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum IdFormat {
+    #[default]
+    Numeric,
+    /// Any string, like a UUID, which is numbered by [`InternedIds`]
+    String,
+}
+
+impl FromStr for IdFormat {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "numeric" => Ok(IdFormat::Numeric),
+            "string" => Ok(IdFormat::String),
+            _ => Err("expected numeric or string"),
+        }
+    }
+}
+
+/// Numbers for transaction IDs of [`IdFormat::String`], counting up from 1 in the
+/// order the IDs first appear, so that the engine can index them like numeric IDs
+#[derive(Debug, Clone, Default)]
+pub struct InternedIds(HashMap<Box<str>, TransactionID>);
+
+impl InternedIds {
+    fn intern(&mut self, id: &str) -> Result<TransactionID, &'static str> {
+        if let Some(number) = self.0.get(id) {
+            return Ok(*number);
+        }
+        let number = TransactionID::try_from(self.0.len() + 1)
+            .map_err(|_| "too many distinct transaction IDs")?;
+        self.0.insert(id.into(), number);
+        Ok(number)
+    }
+
+    /// The IDs with their numbers, ordered by number
+    pub fn numbered(&self) -> Vec<(TransactionID, &str)> {
+        let mut numbered: Vec<_> = self.0.iter().map(|(id, number)| (*number, &**id)).collect();
+        numbered.sort_unstable();
+        numbered
+    }
+
+    /// A CSV file with the columns `tx`, the number, and `id`
+    pub fn to_csv(&self) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let rows = [("tx".to_string(), "id")].into_iter().chain(
+            self.numbered()
+                .into_iter()
+                .map(|(number, id)| (number.to_string(), id)),
+        );
+        for (number, id) in rows {
+            writer
+                .write_record([number.as_str(), id])
+                .expect("writing to memory cannot fail");
+        }
+        let bytes = writer.into_inner().expect("writing to memory cannot fail");
+        String::from_utf8(bytes).expect("IDs are valid UTF-8")
+    }
+}
+
+/// Character encodings of inputs, which are transcoded to UTF-8 while reading
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1, as exported by many Windows systems
+    Latin1,
+}
+
+impl FromStr for Encoding {
+    type Err = &'static str;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Ok(match string.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Encoding::Utf8,
+            "latin-1" | "latin1" | "iso-8859-1" => Encoding::Latin1,
+            _ => return Err("expected utf-8 or latin-1"),
+        })
+    }
+}
+
+/// The error of a row that could not be read at all, after which reading cannot go on
+pub(crate) const READ_FAILED: &str = "failed reading row";
+pub(crate) const ROW_EXCEEDS_LIMITS: &str = "row exceeds limits";
+
+/// The byte order mark some editors put at the start of UTF-8 files
+pub(crate) const BYTE_ORDER_MARK: &str = "\u{feff}";
+
+/// Positions of the known columns within a row
+#[derive(Debug, Clone, PartialEq)]
+struct Columns {
+    ty: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    partner: Option<usize>,
+    timestamp: Option<usize>,
+    currency: Option<usize>,
+    extra: ExtraColumns,
+}
+
+impl Default for Columns {
+    /// The column order used by files without a header row
+    fn default() -> Self {
+        Self {
+            ty: 0,
+            client: 1,
+            tx: 2,
+            amount: Some(3),
+            partner: None,
+            timestamp: None,
+            currency: None,
+            extra: ExtraColumns::default(),
+        }
+    }
+}
+
+impl Columns {
+    /// Parses the first row of a file. Returns `Ok(None)` if it is not a header
+    /// but already a transaction, in which case the default column order applies.
+    fn parse_header(
+        record: &StringRecord,
+        options: &ParseOptions,
+    ) -> Result<Option<Self>, &'static str> {
+        let names: Vec<&str> = record.iter().collect();
+
+        if names
+            .first()
+            .is_some_and(|name| TransactionType::try_from(*name).is_ok())
+        {
+            return Ok(None);
+        }
+
+        let position = |name| names.iter().position(|column| *column == name);
+        Ok(Some(Self {
+            ty: position("type").ok_or("header has no type column")?,
+            client: position("client").ok_or("header has no client column")?,
+            tx: position("tx").ok_or("header has no tx column")?,
+            amount: position("amount"),
+            partner: position("partner"),
+            timestamp: position("timestamp"),
+            currency: position("currency"),
+            extra: ExtraColumns::new(
+                names
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, name)| !name.is_empty() && !KNOWN_COLUMNS.contains(name))
+                    .map(|(index, name)| (index, name.to_string()))
+                    .collect(),
+                options,
+            ),
+        }))
+    }
+}
+
+const KNOWN_COLUMNS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "partner",
+    "timestamp",
+    "currency",
+];
+
+/// The columns of an input besides the known ones, like a memo or a batch ID.
+/// They do not affect transactions, but are kept in the audit log.
+#[derive(Debug, Default, Clone)]
+pub struct ExtraColumns {
+    names: Vec<(usize, String)>,
+    /// Splits rows like the parser does, if there are any columns to capture
+    splitter: Option<FieldSplitter>,
+    /// The fields of the last row captured from, reused for all rows
+    record: StringRecord,
+}
+
+impl ExtraColumns {
+    fn new(names: Vec<(usize, String)>, options: &ParseOptions) -> Self {
+        Self {
+            splitter: (!names.is_empty())
+                .then(|| FieldSplitter::new(options.trim, options.max_fields)),
+            names,
+            record: StringRecord::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The values of these columns in `row`, by column name
+    pub fn capture(&mut self, row: &str) -> BTreeMap<String, String> {
+        let Some(splitter) = &mut self.splitter else {
+            return BTreeMap::new();
+        };
+        if splitter.split(row, &mut self.record).is_err() {
+            return BTreeMap::new();
+        }
+        self.names
+            .iter()
+            .filter_map(|(index, name)| Some((name.clone(), self.record.get(*index)?.to_string())))
+            .collect()
+    }
+}
+
+impl PartialEq for ExtraColumns {
+    fn eq(&self, other: &Self) -> bool {
+        self.names == other.names
+    }
+}
+
+/// Parses rows handed over one at a time, for inputs that do not come as a stream
+#[derive(Debug, Clone)]
+pub struct RowParser {
+    columns: Columns,
+    options: ParseOptions,
+    splitter: FieldSplitter,
+    /// The fields of the current row, reused for all rows
+    record: StringRecord,
+    ids: InternedIds,
+}
+
+impl RowParser {
+    /// A parser for inputs without a header row
+    pub fn new(options: ParseOptions) -> Self {
+        Self {
+            columns: Columns::default(),
+            splitter: FieldSplitter::new(options.trim, options.max_fields),
+            record: StringRecord::new(),
+            options,
+            ids: InternedIds::default(),
+        }
+    }
+
+    /// Takes the columns from the first row of an input. The returned flag is `false`
+    /// if that row was not a header but already a transaction, which then still
+    /// needs to be parsed.
+    pub fn from_first_row(row: &str, options: ParseOptions) -> Result<(Self, bool), &'static str> {
+        if row.len() > options.max_line_length {
+            return Err(ROW_EXCEEDS_LIMITS);
+        }
+        let mut record = StringRecord::new();
+        FieldSplitter::new(options.trim, options.max_fields).split(row, &mut record)?;
+        Self::from_first_record(&record, options)
+    }
+
+    fn from_first_record(
+        record: &StringRecord,
+        options: ParseOptions,
+    ) -> Result<(Self, bool), &'static str> {
+        Ok(match Columns::parse_header(record, &options)? {
+            Some(columns) => (
+                Self {
+                    columns,
+                    splitter: FieldSplitter::new(options.trim, options.max_fields),
+                    record: StringRecord::new(),
+                    options,
+                    ids: InternedIds::default(),
+                },
+                true,
+            ),
+            None => (Self::new(options), false),
+        })
+    }
+
+    /// Parses a row, returning `Ok(None)` if it contains no transaction.
+    /// `warning` is set if the transaction was accepted despite a problem.
+    pub fn parse(
+        &mut self,
+        row: &str,
+        warning: &mut Option<&'static str>,
+    ) -> Result<Option<Transaction>, &'static str> {
+        if row.len() > self.options.max_line_length {
+            return Err(ROW_EXCEEDS_LIMITS);
+        }
+        let mut record = mem::take(&mut self.record);
+        let transaction = self
+            .splitter
+            .split(row, &mut record)
+            .and_then(|()| self.parse_record(&record, warning));
+        self.record = record;
+        transaction
+    }
+
+    fn parse_record(
+        &mut self,
+        record: &StringRecord,
+        warning: &mut Option<&'static str>,
+    ) -> Result<Option<Transaction>, &'static str> {
+        let transaction =
+            Transaction::parse(record, &self.columns, &self.options, &mut self.ids, warning)?;
+        if transaction.is_some() && self.has_partner_column() {
+            validate_partner(self.partner_in(record))?;
+        }
+        if transaction.is_some() && self.has_timestamp_column() {
+            self.timestamp_in(record).ok_or("invalid timestamp")?;
+        }
+        Ok(transaction)
+    }
+
+    /// Whether rows have a `partner` column, in which case every
+    /// transaction has a [`RowParser::partner`]
+    pub fn has_partner_column(&self) -> bool {
+        self.columns.partner.is_some()
+    }
+
+    /// The partner a row was submitted by
+    pub fn partner(&mut self, row: &str) -> Option<String> {
+        self.splitter.split(row, &mut self.record).ok()?;
+        self.partner_in(&self.record).map(String::from)
+    }
+
+    fn partner_in<'a>(&self, record: &'a StringRecord) -> Option<&'a str> {
+        record.get(self.columns.partner?)
+    }
+
+    /// Whether rows have a `timestamp` column, in which case every
+    /// transaction has a [`RowParser::timestamp`]
+    pub fn has_timestamp_column(&self) -> bool {
+        self.columns.timestamp.is_some()
+    }
+
+    /// When a row's transaction happened, in seconds since the Unix epoch
+    pub fn timestamp(&mut self, row: &str) -> Option<u64> {
+        self.splitter.split(row, &mut self.record).ok()?;
+        self.timestamp_in(&self.record)
+    }
+
+    fn timestamp_in(&self, record: &StringRecord) -> Option<u64> {
+        record.get(self.columns.timestamp?)?.parse().ok()
+    }
+
+    /// The columns named in the header besides the known ones
+    pub fn extra_columns(&self) -> &ExtraColumns {
+        &self.columns.extra
+    }
+}
+
+/// Parses transactions one row at a time, so that files of any size can be processed
+pub struct TransactionReader<R> {
+    reader: R,
+    /// The current row, reused for all rows
+    buffer: Vec<u8>,
+    /// The fields of the current row
+    record: StringRecord,
+    /// Whether the buffer holds a first row that turned out not to be a header
+    first_row_pending: bool,
+    encoding: Encoding,
+    parser: RowParser,
+    /// Set if the last transaction was accepted despite a problem
+    warning: Option<&'static str>,
+    line: u64,
+    offset: u64,
+    next_offset: u64,
+    /// Number of rows read so far, not counting the header
+    pub rows_read: u64,
+    /// Number of rows read so far that contained no transaction
+    pub rows_skipped: u64,
+    /// Assertions read since they were last taken
+    assertions: Vec<Assertion>,
+}
+
+/// Where a row is located in the input, for diagnostics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RowContext<'a> {
+    /// 1-based line number
+    pub line: u64,
+    /// Byte offset of the start of the row
+    pub offset: u64,
+    pub text: &'a str,
+    /// The partner the row was submitted by, if the input has a `partner` column
+    pub partner: Option<&'a str>,
+}
+
+impl RowContext<'_> {
+    /// How much of a row is echoed back in diagnostics
+    const MAX_ECHO_LEN: usize = 80;
+
+    /// The row text, truncated to a length suitable for diagnostics
+    pub fn echo(&self) -> String {
+        match self.text.char_indices().nth(Self::MAX_ECHO_LEN) {
+            Some((end, _)) => format!("{}...", &self.text[..end]),
+            None => self.text.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RowContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {} (byte offset {}): {:?}",
+            self.line,
+            self.offset,
+            self.echo()
+        )
+    }
+}
+
+impl<R: io::BufRead> TransactionReader<R> {
+    pub fn new(reader: R) -> Result<Self, &'static str> {
+        Self::with_options(reader, ParseOptions::default())
+    }
 
-    rows.next(); // Skip row of column types
+    pub fn with_options(reader: R, options: ParseOptions) -> Result<Self, &'static str> {
+        let mut transaction_reader = Self {
+            reader,
+            buffer: Vec::new(),
+            record: StringRecord::new(),
+            first_row_pending: false,
+            encoding: options.encoding,
+            parser: RowParser::new(options.clone()),
+            warning: None,
+            line: 0,
+            offset: 0,
+            next_offset: 0,
+            rows_read: 0,
+            rows_skipped: 0,
+            assertions: Vec::new(),
+        };
 
-    let mut transactions = Vec::<Transaction>::new();
-    for row in rows {
-        if let Ok(row) = row {
-            if let Some(transaction) = Transaction::parse(&row)? {
-                transactions.push(transaction);
+        // Blank lines and comments may precede the header
+        while transaction_reader.read_row()?
+            && options.is_ignored(transaction_reader.text().unwrap_or_default())
+        {
+            transaction_reader.rows_read += 1;
+            transaction_reader.rows_skipped += 1;
+        }
+        if !transaction_reader.buffer.is_empty() {
+            transaction_reader.split()?;
+            let (parser, is_header) =
+                RowParser::from_first_record(&transaction_reader.record, options)?;
+            transaction_reader.parser = parser;
+            if !is_header {
+                // The first row is already a transaction, so read it again
+                transaction_reader.first_row_pending = true;
+                transaction_reader.line -= 1;
             }
-        } else {
-            return Err("failed reading row");
         }
+
+        Ok(transaction_reader)
+    }
+
+    /// Reads the next row into the buffer. Returns `false` at the end of the input.
+    fn read_row(&mut self) -> Result<bool, &'static str> {
+        let max_line_length = self.parser.options.max_line_length;
+        self.buffer.clear();
+        // At most the longest row allowed, its line break, and a byte to tell
+        // whether it is longer
+        let limit = max_line_length.saturating_add(3) as u64;
+        let mut row = io::Read::take(&mut self.reader, limit);
+        let read =
+            io::BufRead::read_until(&mut row, b'\n', &mut self.buffer).map_err(|_| READ_FAILED)?;
+        self.offset = self.next_offset;
+        self.next_offset += read as u64;
+        self.line += 1;
+
+        if self.buffer.ends_with(b"\n") {
+            self.buffer.pop();
+            if self.buffer.ends_with(b"\r") {
+                self.buffer.pop();
+            }
+        } else if read as u64 == limit {
+            self.skip_rest_of_line()?;
+        }
+        if self.buffer.len() > max_line_length {
+            self.buffer.truncate(max_line_length);
+            return Err(ROW_EXCEEDS_LIMITS);
+        }
+        if self.offset == 0 && self.buffer.starts_with(BYTE_ORDER_MARK.as_bytes()) {
+            self.buffer.drain(..BYTE_ORDER_MARK.len());
+        }
+        if self.encoding == Encoding::Latin1 && !self.buffer.is_ascii() {
+            // Every byte of Latin-1 is the code point of the same value
+            let text: String = self.buffer.iter().map(|byte| char::from(*byte)).collect();
+            self.buffer = text.into_bytes();
+        }
+        Ok(read > 0)
+    }
+
+    /// Skips the rest of a row that is too long, without keeping it in memory
+    fn skip_rest_of_line(&mut self) -> Result<(), &'static str> {
+        loop {
+            let available = self.reader.fill_buf().map_err(|_| READ_FAILED)?;
+            if available.is_empty() {
+                return Ok(());
+            }
+            let (skipped, end_of_line) = match available.iter().position(|byte| *byte == b'\n') {
+                Some(index) => (index + 1, true),
+                None => (available.len(), false),
+            };
+            self.reader.consume(skipped);
+            self.next_offset += skipped as u64;
+            if end_of_line {
+                return Ok(());
+            }
+        }
+    }
+
+    fn text(&self) -> Result<&str, &'static str> {
+        std::str::from_utf8(&self.buffer).map_err(|_| "row is not valid UTF-8")
+    }
+
+    /// Splits the current row into its fields
+    fn split(&mut self) -> Result<(), &'static str> {
+        let row = std::str::from_utf8(&self.buffer).map_err(|_| "row is not valid UTF-8")?;
+        self.parser.splitter.split(row, &mut self.record)
+    }
+
+    /// A problem with the last transaction that did not prevent it from being read
+    pub fn warning(&self) -> Option<&'static str> {
+        self.warning
+    }
+
+    /// Whether the input has a `partner` column, in which case every
+    /// transaction has a [`TransactionReader::partner`]
+    pub fn has_partner_column(&self) -> bool {
+        self.parser.has_partner_column()
+    }
+
+    /// The partner the last transaction was submitted by
+    pub fn partner(&self) -> Option<&str> {
+        self.parser.partner_in(&self.record)
+    }
+
+    /// Whether the input has a `timestamp` column, in which case every
+    /// transaction has a [`TransactionReader::timestamp`]
+    pub fn has_timestamp_column(&self) -> bool {
+        self.parser.has_timestamp_column()
+    }
+
+    /// The numbers assigned to transaction IDs so far, if they are read as
+    /// [`IdFormat::String`]
+    pub fn interned_ids(&self) -> &InternedIds {
+        &self.parser.ids
+    }
+
+    /// When the last transaction happened, if the input has a `timestamp` column
+    pub fn timestamp(&self) -> Option<u64> {
+        self.parser.timestamp_in(&self.record)
+    }
+
+    /// The assertions read since this was last called, with
+    /// [`ParseOptions::assertions`]
+    pub fn take_assertions(&mut self) -> Vec<Assertion> {
+        std::mem::take(&mut self.assertions)
+    }
+
+    /// How transaction IDs are read
+    pub fn id_format(&self) -> IdFormat {
+        self.parser.options.ids
+    }
+
+    /// The columns named in the header besides the known ones
+    pub fn extra_columns(&self) -> &ExtraColumns {
+        self.parser.extra_columns()
+    }
+
+    /// The row the last transaction or error was read from
+    pub fn row(&self) -> RowContext<'_> {
+        RowContext {
+            line: self.line,
+            offset: self.offset,
+            text: self.text().unwrap_or("<row is not valid UTF-8>"),
+            partner: self.partner(),
+        }
+    }
+}
+
+impl<R: io::BufRead> Iterator for TransactionReader<R> {
+    type Item = Result<Transaction, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.first_row_pending {
+                self.first_row_pending = false;
+                self.line += 1;
+            } else {
+                match self.read_row() {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            self.rows_read += 1;
+
+            let mut warning = None;
+            let parsed = self.split().and_then(|()| {
+                if self.parser.options.assertions {
+                    if let Some(assertion) = Assertion::parse(&self.record, self.line) {
+                        self.assertions.push(assertion?);
+                        return Ok(None);
+                    }
+                }
+                self.parser.parse_record(&self.record, &mut warning)
+            });
+            self.warning = warning;
+            match parsed {
+                Ok(Some(transaction)) => return Some(Ok(transaction)),
+                Ok(None) => self.rows_skipped += 1,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// A stream of transactions along with where each was read from, like a
+/// [`TransactionReader`] or a [`MergedReader`](crate::merge::MergedReader)
+pub trait TransactionSource: Iterator<Item = Result<Transaction, &'static str>> {
+    /// The row the last transaction or error was read from
+    fn row(&self) -> RowContext<'_>;
+    /// A problem with the last transaction that did not prevent it from being read
+    fn warning(&self) -> Option<&'static str>;
+    /// The partner the last transaction was submitted by
+    fn partner(&self) -> Option<&str>;
+    /// When the last transaction happened, if the input has a `timestamp` column
+    fn timestamp(&self) -> Option<u64>;
+    fn has_partner_column(&self) -> bool;
+    fn has_timestamp_column(&self) -> bool;
+    /// The columns named in the header besides the known ones
+    fn extra_columns(&self) -> &ExtraColumns;
+    /// The numbers assigned to transaction IDs so far
+    fn interned_ids(&self) -> &InternedIds;
+    /// Number of rows read so far, not counting headers
+    fn rows_read(&self) -> u64;
+    /// Number of rows read so far that contained no transaction
+    fn rows_skipped(&self) -> u64;
+    /// The assertions read since this was last called, which hold for the
+    /// accounts before the next transaction
+    fn take_assertions(&mut self) -> Vec<Assertion>;
+}
+
+impl<R: io::BufRead> TransactionSource for TransactionReader<R> {
+    fn row(&self) -> RowContext<'_> {
+        TransactionReader::row(self)
+    }
+
+    fn warning(&self) -> Option<&'static str> {
+        TransactionReader::warning(self)
+    }
+
+    fn partner(&self) -> Option<&str> {
+        TransactionReader::partner(self)
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        TransactionReader::timestamp(self)
+    }
+
+    fn has_partner_column(&self) -> bool {
+        TransactionReader::has_partner_column(self)
+    }
+
+    fn has_timestamp_column(&self) -> bool {
+        TransactionReader::has_timestamp_column(self)
+    }
+
+    fn extra_columns(&self) -> &ExtraColumns {
+        TransactionReader::extra_columns(self)
+    }
+
+    fn interned_ids(&self) -> &InternedIds {
+        TransactionReader::interned_ids(self)
     }
 
-    Ok(transactions)
+    fn rows_read(&self) -> u64 {
+        self.rows_read
+    }
+
+    fn rows_skipped(&self) -> u64 {
+        self.rows_skipped
+    }
+
+    fn take_assertions(&mut self) -> Vec<Assertion> {
+        TransactionReader::take_assertions(self)
+    }
+}
+
+/// Checks that transactions with an amount of their own appear in the order of
+/// their IDs, per partner if the input has a `partner` column
+#[derive(Debug, Default)]
+pub struct IdOrderCheck {
+    /// The highest ID so far and its line, by partner
+    last: HashMap<String, (TransactionID, u64)>,
+}
+
+impl IdOrderCheck {
+    pub fn check(&mut self, transaction: &Transaction, row: &RowContext) -> Result<(), String> {
+        if transaction.ty.refers_back() {
+            return Ok(());
+        }
+        let partner = row.partner.unwrap_or_default();
+        match self.last.get_mut(partner) {
+            Some((id, line)) if transaction.id < *id => Err(format!(
+                "transaction {} at line {} is out of order, as transaction {id} came before \
+                 at line {line}",
+                transaction.id, row.line
+            )),
+            Some(last) => {
+                *last = (transaction.id, row.line);
+                Ok(())
+            }
+            None => {
+                self.last
+                    .insert(partner.to_string(), (transaction.id, row.line));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Partners are used in file names, so they are restricted to letters, digits, `-`, and `_`
+fn validate_partner(partner: Option<&str>) -> Result<(), &'static str> {
+    match partner {
+        None | Some("") => Err("no partner"),
+        Some(partner)
+            if partner
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_') =>
+        {
+            Ok(())
+        }
+        Some(_) => Err("invalid partner"),
+    }
+}
+
+pub fn parse_transactions(reader: impl io::BufRead) -> Result<Vec<Transaction>, &'static str> {
+    TransactionReader::new(reader)?.collect()
+}
+
+/// A row skipped by [`parse_transactions_lenient`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalformedRow {
+    /// 1-based line number
+    pub line: u64,
+    pub text: String,
+    pub reason: &'static str,
+}
+
+/// Like [`parse_transactions`], but skips malformed rows instead of failing and
+/// returns them alongside the transactions. Only an invalid header or a failure to
+/// read the input still fails.
+pub fn parse_transactions_lenient(
+    reader: impl io::BufRead,
+    options: ParseOptions,
+) -> Result<(Vec<Transaction>, Vec<MalformedRow>), &'static str> {
+    let mut reader = TransactionReader::with_options(reader, options)?;
+    let (mut transactions, mut malformed) = (Vec::new(), Vec::new());
+    while let Some(transaction) = reader.next() {
+        match transaction {
+            Ok(transaction) => transactions.push(transaction),
+            Err(READ_FAILED) => return Err(READ_FAILED),
+            Err(reason) => malformed.push(MalformedRow {
+                line: reader.line,
+                text: reader.row().text.to_string(),
+                reason,
+            }),
+        }
+    }
+    Ok((transactions, malformed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tx;
+
+    #[test]
+    fn it_validates_the_header() {
+        let reordered = "tx, amount, client, type\n3, 1.5, 2, deposit\n";
+        assert_eq!(
+            parse_transactions(io::Cursor::new(reordered)).unwrap(),
+            [tx(TransactionType::Deposit, 2, 3, 1.5)]
+        );
+
+        let headerless = "deposit, 1, 1, 2.0\nwithdrawal, 1, 2, 1.0\n";
+        assert_eq!(
+            parse_transactions(io::Cursor::new(headerless))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let missing_column = "type, tx, amount\ndeposit, 1, 2.0\n";
+        assert_eq!(
+            parse_transactions(io::Cursor::new(missing_column)),
+            Err("header has no client column")
+        );
+    }
+
+    #[test]
+    fn it_captures_extra_columns() {
+        let input = "type,client,memo,tx,amount,batch id\ndeposit,1,rent,1,5.0,B7\n";
+        let mut reader = TransactionReader::new(io::Cursor::new(input)).unwrap();
+
+        assert_eq!(reader.next().unwrap().unwrap().amount, 5.0);
+        assert_eq!(
+            reader.extra_columns().clone().capture(reader.row().text),
+            BTreeMap::from([
+                ("batch id".to_string(), "B7".to_string()),
+                ("memo".to_string(), "rent".to_string()),
+            ])
+        );
+
+        let options = ParseOptions {
+            trim: false,
+            ..ParseOptions::default()
+        };
+        let input = "type,client,tx,amount,memo\ndeposit,1,1,5.0, rent \n";
+        let mut reader = TransactionReader::with_options(io::Cursor::new(input), options).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(
+            reader.extra_columns().clone().capture(reader.row().text),
+            BTreeMap::from([("memo".to_string(), " rent ".to_string())])
+        );
+        assert!(TransactionReader::new(io::Cursor::new("deposit,1,1,1.0\n"))
+            .unwrap()
+            .extra_columns()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_skips_comments_and_blank_lines() {
+        let input = "# Corrections for March\n\
+                     \n\
+                     type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     \x20\x20# Reverted by ops\n\
+                     \n\
+                     deposit,1,2,1.0\n";
+        assert!(parse_transactions(io::Cursor::new(input)).is_err());
+
+        let options = ParseOptions {
+            comment_prefix: Some("#".to_string()),
+            ..ParseOptions::default()
+        };
+        let mut reader = TransactionReader::with_options(io::Cursor::new(input), options).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next().unwrap().unwrap().id, 2);
+        assert_eq!(reader.row().line, 7);
+        assert!(reader.next().is_none());
+        assert_eq!((reader.rows_read, reader.rows_skipped), (6, 4));
+
+        let headerless = "\n# Deposits\ndeposit,1,1,5.0\n";
+        let options = ParseOptions {
+            comment_prefix: Some("#".to_string()),
+            ..ParseOptions::default()
+        };
+        let mut reader =
+            TransactionReader::with_options(io::Cursor::new(headerless), options).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.row().line, 3);
+    }
+
+    #[test]
+    fn it_normalizes_transaction_types() {
+        let input = "type,client,tx,amount\n\
+                     Deposit,1,1,5.0\n\
+                     WITHDRAWAL,1,2,1.0\n\
+                     Withdraw,1,3,1.0\n\
+                     charge_back,1,1\n";
+        assert_eq!(
+            parse_transactions(io::Cursor::new(input)),
+            Err("invalid transaction type")
+        );
+
+        let options = ParseOptions {
+            type_aliases: parse_type_aliases(
+                "withdraw = \"withdrawal\"\ncharge_back = \"Chargeback\"",
+            )
+            .unwrap(),
+            ..ParseOptions::default()
+        };
+        let types: Vec<TransactionType> =
+            TransactionReader::with_options(io::Cursor::new(input), options)
+                .unwrap()
+                .map(|transaction| transaction.unwrap().ty)
+                .collect();
+        use TransactionType::*;
+        assert_eq!(types, [Deposit, Withdrawal, Withdrawal, Chargeback]);
+
+        assert!(parse_type_aliases("refund = \"deposit back\"").is_err());
+    }
+
+    #[test]
+    fn it_applies_the_dispute_amount_policy() {
+        let input = "type,client,tx,amount\ndispute,1,1,5.0\n";
+        let read = |dispute_amounts| {
+            let options = ParseOptions {
+                dispute_amounts,
+                ..ParseOptions::default()
+            };
+            let mut reader =
+                TransactionReader::with_options(io::Cursor::new(input), options).unwrap();
+            let transaction = reader.next().unwrap();
+            (
+                transaction.map(|transaction| transaction.amount),
+                reader.warning(),
+            )
+        };
+
+        assert_eq!(read(AmountPolicy::Ignore), (Ok(0.0), None));
+        assert!(read(AmountPolicy::Warn).1.is_some());
+        assert!(read(AmountPolicy::Reject).0.is_err());
+    }
+
+    #[test]
+    fn it_applies_the_missing_amount_policy() {
+        let input = "type,client,tx,amount\ndeposit,1,1,\nwithdrawal,1,2\ndispute,1,1\n";
+        let read = |missing_amounts| {
+            let options = ParseOptions {
+                missing_amounts,
+                ..ParseOptions::default()
+            };
+            TransactionReader::with_options(io::Cursor::new(input), options)
+                .unwrap()
+                .map(|transaction| transaction.map(|transaction| transaction.amount))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            read(MissingAmountPolicy::Reject),
+            [Err("no amount"), Err("no amount"), Ok(0.0)]
+        );
+        assert_eq!(read(MissingAmountPolicy::Zero), [Ok(0.0), Ok(0.0), Ok(0.0)]);
+        assert_eq!(read(MissingAmountPolicy::Skip), [Ok(0.0)]);
+    }
+
+    #[test]
+    fn it_reads_quoted_and_padded_fields() {
+        let input = "type,client,tx,amount\n\
+                     \"deposit\",  1,  1,\" 2.5 \"\n\
+                     dispute,1,1\n";
+        let read = |trim| {
+            let options = ParseOptions {
+                trim,
+                ..ParseOptions::default()
+            };
+            TransactionReader::with_options(io::Cursor::new(input), options)
+                .unwrap()
+                .map(|transaction| transaction.map(|transaction| transaction.amount))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(read(true), [Ok(2.5), Ok(0.0)]);
+        assert!(read(false)[0].is_err());
+        assert_eq!(read(false)[1], Ok(0.0));
+    }
+
+    #[test]
+    fn it_numbers_string_transaction_ids() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,3f2a9c1e-8b4d-4e6f-a1c2-5d7e9f0b1a2c,5.0\n\
+                     deposit,1,\"ref,7\",2.0\n\
+                     dispute,1,3f2a9c1e-8b4d-4e6f-a1c2-5d7e9f0b1a2c\n\
+                     deposit,1,,1.0\n";
+        let options = ParseOptions {
+            ids: IdFormat::String,
+            ..ParseOptions::default()
+        };
+        let mut reader = TransactionReader::with_options(io::Cursor::new(input), options).unwrap();
+        let ids: Vec<_> = reader
+            .by_ref()
+            .map(|transaction| transaction.map(|transaction| transaction.id))
+            .collect();
+
+        assert_eq!(ids, [Ok(1), Ok(2), Ok(1), Err("invalid transaction ID")]);
+        assert_eq!(
+            reader.interned_ids().to_csv(),
+            "tx,id\n1,3f2a9c1e-8b4d-4e6f-a1c2-5d7e9f0b1a2c\n2,\"ref,7\"\n"
+        );
+    }
+
+    #[test]
+    fn it_checks_the_order_of_transaction_ids() {
+        let input = "partner,type,client,tx,amount\n\
+                     a,deposit,1,5,1.0\n\
+                     b,deposit,1,2,1.0\n\
+                     a,dispute,1,5\n\
+                     a,withdrawal,1,5,1.0\n\
+                     a,deposit,1,3,1.0\n";
+        let mut reader = TransactionReader::new(io::Cursor::new(input)).unwrap();
+        let mut id_order = IdOrderCheck::default();
+        let outcomes: Vec<_> = std::iter::from_fn(|| {
+            let transaction = reader.next()?.unwrap();
+            Some(id_order.check(&transaction, &reader.row()))
+        })
+        .collect();
+
+        assert_eq!(
+            outcomes,
+            [
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err(
+                    "transaction 3 at line 6 is out of order, as transaction 5 came before \
+                     at line 5"
+                        .to_string()
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn it_limits_the_size_of_rows() {
+        let input = format!(
+            "type,client,tx,amount\n{}\ndeposit,1,1,1.0\n{}\ndeposit,1,2,2.0",
+            "x".repeat(100),
+            ",".repeat(10),
+        );
+        let options = ParseOptions {
+            max_line_length: 50,
+            max_fields: 8,
+            ..ParseOptions::default()
+        };
+        let (transactions, malformed) =
+            parse_transactions_lenient(io::Cursor::new(input), options).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(
+            malformed
+                .iter()
+                .map(|row| (row.line, row.reason))
+                .collect::<Vec<_>>(),
+            [(2, "row exceeds limits"), (4, "row exceeds limits")]
+        );
+    }
+
+    #[test]
+    fn it_rejects_pathological_amounts() {
+        let options = ParseOptions::default();
+        assert_eq!(parse_amount("10.50", &options), Ok(10.5));
+        assert_eq!(parse_amount(".5", &options), Ok(0.5));
+        for amount in ["inf", "NaN", "+-5", "1.2.3", "1e30", "-5", ".", "1_000"] {
+            assert!(parse_amount(amount, &options).is_err(), "{amount}");
+        }
+        assert!(parse_amount("1234567890123", &options).is_err());
+        assert!(parse_amount("1.123456789", &options).is_err());
+
+        let options = ParseOptions {
+            signed_amounts: true,
+            scientific_amounts: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(parse_amount("-5", &options), Ok(-5.0));
+        assert_eq!(parse_amount("1.5e3", &options), Ok(1500.0));
+        assert!(parse_amount("+-5", &options).is_err());
+        assert!(parse_amount("1e", &options).is_err());
+        assert!(parse_amount("1e39", &options).is_err());
+
+        let options = ParseOptions {
+            thousands_separators: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(parse_amount("1,234.5", &options), Ok(1234.5));
+        assert_eq!(parse_amount("1_234_567", &options), Ok(1234567.0));
+        for amount in ["1,23.5", "1234,567", ",123", "1.234,5", "1,,234"] {
+            assert!(parse_amount(amount, &options).is_err(), "{amount}");
+        }
+        let input = "type,client,tx,amount\ndeposit,1,1,\"1,234.5\"\n";
+        let mut reader = TransactionReader::with_options(io::Cursor::new(input), options).unwrap();
+        assert_eq!(
+            reader.next().unwrap(),
+            Ok(tx(TransactionType::Deposit, 1, 1, 1234.5))
+        );
+    }
+
+    #[test]
+    fn it_strips_currencies_from_amounts() {
+        let input = "type,client,tx,amount,currency\n\
+                     deposit,1,1,$10.50,USD\n\
+                     deposit,1,2,\"10.50 EUR\",eur\n\
+                     deposit,1,3,€ 2,\n\
+                     deposit,1,4,10.50 EUR,USD\n\
+                     deposit,1,5,$,\n";
+        assert!(parse_transactions(io::Cursor::new(input)).is_err());
+
+        let options = ParseOptions {
+            strip_currency: true,
+            missing_amounts: MissingAmountPolicy::Reject,
+            ..ParseOptions::default()
+        };
+        let amounts: Vec<_> = TransactionReader::with_options(io::Cursor::new(input), options)
+            .unwrap()
+            .map(|transaction| transaction.map(|transaction| transaction.amount))
+            .collect();
+        assert_eq!(
+            amounts,
+            [
+                Ok(10.5),
+                Ok(10.5),
+                Ok(2.0),
+                Err("amount is not in the currency of the currency column"),
+                Err("no amount"),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_locates_rows() {
+        let input = "type,client,tx,amount\r\ndeposit,1,1,1.0\r\ndeposit,x,2,1.0\n";
+        let mut reader = TransactionReader::new(io::Cursor::new(input)).unwrap();
+
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next().unwrap(), Err("invalid client ID"));
+        assert_eq!(
+            reader.row(),
+            RowContext {
+                line: 3,
+                offset: 40,
+                text: "deposit,x,2,1.0",
+                partner: None,
+            }
+        );
+        assert!(reader.next().is_none());
+
+        let long_row = RowContext {
+            line: 1,
+            offset: 0,
+            text: &"1".repeat(100),
+            partner: None,
+        };
+        assert_eq!(long_row.echo(), format!("{}...", "1".repeat(80)));
+    }
+
+    #[test]
+    fn it_collects_malformed_rows() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,x,2,1.0\n\
+                     refund,1,3,1.0\n\
+                     withdrawal,1,4,0.5\n";
+        let (transactions, malformed) =
+            parse_transactions_lenient(io::Cursor::new(input), ParseOptions::default()).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(
+            malformed,
+            [
+                MalformedRow {
+                    line: 3,
+                    text: "deposit,x,2,1.0".to_string(),
+                    reason: "invalid client ID",
+                },
+                MalformedRow {
+                    line: 4,
+                    text: "refund,1,3,1.0".to_string(),
+                    reason: "invalid transaction type",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_tolerates_windows_exports() {
+        let input = b"\xef\xbb\xbftype,client,tx,amount\r\ndeposit,1,1,1.0\r\n";
+        assert_eq!(parse_transactions(io::Cursor::new(input)).unwrap().len(), 1);
+        let headerless = b"\xef\xbb\xbfdeposit,1,1,1.0\r\n";
+        assert_eq!(
+            parse_transactions(io::Cursor::new(headerless))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let latin1 = b"type,client,tx,amount,memo\ndeposit,1,1,1.0,Gr\xfc\xdfe\n";
+        assert!(parse_transactions(io::Cursor::new(latin1)).is_err());
+        let options = ParseOptions {
+            encoding: Encoding::Latin1,
+            ..ParseOptions::default()
+        };
+        let mut reader = TransactionReader::with_options(io::Cursor::new(latin1), options).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.row().text, "deposit,1,1,1.0,Grüße");
+    }
+
+    #[test]
+    fn it_rejects_disputes_from_other_clients() {
+        let mut account = Account::default();
+        let mut past_transactions = HashMap::new();
+        let deposit = tx(TransactionType::Deposit, 1, 1, 10.0);
+        deposit
+            .process(&mut account, &mut past_transactions)
+            .unwrap();
+
+        let mut other_account = Account::default();
+        let dispute = tx(TransactionType::Dispute, 2, 1, 0.0);
+        assert_eq!(
+            dispute.process(&mut other_account, &mut past_transactions),
+            Err(Rejection::ClientMismatch)
+        );
+        assert_eq!(other_account, Account::default());
+        assert_eq!(
+            past_transactions[&1].dispute_state,
+            DisputeState::Undisputed
+        );
+    }
+
+    #[test]
+    fn it_withdraws_from_the_total() {
+        // With funds held, the total is more than what is available
+        let mut account = Account {
+            available: 10.0,
+            held: 5.0,
+            total: 15.0,
+            ..Account::default()
+        };
+        let withdrawal = tx(TransactionType::Withdrawal, 1, 3, 3.0);
+        withdrawal
+            .process(&mut account, &mut HashMap::new())
+            .unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (7.0, 5.0, 12.0)
+        );
+    }
 }
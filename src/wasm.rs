@@ -0,0 +1,48 @@
+//! JS bindings for running the engine in a browser, built with
+//! `--target wasm32-unknown-unknown --features wasm`.
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::session;
+
+/// Processes a complete CSV input and returns the resulting accounts as CSV.
+/// Rejected transactions are left out, like on the command line.
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv(input: &str) -> Result<String, String> {
+    session::process_csv(input)
+}
+
+/// Processes an input handed over in chunks, such as a file being read or a
+/// stream of rows, keeping the engine's state across calls
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Session(session::Session);
+
+#[wasm_bindgen]
+impl Session {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Processes the complete rows in the next chunk of input. Rows may be split
+    /// across chunks.
+    pub fn push(&mut self, chunk: &str) -> Result<(), String> {
+        self.0.push(chunk)
+    }
+
+    /// Processes the last row of an input that does not end with a newline
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.0.finish()
+    }
+
+    /// The accounts so far, as CSV
+    pub fn accounts(&self) -> String {
+        self.0.accounts_csv()
+    }
+
+    /// The summary of the rows so far, as JSON
+    pub fn summary(&self) -> String {
+        serde_json::to_string(&self.0.summary()).expect("summaries are always serializable")
+    }
+}
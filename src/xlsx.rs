@@ -0,0 +1,127 @@
+//! Excel workbooks as input. The first sheet holds the same columns as a CSV
+//! input, with a header row, and is turned into one so it is checked like any
+//! other input.
+
+use std::io::{Read, Seek};
+
+use calamine::{Data, Range, Reader, Xlsx};
+
+/// The days between Excel's epoch of 1899-12-30 and the Unix epoch
+const UNIX_EPOCH_DAYS: f64 = 25_569.0;
+
+/// The first sheet of the workbook in `reader` as a CSV input
+pub fn to_csv<R: Read + Seek>(reader: R) -> Result<String, String> {
+    let mut workbook = Xlsx::new(reader).map_err(|err| err.to_string())?;
+    let sheet = workbook
+        .worksheet_range_at(0)
+        .ok_or("the workbook has no sheets")?
+        .map_err(|err| err.to_string())?;
+    sheet_to_csv(&sheet)
+}
+
+fn sheet_to_csv(sheet: &Range<Data>) -> Result<String, String> {
+    let (first_row, first_column) = sheet.start().unwrap_or_default();
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for (index, row) in sheet.rows().enumerate() {
+        // Rows left empty, like those cleared by hand, are not transactions
+        if row.iter().all(|cell| *cell == Data::Empty) {
+            continue;
+        }
+        let fields = row
+            .iter()
+            .enumerate()
+            .map(|(column, cell)| {
+                cell_to_string(cell).map_err(|err| {
+                    let row = first_row as usize + index + 1;
+                    let column = column_name(first_column as usize + column);
+                    format!("{err} in cell {column}{row}")
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        writer
+            .write_record(&fields)
+            .map_err(|err| err.to_string())?;
+    }
+    let bytes = writer.into_inner().map_err(|err| err.to_string())?;
+    Ok(String::from_utf8(bytes).expect("the fields are strings"))
+}
+
+/// The value of `cell` as it would be exported to CSV by Excel. Numbers are
+/// rounded to the 15 significant digits Excel shows, so that amounts computed in
+/// a sheet, like 0.1 + 0.2, are not written as 0.30000000000000004. Dates are
+/// written as seconds since the Unix epoch, as in the `timestamp` column.
+fn cell_to_string(cell: &Data) -> Result<String, String> {
+    Ok(match cell {
+        Data::Empty => String::new(),
+        Data::String(string) | Data::DateTimeIso(string) | Data::DurationIso(string) => {
+            string.clone()
+        }
+        Data::Int(int) => int.to_string(),
+        Data::Float(float) => {
+            let rounded: f64 = format!("{float:.14e}")
+                .parse()
+                .expect("floats are parseable");
+            rounded.to_string()
+        }
+        Data::Bool(bool) => bool.to_string(),
+        Data::DateTime(date_time) => {
+            let seconds = ((date_time.as_f64() - UNIX_EPOCH_DAYS) * 86_400.0).round();
+            if seconds < 0.0 {
+                return Err("date before 1970".to_string());
+            }
+            (seconds as u64).to_string()
+        }
+        Data::Error(err) => return Err(format!("error {err}")),
+    })
+}
+
+/// The name of a column like `AB`, from its index starting at 0
+fn column_name(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).expect("column names are ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use calamine::{CellErrorType, ExcelDateTime, ExcelDateTimeType};
+
+    use super::*;
+
+    #[test]
+    fn it_converts_sheets_to_csv() {
+        let mut sheet = Range::new((1, 1), (4, 4));
+        let header = ["type", "client", "tx", "amount"];
+        for (column, name) in header.into_iter().enumerate() {
+            sheet.set_value((1, 1 + column as u32), Data::String(name.to_string()));
+        }
+        sheet.set_value((2, 1), Data::String("deposit".to_string()));
+        sheet.set_value((2, 2), Data::Float(1.0));
+        sheet.set_value((2, 3), Data::Int(1));
+        sheet.set_value((2, 4), Data::Float(0.1 + 0.2));
+        sheet.set_value((4, 1), Data::String("dispute".to_string()));
+        sheet.set_value((4, 2), Data::Float(1.0));
+        sheet.set_value((4, 3), Data::Float(1.0));
+        assert_eq!(
+            sheet_to_csv(&sheet).unwrap(),
+            "type,client,tx,amount\ndeposit,1,1,0.3\ndispute,1,1,\n"
+        );
+
+        sheet.set_value((3, 4), Data::Error(CellErrorType::Div0));
+        assert_eq!(
+            sheet_to_csv(&sheet),
+            Err("error #DIV/0! in cell E4".to_string())
+        );
+
+        let date = ExcelDateTime::new(45_444.5, ExcelDateTimeType::DateTime, false);
+        assert_eq!(cell_to_string(&Data::DateTime(date)).unwrap(), "1717243200");
+        assert_eq!(column_name(27), "AB");
+    }
+}